@@ -1,4 +1,10 @@
 fn main() {
     #[cfg(windows)]
     panic!("This program does not support Windows.");
+
+    // `self-update` downloads the release built for the same target
+    println!(
+        "cargo:rustc-env=CONFINUUM_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
 }
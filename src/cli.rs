@@ -4,17 +4,29 @@ use std::{
     borrow::Cow,
     cell::RefCell,
     fs::{self, File},
-    io::{BufWriter, Write},
+    io::{BufWriter, IsTerminal, Write},
     path::PathBuf,
-    rc::Rc,
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        mpsc, Mutex,
+    },
 };
 
 use anyhow::{anyhow, Result};
-use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueHint};
+use clap::{error::ErrorKind, ArgAction, CommandFactory, Parser, Subcommand, ValueHint};
 use clap_complete::Shell;
-use spinoff::{spinners::SpinnerFrames, Color, Spinner};
+use rayon::prelude::*;
+use spinoff::{
+    spinners::{self, SpinnerFrames},
+    Color, Spinner,
+};
 
-use crate::{commands, github};
+use crate::{
+    commands, completions,
+    config::{self, ConfinuumConfig, GitProtocol, Hosting, LinkMode, SignatureSource},
+    deployment, git, github, lock, logging, updates,
+};
 
 #[derive(Debug, Parser)]
 #[command(
@@ -24,9 +36,69 @@ use crate::{commands, github};
     long_about = None,
 )]
 #[command(propagate_version = true)]
+#[command(after_help = "Exit status:
+  0  Success
+  1  Failure not covered by another code
+  2  Confinuum has not been initialized (run `confinuum init`)
+  3  The remote has changes that need to be pulled (run `confinuum update`)
+  4  Authentication with the remote or GitHub failed
+  5  Merging remote changes resulted in conflicts
+  6  The GitHub authorization expired or was revoked (run `confinuum auth login`)
+  7  Another confinuum process kept the config locked for over a minute
+  130  Interrupted by Ctrl-C or SIGTERM")]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+    /// Don't pipe long output (such as diffs) through a pager
+    #[arg(long, global = true)]
+    pub no_pager: bool,
+    /// Run entries' pre/post deploy hooks when deploying them
+    /// Setting CONFINUUM_RUN_HOOKS does the same for every command on a machine.
+    #[arg(long, global = true)]
+    pub run_hooks: bool,
+    /// Commit uncommitted changes already in the config repo along with a command's own changes,
+    /// instead of leaving them out with a warning
+    #[arg(long, global = true)]
+    pub include_dirty: bool,
+    /// Print extra diagnostics, such as which credentials were used to authenticate with the remote
+    /// (-vv and -vvv for more detail, RUST_LOG overrides the level)
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Don't print progress or informational messages, only errors, warnings and a line with the
+    /// outcome (-qq leaves that out too, so nothing is printed on success). For scripts and cron.
+    #[arg(short, long, global = true, action = ArgAction::Count, conflicts_with = "verbose")]
+    pub quiet: u8,
+    /// Don't contact the remote: skip checking it for changes and pushing to it
+    #[arg(long, global = true)]
+    pub offline: bool,
+    /// Name to commit changes as, instead of the one from the signature source (requires --email)
+    #[arg(
+        long = "name",
+        value_name = "NAME",
+        global = true,
+        requires = "author_email"
+    )]
+    pub author_name: Option<String>,
+    /// Email to commit changes as, instead of the one from the signature source (requires --name)
+    #[arg(
+        long = "email",
+        value_name = "EMAIL",
+        global = true,
+        requires = "author_name"
+    )]
+    pub author_email: Option<String>,
+    /// Use auth files (like hosts.toml) even if other users can read them, with a warning
+    #[arg(long, global = true)]
+    pub insecure_auth_files: bool,
+    /// Don't show spinners, just print the outcome of each step (the default when output isn't a terminal)
+    #[arg(long, global = true)]
+    pub no_progress: bool,
+    /// Subject of the commit message, instead of the generated one (the list of files is kept)
+    #[arg(short, long, value_name = "SUBJECT", global = true)]
+    pub message: Option<String>,
+    /// Open the commit message in $VISUAL or $EDITOR before committing
+    #[arg(long, global = true)]
+    pub edit: bool,
 }
 
 #[derive(Debug, Subcommand)]
@@ -35,11 +107,26 @@ pub enum EntryCommand {
     #[command(about = "Create a new config entry", long_about = None)]
     Create {
         /// Files to add to the config entry (optional, you can add files later)
-        #[clap(value_hint = ValueHint::FilePath)]
+        #[clap(value_hint = ValueHint::FilePath, conflicts_with = "from")]
         files: Option<Vec<PathBuf>>,
+        /// Create the entry from files already in the config repo, in this directory (relative to the repo)
+        #[arg(long, value_name = "DIR")]
+        from: Option<PathBuf>,
+        /// Directory to deploy the files from --from to (asked for if not given)
+        #[arg(long, value_name = "DIR", requires = "from", value_hint = ValueHint::DirPath)]
+        target: Option<PathBuf>,
         /// Push the new config entry to the remote repo(s) after creating it, instead of waiting for a manual push (without this flag the change(s) will be committed locally but not pushed)
         #[clap(short = 'p', long)]
         push: bool,
+        /// Copy the file a symlink points to when one of the files is a symlink (by default symlinks are refused, since deploying them would leave a link pointing at a link)
+        #[arg(long, overrides_with = "no_follow_symlinks")]
+        follow_symlinks: bool,
+        /// Refuse to add files that are symlinks (the default)
+        #[arg(long, overrides_with = "follow_symlinks")]
+        no_follow_symlinks: bool,
+        /// Don't list the files and ask for confirmation before copying them
+        #[arg(short = 'y', long)]
+        yes: bool,
     },
     #[command(about = "Delete the config entry (files will be restored to their original locations)", long_about = None)]
     Delete {
@@ -54,25 +141,64 @@ pub enum EntryCommand {
         push: bool,
     },
     #[command(about = "List files in the config entry", long_about = None)]
-    Show,
+    Show {
+        /// Show the size of each file, and the total size of the entry
+        #[arg(long)]
+        stat: bool,
+        /// Characters to draw the file tree with (detected from the locale and terminal by default)
+        #[arg(long, value_name = "STYLE")]
+        tree_style: Option<commands::TreeStyle>,
+    },
+    #[command(about = "Set the description shown for the config entry by `list` and `show`", long_about = None)]
+    #[command(visible_alias = "describe")]
+    SetDescription {
+        /// The new description (prompted for if not given)
+        #[arg(conflicts_with = "clear")]
+        description: Option<String>,
+        /// Remove the description
+        #[arg(long)]
+        clear: bool,
+    },
+    #[command(about = "Push only the local commits that change the config entry, keeping the others local", long_about = None)]
+    Push,
+    #[command(about = "Link each of the entry's files, or its whole directory as one link", long_about = None)]
+    SetLinkMode {
+        /// `files` for a link per file, `directory` to link the target directory itself
+        #[arg(value_enum)]
+        link_mode: LinkMode,
+    },
     #[command(about = "Check if the config entry is up to date", long_about = None)]
     Check {
         /// Print the diff between the local and remote config files
         #[arg(short = 'd', long)]
         print_diff: bool,
+        /// Highlight changed words within modified lines of the diff
+        #[arg(short = 'w', long, requires = "print_diff")]
+        word_diff: bool,
     },
     #[command(about = "Add one or more files to an existing config entry", long_about = None)]
     #[command(visible_alias = "add")]
     AddFiles {
+        /// The files to add (picked from the entry's target directory when left out)
         #[clap(value_hint = ValueHint::FilePath)]
         files: Vec<PathBuf>,
         /// Push new files to the remote repo immediately, instead of waiting for a manual push (without this flag the change(s) will be committed locally but not pushed)
         #[clap(short = 'p', long)]
         push: bool,
+        /// Copy the file a symlink points to when one of the files is a symlink (by default symlinks are refused, since deploying them would leave a link pointing at a link)
+        #[arg(long, overrides_with = "no_follow_symlinks")]
+        follow_symlinks: bool,
+        /// Refuse to add files that are symlinks (the default)
+        #[arg(long, overrides_with = "follow_symlinks")]
+        no_follow_symlinks: bool,
+        /// Don't list the files and ask for confirmation before copying them
+        #[arg(short = 'y', long)]
+        yes: bool,
     },
     #[command(about = "Remove one or more files from an existing config entry (files will be restored to their original locations)", long_about = None)]
     #[command(visible_alias = "rm", visible_alias = "remove")]
     RemoveFiles {
+        /// The files to remove (picked from the entry's files when left out)
         #[clap(value_hint = ValueHint::FilePath)]
         files: Vec<PathBuf>,
         /// Don't ask for confirmation before removing the file(s)
@@ -85,6 +211,62 @@ pub enum EntryCommand {
         #[clap(short = 'p', long)]
         push: bool,
     },
+    #[command(about = "Stop managing one or more files, leaving their current contents in place (deployed links are replaced with copies)", long_about = None)]
+    Untrack {
+        #[clap(value_hint = ValueHint::FilePath, required = true)]
+        files: Vec<PathBuf>,
+        /// Push changes to the remote repo instead of waiting for a manual push (without this flag the change(s) will be committed locally but not pushed)
+        #[clap(short = 'p', long)]
+        push: bool,
+    },
+    #[command(about = "Stop deploying the config entry without deleting it (its deployed files are removed)", long_about = None)]
+    Disable,
+    #[command(about = "Deploy a disabled config entry again", long_about = None)]
+    Enable,
+    #[command(about = "Manage the tags of the config entry", long_about = None)]
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+#[command(about, author, version, arg_required_else_help = true)]
+pub enum TagCommand {
+    #[command(about = "Add one or more tags to the entry", long_about = None)]
+    Add {
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+    #[command(about = "Remove one or more tags from the entry", long_about = None)]
+    #[command(visible_alias = "rm")]
+    Remove {
+        #[arg(required = true)]
+        tags: Vec<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+#[command(about, author, version, arg_required_else_help = true)]
+pub enum RemoteCommand {
+    #[command(about = "Add a remote to a config created with `init --local`, and offer to push the config to it", long_about = None)]
+    Add {
+        /// URL of an empty remote repository
+        #[clap(value_hint = ValueHint::Url)]
+        url: String,
+    },
+    #[command(about = "Change the URL of the remote (updates the stored git protocol to match)", long_about = None)]
+    SetUrl {
+        /// The new URL of the remote repository
+        #[clap(value_hint = ValueHint::Url)]
+        url: String,
+    },
+    #[command(about = "Remove the remote, keeping the config only on this machine", long_about = None)]
+    #[command(visible_alias = "rm")]
+    Remove,
+    #[command(about = "List the config repo's remotes and their URLs", long_about = None)]
+    #[command(visible_alias = "ls")]
+    List,
 }
 
 #[derive(Debug, Subcommand)]
@@ -104,6 +286,45 @@ pub enum UtilCommand {
         #[clap(value_hint = ValueHint::FilePath)]
         output: Option<PathBuf>,
     },
+    /// Print the name of each entry, for the shell completions
+    #[command(hide = true)]
+    ListEntries,
+}
+
+#[derive(Debug, Subcommand)]
+#[command(about, author, version, arg_required_else_help = true)]
+pub enum AuthCommand {
+    #[command(about = "Log in to GitHub or GitLab (replaces any stored credentials)", long_about = None)]
+    Login {
+        /// Read a personal access token from stdin instead of using the browser (or prompting for it, with --gitlab and --gitea)
+        #[arg(long)]
+        with_token: bool,
+        /// Log in to GitLab with a personal access token instead of GitHub
+        #[arg(long)]
+        gitlab: bool,
+        /// URL of a self-hosted GitLab instance to log in to (implies --gitlab)
+        #[arg(long, value_name = "URL", value_hint = ValueHint::Url)]
+        gitlab_url: Option<String>,
+        /// Log in to Codeberg (or the instance given with --gitea-url) with an access token instead of GitHub
+        #[arg(long, conflicts_with_all = ["gitlab", "gitlab_url"])]
+        gitea: bool,
+        /// URL of a Gitea or Forgejo instance to log in to (implies --gitea)
+        #[arg(long, value_name = "URL", value_hint = ValueHint::Url, conflicts_with_all = ["gitlab", "gitlab_url"])]
+        gitea_url: Option<String>,
+    },
+    #[command(about = "Remove the stored GitHub credentials", long_about = None)]
+    Logout {
+        /// Remove the stored GitLab credentials instead
+        #[arg(long)]
+        gitlab: bool,
+        /// Remove the stored Gitea credentials instead (for all instances)
+        #[arg(long, conflicts_with = "gitlab")]
+        gitea: bool,
+    },
+    #[command(about = "Update the name and email used to sign commits from your GitHub account", long_about = None)]
+    Refresh,
+    #[command(about = "Show the logged in GitHub user and check that the token works", long_about = None)]
+    Status,
 }
 
 #[derive(Debug, Subcommand)]
@@ -118,38 +339,295 @@ pub enum Command {
     #[command(about = "Initialize the confinuum config file", long_about = None)]
     Init {
         /// Initialize from git repo containing an existing confinuum config
-        #[arg(long, value_hint=ValueHint::Url)]
+        #[arg(long, value_hint=ValueHint::Url, conflicts_with_all = ["create_repo", "remote"])]
         git: Option<String>,
-        /// Force overwrite of config file if it already exists
+        /// Force overwrite of config file if it already exists (it's backed up first)
         #[clap(short, long)]
         force: bool,
+        /// Back up the whole config repo before overwriting it, not just config.toml
+        #[arg(long, requires = "force")]
+        backup_repo: bool,
+        /// Create a new repository with this name to host your configs
+        #[arg(
+            long,
+            alias = "repo-name",
+            value_name = "NAME",
+            conflicts_with = "remote"
+        )]
+        create_repo: Option<String>,
+        /// Create the repository from a GitHub template repository (owner/repo) containing a confinuum config
+        #[arg(long, value_name = "OWNER/REPO", conflicts_with_all = ["git", "remote", "gitlab_url", "gitea_url"])]
+        template: Option<String>,
+        /// Service to create the repository on (default github)
+        #[arg(long, value_name = "SERVICE", requires = "create_repo")]
+        host: Option<Hosting>,
+        /// URL of a self-hosted GitLab instance, to create the repository on or sign commits with
+        #[arg(long, value_name = "URL", value_hint = ValueHint::Url, conflicts_with = "git")]
+        gitlab_url: Option<String>,
+        /// URL of a Gitea or Forgejo instance other than Codeberg, to create the repository on or sign commits with
+        #[arg(long, value_name = "URL", value_hint = ValueHint::Url, conflicts_with_all = ["git", "gitlab_url"])]
+        gitea_url: Option<String>,
+        /// Use an existing remote repository to host your configs
+        #[arg(long, value_name = "URL", value_hint = ValueHint::Url)]
+        remote: Option<String>,
+        /// Only create the local config repo, without a remote (add one later with `confinuum remote add`)
+        #[arg(long, conflicts_with_all = ["git", "create_repo", "template", "host", "gitlab_url", "gitea_url", "remote", "protocol", "public", "private", "description"])]
+        local: bool,
+        /// Protocol to use for the created repository
+        #[arg(long)]
+        protocol: Option<GitProtocol>,
+        /// Where to get the name and email used to sign commits
+        #[arg(long)]
+        signature: Option<SignatureSource>,
+        /// Make the created repository public
+        #[arg(long, conflicts_with = "private")]
+        public: bool,
+        /// Make the created repository private (default)
+        #[arg(long)]
+        private: bool,
+        /// Description of the created repository
+        #[arg(long)]
+        description: Option<String>,
+        /// Don't prompt for anything, fail if a required option is missing
+        #[arg(short = 'y', long)]
+        yes: bool,
+        /// Shallow clone the config repo with this many commits of history (used with --git)
+        #[arg(long, requires = "git", value_parser = clap::value_parser!(u32).range(1..))]
+        depth: Option<u32>,
     },
     #[command(about = "Create, modify and view entries", long_about = None)]
     Entry {
-        /// Name of the config entry
-        name: String,
+        /// Name of the config entry (`show`, `check` and `push` let you pick it when left out)
+        name: Option<String>,
         /// Action to perform on the entry
         #[command(subcommand)]
         command: EntryCommand,
     },
     #[command(about = "List all config entries", long_about = None)]
     #[command(visible_alias = "ls")]
-    List,
+    List {
+        /// Only list entries with this tag (can be given more than once)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Only list entries whose name contains this (ignoring case)
+        #[arg(long, value_name = "TEXT")]
+        filter: Option<String>,
+        /// The order to list the entries in
+        #[arg(long, value_enum, default_value = "name")]
+        sort: commands::ListSort,
+        /// Show a table with each entry's target, tags, deploy status and date of the last change
+        #[arg(short, long)]
+        long: bool,
+    },
+    #[command(about = "Show the history of config changes", long_about = None)]
+    Log {
+        /// Only show commits that changed this config entry (optional)
+        name: Option<String>,
+        /// Limit the number of commits shown
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+        /// Only show commits made on or after this date (YYYY-MM-DD, optionally with HH:MM)
+        #[arg(long, value_name = "DATE")]
+        since: Option<commands::LogDate>,
+        /// Only show commits made on or before this date (YYYY-MM-DD, optionally with HH:MM)
+        #[arg(long, value_name = "DATE")]
+        until: Option<commands::LogDate>,
+    },
+    #[command(about = "Show changes to the config since the last commit", long_about = None)]
+    Diff {
+        /// Only show changes to this config entry (optional)
+        name: Option<String>,
+        /// Highlight changed words within modified lines
+        #[arg(short = 'w', long)]
+        word_diff: bool,
+    },
+    #[command(about = "Search the files of all config entries for a pattern", long_about = None)]
+    Grep {
+        /// The regular expression to search for
+        pattern: String,
+        /// Only search the files of this config entry (optional)
+        name: Option<String>,
+        /// Match regardless of case
+        #[arg(short = 'i', long)]
+        ignore_case: bool,
+        /// Only print the files that match, not the matching lines
+        #[arg(short = 'l', long)]
+        files_with_matches: bool,
+        /// Print each match as a line of JSON, for scripts
+        #[arg(long)]
+        json: bool,
+    },
     #[command(about = "Push config changes to remote repo", long_about = None)]
-    Push,
+    Push {
+        /// Only push the local commits that change entries with this tag, keeping the others local (can be given more than once)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
     #[command(about = "Check for config updates", long_about = None)]
     #[command(visible_alias = "?")]
     Check {
         /// Print the diff between the local and remote config files
         #[arg(short = 'd', long)]
         print_diff: bool,
+        /// Highlight changed words within modified lines of the diff
+        #[arg(short = 'w', long, requires = "print_diff")]
+        word_diff: bool,
+        /// Print a single stable line for scripts and shell prompts: `up-to-date`, or
+        /// `behind:<commits>:<entries>` with the number of commits to pull and a comma separated
+        /// list of the entries they change (e.g. `behind:3:nvim,zsh`)
+        #[arg(long, conflicts_with = "print_diff")]
+        porcelain: bool,
         /// Check for updates for a specific config entry (optional)
         name: Option<String>,
+        /// Only report entries with this tag (can be given more than once)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
     },
     #[command(name="update", about = "Update config from the remote repo", long_about = None)]
     Update,
+    #[command(about = "Import existing dotfiles into new config entries", long_about = None)]
+    Import {
+        /// A GNU Stow directory, each package in it becomes an entry
+        #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+        #[arg(required_unless_present = "bare_repo", conflicts_with = "bare_repo")]
+        stow: Option<PathBuf>,
+        /// A bare git repo whose work tree is --target (e.g. ~/.dotfiles), its files are grouped
+        /// into entries and deployed in place of the originals
+        #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+        bare_repo: Option<PathBuf>,
+        /// The directory the packages were stowed into, or the bare repo's work tree (defaults to $HOME)
+        #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+        target: Option<PathBuf>,
+        /// Remove stow's links and deploy the new entries in their place
+        #[arg(long, conflicts_with = "bare_repo")]
+        unstow: bool,
+        /// Only show the entries that would be created
+        #[arg(long)]
+        dry_run: bool,
+    },
+    #[command(about = "Export deployed files as an archive or shell script, for machines without confinuum", long_about = None)]
+    Export {
+        /// The entries to export (defaults to all enabled entries)
+        entries: Vec<String>,
+        /// The format to write
+        #[arg(long, value_enum, default_value = "tar.gz")]
+        format: commands::ExportFormat,
+        /// The file to write to (defaults to stdout)
+        #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+        out: Option<PathBuf>,
+        /// Only export entries with this tag (can be given more than once)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
     #[command(name = "redeploy", about = "Redeploy all configs", long_about = None)]
-    Redeploy,
+    Redeploy {
+        /// Only redeploy this entry
+        #[arg(conflicts_with_all = ["only", "except"])]
+        name: Option<String>,
+        /// Only redeploy these entries (comma separated)
+        #[arg(long, value_delimiter = ',', conflicts_with = "except")]
+        only: Vec<String>,
+        /// Redeploy all entries except these (comma separated)
+        #[arg(long, value_delimiter = ',')]
+        except: Vec<String>,
+        /// Only redeploy entries with this tag (can be given more than once)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+        /// Back up and replace files at target paths that aren't links to the config, and deploy even
+        /// if entries have files at the same path (the entry last by name wins)
+        #[arg(long)]
+        force: bool,
+        /// Replace the files without asking for confirmation
+        #[arg(short = 'y', long, requires = "force")]
+        yes: bool,
+    },
+    #[command(about = "Remove the deployed links, leaving the config as it is", long_about = None)]
+    Undeploy {
+        /// The entry to undeploy (defaults to all entries)
+        name: Option<String>,
+        /// Replace the links with copies of the files instead of removing them
+        #[arg(long)]
+        restore: bool,
+    },
+    #[command(about = "Restore deployed files as plain copies, then delete the local config and credentials", long_about = None)]
+    Uninstall {
+        /// Keep the local clone of the config
+        #[arg(long)]
+        keep_repo: bool,
+        /// Keep the stored credentials
+        #[arg(long)]
+        keep_auth: bool,
+        /// Uninstall without asking for confirmation
+        #[clap(short = 'y', long = "yes")]
+        no_confirm: bool,
+    },
+    #[command(about = "Roll the config back to the state of a previous commit", long_about = None)]
+    Restore {
+        /// The commit to restore (anything git understands, e.g. a commit hash or HEAD~2)
+        commit: String,
+        /// Reset the branch to the commit instead of creating a new commit (rewrites history)
+        #[arg(long)]
+        hard: bool,
+        /// Restore even if there are uncommitted changes, discarding them
+        #[arg(short, long)]
+        force: bool,
+    },
+    #[command(about = "Show uncommitted changes to deployed files and offer to commit them", long_about = None)]
+    #[command(visible_alias = "st")]
+    Status {
+        /// Commit the changes without asking for confirmation
+        #[clap(short = 'y', long)]
+        no_confirm: bool,
+        /// Only show and commit changes to entries with this tag (can be given more than once)
+        #[arg(long = "tag", value_name = "TAG")]
+        tags: Vec<String>,
+    },
+    #[command(about = "Commit local changes, pull remote changes and push, so the local config and remote agree", long_about = None)]
+    Sync {
+        /// Keep running, syncing every <INTERVAL> seconds
+        #[arg(long)]
+        interval: Option<u64>,
+        /// Don't push local commits, only commit and pull
+        #[arg(long)]
+        no_push: bool,
+        /// Don't redeploy entries after pulling changes
+        #[arg(long)]
+        no_deploy: bool,
+    },
+    #[command(about = "Watch the deployed files and commit changes to them automatically", long_about = None)]
+    Watch {
+        /// Seconds to wait for changes to settle before committing them
+        #[arg(long, value_name = "SECS", default_value_t = 2)]
+        debounce: u64,
+        /// Push each commit to the remote
+        #[arg(short = 'p', long)]
+        push: bool,
+    },
+    #[command(about = "Check for common setup problems", long_about = None)]
+    Doctor {
+        /// Fix the problems that are safe to fix automatically (missing links, auth file permissions)
+        #[arg(long)]
+        fix: bool,
+    },
+    #[command(about = "Manage the config repo's remote", long_about = None)]
+    Remote {
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+    #[command(about = "Manage GitHub authentication", long_about = None)]
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
+    #[command(about = "Update confinuum to the latest release", long_about = None)]
+    SelfUpdate {
+        /// Only check whether there's a newer release
+        #[arg(long)]
+        check: bool,
+        /// Update without asking for confirmation
+        #[arg(short = 'y', long)]
+        yes: bool,
+    },
     #[command(about = "Utility commands", long_about = None)]
     Util {
         #[command(subcommand)]
@@ -157,6 +635,41 @@ pub enum Command {
     },
 }
 
+impl Command {
+    /// Whether the command changes the config repo, config.toml or the deployed links, and so has
+    /// to hold the lock while it runs
+    fn changes_config(&self) -> bool {
+        match self {
+            Command::Entry { command, .. } => !matches!(
+                command,
+                EntryCommand::Show { .. } | EntryCommand::Check { .. }
+            ),
+            Command::List { .. }
+            | Command::Log { .. }
+            | Command::Diff { .. }
+            | Command::Grep { .. }
+            | Command::Export { .. }
+            | Command::SelfUpdate { .. }
+            | Command::Util { .. } => false,
+            Command::Remote {
+                command: RemoteCommand::List,
+            } => false,
+            Command::Auth { command } => matches!(command, AuthCommand::Refresh),
+            Command::Doctor { fix } => *fix,
+            // Takes the lock for each round instead, so other commands can run in between
+            Command::Sync { interval, .. } => interval.is_none(),
+            Command::Watch { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+/// Correct a mistyped entry name given to a command, see `ConfinuumConfig::resolve_entry_name`
+fn resolve_entry_name(name: Option<String>) -> Result<Option<String>> {
+    name.map(|name| ConfinuumConfig::load()?.resolve_entry_name(name))
+        .transpose()
+}
+
 impl Cli {
     pub async fn run() -> Result<()> {
         let args = match Self::try_parse() {
@@ -169,38 +682,376 @@ impl Cli {
                 _ => return Err(anyhow!("{}", e)),
             },
         };
-        let github = github::Github::new().await?;
+        logging::init(args.verbose);
+        // URLs can have tokens in them
+        tracing::debug!(
+            "confinuum {} running {}",
+            env!("CARGO_PKG_VERSION"),
+            github::redact_tokens(&format!("{:?}", args.command))
+        );
+        if args.run_hooks {
+            deployment::enable_hooks();
+        }
+        if args.include_dirty {
+            git::include_dirty();
+        }
+        if args.insecure_auth_files {
+            config::allow_insecure_auth_files();
+        }
+        if args.no_progress {
+            disable_progress();
+        }
+        if args.quiet > 0 {
+            enable_quiet(args.quiet);
+        }
+        // Scripts parse the single line it prints, so no notes may end up alongside it
+        if let Command::Check {
+            porcelain: true, ..
+        } = args.command
+        {
+            enable_quiet(2);
+        }
+        if let Some(subject) = args.message {
+            git::override_subject(subject);
+        }
+        if args.edit {
+            git::enable_edit_message();
+            // A spinner would draw over the editor
+            disable_progress();
+        }
+        if let (Some(name), Some(email)) = (args.author_name, args.author_email) {
+            git::override_identity(name, email)?;
+        }
+        // Held until the command returns, so concurrent runs (e.g. from cron) take turns
+        let _lock = match args.command.changes_config() {
+            true => lock::acquire()?,
+            false => None,
+        };
+        // Runs before the credentials are loaded, since problems with them are among those reported
+        if let Command::Doctor { fix } = args.command {
+            return commands::doctor(fix);
+        }
+        // Runs on every <TAB>, so it skips loading the credentials
+        if let Command::Util {
+            command: UtilCommand::ListEntries,
+        } = args.command
+        {
+            enable_quiet(2);
+            return commands::list_names();
+        }
+        let github = github::Github::new()?;
+        // Not after commands that are about updating, or run by scripts
+        let check_updates = !args.offline
+            && !matches!(
+                args.command,
+                Command::SelfUpdate { .. } | Command::Util { .. }
+            );
 
-        match args.command {
-            Command::Init { git, force } => commands::init(git, force, &github).await,
-            Command::Entry { name, command } => match command {
-                EntryCommand::Create { files, push } => {
-                    commands::new(name, files, push, &github).await
+        let result = match args.command {
+            Command::Init {
+                git,
+                force,
+                backup_repo,
+                create_repo,
+                template,
+                host,
+                gitlab_url,
+                gitea_url,
+                remote,
+                local,
+                protocol,
+                signature,
+                public,
+                private: _,
+                description,
+                yes,
+                depth,
+            } => {
+                let opts = commands::InitOptions {
+                    git,
+                    force,
+                    backup_repo,
+                    create_repo,
+                    template,
+                    hosting: host,
+                    gitlab_url,
+                    gitea_url,
+                    remote,
+                    local,
+                    protocol,
+                    signature,
+                    public,
+                    description,
+                    yes,
+                    depth,
+                };
+                commands::init(opts, github).await
+            }
+            Command::Entry { name, command } => {
+                // A mistyped name is corrected before any command sees it, except for a new entry
+                let name = match (name, &command) {
+                    (Some(name), EntryCommand::Create { .. }) => name,
+                    (Some(name), _) => ConfinuumConfig::load()?.resolve_entry_name(name)?,
+                    (
+                        None,
+                        EntryCommand::Show { .. } | EntryCommand::Check { .. } | EntryCommand::Push,
+                    ) => ConfinuumConfig::load()?.select_entry()?,
+                    (None, _) => {
+                        return Err(anyhow!(
+                            "The name of the entry is required, as in `confinuum entry <NAME> <COMMAND>`"
+                        ))
+                    }
+                };
+                match command {
+                    EntryCommand::Create {
+                        from: Some(from),
+                        target,
+                        push,
+                        ..
+                    } => {
+                        commands::new_from_repo(name, from, target, push, args.offline, &github)
+                            .await
+                    }
+                    EntryCommand::Create {
+                        files,
+                        push,
+                        follow_symlinks,
+                        yes,
+                        ..
+                    } => {
+                        commands::new(
+                            name,
+                            files,
+                            push,
+                            follow_symlinks,
+                            yes,
+                            args.offline,
+                            &github,
+                        )
+                        .await
+                    }
+                    EntryCommand::Delete {
+                        no_confirm,
+                        no_replace_files,
+                        push,
+                    } => {
+                        commands::delete(
+                            name,
+                            no_confirm,
+                            no_replace_files,
+                            push,
+                            args.offline,
+                            &github,
+                        )
+                        .await
+                    }
+                    EntryCommand::Show { stat, tree_style } => {
+                        commands::show(name, stat, tree_style)
+                    }
+                    EntryCommand::SetDescription { description, clear } => {
+                        commands::set_description(name, description, clear, &github).await
+                    }
+                    EntryCommand::Push => commands::push_entry(name),
+                    EntryCommand::SetLinkMode { link_mode } => {
+                        commands::set_link_mode(name, link_mode, &github).await
+                    }
+                    EntryCommand::Check {
+                        print_diff,
+                        word_diff,
+                    } => commands::check(
+                        print_diff,
+                        word_diff,
+                        false,
+                        Some(name),
+                        Vec::new(),
+                        args.no_pager,
+                        args.offline,
+                    ),
+                    EntryCommand::AddFiles {
+                        files,
+                        push,
+                        follow_symlinks,
+                        no_follow_symlinks: _,
+                        yes,
+                    } => {
+                        commands::add(
+                            name,
+                            files,
+                            push,
+                            follow_symlinks,
+                            yes,
+                            args.offline,
+                            &github,
+                        )
+                        .await
+                    }
+                    EntryCommand::RemoveFiles {
+                        files,
+                        no_confirm,
+                        no_replace_files,
+                        push,
+                    } => {
+                        commands::remove(
+                            name,
+                            files,
+                            no_confirm,
+                            no_replace_files,
+                            push,
+                            args.offline,
+                            &github,
+                        )
+                        .await
+                    }
+                    EntryCommand::Untrack { files, push } => {
+                        commands::untrack(name, files, push, args.offline, &github).await
+                    }
+                    EntryCommand::Disable => commands::disable(name, &github).await,
+                    EntryCommand::Enable => commands::enable(name, &github).await,
+                    EntryCommand::Tag { command } => match command {
+                        TagCommand::Add { tags } => commands::add_tags(name, tags, &github).await,
+                        TagCommand::Remove { tags } => {
+                            commands::remove_tags(name, tags, &github).await
+                        }
+                    },
+                }
+            }
+            Command::List {
+                tags,
+                filter,
+                sort,
+                long,
+            } => commands::list(tags, sort, filter, long),
+            Command::Log {
+                name,
+                max_count,
+                since,
+                until,
+            } => commands::log(
+                resolve_entry_name(name)?,
+                max_count,
+                since,
+                until,
+                args.no_pager,
+            ),
+            Command::Diff { name, word_diff } => {
+                commands::diff(resolve_entry_name(name)?, word_diff, args.no_pager)
+            }
+            Command::Grep {
+                pattern,
+                name,
+                ignore_case,
+                files_with_matches,
+                json,
+            } => commands::grep(
+                pattern,
+                resolve_entry_name(name)?,
+                ignore_case,
+                files_with_matches,
+                json,
+            ),
+            Command::Push { tags } => commands::push_tagged(tags),
+            Command::Check {
+                print_diff,
+                word_diff,
+                porcelain,
+                name,
+                tags,
+            } => commands::check(
+                print_diff,
+                word_diff,
+                porcelain,
+                resolve_entry_name(name)?,
+                tags,
+                args.no_pager,
+                args.offline,
+            ),
+            Command::Update => commands::update(args.offline, &github).await,
+            Command::Import {
+                stow: Some(stow),
+                target,
+                unstow,
+                dry_run,
+                ..
+            } => commands::import_stow(stow, target, unstow, dry_run, args.offline, &github).await,
+            Command::Import {
+                bare_repo: Some(bare_repo),
+                target,
+                dry_run,
+                ..
+            } => {
+                commands::import_bare_repo(bare_repo, target, dry_run, args.offline, &github).await
+            }
+            Command::Import { .. } => unreachable!("clap requires --stow or --bare-repo"),
+            Command::Export {
+                entries,
+                format,
+                out,
+                tags,
+            } => commands::export(entries, tags, format, out),
+            Command::Redeploy {
+                name,
+                only,
+                except,
+                tags,
+                force,
+                yes,
+            } => commands::redeploy(name, only, except, tags, force, yes),
+            Command::Undeploy { name, restore } => commands::undeploy_entries(name, restore),
+            Command::Uninstall {
+                keep_repo,
+                keep_auth,
+                no_confirm,
+            } => commands::uninstall(keep_repo, keep_auth, no_confirm),
+            Command::Restore {
+                commit,
+                hard,
+                force,
+            } => commands::restore(commit, hard, force, &github).await,
+            Command::Status { no_confirm, tags } => {
+                commands::status(no_confirm, tags, &github).await
+            }
+            Command::Sync {
+                interval,
+                no_push,
+                no_deploy,
+            } => commands::sync(interval, no_push, no_deploy, &github).await,
+            Command::Watch { debounce, push } => {
+                commands::watch(debounce, push, args.offline, &github).await
+            }
+            Command::Doctor { .. } => unreachable!("doctor runs before credentials are loaded"),
+            Command::Remote { command } => match command {
+                RemoteCommand::Add { url } => {
+                    commands::add_remote(url, args.offline, &github).await
                 }
-                EntryCommand::Delete {
-                    no_confirm,
-                    no_replace_files,
-                    push,
-                } => commands::delete(name, no_confirm, no_replace_files, push, &github).await,
-                EntryCommand::Show => commands::show(name),
-                EntryCommand::Check { print_diff } => commands::check(print_diff, Some(name)),
-                EntryCommand::AddFiles { files, push } => {
-                    commands::add(name, files, push, &github).await
+                RemoteCommand::SetUrl { url } => commands::set_remote_url(url, &github).await,
+                RemoteCommand::Remove => commands::remove_remote(),
+                RemoteCommand::List => commands::list_remotes(),
+            },
+            Command::Auth { command } => match command {
+                AuthCommand::Login {
+                    with_token,
+                    gitea,
+                    gitea_url,
+                    ..
+                } if gitea || gitea_url.is_some() => {
+                    commands::auth::login_gitea(with_token, gitea_url).await
                 }
-                EntryCommand::RemoveFiles {
-                    files,
-                    no_confirm,
-                    no_replace_files,
-                    push,
-                } => {
-                    commands::remove(name, files, no_confirm, no_replace_files, push, &github).await
+                AuthCommand::Login {
+                    with_token,
+                    gitlab,
+                    gitlab_url,
+                    ..
+                } if gitlab || gitlab_url.is_some() => {
+                    commands::auth::login_gitlab(with_token, gitlab_url).await
                 }
+                AuthCommand::Login { with_token, .. } => commands::auth::login(with_token).await,
+                AuthCommand::Logout { gitlab: true, .. } => commands::auth::logout_gitlab(),
+                AuthCommand::Logout { gitea: true, .. } => commands::auth::logout_gitea(),
+                AuthCommand::Logout { .. } => commands::auth::logout(&github),
+                AuthCommand::Refresh => commands::auth::refresh(&github).await,
+                AuthCommand::Status => commands::auth::status(&github).await,
             },
-            Command::List => commands::list(),
-            Command::Push => commands::push(),
-            Command::Check { print_diff, name } => commands::check(print_diff, name),
-            Command::Update => commands::update(),
-            Command::Redeploy => commands::redeploy(),
+            Command::SelfUpdate { check, yes } => commands::self_update(check, yes).await,
             Command::Util { command } => match command {
                 UtilCommand::Mangen { output } => {
                     if output.is_file() {
@@ -239,13 +1090,169 @@ impl Cli {
                     } else {
                         BufWriter::new(Box::new(std::io::stdout()))
                     };
-                    clap_complete::generate(shell, &mut Cli::command(), "confinuum", &mut out);
+                    completions::generate(shell, &mut out)?;
                     out.flush()?;
                     Ok(())
                 }
+                UtilCommand::ListEntries => {
+                    unreachable!("list-entries runs before credentials are loaded")
+                }
             },
+        };
+        if result.is_ok() && args.verbose > 0 {
+            git::report_credential();
+        }
+        if result.is_ok() {
+            print_summary();
+            if check_updates {
+                updates::notify().await;
+            }
+        }
+        result
+    }
+}
+
+/// Set by --no-progress
+static NO_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Hide spinners for the rest of this invocation
+pub fn disable_progress() {
+    NO_PROGRESS.store(true, Ordering::Relaxed);
+}
+
+/// Whether spinners are shown
+/// They redraw the line and hide the cursor, which only works on a terminal, so they're left out
+/// when stdout is piped or redirected.
+pub fn progress_enabled() -> bool {
+    !NO_PROGRESS.load(Ordering::Relaxed) && !quiet() && std::io::stdout().is_terminal()
+}
+
+/// How many times --quiet was passed
+static QUIET: AtomicU8 = AtomicU8::new(0);
+
+/// The last outcome a spinner finished with while quiet, printed once the command succeeds
+static SUMMARY: Mutex<Option<String>> = Mutex::new(None);
+
+/// Hide spinners and informational messages for the rest of this invocation
+/// At level 1 the outcome of the command is still printed as one line at the end, at level 2
+/// nothing is printed unless something goes wrong.
+pub fn enable_quiet(level: u8) {
+    QUIET.store(level, Ordering::Relaxed);
+}
+
+/// Whether informational messages are hidden
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed) > 0
+}
+
+/// Print the line kept by `-q` in place of the spinners' outcomes
+pub fn print_summary() {
+    if let Some(summary) = SUMMARY.lock().unwrap().take() {
+        if QUIET.load(Ordering::Relaxed) == 1 {
+            println!("{}", summary);
+        }
+    }
+}
+
+/// `println!`, unless --quiet was passed, in which case the message only goes to the log file
+/// For confirmations, notes and hints, not for the output a command was run for (like `list`).
+macro_rules! info {
+    () => {
+        if !$crate::cli::quiet() {
+            println!();
+        }
+    };
+    ($($arg:tt)*) => {
+        if $crate::cli::quiet() {
+            tracing::info!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+pub(crate) use info;
+
+/// A spinner, or when progress is hidden, nothing until the outcome is printed as a plain line
+pub struct ProgressSpinner {
+    spinner: Option<Spinner>,
+    /// The text shown next to the spinner, so that it can be put back after showing progress
+    message: String,
+}
+
+impl ProgressSpinner {
+    pub fn update_text(&mut self, message: impl Into<Cow<'static, str>>) {
+        let message = message.into();
+        self.message = message.to_string();
+        if let Some(spinner) = &mut self.spinner {
+            spinner.update_text(message);
+        }
+    }
+}
+
+thread_local! {
+    /// The spinner last started on this thread, which code that isn't passed a spinner (like
+    /// deploying) shows its progress in rather than starting a second one
+    static CURRENT_SPINNER: RefCell<Weak<RefCell<ProgressSpinner>>> =
+        const { RefCell::new(Weak::new()) };
+}
+
+/// How many items it takes for `par_progress` to show a spinner of its own
+const PROGRESS_MIN_ITEMS: usize = 100;
+
+/// Run `work` for every item on the thread pool, showing how many items are done and the last
+/// one's path (from `path`) in the current spinner, or in a new one if there are many items
+/// The results are in the order of `items`, whatever order the work finishes in, and every item
+/// is worked on even if others fail.
+pub fn par_progress<T: Sync, R: Send>(
+    items: &[T],
+    message: &str,
+    path: impl Fn(&T) -> PathBuf + Sync,
+    work: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let current = CURRENT_SPINNER.with(|current| current.borrow().upgrade());
+    let previous = current
+        .as_ref()
+        .map(|spinner| spinner.borrow().message.clone());
+    let spinner = current.or_else(|| {
+        (items.len() >= PROGRESS_MIN_ITEMS && progress_enabled())
+            .then(|| Spinner::new_shared(spinners::Dots9, message.to_owned(), Color::Blue))
+    });
+    let Some(spinner) = spinner else {
+        return items.par_iter().map(&work).collect();
+    };
+
+    let results = std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        let (path, work) = (&path, &work);
+        let workers = scope.spawn(move || {
+            items
+                .par_iter()
+                .map_with(sender, |sender, item| {
+                    let result = work(item);
+                    sender.send(path(item)).ok();
+                    result
+                })
+                .collect::<Vec<_>>()
+        });
+        // The channel closes once every worker is done with its items
+        for (done, path) in receiver.iter().enumerate() {
+            spinner.update_text(format!(
+                "{} ({}/{}) {}",
+                message,
+                done + 1,
+                items.len(),
+                path.display()
+            ));
         }
+        workers
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    });
+    match previous {
+        Some(previous) => spinner.update_text(previous),
+        None => spinner.clear(),
     }
+    results
 }
 
 pub trait CreateSharedSpinner {
@@ -253,7 +1260,7 @@ pub trait CreateSharedSpinner {
         frames: impl Into<SpinnerFrames>,
         message: impl Into<Cow<'static, str>>,
         color: Color,
-    ) -> Rc<RefCell<Self>>;
+    ) -> Rc<RefCell<ProgressSpinner>>;
 }
 
 impl CreateSharedSpinner for spinoff::Spinner {
@@ -261,9 +1268,19 @@ impl CreateSharedSpinner for spinoff::Spinner {
         frames: impl Into<SpinnerFrames>,
         message: impl Into<Cow<'static, str>>,
         color: Color,
-    ) -> Rc<RefCell<Self>> {
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Hide).ok();
-        Rc::new(RefCell::new(Spinner::new(frames, message, color)))
+    ) -> Rc<RefCell<ProgressSpinner>> {
+        let message = message.into();
+        let text = message.to_string();
+        let spinner = progress_enabled().then(|| {
+            crossterm::execute!(std::io::stdout(), crossterm::cursor::Hide).ok();
+            Spinner::new(frames, message, color)
+        });
+        let shared = Rc::new(RefCell::new(ProgressSpinner {
+            spinner,
+            message: text,
+        }));
+        CURRENT_SPINNER.with(|current| *current.borrow_mut() = Rc::downgrade(&shared));
+        shared
     }
 }
 
@@ -277,53 +1294,83 @@ pub trait SharedSpinner {
     fn update_text(&self, message: impl Into<Cow<'static, str>>);
 }
 
-impl SharedSpinner for Rc<RefCell<spinoff::Spinner>> {
-    fn stop(self) {
-        let unwrapped = Rc::try_unwrap(self);
-        if let Ok(unwrapped) = unwrapped {
-            unwrapped.into_inner().stop();
+/// How a spinner finished, for printing it as a plain line when the spinner isn't shown
+enum Outcome {
+    /// Printed as is, or with --quiet kept as the summary
+    Done(String),
+    /// Printed to stderr with --quiet, since warnings and failures are never hidden
+    Problem(String),
+}
+
+/// Stop the spinner with `stop` if it's shown, or print the outcome (if any) in its place
+fn finish(
+    shared: Rc<RefCell<ProgressSpinner>>,
+    stop: impl FnOnce(Spinner),
+    outcome: Option<Outcome>,
+) {
+    match Rc::try_unwrap(shared).map(RefCell::into_inner) {
+        Ok(ProgressSpinner {
+            spinner: Some(spinner),
+            ..
+        }) => stop(spinner),
+        Ok(ProgressSpinner { spinner: None, .. }) => {
+            match outcome {
+                Some(Outcome::Done(message)) if quiet() => {
+                    tracing::info!("{}", message);
+                    *SUMMARY.lock().unwrap() = Some(message);
+                }
+                Some(Outcome::Problem(message)) if quiet() => eprintln!("{}", message),
+                Some(Outcome::Done(message) | Outcome::Problem(message)) => println!("{}", message),
+                None => {}
+            }
+            return;
         }
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+        // Still borrowed by callbacks that outlived the operation, so it can't be stopped
+        Err(_) if !progress_enabled() => return,
+        Err(_) => {}
+    }
+    crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+}
+
+impl SharedSpinner for Rc<RefCell<ProgressSpinner>> {
+    fn stop(self) {
+        finish(self, |spinner| spinner.stop(), None);
     }
 
     fn clear(self) {
-        let unwrapped = Rc::try_unwrap(self);
-        if let Ok(unwrapped) = unwrapped {
-            unwrapped.into_inner().clear();
-        }
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+        finish(self, |spinner| spinner.clear(), None);
     }
 
     fn stop_with_message(self, message: &str) {
-        let unwrapped = Rc::try_unwrap(self);
-        if let Ok(unwrapped) = unwrapped {
-            unwrapped.into_inner().stop_with_message(message);
-        }
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+        finish(
+            self,
+            |spinner| spinner.stop_with_message(message),
+            Some(Outcome::Done(message.to_owned())),
+        );
     }
 
     fn success(self, message: &str) {
-        let unwrapped = Rc::try_unwrap(self);
-        if let Ok(unwrapped) = unwrapped {
-            unwrapped.into_inner().success(message);
-        }
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+        finish(
+            self,
+            |spinner| spinner.success(message),
+            Some(Outcome::Done(format!("✔ {}", message))),
+        );
     }
 
     fn warn(self, message: &str) {
-        let unwrapped = Rc::try_unwrap(self);
-        if let Ok(unwrapped) = unwrapped {
-            unwrapped.into_inner().warn(message);
-        }
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+        finish(
+            self,
+            |spinner| spinner.warn(message),
+            Some(Outcome::Problem(format!("! {}", message))),
+        );
     }
 
     fn fail(self, message: &str) {
-        let unwrapped = Rc::try_unwrap(self);
-        if let Ok(unwrapped) = unwrapped {
-            unwrapped.into_inner().fail(message);
-        }
-        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+        finish(
+            self,
+            |spinner| spinner.fail(message),
+            Some(Outcome::Problem(format!("✘ {}", message))),
+        );
     }
 
     fn update_text(&self, message: impl Into<Cow<'static, str>>) {
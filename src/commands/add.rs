@@ -1,107 +1,120 @@
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
-    config::{ConfinuumConfig, SignatureSource},
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{AddPlan, CommitKind, ConfigEntry, ConfinuumConfig},
     git::{self, RepoExtensions},
     github::Github,
+    interrupt,
+    rollback::Rollback,
 };
 use anyhow::{anyhow, Context, Result};
-use git2::{FetchOptions, IndexAddOption, Repository};
+use crossterm::style::Stylize;
+use dialoguer::theme::ColorfulTheme;
+use git2::Repository;
 use spinoff::{spinners, Color, Spinner};
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::HashSet,
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+/// How many of the planned files to list before summarizing the rest
+const LISTED_FILES: usize = 20;
+
+/// The most files the picker offers, so that walking a big target directory (like the home
+/// directory) doesn't take forever
+const MAX_PICKER_FILES: usize = 1000;
 
 /// Add files to an existing config entry
-pub async fn add(name: String, files: Vec<PathBuf>, push: bool, github: &Github) -> Result<()> {
+/// Without any files, the files to add are picked from the entry's target directory.
+pub async fn add(
+    name: String,
+    files: Vec<PathBuf>,
+    push: bool,
+    follow_symlinks: bool,
+    yes: bool,
+    offline: bool,
+    github: &Github,
+) -> Result<()> {
     let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
     let repo = Repository::open(&config_dir)
         .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
-    let mut remote = repo.find_remote("origin")?;
-    let spinner = Spinner::new_shared(
-        spinners::Dots9,
-        "Connecting to remote 'origin'",
-        Color::Blue,
-    );
-    {
-        spinner.update_text("Checking for changes on remote");
-        let mut fetch_opt = FetchOptions::new();
-        fetch_opt.update_fetchhead(true);
-        fetch_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-        remote
-            .fetch(&["main"], Some(&mut fetch_opt), None)
-            .context("Failed to fetch from remote 'origin'")?;
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-        let analysis = repo.merge_analysis(&[&fetch_commit])?;
-        remote.disconnect()?;
-        if analysis.0.is_up_to_date() {
-            spinner.update_text("No changes found on remote, continuing");
-        } else {
-            spinner.fail("Changes found on remote");
-            return Err(anyhow!(
-                "Changes found on remote. Please pull them before adding files."
-            ));
-        }
-
-        let mut config = ConfinuumConfig::load()?;
-        if !config.entries.contains_key(&name) {
-            return Err(anyhow!(
-                "Entry named {} does not exist! Use the `new` subcommand to create it.",
-                name
-            ));
-        }
+    let mut config = ConfinuumConfig::load()?;
+    let offline = git::check_remote(&repo, &config.confinuum.branch, "adding files", offline)?;
+    let entry = config.entry_mut(&name)?;
+    let picked = files.is_empty();
+    let files = if picked {
+        pick_untracked_files(entry, follow_symlinks)?
+    } else {
+        files
+    };
+    if files.is_empty() {
+        info!("No files selected, nothing was added to {}", name);
+        return Ok(());
+    }
+    let plan = ConfinuumConfig::plan_add(entry, files, None, follow_symlinks)
+        .context("Failed to add files to config")?;
+    // Picked files were just chosen one by one, so they aren't listed again
+    if !confirm_plan(&name, &plan, yes || picked)? {
+        return Ok(());
+    }
 
-        let entry = config.entries.get_mut(&name).unwrap();
+    let spinner = Spinner::new_shared(spinners::Dots9, "Adding files", Color::Blue);
+    let mismatch = {
+        let rollback = Rollback::new(&[&name])?;
         let mut result_files = HashSet::new();
-        ConfinuumConfig::add_files_recursive(entry, files, None, &mut Some(&mut result_files))
+        ConfinuumConfig::apply_add(entry, plan, &mut Some(&mut result_files))
             .context("Failed to add files to config")?;
         config.save().context("Failed to save config file")?;
 
-        let mut index = repo.index()?;
-        let mut imp = |path: &std::path::Path, _data: &[u8]| {
-            if path.starts_with(".git") {
-                return 1; // skip .git/
-            }
-            return 0;
-        };
-        index
-            .add_all(["*"], IndexAddOption::DEFAULT, Some(&mut imp))
-            .context("Could not add files")?;
-        let oid = index.write_tree().context("Failed to write tree")?;
+        let staged = git::stage_paths(
+            &repo,
+            &[&name],
+            result_files
+                .iter()
+                .map(|file| PathBuf::from(&name).join(file)),
+        )?;
         let parent_commit = repo
             .find_last_commit()
             .context("Failed to retrieve last commit")?;
-        let sig = match &config.confinuum.signature_source {
-            SignatureSource::Github => github
-                .get_user_signature()
-                .await
-                .context("Could not fetch user signature from github")?,
-            SignatureSource::GitConfig => {
-                // allows users to set values in config if they don't exist
-                git::gitconfig::get_user_sig()?
-            }
-        };
+        let sig = git::commit_signature(&config, github).await?;
         let tree = repo
-            .find_tree(oid)
+            .find_tree(staged.oid)
             .context("Failed to find new commit tree")?;
-        let message = format!(
-            "Added {} files to `{}`\n\nNew files:\n{}",
-            result_files.len(),
-            name,
-            result_files
-                .iter()
-                .map(|f| f.display().to_string())
-                .collect::<Vec<_>>()
-                .join("\n")
+        let files = result_files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        let subject = config.confinuum.commit_message.subject(
+            CommitKind::Add,
+            &name,
+            &files,
+            format!("Added {} files to `{}`", files.len(), name),
         );
+        let message = staged.message(format!("{}\n\nNew files:\n{}", subject, files.join("\n")));
 
-        repo.commit(Some("HEAD"), &sig, &sig, &message, &tree, &[&parent_commit])
+        interrupt::check()?;
+        repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
             .context("Failed to commit files")?;
+        rollback.keep();
 
-        super::deploy(Some(&name))?;
-    }
+        super::deploy(Some(&HashSet::from([name.clone()])))?;
+        config.entries[&name].mismatch()
+    };
 
     spinner.success("Files added successfully");
+    if let Some(reason) = mismatch {
+        info!(
+            "{} {} doesn't deploy on this machine ({}), so the files were copied into the config but left as they are",
+            "!".yellow(),
+            name,
+            reason
+        );
+    }
 
-    if push {
+    if push && offline {
+        info!("Skipping push while offline, run `confinuum push` once the remote is reachable.");
+    } else if push {
         let spinner = Spinner::new_shared(
             spinners::Dots9,
             "Connecting to remote 'origin'",
@@ -111,8 +124,12 @@ pub async fn add(name: String, files: Vec<PathBuf>, push: bool, github: &Github)
             let mut pushopt = git2::PushOptions::new();
             pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
             spinner.update_text("Pushing changes to remote");
+            let mut remote = git::find_origin(&repo)?;
             remote
-                .push(&["refs/heads/main:refs/heads/main"], Some(&mut pushopt))
+                .push(
+                    &[git::branch_refspec(&config.confinuum.branch)],
+                    Some(&mut pushopt),
+                )
                 .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
             // Scope to ensure that all references to spinner are dropped before we call success
         }
@@ -121,3 +138,136 @@ pub async fn add(name: String, files: Vec<PathBuf>, push: bool, github: &Github)
 
     Ok(())
 }
+
+/// List the files that adding will copy, with their total size, and ask whether to go ahead
+/// Nothing is asked with `yes`, when there's nothing to copy, or when stdin isn't a terminal (so
+/// scripts aren't left waiting on a prompt).
+pub(super) fn confirm_plan(name: &str, plan: &AddPlan, yes: bool) -> Result<bool> {
+    if yes || plan.is_empty() || !std::io::stdin().is_terminal() {
+        return Ok(true);
+    }
+    println!(
+        "{} files ({}) will be copied into {}:",
+        plan.len(),
+        super::show::format_size(plan.size()),
+        name.bold()
+    );
+    for file in plan.files().take(LISTED_FILES) {
+        println!("  {}", file.display());
+    }
+    if plan.len() > LISTED_FILES {
+        println!("  ... and {} more", plan.len() - LISTED_FILES);
+    }
+    let confirmed = dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Copy them?")
+        .default(true)
+        .interact_opt()
+        .context("Failed to interact with user, cancelling.")?
+        == Some(true);
+    if !confirmed {
+        info!("Cancelled, nothing was added to {}", name);
+    }
+    Ok(confirmed)
+}
+
+/// Let the user pick which files in the entry's target directory to add, for when none were given
+/// Only files that aren't in the entry yet are offered, leaving out ones ignored by the git
+/// repository the target directory is in (if any). Without a terminal to pick on, this fails like
+/// it always has when no files are given.
+fn pick_untracked_files(entry: &ConfigEntry, follow_symlinks: bool) -> Result<Vec<PathBuf>> {
+    let target_dir = match &entry.target_dir {
+        Some(target_dir) if entry.target_file.is_none() && std::io::stdin().is_terminal() => {
+            target_dir
+        }
+        _ => {
+            return Err(anyhow!(
+                "No files given, pass the files to add to {}",
+                entry.name
+            ))
+        }
+    };
+    let ignores = Repository::discover(target_dir).ok();
+    let mut candidates = Vec::new();
+    untracked_files(
+        entry,
+        target_dir,
+        target_dir,
+        ignores.as_ref(),
+        follow_symlinks,
+        &mut candidates,
+    )?;
+    if candidates.is_empty() {
+        return Err(anyhow!(
+            "There are no files in {} that aren't in {} already",
+            target_dir.display(),
+            entry.name
+        ));
+    }
+    candidates.sort();
+    if candidates.len() >= MAX_PICKER_FILES {
+        info!(
+            "{} Only the first {} files in {} are listed, pass the files to add to pick others",
+            "!".yellow(),
+            MAX_PICKER_FILES,
+            target_dir.display()
+        );
+    }
+    let selected = dialoguer::MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "Files to add to {} (space to select, enter to confirm)",
+            entry.name
+        ))
+        .items(
+            &candidates
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>(),
+        )
+        .interact()?;
+    Ok(selected
+        .into_iter()
+        .map(|index| target_dir.join(&candidates[index]))
+        .collect())
+}
+
+/// Collect the files under `dir` that aren't in the entry, relative to its target directory
+/// .git directories and what `ignores` ignores are skipped. Symlinked directories are never walked
+/// into, and symlinks are only collected with `follow_symlinks`, since adding refuses them
+/// otherwise.
+fn untracked_files(
+    entry: &ConfigEntry,
+    target_dir: &Path,
+    dir: &Path,
+    ignores: Option<&Repository>,
+    follow_symlinks: bool,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for item in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        if files.len() >= MAX_PICKER_FILES {
+            break;
+        }
+        let path = item?.path();
+        if path.file_name() == Some(".git".as_ref()) || is_ignored(ignores, &path) {
+            continue;
+        }
+        if path.is_dir() && !path.is_symlink() {
+            untracked_files(entry, target_dir, &path, ignores, follow_symlinks, files)?;
+            continue;
+        }
+        let relative = path.strip_prefix(target_dir)?;
+        if !entry.files.contains(relative) && (follow_symlinks || !path.is_symlink()) {
+            files.push(relative.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a path is ignored by the repository it's in
+fn is_ignored(repo: Option<&Repository>, path: &Path) -> bool {
+    repo.and_then(|repo| {
+        path.strip_prefix(repo.workdir()?)
+            .ok()
+            .map(|path| (repo, path))
+    })
+    .is_some_and(|(repo, path)| repo.is_path_ignored(path).unwrap_or(false))
+}
@@ -0,0 +1,217 @@
+use std::io::Read;
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    error::ConfinuumError,
+    gitea::{Gitea, GiteaAuthFile},
+    github::{self, AuthFile, Github, TokenSource},
+    gitlab::{Gitlab, GitlabAuthFile},
+    hosting::Provider,
+};
+
+/// Log in to GitHub, replacing any stored credentials
+/// With `with_token`, a personal access token is read from stdin instead of using the device flow
+pub async fn login(with_token: bool) -> Result<()> {
+    let github = if with_token {
+        Github::login_with_token(read_token()?).await?
+    } else {
+        Github::login().await?
+    };
+    let user = github.get_auth_user().await?;
+    info!(
+        "{} Logged in to GitHub as {}",
+        "✔".green(),
+        user.name.bold()
+    );
+    Ok(())
+}
+
+/// Log in to GitLab with a personal access token, replacing any stored GitLab credentials
+/// The token is prompted for, or read from stdin with `with_token`
+pub async fn login_gitlab(with_token: bool, url: Option<String>) -> Result<()> {
+    let mut gitlab = Gitlab::new(url.as_deref())?;
+    if with_token {
+        gitlab.login_with_token(read_token()?).await?;
+    } else {
+        gitlab.login().await?;
+    }
+    let user = gitlab.get_auth_user().await?;
+    info!(
+        "{} Logged in to GitLab at {} as {}",
+        "✔".green(),
+        gitlab.url(),
+        user.name.bold()
+    );
+    Ok(())
+}
+
+/// Log in to a Gitea or Forgejo instance (Codeberg by default) with an access token, replacing any
+/// stored credentials for that instance
+/// The token is prompted for, or read from stdin with `with_token`
+pub async fn login_gitea(with_token: bool, url: Option<String>) -> Result<()> {
+    let mut gitea = Gitea::new(url.as_deref())?;
+    if with_token {
+        gitea.login_with_token(read_token()?).await?;
+    } else {
+        gitea.login().await?;
+    }
+    let user = gitea.get_auth_user().await?;
+    info!(
+        "{} Logged in to {} as {}",
+        "✔".green(),
+        gitea.url(),
+        user.name.bold()
+    );
+    Ok(())
+}
+
+/// Read a personal access token from stdin
+fn read_token() -> Result<String> {
+    let mut token = String::new();
+    std::io::stdin()
+        .read_to_string(&mut token)
+        .context("Could not read token from stdin")?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("No token provided on stdin"));
+    }
+    Ok(token.to_owned())
+}
+
+/// Update the name and email used to sign commits from the GitHub account
+pub async fn refresh(github: &Github) -> Result<()> {
+    let spinner = Spinner::new_shared(spinners::Dots9, "Fetching GitHub user", Color::Blue);
+    let user = github.refresh_user().await?;
+    spinner.success(&format!(
+        "Commits will be signed as {} <{}>",
+        user.name, user.email
+    ));
+    Ok(())
+}
+
+/// Remove the stored GitHub credentials
+pub fn logout(github: &Github) -> Result<()> {
+    if Github::logout()? {
+        info!("{} Logged out of GitHub", "✔".green());
+    } else if github.token_source() != TokenSource::Confinuum {
+        info!("Not logged in with confinuum, the gh CLI's login is being used instead. Run `gh auth logout` to log out of it.");
+    } else {
+        info!("Not logged in to GitHub");
+    }
+    Ok(())
+}
+
+/// Remove the stored GitLab credentials
+pub fn logout_gitlab() -> Result<()> {
+    if Gitlab::logout()? {
+        info!("{} Logged out of GitLab", "✔".green());
+    } else {
+        info!("Not logged in to GitLab");
+    }
+    Ok(())
+}
+
+/// Remove the stored Gitea credentials, for every instance
+pub fn logout_gitea() -> Result<()> {
+    if Gitea::logout(None)? {
+        info!("{} Logged out of Gitea", "✔".green());
+    } else {
+        info!("Not logged in to Gitea");
+    }
+    Ok(())
+}
+
+/// Show who is logged in, the token's scopes, and whether GitHub still accepts the token
+pub async fn status(github: &Github) -> Result<()> {
+    if GitlabAuthFile::exists()? {
+        let auth_file = GitlabAuthFile::load()?;
+        println!(
+            "Logged in to GitLab at {} as {} ({})",
+            auth_file.url,
+            auth_file.user.name.bold(),
+            auth_file.user.email
+        );
+    }
+    if GiteaAuthFile::exists()? {
+        let mut hosts = GiteaAuthFile::load()?.hosts.into_iter().collect::<Vec<_>>();
+        hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (url, host) in hosts {
+            println!(
+                "Logged in to {} as {} ({})",
+                url,
+                host.user.name.bold(),
+                host.user.email
+            );
+        }
+    }
+    if !github.is_logged_in() {
+        println!("Not logged in to GitHub. Run `confinuum auth login` to log in.");
+        return Ok(());
+    }
+    let stored_scopes = if github.token_source() == TokenSource::Confinuum {
+        let auth_file = AuthFile::load()?;
+        println!(
+            "Logged in to GitHub as {} ({})",
+            auth_file.user.name.bold(),
+            auth_file.user.email
+        );
+        println!("Token type: {}", auth_file.auth.token_type);
+        auth_file.auth.scopes
+    } else {
+        println!("Using the gh CLI's login to GitHub");
+        Vec::new()
+    };
+
+    let spinner = Spinner::new_shared(spinners::Dots9, "Checking token", Color::Blue);
+    match github.verify().await {
+        Ok((login, scopes)) => {
+            spinner.success("Token is valid");
+            if github.token_source() != TokenSource::Confinuum {
+                println!("Logged in to GitHub as {}", login.bold());
+            }
+            let scopes = if scopes.is_empty() {
+                stored_scopes
+            } else {
+                scopes
+            };
+            println!("Token scopes: {}", scopes.join(", "));
+            if github.token_source() == TokenSource::Confinuum {
+                // `repo` covers `public_repo`, and the other parent scopes work the same way
+                let missing = github::oauth_scopes()
+                    .into_iter()
+                    .filter(|wanted| {
+                        !scopes.iter().any(|granted| {
+                            granted == wanted
+                                || wanted.starts_with(&format!("{}:", granted))
+                                || (granted == "repo" && wanted == "public_repo")
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                if !missing.is_empty() {
+                    println!(
+                        "{} The token is missing requested scope{} {}. Run `confinuum auth login` to log in again.",
+                        "!".yellow(),
+                        if missing.len() == 1 { "" } else { "s" },
+                        missing.join(", ")
+                    );
+                }
+            }
+            Ok(())
+        }
+        Err(e) if github::is_auth_error(&e) => {
+            spinner.fail("Token has expired or been revoked");
+            // AuthFailed is the root error so the exit code doesn't depend on how it's found
+            Err(anyhow::Error::from(ConfinuumError::AuthFailed)
+                .context(format!("GitHub rejected the token: {}", e)))
+        }
+        // Not being able to reach GitHub says nothing about the token
+        Err(e) => {
+            spinner.fail("Could not check the token");
+            Err(e.context("Could not reach GitHub"))
+        }
+    }
+}
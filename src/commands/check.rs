@@ -1,65 +1,92 @@
+use std::collections::HashSet;
+
 use crate::{
     cli::{CreateSharedSpinner, SharedSpinner},
     config::ConfinuumConfig,
-    git,
+    git::{self, RepoExtensions},
+    pager,
 };
 use anyhow::{anyhow, Context, Result};
 use crossterm::style::Stylize;
-use git2::{DiffFormat, DiffOptions, Direction, FetchOptions, Repository};
+use git2::{DiffFormat, DiffOptions, Repository};
 use spinoff::{spinners, Spinner};
 
 // TODO: Update this to use the new config format and check individual entries
-pub fn check(print_diff: bool, name: Option<String>) -> Result<()> {
+/// Check the remote for changes that haven't been pulled yet
+/// With `porcelain`, a single line is printed in place of the report, see `print_porcelain`.
+pub fn check(
+    print_diff: bool,
+    word_diff: bool,
+    porcelain: bool,
+    name: Option<String>,
+    tags: Vec<String>,
+    no_pager: bool,
+    offline: bool,
+) -> Result<()> {
     let config_dir = ConfinuumConfig::get_dir()?;
     if !config_dir.exists() {
         return Err(anyhow!("Config directory does not exist"));
     }
     let repo =
         Repository::open(config_dir).context("Failed to open config directory as a git repo")?;
+    let config = ConfinuumConfig::load()?;
+    let tagged = config.tagged_entries(&tags);
+    if offline {
+        if porcelain {
+            return Err(anyhow!(
+                "--porcelain needs to contact the remote, so it can't be used with --offline"
+            ));
+        }
+        return local_status(&repo, name.as_deref(), tagged.as_ref());
+    }
     let spinner = Spinner::new_shared(
         spinners::Dots9,
         "Connecting to remote 'origin'",
         spinoff::Color::Blue,
     );
 
-    let (analysis, diff_files) = {
-        let mut remote = repo
-            .find_remote("origin")
-            .context("Failed to find remote named 'origin'")?;
-        remote.connect_auth(
-            Direction::Fetch,
-            Some(git::construct_callbacks(spinner.clone())),
-            None,
-        )?;
-        let mut fetch_opt = FetchOptions::new();
-        fetch_opt.update_fetchhead(true);
-
-        fetch_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-
-        remote
-            .fetch(&["main"], Some(&mut fetch_opt), None)
-            .context("Failed to fetch from remote 'origin'")?;
-
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-        //let head_commit = repo.reference_to_annotated_commit(&head)?;
+    let (analysis, behind, diff_files, rendered_diff, remote_config) = {
+        let fetch_commit = git::fetch_origin(&repo, &spinner, &config.confinuum.branch)?;
         let analysis = repo.merge_analysis(&[&fetch_commit])?;
+        let (_, behind) =
+            repo.graph_ahead_behind(repo.find_last_commit()?.id(), fetch_commit.id())?;
 
         let head = repo.head()?;
         let head_tree = head.peel_to_tree()?;
-        let fetch_tree = fetch_head.peel_to_tree()?;
+        let fetch_tree = repo.find_commit(fetch_commit.id())?.tree()?;
         let mut diff_opt = DiffOptions::default();
-        let diff =
+        let mut diff =
             repo.diff_tree_to_tree(Some(&head_tree), Some(&fetch_tree), Some(&mut diff_opt))?;
-        let diff_files = git::diff_files(&diff)?;
+        let diff_files = git::diff_files(&mut diff)?;
 
-        if print_diff {
-            git::print_diff(&diff, DiffFormat::Patch)?;
-        }
+        // Render the diff now and print it once the spinner is done, so the pager gets the whole terminal
+        let rendered_diff = if print_diff {
+            let mut rendered = Vec::new();
+            git::print_diff(&diff, DiffFormat::Patch, word_diff, &mut rendered)?;
+            Some(rendered)
+        } else {
+            None
+        };
+
+        let remote_config = git::config_at_tree(&repo, &fetch_tree).ok();
 
-        (analysis, diff_files)
+        (analysis, behind, diff_files, rendered_diff, remote_config)
     };
 
+    let (mut entries, config_updated, unowned) = git::diff_entries(&diff_files)?;
+    if let Some(tagged) = &tagged {
+        entries.retain(|name, _| tagged.contains(name));
+    }
+    if let Some(name) = &name {
+        entries.retain(|entry_name, _| entry_name == name);
+    }
+
+    if porcelain {
+        spinner.clear();
+        print_porcelain(analysis.0.is_up_to_date(), behind, entries.into_keys());
+        return Ok(());
+    }
+
     if analysis.0.is_up_to_date() {
         spinner.success("Config is up to date");
     } else {
@@ -69,7 +96,10 @@ pub fn check(print_diff: bool, name: Option<String>) -> Result<()> {
         ));
     }
 
-    let (entries, config_updated) = git::diff_entries(&diff_files)?;
+    if let Some(rendered_diff) = rendered_diff {
+        pager::page(&rendered_diff, no_pager)?;
+    }
+
     if config_updated {
         println!(
             "\nFound changes in {}{}",
@@ -103,7 +133,97 @@ pub fn check(print_diff: bool, name: Option<String>) -> Result<()> {
                     .join(", ")
             );
         }
+        git::print_unowned(&unowned, remote_config.as_ref());
+    }
+
+    Ok(())
+}
+
+/// Print the result of a check as one line, for shell prompts and scripts
+/// The line is either `up-to-date`, or `behind:<commits>:<entries>` when the remote has commits
+/// that haven't been pulled, where `<commits>` is how many and `<entries>` is a comma separated,
+/// sorted list of the entries they change (empty if they only change other files). Since entry
+/// names may contain `:`, everything after the second `:` is the list. Entry names can't contain
+/// `,` (see `ConfigEntry::validate_name`), so the list splits unambiguously.
+/// Nothing else is printed to stdout with --porcelain, since it makes the command quiet.
+/// This format is stable, so scripts can rely on it.
+fn print_porcelain(up_to_date: bool, behind: usize, entries: impl Iterator<Item = String>) {
+    if up_to_date {
+        println!("up-to-date");
+        return;
+    }
+    let mut entries = entries.collect::<Vec<_>>();
+    entries.sort();
+    println!("behind:{}:{}", behind, entries.join(","));
+}
+
+/// Report what can be known without contacting the remote: commits that haven't been pushed (as of
+/// the last fetch), and whether each entry's files are deployed
+/// Entries are limited to `name` and to the names in `tagged`, when given.
+pub(super) fn local_status(
+    repo: &Repository,
+    name: Option<&str>,
+    tagged: Option<&HashSet<String>>,
+) -> Result<()> {
+    println!("{} Offline, showing local information only\n", "!".yellow());
+
+    let head = repo.find_last_commit()?;
+    let remote_commit = repo
+        .find_reference("refs/remotes/origin/main")
+        .or_else(|_| repo.find_reference("FETCH_HEAD"))
+        .and_then(|reference| reference.peel_to_commit());
+    match remote_commit {
+        Ok(remote_commit) => {
+            let (ahead, behind) = repo.graph_ahead_behind(head.id(), remote_commit.id())?;
+            if ahead > 0 {
+                println!(
+                    "{} unpushed commit{}",
+                    ahead.to_string().bold(),
+                    if ahead == 1 { "" } else { "s" }
+                );
+            } else {
+                println!("No unpushed commits");
+            }
+            if behind > 0 {
+                println!(
+                    "{} commit{} from the last fetch not pulled yet",
+                    behind.to_string().bold(),
+                    if behind == 1 { "" } else { "s" }
+                );
+            }
+        }
+        Err(_) => println!("The remote hasn't been fetched yet, so unpushed commits are unknown"),
     }
 
+    let config = ConfinuumConfig::load()?;
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let mut entries = config
+        .entries
+        .iter()
+        .filter(|(entry_name, _)| name.is_none_or(|name| name == entry_name.as_str()))
+        .filter(|(entry_name, _)| tagged.is_none_or(|tagged| tagged.contains(*entry_name)))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    println!();
+    for (_, entry) in entries {
+        if entry.deploy_dir().is_none() {
+            println!("{}: uninitialized", super::list::styled_name(entry));
+            continue;
+        }
+        let deployed = entry
+            .files
+            .iter()
+            .filter(|file| super::is_deployed(&config_dir, entry, file))
+            .count();
+        println!(
+            "{}: {}/{} files deployed",
+            super::list::styled_name(entry),
+            deployed,
+            entry.files.len()
+        );
+        if let Some(conditions) = super::list::conditions_note(entry) {
+            println!("  {}", conditions);
+        }
+    }
     Ok(())
 }
@@ -1,12 +1,16 @@
+use std::collections::HashSet;
+
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
-    config::{ConfinuumConfig, SignatureSource},
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{CommitKind, ConfinuumConfig, LinkMode},
     git::{self, RepoExtensions},
     github::Github,
+    interrupt,
 };
 use anyhow::{anyhow, Context, Result};
-use git2::{FetchOptions, IndexAddOption, Repository};
+use git2::Repository;
 use spinoff::{spinners, Color, Spinner};
+use std::path::PathBuf;
 
 /// Remove a config entry (files will be restored to their original locations unless no_replace_files is set)
 pub async fn delete(
@@ -14,6 +18,7 @@ pub async fn delete(
     no_confirm: bool,
     no_replace_files: bool,
     push: bool,
+    offline: bool,
     github: &Github,
 ) -> Result<()> {
     // Load config file
@@ -21,40 +26,11 @@ pub async fn delete(
     let config_dir = ConfinuumConfig::get_dir()?;
 
     // Ensure that the entry exists
-    if !config.entries.contains_key(&name) {
-        return Err(anyhow!("No entry named {}", name));
-    }
+    config.entry(&name)?;
 
     // Ensure that there aren't unfetched changes on the remote
     let repo = Repository::open(&config_dir)?;
-    let mut remote = repo.find_remote("origin")?;
-    let spinner = Spinner::new_shared(
-        spinners::Dots9,
-        "Connecting to remote 'origin'",
-        Color::Blue,
-    );
-    {
-        // Scope to ensure that all references to spinner are dropped before we call success
-        spinner.update_text("Checking for changes on remote");
-        let mut fetch_opt = FetchOptions::new();
-        fetch_opt.update_fetchhead(true);
-        fetch_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-        remote
-            .fetch(&["main"], Some(&mut fetch_opt), None)
-            .context("Failed to fetch from remote 'origin'")?;
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-        // Check if up to date
-        let analysis = repo.merge_analysis(&[&fetch_commit])?;
-        remote.disconnect()?;
-        if !analysis.0.is_up_to_date() {
-            spinner.fail("Changes found on remote");
-            return Err(anyhow!(
-                "Changes found on remote. Please pull them before deleting files."
-            ));
-        }
-    }
-    spinner.clear();
+    let offline = git::check_remote(&repo, &config.confinuum.branch, "deleting files", offline)?;
 
     let confirm = no_confirm || {
         let selection = dialoguer::Select::new()
@@ -77,6 +53,8 @@ pub async fn delete(
     }
 
     // Perform the actual deletion
+    // Stopping halfway would leave the entry partly restored, so Ctrl-C waits for it to finish
+    let _deferred = interrupt::Deferred::new();
     let spinner = Spinner::new_shared(
         spinners::Dots9,
         "Confirmed deletion, continuing",
@@ -85,11 +63,22 @@ pub async fn delete(
     {
         // Scope to ensure that all references to spinner are dropped before we call success
         let entry = config.entries.get(&name).unwrap();
-        if no_replace_files {
+        let names = HashSet::from([name.clone()]);
+        if entry.link_mode == LinkMode::Directory {
+            // The files are only reachable through the directory link, so they have to be copied
+            // out before the entry's directory is deleted
+            if no_replace_files {
+                spinner.update_text("Skipping file restoration, deleting the directory link");
+                super::undeploy(Some(&names))?;
+            } else {
+                spinner.update_text("Restoring the directory to its original location");
+                super::restore_files(Some(&names))?;
+            }
+        } else if no_replace_files {
             // Delete deployed symlinks
             spinner.update_text("Skipping file restoration, deleting symlinks");
             for file in entry.files.iter() {
-                let target_path = entry.target_dir.as_ref().ok_or(anyhow!(
+                let target_path = entry.deploy_dir().ok_or(anyhow!(
                 "Entry {} does not have a target directory, cannot restore files. Cancelling deletion.",
                 name
             ))?.join(file);
@@ -100,7 +89,7 @@ pub async fn delete(
             // Restore files to their original locations, and delete symlinks
             spinner.update_text("Restoring files to original locations");
             for file in entry.files.iter() {
-                let target_path = entry.target_dir.as_ref().ok_or(anyhow!(
+                let target_path = entry.deploy_dir().ok_or(anyhow!(
                 "Entry {} does not have a target directory, cannot restore files. Cancelling deletion.",
                 name
             ))?.join(file);
@@ -109,13 +98,7 @@ pub async fn delete(
                     std::fs::remove_file(&target_path)
                         .with_context(|| format!("Cannot remove {}", target_path.display()))?;
                 }
-                std::fs::copy(&repo_path, &target_path).with_context(|| {
-                    format!(
-                        "Cannot copy {} to {}",
-                        repo_path.display(),
-                        target_path.display()
-                    )
-                })?;
+                super::move_file(&repo_path, &target_path)?;
             }
         }
         spinner.update_text("Deleting files from repository");
@@ -136,58 +119,53 @@ pub async fn delete(
         spinner.update_text("Committing changes");
 
         // Commit the changes
-        let mut index = repo.index()?;
-        let mut imp = |path: &std::path::Path, _data: &[u8]| {
-            if path.starts_with(".git") {
-                return 1; // skip .git/
-            }
-            return 0;
-        };
-        // Add all files to the index
-        index
-            .add_all(["*"], IndexAddOption::DEFAULT, Some(&mut imp))
-            .context("Could not add files")?;
-        let oid = index.write_tree().context("Failed to write tree")?;
+        // The entry's directory is gone, so this removes everything in it from the index
+        let staged = git::stage_paths(&repo, &[&name], [PathBuf::from(&name)])?;
         // Get the last commit
         let parent_commit = repo
             .find_last_commit()
             .context("Failed to retrieve last commit")?;
         // Await the user signature from the GitHub API
-        let sig = match &config.confinuum.signature_source {
-            SignatureSource::Github => github
-                .get_user_signature()
-                .await
-                .context("Could not fetch user signature from github")?,
-            SignatureSource::GitConfig => {
-                // allows users to set values in config if they don't exist
-                git::gitconfig::get_user_sig()?
-            }
-        };
+        let sig = git::commit_signature(&config, github).await?;
         let tree = repo
-            .find_tree(oid)
+            .find_tree(staged.oid)
             .context("Failed to find new commit tree")?;
-        let message = format!(
-            "Deleted entry `{}`\n\nDeleted files:\n{}",
-            name,
-            removed_entry
-                .files
-                .iter()
-                .map(|f| f.display().to_string())
-                .collect::<Vec<_>>()
-                .join("\n")
+        let files = removed_entry
+            .files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        let subject = config.confinuum.commit_message.subject(
+            CommitKind::Delete,
+            &name,
+            &files,
+            format!("Deleted entry `{}`", name),
         );
+        let message = staged.message(format!(
+            "{}\n\nDeleted files:\n{}",
+            subject,
+            files.join("\n")
+        ));
 
         // Make the commit
-        repo.commit(Some("HEAD"), &sig, &sig, &message, &tree, &[&parent_commit])
+        repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
             .context("Failed to commit files")?;
 
-        if push {
+        if push && offline {
+            info!(
+                "Skipping push while offline, run `confinuum push` once the remote is reachable."
+            );
+        } else if push {
             // Push the changes
             spinner.update_text("Pushing changes to remote");
             let mut pushopt = git2::PushOptions::new();
             pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+            let mut remote = git::find_origin(&repo)?;
             remote
-                .push(&["refs/heads/main:refs/heads/main"], Some(&mut pushopt))
+                .push(
+                    &[git::branch_refspec(&config.confinuum.branch)],
+                    Some(&mut pushopt),
+                )
                 .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
         }
     }
@@ -0,0 +1,50 @@
+use crate::{cli::info, config::ConfinuumConfig, git, github::Github};
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+
+/// Set or clear an entry's description, and commit the change to config.toml
+/// The description is only shown by `list` and `show`, so nothing is redeployed.
+pub async fn set_description(
+    name: String,
+    description: Option<String>,
+    clear: bool,
+    github: &Github,
+) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let mut config = ConfinuumConfig::load()?;
+    let entry = config.entry_mut(&name)?;
+
+    let description = match description {
+        _ if clear => None,
+        Some(description) => Some(description),
+        None => Some(
+            dialoguer::Input::<String>::new()
+                .with_prompt(format!("Description for {}", name))
+                .with_initial_text(entry.description.clone().unwrap_or_default())
+                .interact_text()
+                .context("Failed to interact with user, cancelling.")?,
+        ),
+    }
+    .map(|description| description.trim().to_owned())
+    .filter(|description| !description.is_empty());
+    if description == entry.description {
+        info!("The description of {} is unchanged", name);
+        return Ok(());
+    }
+    entry.description = description;
+    let message = match &entry.description {
+        Some(description) => format!("Set description of `{}`\n\n{}", name, description),
+        None => format!("Cleared description of `{}`", name),
+    };
+    git::commit_config(&repo, &config, &message, github).await?;
+
+    info!(
+        "{} Updated the description of {}. Run `confinuum push` to push the change to the remote.",
+        "✔".green(),
+        name
+    );
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::{DiffFormat, DiffOptions, Repository};
+
+use crate::{
+    config::ConfinuumConfig,
+    git::{self, RepoExtensions},
+    pager,
+};
+
+/// Show the changes made to the config since the last commit, usually by editing files through
+/// their deployed links. Unlike `check`, this compares against the local HEAD, so no network is
+/// needed.
+pub fn diff(name: Option<String>, word_diff: bool, no_pager: bool) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let mut diff_opt = DiffOptions::new();
+    diff_opt.include_untracked(false).include_ignored(false);
+    if let Some(name) = &name {
+        let config = ConfinuumConfig::load()?;
+        if !config.entries.contains_key(name) {
+            return Err(config.entry_not_found(name).into());
+        }
+        diff_opt.pathspec(format!("{}/", name));
+    }
+
+    let head_tree = repo.find_last_commit()?.tree()?;
+    let diff = repo
+        .diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut diff_opt))
+        .context("Failed to diff working directory")?;
+    if diff.deltas().len() == 0 {
+        match name {
+            Some(name) => println!("No uncommitted changes in {}", name.yellow()),
+            None => println!("No uncommitted changes"),
+        }
+        return Ok(());
+    }
+
+    let mut rendered = Vec::new();
+    git::print_diff(&diff, DiffFormat::Patch, word_diff, &mut rendered)?;
+    pager::page(&rendered, no_pager)
+}
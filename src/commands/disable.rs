@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+
+use crate::{cli::info, config::ConfinuumConfig, git, github::Github};
+
+/// Stop deploying an entry without removing it, and remove its deployed links
+pub async fn disable(name: String, github: &Github) -> Result<()> {
+    set_disabled(name, true, github).await
+}
+
+/// Deploy a disabled entry again
+pub async fn enable(name: String, github: &Github) -> Result<()> {
+    set_disabled(name, false, github).await
+}
+
+async fn set_disabled(name: String, disabled: bool, github: &Github) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let mut config = ConfinuumConfig::load()?;
+    let entry = config.entry_mut(&name)?;
+    if entry.disabled == disabled {
+        info!(
+            "{} is already {}",
+            name,
+            if disabled { "disabled" } else { "enabled" }
+        );
+        return Ok(());
+    }
+    entry.disabled = disabled;
+    let message = if disabled {
+        format!("Disabled `{}`", name)
+    } else {
+        format!("Enabled `{}`", name)
+    };
+    git::commit_config(&repo, &config, &message, github).await?;
+
+    // Deploying loads the config again, so this has to happen after it's saved
+    let names = HashSet::from([name.clone()]);
+    if disabled {
+        super::undeploy(Some(&names))?;
+        info!(
+            "{} Disabled {}, its files were removed from {}. Run `confinuum push` to push the change to the remote.",
+            "✔".green(),
+            name,
+            config.entries[&name]
+                .deploy_dir()
+                .map_or("its target directory".to_owned(), |dir| dir.display().to_string())
+        );
+    } else {
+        super::deploy(Some(&names))?;
+        info!(
+            "{} Enabled and deployed {}. Run `confinuum push` to push the change to the remote.",
+            "✔".green(),
+            name
+        );
+    }
+    Ok(())
+}
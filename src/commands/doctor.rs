@@ -0,0 +1,440 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use crossterm::style::Stylize;
+use git2::{Direction, Repository};
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{CreateSharedSpinner, SharedSpinner},
+    config::{self, ConfigEntry, ConfinuumConfig, GitProtocol, Hosting, LinkMode, SignatureSource},
+    git,
+    gitea::{Gitea, GiteaAuthFile},
+    github::{AuthFile, Github},
+    gitlab::{Gitlab, GitlabAuthFile},
+    hosting::Provider,
+};
+
+enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Prints the result of each check as it's made, and counts the failures
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn print(&mut self, outcome: Outcome, message: impl AsRef<str>, hint: Option<&str>) {
+        let icon = match outcome {
+            Outcome::Pass => "✔".green(),
+            Outcome::Warn => "!".yellow(),
+            Outcome::Fail => {
+                self.failures += 1;
+                "✘".red()
+            }
+        };
+        println!("{} {}", icon, message.as_ref());
+        if let Some(hint) = hint {
+            println!("  {} {}", "\u{21B3}".dark_grey(), hint.dark_grey());
+        }
+    }
+
+    fn pass(&mut self, message: impl AsRef<str>) {
+        self.print(Outcome::Pass, message, None);
+    }
+
+    fn warn(&mut self, message: impl AsRef<str>, hint: &str) {
+        self.print(Outcome::Warn, message, Some(hint));
+    }
+
+    fn fail(&mut self, message: impl AsRef<str>, hint: &str) {
+        self.print(Outcome::Fail, message, Some(hint));
+    }
+}
+
+/// Check for common setup problems and print a hint for each one found
+/// With `fix`, the problems that can be repaired without risking any data are fixed: missing
+/// links are created and auth files are made private.
+pub fn doctor(fix: bool) -> Result<()> {
+    let mut report = Report::default();
+
+    check_environment(&mut report);
+    check_auth_files(&mut report, fix);
+    let config = match ConfinuumConfig::load() {
+        Ok(config) => {
+            report.pass("config.toml parses");
+            Some(config)
+        }
+        Err(e) => {
+            report.fail(
+                format!("Could not load config.toml: {:#}", e),
+                "Run `confinuum init` to create a config, or fix the file by hand",
+            );
+            None
+        }
+    };
+    if let Some(config) = &config {
+        check_repo(&mut report, config);
+        check_credentials(&mut report, config);
+        let config_dir = ConfinuumConfig::get_dir()?;
+        let mut names = config.entries.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            check_entry(&mut report, &config_dir, &config.entries[name], fix);
+        }
+    }
+
+    if report.failures > 0 {
+        return Err(anyhow!(
+            "{} check{} failed",
+            report.failures,
+            if report.failures == 1 { "" } else { "s" }
+        ));
+    }
+    println!("\nNo problems found");
+    Ok(())
+}
+
+fn check_environment(report: &mut Report) {
+    match std::env::var("HOME").map(PathBuf::from) {
+        Err(_) => report.fail(
+            "HOME is not set",
+            "Set HOME to your home directory, it's used to find your SSH keys and (by default) the config",
+        ),
+        Ok(home) if !home.is_absolute() || !home.is_dir() => report.fail(
+            format!(
+                "HOME ({}) is not an absolute path to a directory",
+                home.display()
+            ),
+            "Set HOME to your home directory",
+        ),
+        Ok(home) => report.pass(format!("HOME is {}", home.display())),
+    }
+    match ConfinuumConfig::resolve_dir() {
+        Ok((dir, source)) => report.pass(format!(
+            "The config directory is {} (found through {})",
+            dir.display(),
+            source
+        )),
+        Err(e) => report.fail(
+            format!("{:#}", e),
+            "Set CONFINUUM_CONFIG_DIR to an absolute path, or HOME to your home directory",
+        ),
+    }
+}
+
+fn check_auth_files(report: &mut Report, fix: bool) {
+    let paths = [
+        AuthFile::get_path(),
+        GitlabAuthFile::get_path(),
+        GiteaAuthFile::get_path(),
+    ];
+    for path in paths.into_iter().flatten().filter(|path| path.is_file()) {
+        let exposed = match config::exposed_permissions(&path) {
+            Ok(exposed) => exposed,
+            Err(e) => {
+                report.fail(format!("{:#}", e), "Check that the file is readable");
+                continue;
+            }
+        };
+        let Some(exposed) = exposed else {
+            report.pass(format!("{} is only readable by you", path.display()));
+            continue;
+        };
+        let fixed = fix && fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).is_ok();
+        if fixed {
+            report.pass(format!("Made {} private", path.display()));
+        } else {
+            report.fail(
+                format!(
+                    "{} has permissions {:o}, so other users can access your token",
+                    path.display(),
+                    exposed | 0o600
+                ),
+                &format!(
+                    "Run `chmod 600 {}` or `confinuum doctor --fix`",
+                    path.display()
+                ),
+            );
+        }
+    }
+}
+
+fn check_repo(report: &mut Report, config: &ConfinuumConfig) {
+    let repo = match ConfinuumConfig::get_dir().and_then(|dir| Ok(Repository::open(dir)?)) {
+        Ok(repo) => repo,
+        Err(e) => {
+            report.fail(
+                format!("Could not open the config repo: {:#}", e),
+                "Run `confinuum init` to set up the config repo",
+            );
+            return;
+        }
+    };
+    report.pass("The config repo opens");
+    let Ok(mut remote) = repo.find_remote("origin") else {
+        // Configs created with `init --local` don't have one until it's added
+        report.warn(
+            "The config repo has no remote named 'origin', so configs aren't synced",
+            "Add one with `confinuum remote add <url>`",
+        );
+        return;
+    };
+    let url = remote.url().unwrap_or_default().to_owned();
+    let is_ssh = url.starts_with("ssh://") || (!url.contains("://") && url.contains('@'));
+    match (&config.confinuum.git_protocol, is_ssh) {
+        (GitProtocol::Ssh, false) | (GitProtocol::Https, true) => report.warn(
+            format!(
+                "Remote 'origin' ({}) doesn't use the configured protocol ({:?})",
+                url, config.confinuum.git_protocol
+            ),
+            "Change the remote's URL with `confinuum remote set-url <url>`, which updates `git_protocol` to match",
+        ),
+        _ => report.pass(format!("Remote 'origin' is {}", url)),
+    }
+    if !is_ssh && https_credentials().is_none() {
+        // Connecting would prompt for a password, which is the problem being reported
+        report.warn(
+            "Skipped connecting to the remote, since there's no git credential helper to authenticate with",
+            "Set up a credential helper (e.g. `gh auth setup-git`), or switch to SSH",
+        );
+        return;
+    }
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        "Connecting to remote 'origin'",
+        Color::Blue,
+    );
+    // The connection holds on to the spinner, so it's dropped before the spinner is cleared
+    let connected = remote
+        .connect_auth(
+            Direction::Fetch,
+            Some(git::construct_callbacks(spinner.clone())),
+            None,
+        )
+        .map(|_| ());
+    spinner.clear();
+    match connected {
+        Ok(()) => report.pass("Remote 'origin' is reachable"),
+        Err(e) if e.code() == git2::ErrorCode::Auth => report.fail(
+            format!("Remote 'origin' rejected your credentials: {}", e.message()),
+            "Check that your SSH key or token has access to the repository",
+        ),
+        Err(e) => report.fail(
+            format!("Could not reach remote 'origin': {}", e.message()),
+            "Check your network connection and the remote's URL",
+        ),
+    }
+}
+
+/// The credential helper git would use for HTTPS remotes
+fn https_credentials() -> Option<String> {
+    git2::Config::open_default()
+        .ok()?
+        .get_string("credential.helper")
+        .ok()
+        .filter(|helper| !helper.is_empty())
+}
+
+fn check_credentials(report: &mut Report, config: &ConfinuumConfig) {
+    match config.confinuum.git_protocol {
+        GitProtocol::Ssh => match git::find_ssh_key() {
+            Ok(key) => report.pass(format!("Found SSH key {}", key.display())),
+            Err(_) => report.fail(
+                "No SSH key found in ~/.ssh",
+                "Create one with `ssh-keygen -t ed25519` and add the public key to your account",
+            ),
+        },
+        GitProtocol::Https => match https_credentials() {
+            Some(helper) => report.pass(format!("Using git credential helper '{}'", helper)),
+            None => report.warn(
+                "No git credential helper is configured, so pushing will ask for a password",
+                "Set up a credential helper (e.g. `gh auth setup-git`), or switch to SSH",
+            ),
+        },
+    }
+
+    let hosting = match config.confinuum.signature_source {
+        SignatureSource::Github => Hosting::Github,
+        SignatureSource::Gitlab => Hosting::Gitlab,
+        SignatureSource::Gitea => Hosting::Gitea,
+        SignatureSource::GitConfig => return,
+    };
+    let logged_in = match hosting {
+        Hosting::Github => Github::new().map(|github| github.is_logged_in()),
+        Hosting::Gitlab => {
+            Gitlab::new(config.confinuum.gitlab_url.as_deref()).map(|gitlab| gitlab.is_logged_in())
+        }
+        Hosting::Gitea => {
+            Gitea::new(config.confinuum.gitea_url.as_deref()).map(|gitea| gitea.is_logged_in())
+        }
+    };
+    match logged_in {
+        Ok(true) => report.pass(format!("Logged in to {} to sign commits", hosting)),
+        Ok(false) => report.warn(
+            format!(
+                "Not logged in to {}, commits will be signed with your git config",
+                hosting
+            ),
+            &format!("Run `{}` to log in", hosting.login_command()),
+        ),
+        Err(e) => report.fail(
+            format!("Could not load your {} credentials: {:#}", hosting, e),
+            &format!("Run `{}` to log in again", hosting.login_command()),
+        ),
+    }
+}
+
+fn check_entry(report: &mut Report, config_dir: &Path, entry: &ConfigEntry, fix: bool) {
+    let name = entry.name.as_str().bold();
+    let (Some(target), Some(target_dir)) = (entry.target(), entry.deploy_dir()) else {
+        report.warn(
+            format!("{} is uninitialized", name),
+            &format!(
+                "Add files with `confinuum entry {} add <files>`",
+                entry.name
+            ),
+        );
+        return;
+    };
+    if entry.disabled {
+        report.pass(format!("{} is disabled", name));
+        return;
+    }
+    if let Some(reason) = entry.mismatch() {
+        report.pass(format!(
+            "{} isn't deployed on this machine ({})",
+            name, reason
+        ));
+        return;
+    }
+    if !target.is_absolute() {
+        report.fail(
+            format!("{} has a relative target ({})", name, target.display()),
+            "Set its target_dir (or target_file) to an absolute path in config.toml",
+        );
+        return;
+    }
+    let source_dir = config_dir.join(&entry.name);
+    if entry.link_mode == LinkMode::Directory {
+        if target_dir.read_link().ok().as_ref() == Some(&source_dir) {
+            report.pass(format!("{}: {} linked", name, target_dir.display()));
+        } else if target_dir.exists() || target_dir.is_symlink() {
+            report.fail(
+                format!(
+                    "{}: {} is not a link to the config repo",
+                    name,
+                    target_dir.display()
+                ),
+                "Run `confinuum redeploy` to replace it with the link, moving anything in it to .backups",
+            );
+        } else {
+            report.warn(
+                format!("{}: {} is not deployed", name, target_dir.display()),
+                "Run `confinuum redeploy`",
+            );
+        }
+        return;
+    }
+    if target_dir.read_link().ok().as_ref() == Some(&source_dir) {
+        report.fail(
+            format!(
+                "{}: {} links to the whole entry directory, but the entry links each file",
+                name,
+                target_dir.display()
+            ),
+            "Run `confinuum redeploy` to link the files instead, or set `link_mode = \"directory\"`",
+        );
+        return;
+    }
+    if !target_dir.is_dir() {
+        report.warn(
+            format!(
+                "{}'s target_dir {} doesn't exist",
+                name,
+                target_dir.display()
+            ),
+            "It will be created the next time the entry is deployed",
+        );
+    }
+
+    let mut problems = 0;
+    for file in &entry.files {
+        let source_path = config_dir.join(&entry.name).join(file);
+        let target_path = target_dir.join(file);
+        if !source_path.is_file() {
+            problems += 1;
+            report.fail(
+                format!(
+                    "{}: {} is missing from the config repo",
+                    name,
+                    file.display()
+                ),
+                &format!(
+                    "Restore it from history, or remove it with `confinuum entry {} remove`",
+                    entry.name
+                ),
+            );
+            continue;
+        }
+        let link = target_path.read_link().ok();
+        if link.as_ref() == Some(&source_path) {
+            continue;
+        }
+        problems += 1;
+        // Missing and dangling links can be replaced without losing anything
+        let missing = !target_path.exists();
+        if missing && fix && relink(&source_path, &target_path).is_ok() {
+            report.pass(format!("{}: linked {}", name, target_path.display()));
+            continue;
+        }
+        let message = match (link, missing) {
+            (_, true) => format!("{}: {} is not deployed", name, target_path.display()),
+            (Some(link), false) => format!(
+                "{}: {} links to {} instead",
+                name,
+                target_path.display(),
+                link.display()
+            ),
+            (None, false) => format!(
+                "{}: {} is a regular file, not a link to the config repo",
+                name,
+                target_path.display()
+            ),
+        };
+        if missing {
+            report.warn(
+                message,
+                "Run `confinuum redeploy` or `confinuum doctor --fix`",
+            );
+        } else {
+            report.fail(
+                message,
+                "Run `confinuum redeploy` to replace it with the link",
+            );
+        }
+    }
+    if problems == 0 {
+        report.pass(format!("{}: {} files linked", name, entry.files.len()));
+    }
+}
+
+/// Link a file from the config repo into place, replacing a dangling link if there is one
+fn relink(source_path: &Path, target_path: &Path) -> Result<()> {
+    if target_path.is_symlink() {
+        fs::remove_file(target_path)?;
+    }
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(source_path, target_path)?;
+    Ok(())
+}
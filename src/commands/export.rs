@@ -0,0 +1,243 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    fs,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use crossterm::style::Stylize;
+use flate2::{write::GzEncoder, Compression};
+use git2::Repository;
+
+use crate::{
+    cli::info,
+    config::ConfinuumConfig,
+    git::{self, RepoExtensions},
+};
+
+/// The formats `export` can write
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// A gzipped tarball to extract into a home directory
+    #[value(name = "tar.gz")]
+    TarGz,
+    /// A POSIX shell script that writes the files when run, e.g. piped to `ssh host sh`
+    Script,
+}
+
+/// A file to export, as it will be written to the other machine
+struct ExportFile {
+    /// The file in the config repo
+    source: PathBuf,
+    contents: Vec<u8>,
+    executable: bool,
+}
+
+/// Write the deployed files of the given entries (or all enabled entries) as plain files, for
+/// machines that don't have confinuum installed
+/// Paths in the output are relative to $HOME, so files deployed outside of it are left out. The
+/// files and config.toml are read from the last commit, so uncommitted edits (e.g. made through
+/// the deployed links) aren't exported, and exporting the same commit twice gives the same bytes.
+pub fn export(
+    names: Vec<String>,
+    tags: Vec<String>,
+    format: ExportFormat,
+    out: Option<PathBuf>,
+) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Could not get config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let head = repo.find_last_commit()?;
+    let tree = head.tree()?;
+    let config = git::config_at_tree(&repo, &tree)
+        .context("Could not read config.toml from the last commit")?;
+    let home = PathBuf::from(std::env::var("HOME").context("Could not find home directory")?);
+    if let Some(name) = names
+        .iter()
+        .find(|name| !config.entries.contains_key(*name))
+    {
+        return Err(config.entry_not_found(name).into());
+    }
+    let tagged = config.tagged_entries(&tags);
+
+    let mut entries = config
+        .entries
+        .values()
+        .filter(|entry| names.is_empty() || names.contains(&entry.name))
+        .filter(|entry| {
+            tagged
+                .as_ref()
+                .is_none_or(|tagged| tagged.contains(&entry.name))
+        })
+        .filter(|entry| entry.deploy_dir().is_some() && !entry.files.is_empty())
+        .collect::<Vec<_>>();
+    // Naming a disabled entry exports it anyway, but it's left out of a full export
+    entries.retain(|entry| {
+        if entry.disabled && !names.contains(&entry.name) {
+            eprintln!("{} skipped (disabled)", entry.name);
+            return false;
+        }
+        true
+    });
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    if entries.is_empty() {
+        return Err(anyhow!("No entries to export"));
+    }
+
+    // Keyed by the path relative to $HOME, which also gives the output a stable order
+    let mut files = BTreeMap::<PathBuf, ExportFile>::new();
+    for entry in &entries {
+        let deploy_dir = entry.deploy_dir().unwrap();
+        for file in &entry.files {
+            let target = deploy_dir.join(file);
+            let Ok(relative) = target.strip_prefix(&home) else {
+                eprintln!(
+                    "{} Skipping {}, it's deployed outside of {}",
+                    "!".yellow(),
+                    target.display(),
+                    home.display()
+                );
+                continue;
+            };
+            let source = config_dir.join(&entry.name).join(file);
+            let tree_entry = tree
+                .get_path(&Path::new(&entry.name).join(file))
+                .with_context(|| format!("{} is not committed", source.display()))?;
+            let contents = tree_entry
+                .to_object(&repo)?
+                .peel_to_blob()
+                .with_context(|| format!("Could not read {}", source.display()))?
+                .content()
+                .to_vec();
+            let executable = tree_entry.filemode() == i32::from(git2::FileMode::BlobExecutable);
+            files.insert(
+                relative.to_path_buf(),
+                ExportFile {
+                    source,
+                    contents,
+                    executable,
+                },
+            );
+        }
+    }
+
+    let output = match format {
+        ExportFormat::TarGz => {
+            if out.is_none() && std::io::stdout().is_terminal() {
+                return Err(anyhow!(
+                    "Refusing to write an archive to the terminal, pass --out or redirect the output"
+                ));
+            }
+            // The time of the last commit rather than the files' own, which differ between clones
+            let mtime = head.time().seconds().max(0) as u64;
+            archive(&files, mtime)?
+        }
+        ExportFormat::Script => script(&files)?.into_bytes(),
+    };
+    match &out {
+        Some(out) => {
+            fs::write(out, output).with_context(|| format!("Could not write {}", out.display()))?
+        }
+        None => std::io::stdout()
+            .lock()
+            .write_all(&output)
+            .context("Could not write to stdout")?,
+    }
+    if let Some(out) = out {
+        info!(
+            "Exported {} file{} from {} entr{} to {}",
+            files.len(),
+            if files.len() == 1 { "" } else { "s" },
+            entries.len(),
+            if entries.len() == 1 { "y" } else { "ies" },
+            out.display()
+        );
+    }
+    Ok(())
+}
+
+/// Build a gzipped tarball of the files
+/// Owners and timestamps are fixed, so the archive only depends on the files' contents.
+fn archive(files: &BTreeMap<PathBuf, ExportFile>, mtime: u64) -> Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, file) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file.contents.len() as u64);
+        header.set_mode(if file.executable { 0o755 } else { 0o644 });
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        builder
+            .append_data(&mut header, path, file.contents.as_slice())
+            .with_context(|| format!("Could not archive {}", file.source.display()))?;
+    }
+    let encoder = builder.into_inner().context("Could not write archive")?;
+    encoder.finish().context("Could not compress archive")
+}
+
+/// Build a shell script that writes the files into $HOME with here-documents
+fn script(files: &BTreeMap<PathBuf, ExportFile>) -> Result<String> {
+    let mut script = String::from("#!/bin/sh\n# Generated by confinuum export\nset -e\n\n");
+    let dirs = files
+        .keys()
+        .filter_map(|path| path.parent())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        script.push_str(&format!("mkdir -p \"$HOME\"/{}\n", shell_quote(dir)));
+    }
+    for (path, file) in files {
+        // Shells can't pass NUL bytes through a here-document, and other encodings would get
+        // mangled by the terminal
+        let text = std::str::from_utf8(&file.contents)
+            .ok()
+            .filter(|text| !text.contains('\0'))
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is a binary file, which can't be exported as a script. Use --format tar.gz instead.",
+                    file.source.display()
+                )
+            })?;
+        let target = format!("\"$HOME\"/{}", shell_quote(path));
+        let delimiter = delimiter(text);
+        script.push('\n');
+        if text.is_empty() {
+            script.push_str(&format!(": > {}\n", target));
+        } else if let Some(text) = text.strip_suffix('\n') {
+            script.push_str(&format!(
+                "cat > {} <<'{}'\n{}\n{}\n",
+                target, delimiter, text, delimiter
+            ));
+        } else {
+            // The here-document adds a trailing newline the file doesn't have, which the command
+            // substitution strips again
+            script.push_str(&format!(
+                "printf '%s' \"$(cat <<'{}'\n{}\n{}\n)\" > {}\n",
+                delimiter, text, delimiter, target
+            ));
+        }
+        if file.executable {
+            script.push_str(&format!("chmod +x {}\n", target));
+        }
+    }
+    Ok(script)
+}
+
+/// A here-document delimiter that doesn't appear as a line of the text
+fn delimiter(text: &str) -> String {
+    let lines = text.lines().collect::<HashSet<_>>();
+    let mut delimiter = "CONFINUUM_EOF".to_owned();
+    let mut n = 1;
+    while lines.contains(delimiter.as_str()) {
+        delimiter = format!("CONFINUUM_EOF_{}", n);
+        n += 1;
+    }
+    delimiter
+}
+
+/// Quote a path for a POSIX shell
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use crate::{cli::info, config::ConfinuumConfig};
+
+/// How much of the start of a file is checked for NUL bytes to tell if it's binary, like git does
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// A line that matched, as printed with --json
+#[derive(Serialize)]
+struct Match<'a> {
+    entry: &'a str,
+    path: &'a Path,
+    /// Not given with --files-with-matches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    /// Byte ranges of the matches within the line
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ranges: Vec<(usize, usize)>,
+}
+
+/// Search the files in the config repo for a regex, in every entry or only in `name`
+/// Prints each matching line as `entry:path:line: text`, or only `entry:path` once per file with
+/// `files_with_matches`. Binary files, and files the config repo ignores, are skipped.
+pub fn grep(
+    pattern: String,
+    name: Option<String>,
+    ignore_case: bool,
+    files_with_matches: bool,
+    json: bool,
+) -> Result<()> {
+    let regex = RegexBuilder::new(&pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|e| anyhow!("Invalid pattern {:?}: {}", pattern, e))?;
+    let config = ConfinuumConfig::load()?;
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let mut entries = match &name {
+        Some(name) => vec![config.entry(name)?],
+        None => config.entries.values().collect(),
+    };
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut matched_files = 0;
+    for entry in entries {
+        for file in &entry.files {
+            let in_repo = Path::new(&entry.name).join(file);
+            if repo.is_path_ignored(&in_repo).unwrap_or(false) {
+                continue;
+            }
+            let path = config_dir.join(&in_repo);
+            let contents = match fs::read(&path) {
+                Ok(contents) => contents,
+                Err(e) => {
+                    tracing::debug!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if contents[..contents.len().min(BINARY_CHECK_LEN)].contains(&0) {
+                tracing::debug!("Skipping binary file {}", path.display());
+                continue;
+            }
+            let contents = String::from_utf8_lossy(&contents);
+            let mut found = false;
+            for (number, line) in contents.lines().enumerate() {
+                let ranges = regex
+                    .find_iter(line)
+                    .map(|found| (found.start(), found.end()))
+                    .collect::<Vec<_>>();
+                if ranges.is_empty() {
+                    continue;
+                }
+                found = true;
+                if files_with_matches {
+                    break;
+                }
+                if json {
+                    let found = Match {
+                        entry: &entry.name,
+                        path: file,
+                        line: Some(number + 1),
+                        text: Some(line),
+                        ranges,
+                    };
+                    writeln!(out, "{}", serde_json::to_string(&found)?)?;
+                } else {
+                    writeln!(
+                        out,
+                        "{}:{}:{}: {}",
+                        entry.name.as_str().yellow(),
+                        file.display().to_string().blue(),
+                        (number + 1).to_string().green(),
+                        highlight(line, &regex)
+                    )?;
+                }
+            }
+            if !found {
+                continue;
+            }
+            matched_files += 1;
+            if !files_with_matches {
+                continue;
+            }
+            if json {
+                let found = Match {
+                    entry: &entry.name,
+                    path: file,
+                    line: None,
+                    text: None,
+                    ranges: Vec::new(),
+                };
+                writeln!(out, "{}", serde_json::to_string(&found)?)?;
+            } else {
+                writeln!(
+                    out,
+                    "{}:{}",
+                    entry.name.as_str().yellow(),
+                    file.display().to_string().blue()
+                )?;
+            }
+        }
+    }
+    out.flush()?;
+    drop(out);
+    if matched_files == 0 && !json {
+        match name {
+            Some(name) => info!("No matches in {}", name.yellow()),
+            None => info!("No matches"),
+        }
+    }
+    Ok(())
+}
+
+/// The line with every match of the regex highlighted
+fn highlight(line: &str, regex: &Regex) -> String {
+    let mut highlighted = String::new();
+    let mut last = 0;
+    for found in regex.find_iter(line) {
+        highlighted.push_str(&line[last..found.start()]);
+        highlighted.push_str(&found.as_str().red().bold().to_string());
+        last = found.end();
+    }
+    highlighted.push_str(&line[last..]);
+    highlighted
+}
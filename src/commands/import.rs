@@ -0,0 +1,512 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use common_path::common_path_all;
+use crossterm::style::Stylize;
+use dialoguer::theme::ColorfulTheme;
+use git2::Repository;
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{ConfigEntry, ConfinuumConfig},
+    git::{self, RepoExtensions},
+    github::Github,
+    interrupt,
+    rollback::Rollback,
+};
+
+/// Files in a stow package that are stow's own, rather than part of the package
+const STOW_FILES: [&str; 2] = [".stow-local-ignore", ".git"];
+
+/// The files of a bare repo grouped by entry name, each group with the files that can be copied
+/// and those that can't (symlinks and files deleted from the work tree)
+type Groups = BTreeMap<String, (Vec<PathBuf>, Vec<PathBuf>)>;
+
+/// A stow package or a group of files from a bare repo, and the entry it becomes
+struct Package {
+    name: String,
+    /// The package's directory in the stow tree, or the bare repo's work tree
+    dir: PathBuf,
+    /// The directory within the package that all its files are in, which maps to the entry's
+    /// target_dir
+    base: PathBuf,
+    target_dir: PathBuf,
+    /// The package's files, relative to `base`
+    files: Vec<PathBuf>,
+    /// Why the package can't be imported, if it can't
+    skip: Option<String>,
+}
+
+impl Package {
+    /// Where the entry deploys to: its target_dir, or the file if the package only has one
+    fn target(&self) -> PathBuf {
+        match self.files.as_slice() {
+            [file] => self.target_dir.join(file),
+            _ => self.target_dir.clone(),
+        }
+    }
+}
+
+/// Create an entry for each package in a GNU Stow directory
+/// Stow packages mirror the directory they're stowed into (`target`, usually $HOME), so each
+/// entry's target_dir is the deepest directory that holds all of the package's files. With
+/// `unstow`, the links stow made are removed and the entries are deployed in their place,
+/// otherwise they're left alone and nothing is deployed.
+pub async fn import_stow(
+    stow_dir: PathBuf,
+    target: Option<PathBuf>,
+    unstow: bool,
+    dry_run: bool,
+    offline: bool,
+    github: &Github,
+) -> Result<()> {
+    let stow_dir = stow_dir
+        .canonicalize()
+        .with_context(|| format!("Stow directory {} does not exist", stow_dir.display()))?;
+    let target = match target {
+        Some(target) => target,
+        None => PathBuf::from(std::env::var("HOME").context("HOME is not set, pass --target")?),
+    };
+    let mut config = ConfinuumConfig::load()?;
+    let packages = find_packages(&stow_dir, &target, &config)?;
+    if packages.is_empty() {
+        return Err(anyhow!(
+            "{} doesn't contain any stow packages",
+            stow_dir.display()
+        ));
+    }
+
+    print_packages(&packages);
+    let packages = packages
+        .into_iter()
+        .filter(|package| package.skip.is_none())
+        .collect::<Vec<_>>();
+    if dry_run {
+        info!(
+            "\nDry run, nothing was changed. {} entr{} would be created.",
+            packages.len(),
+            if packages.len() == 1 { "y" } else { "ies" }
+        );
+        return Ok(());
+    }
+    if packages.is_empty() {
+        info!("\nNothing to import.");
+        return Ok(());
+    }
+    info!();
+
+    import_packages(
+        &mut config,
+        &packages,
+        &format!("stow directory {}", stow_dir.display()),
+        offline,
+        github,
+    )
+    .await?;
+
+    if unstow {
+        for package in &packages {
+            remove_stow_links(&stow_dir, &target, package)?;
+        }
+        let names = packages
+            .iter()
+            .map(|package| package.name.clone())
+            .collect::<HashSet<_>>();
+        // Deploying loads the config again, so this has to happen after it's saved
+        super::deploy(Some(&names))?;
+    }
+    if !unstow {
+        info!("The stow links were left in place. Unstow the packages (`stow -D`) and run `confinuum redeploy` to switch to confinuum's links.");
+    }
+    info!("Run `confinuum push` to push the new entries to the remote.");
+    Ok(())
+}
+
+/// Create an entry for each package in a bare git repo whose work tree is `target` (usually $HOME)
+/// The repo's tracked files are grouped by their top level directory in the work tree (or their
+/// directory in ~/.config), and the groups can be renamed or merged before the entries are
+/// created. The files are copied into the config and deployed in place of the originals, while the
+/// bare repo itself is left untouched.
+pub async fn import_bare_repo(
+    bare_repo: PathBuf,
+    target: Option<PathBuf>,
+    dry_run: bool,
+    offline: bool,
+    github: &Github,
+) -> Result<()> {
+    let target = match target {
+        Some(target) => target,
+        None => PathBuf::from(std::env::var("HOME").context("HOME is not set, pass --target")?),
+    };
+    let target = target
+        .canonicalize()
+        .with_context(|| format!("Work tree {} does not exist", target.display()))?;
+    let bare = Repository::open_bare(&bare_repo)
+        .with_context(|| format!("{} is not a bare git repository", bare_repo.display()))?;
+    let mut config = ConfinuumConfig::load()?;
+
+    // The index lists what's tracked, including changes that were staged but not committed
+    let mut groups = Groups::new();
+    for entry in bare
+        .index()
+        .context("Could not read the repository's index")?
+        .iter()
+    {
+        let file = PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+        let path = target.join(&file);
+        let dir = group_dir(&file);
+        let (files, skipped) = groups.entry(group_name(&dir)).or_default();
+        // Symlinks and files deleted from the work tree can't be copied into the config
+        if path.is_file() && !path.is_symlink() {
+            files.push(file);
+        } else {
+            skipped.push(file);
+        }
+    }
+    if groups.is_empty() {
+        return Err(anyhow!("{} doesn't track any files", bare_repo.display()));
+    }
+    for (name, (_, skipped)) in &groups {
+        for file in skipped {
+            info!(
+                "{}",
+                format!(
+                    "{}: skipped {}, it's missing or a symlink",
+                    name,
+                    file.display()
+                )
+                .dark_grey()
+            );
+        }
+    }
+
+    if std::io::stdin().is_terminal() {
+        groups = map_groups(groups)?;
+    }
+    let mut packages = groups
+        .into_iter()
+        .map(|(name, (files, _))| package(name, target.clone(), files, &target, &config))
+        .collect::<Vec<_>>();
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    print_packages(&packages);
+    let packages = packages
+        .into_iter()
+        .filter(|package| package.skip.is_none())
+        .collect::<Vec<_>>();
+    if dry_run {
+        info!(
+            "\nDry run, nothing was changed. {} entr{} would be created.",
+            packages.len(),
+            if packages.len() == 1 { "y" } else { "ies" }
+        );
+        return Ok(());
+    }
+    if packages.is_empty() {
+        info!("\nNothing to import.");
+        return Ok(());
+    }
+    info!();
+
+    import_packages(
+        &mut config,
+        &packages,
+        &format!("bare repository {}", bare_repo.display()),
+        offline,
+        github,
+    )
+    .await?;
+    let names = packages
+        .iter()
+        .map(|package| package.name.clone())
+        .collect::<HashSet<_>>();
+    // Replaces the original files with links to their copies in the config
+    super::deploy(Some(&names))?;
+
+    info!("\nCreated entries:");
+    for package in &packages {
+        info!(
+            "  {}: {} files in {}",
+            package.name.as_str().bold().yellow(),
+            package.files.len(),
+            package.target().display()
+        );
+    }
+    info!(
+        "{} was left untouched. Once you've checked that your files are deployed as expected, you can delete it.",
+        bare_repo.display()
+    );
+    info!("Run `confinuum push` to push the new entries to the remote.");
+    Ok(())
+}
+
+/// The directory a file in the work tree is grouped by: ~/.config/<dir> for XDG config files, its
+/// top level directory otherwise, or the file itself if it's directly in the work tree
+fn group_dir(file: &Path) -> PathBuf {
+    let parts = file.iter().collect::<Vec<_>>();
+    match parts.as_slice() {
+        [config, dir, _, ..] if *config == ".config" => Path::new(config).join(dir),
+        [dir, _, ..] => PathBuf::from(dir),
+        _ => file.to_path_buf(),
+    }
+}
+
+/// The entry name for a group, its directory's name without the leading dot (`.zshrc` is `zshrc`)
+fn group_name(dir: &Path) -> String {
+    let name = dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    match name.strip_prefix('.') {
+        Some(stripped) if !stripped.is_empty() => stripped.to_owned(),
+        _ => name,
+    }
+}
+
+/// Ask for each group's entry name, where giving two groups the same name merges them and an
+/// empty name leaves the group out
+fn map_groups(groups: Groups) -> Result<Groups> {
+    println!("Choose a name for each entry (the same name merges groups, an empty one skips it):");
+    let mut mapped = Groups::new();
+    for (name, (files, skipped)) in groups {
+        if files.is_empty() {
+            continue;
+        }
+        let dirs = files
+            .iter()
+            .map(|file| group_dir(file).display().to_string())
+            .collect::<std::collections::BTreeSet<_>>();
+        let new_name: String = dialoguer::Input::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "{} ({} files)",
+                dirs.into_iter().collect::<Vec<_>>().join(", "),
+                files.len()
+            ))
+            // Prefilled rather than a default, so that it can be cleared to skip the group
+            .with_initial_text(name)
+            .allow_empty(true)
+            .interact_text()?;
+        let new_name = new_name.trim();
+        if new_name.is_empty() {
+            continue;
+        }
+        let (mapped_files, mapped_skipped) = mapped.entry(new_name.to_owned()).or_default();
+        mapped_files.extend(files);
+        mapped_skipped.extend(skipped);
+    }
+    Ok(mapped)
+}
+
+/// Print the entries that importing would create, and why others are skipped
+fn print_packages(packages: &[Package]) {
+    for package in packages {
+        match &package.skip {
+            Some(reason) => info!(
+                "{}: {}",
+                package.name.as_str().dark_grey(),
+                format!("skipped, {}", reason).dark_grey()
+            ),
+            None => info!(
+                "{}: {} files\n\u{21B3} {}",
+                package.name.as_str().bold().yellow(),
+                package.files.len(),
+                package.target().display()
+            ),
+        }
+    }
+}
+
+/// Copy the packages' files into the config as new entries, and commit them
+async fn import_packages(
+    config: &mut ConfinuumConfig,
+    packages: &[Package],
+    source: &str,
+    offline: bool,
+    github: &Github,
+) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    git::check_remote(&repo, &config.confinuum.branch, "importing", offline)?;
+
+    let names = packages
+        .iter()
+        .map(|package| package.name.as_str())
+        .collect::<Vec<_>>();
+    let rollback = Rollback::new(&names)?;
+    let spinner = Spinner::new_shared(spinners::Dots9, "Importing packages", Color::Blue);
+    let mut imported_files = Vec::new();
+    for package in packages {
+        spinner.update_text(format!("Importing {}", package.name));
+        let mut entry = ConfigEntry::new(package.name.clone());
+        // Like `entry create`, a lone file is deployed to its exact path
+        match package.files.as_slice() {
+            [_] => entry.target_file = Some(package.target()),
+            _ => entry.target_dir = Some(package.target_dir.clone()),
+        }
+        let base = package.dir.join(&package.base);
+        let mut files = HashSet::new();
+        ConfinuumConfig::add_files_recursive(
+            &mut entry,
+            package.files.iter().map(|file| base.join(file)).collect(),
+            Some(base.clone()),
+            false,
+            &mut Some(&mut files),
+        )
+        .with_context(|| format!("Failed to import {}", package.name))?;
+        imported_files.extend(
+            files
+                .into_iter()
+                .map(|file| Path::new(&package.name).join(file)),
+        );
+        config.entries.insert(package.name.clone(), entry);
+    }
+    config.save().context("Failed to save config file")?;
+
+    spinner.update_text("Committing changes");
+    let staged = git::stage_paths(&repo, &names, imported_files)?;
+    let parent_commit = repo
+        .find_last_commit()
+        .context("Failed to retrieve last commit")?;
+    let sig = git::commit_signature(config, github).await?;
+    let tree = repo
+        .find_tree(staged.oid)
+        .context("Failed to find new commit tree")?;
+    let message = staged.message(format!(
+        "Imported {} entr{} from {}\n\n{}",
+        packages.len(),
+        if packages.len() == 1 { "y" } else { "ies" },
+        source,
+        packages
+            .iter()
+            .map(|package| format!(
+                "{}: {} files in {}",
+                package.name,
+                package.files.len(),
+                package.target().display()
+            ))
+            .collect::<Vec<_>>()
+            .join("\n")
+    ));
+    interrupt::check()?;
+    repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
+        .context("Failed to commit files")?;
+    rollback.keep();
+
+    spinner.success(&format!(
+        "Imported {} entr{} from {}",
+        packages.len(),
+        if packages.len() == 1 { "y" } else { "ies" },
+        source
+    ));
+    Ok(())
+}
+
+/// Read the packages in a stow directory, which are its top level directories
+fn find_packages(stow_dir: &Path, target: &Path, config: &ConfinuumConfig) -> Result<Vec<Package>> {
+    let mut packages = Vec::new();
+    for item in
+        fs::read_dir(stow_dir).with_context(|| format!("Could not read {}", stow_dir.display()))?
+    {
+        let dir = item?.path();
+        let name = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !dir.is_dir() || name.starts_with('.') {
+            continue;
+        }
+        let mut files = Vec::new();
+        package_files(&dir, &dir, &mut files)?;
+        packages.push(package(name, dir, files, target, config));
+    }
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(packages)
+}
+
+/// Build a package from its files, relative to `dir`, which are deployed to the same place in `target`
+fn package(
+    name: String,
+    dir: PathBuf,
+    files: Vec<PathBuf>,
+    target: &Path,
+    config: &ConfinuumConfig,
+) -> Package {
+    // The deepest directory holding every file, so `.config/nvim` for a package of nvim files
+    let base = common_path_all(files.iter().filter_map(|file| file.parent())).unwrap_or_default();
+    let files = files
+        .iter()
+        .filter_map(|file| file.strip_prefix(&base).ok())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    let skip = if config.entries.contains_key(&name) {
+        Some("an entry with that name already exists".to_owned())
+    } else if files.is_empty() {
+        Some("it has no files".to_owned())
+    } else {
+        ConfigEntry::validate_name(&name)
+            .err()
+            .map(|e| e.to_string())
+    };
+    // Joining an empty path would leave a trailing slash
+    let target_dir = if base.as_os_str().is_empty() {
+        target.to_path_buf()
+    } else {
+        target.join(&base)
+    };
+    Package {
+        name,
+        dir,
+        base,
+        files,
+        target_dir,
+        skip,
+    }
+}
+
+/// Collect the files of a package, relative to the package directory
+fn package_files(package_dir: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for item in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        let path = item?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| STOW_FILES.iter().any(|stow| name == *stow))
+        {
+            continue;
+        }
+        if path.is_dir() && !path.is_symlink() {
+            package_files(package_dir, &path, files)?;
+        } else {
+            files.push(path.strip_prefix(package_dir)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/// Remove the links stow made for a package's files
+/// Stow links whole directories when no other package shares them, so each of the file's parent
+/// directories is checked as well as the file itself.
+fn remove_stow_links(stow_dir: &Path, target: &Path, package: &Package) -> Result<()> {
+    for file in &package.files {
+        let mut path = target.to_path_buf();
+        for component in package.base.join(file).components() {
+            path.push(component);
+            let into_stow = path.is_symlink()
+                && path
+                    .canonicalize()
+                    .is_ok_and(|resolved| resolved.starts_with(stow_dir));
+            if into_stow {
+                fs::remove_file(&path)
+                    .with_context(|| format!("Could not remove {}", path.display()))?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
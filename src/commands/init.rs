@@ -1,21 +1,126 @@
 use anyhow::{anyhow, Context, Result};
-use dialoguer::{theme::ColorfulTheme, Select};
-use git2::Repository;
+use dialoguer::{theme::ColorfulTheme, MultiSelect, Select};
+use git2::{build::RepoBuilder, Direction, FetchOptions, Repository};
 use git_url_parse::GitUrl;
 use spinoff::{spinners, Color, Spinner};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tempdir::TempDir;
 
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
-    config::{ConfinuumConfig, GitProtocol, SignatureSource},
-    git::{self},
-    github::{Github, RepoCreateInfo},
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{ConfinuumConfig, GitProtocol, Hosting, SignatureSource, BACKUP_DIR},
+    error::ConfinuumError,
+    git::{self, RepoExtensions},
+    gitea::{self, Gitea},
+    github::Github,
+    gitlab::Gitlab,
+    hosting::{self, HostedRepo, Provider},
 };
 
+/// Options for `confinuum init`, each of which skips the corresponding prompt when set
+pub struct InitOptions {
+    /// Initialize from a git repo containing an existing confinuum config
+    pub git: Option<String>,
+    /// Overwrite the config if it already exists
+    pub force: bool,
+    /// Back up the whole config repo before overwriting it, instead of only config.toml
+    pub backup_repo: bool,
+    /// Create a repository with this name to host the config
+    pub create_repo: Option<String>,
+    /// Create the repository from this GitHub template (`owner/repo`), and clone it
+    pub template: Option<String>,
+    /// The service to create the repository on
+    pub hosting: Option<Hosting>,
+    /// The URL of a self-hosted GitLab instance
+    pub gitlab_url: Option<String>,
+    /// The URL of a Gitea or Forgejo instance other than Codeberg
+    pub gitea_url: Option<String>,
+    /// Use this existing remote repository to host the config
+    pub remote: Option<String>,
+    /// Don't set up a remote at all, one can be added later with `confinuum remote add`
+    pub local: bool,
+    pub protocol: Option<GitProtocol>,
+    pub signature: Option<SignatureSource>,
+    /// Make the created repository public
+    pub public: bool,
+    /// Description of the created repository
+    pub description: Option<String>,
+    /// Never prompt, and fail if a decision wasn't provided through the options
+    pub yes: bool,
+    /// Only clone this many commits of history when initializing from a git repo
+    pub depth: Option<u32>,
+}
+
+impl InitOptions {
+    /// List the decisions that would have to be prompted for
+    fn missing_decisions(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if self.git.is_some() {
+            return missing;
+        }
+        if self.create_repo.is_none() && self.remote.is_none() && !self.local {
+            missing.push("--create-repo <NAME> or --remote <URL>");
+        }
+        if self.create_repo.is_some() && self.protocol.is_none() {
+            missing.push("--protocol <ssh|https>");
+        }
+        // A template's config already says how to sign commits
+        if self.signature.is_none() && self.template.is_none() {
+            missing.push("--signature <github|gitlab|gitea|gitconfig>");
+        }
+        missing
+    }
+}
+
 /// Initialize the confinuum config file
-pub async fn init(git: Option<String>, force: bool, github: &Github) -> Result<()> {
-    if ConfinuumConfig::exists()? && !force {
-        return Err(anyhow::anyhow!(
-            "Config file already exists. Use --force to overwrite."
+pub async fn init(opts: InitOptions, mut github: Github) -> Result<()> {
+    let force = opts.force;
+    if opts.yes {
+        let missing = opts.missing_decisions();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "Cannot initialize without prompting, the following options are missing:\n    {}",
+                missing.join("\n    ")
+            ));
+        }
+    }
+    if let Some(name) = &opts.create_repo {
+        validate_repo_name(name)?;
+    }
+    if ConfinuumConfig::exists()? {
+        if !force {
+            return Err(ConfinuumError::AlreadyInitialized.into());
+        }
+        let confirmed = opts.yes
+            || dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "This will overwrite your existing config in {}. Continue?",
+                    ConfinuumConfig::get_dir()?.display()
+                ))
+                .default(false)
+                .interact()?;
+        if !confirmed {
+            println!("Cancelled, your config was left as is.");
+            return Ok(());
+        }
+        let backup_dir = backup_config(opts.backup_repo)?;
+        info!(
+            "Backed up your existing {} to {}",
+            if opts.backup_repo {
+                "config repo"
+            } else {
+                "config.toml"
+            },
+            backup_dir.display()
+        );
+    }
+    if opts.template.is_some() && !matches!(opts.hosting, None | Some(Hosting::Github)) {
+        return Err(anyhow!(
+            "--template can only be used to create the repository on GitHub"
         ));
     }
     // Create config directory if it doesn't exist
@@ -29,129 +134,268 @@ pub async fn init(git: Option<String>, force: bool, github: &Github) -> Result<(
     };
 
     // If user provided a git url, we can just clone it as it's already set up
-    if let Some(git_url) = git {
-        // Clone the repo
-        // TODO: Ensure the clone contains a valid config file, and if so validate the entries
-        Repository::clone(&git_url, config_dir).context(format!("Failed to clone {}", git_url))?;
-        super::deploy(None::<&str>)?;
-        return Ok(());
+    if let Some(git_url) = &opts.git {
+        return init_from_git(git_url, &config_dir, opts.depth, opts.yes);
     }
 
     let items = vec![
         "Create a new GitHub repository for me",
+        "Create a new GitLab repository for me",
+        "Create a new repository on a self-hosted GitLab instance",
+        "Create a new repository on Gitea/Forgejo (such as Codeberg)",
         "I'll create my own remote repository",
     ];
 
-    let selection = Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("How would you like to host your configs?")
-        .items(&items)
-        .default(0)
-        .interact_opt()?
-        .ok_or(anyhow!("No selection made, cancelling."))?;
-
-    let remote_url = match selection {
-        0 => {
-            let repo_info = RepoCreateInfo {
-                name: "confinuum-config".to_owned(),
-                description: "My confinuum config".to_owned(),
-                private: true,
-                is_template: false,
-                opt: None,
+    let selection = if opts.local {
+        5
+    } else if opts.template.is_some() {
+        0
+    } else if opts.create_repo.is_some() {
+        match (opts.hosting, &opts.gitlab_url, &opts.gitea_url) {
+            (Some(Hosting::Gitea), _, _) | (_, _, Some(_)) => 3,
+            (_, Some(_), _) => 2,
+            (Some(Hosting::Gitlab), None, None) => 1,
+            _ => 0,
+        }
+    } else if opts.remote.is_some() {
+        4
+    } else {
+        Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("How would you like to host your configs?")
+            .items(&items)
+            .default(0)
+            .interact_opt()?
+            .ok_or(anyhow!("No selection made, cancelling."))?
+    };
+
+    let mut gitlab_url = opts.gitlab_url.clone();
+    let mut gitea_url = opts.gitea_url.clone();
+    let hosting = match selection {
+        0 => Some(Hosting::Github),
+        1 => Some(Hosting::Gitlab),
+        2 => {
+            if gitlab_url.is_none() {
+                gitlab_url = Some(
+                    dialoguer::Input::with_theme(&ColorfulTheme::default())
+                        .with_prompt("URL of your GitLab instance")
+                        .interact_text()?,
+                );
+            }
+            Some(Hosting::Gitlab)
+        }
+        3 => {
+            if gitea_url.is_none() && !opts.yes {
+                let url: String = dialoguer::Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt("URL of your Gitea or Forgejo instance")
+                    .default(gitea::DEFAULT_URL.to_owned())
+                    .interact_text()?;
+                gitea_url = Some(url).filter(|url| url != gitea::DEFAULT_URL);
+            }
+            Some(Hosting::Gitea)
+        }
+        4 | 5 => None,
+        _ => unreachable!("Invalid selection made"),
+    };
+    let mut gitlab = Gitlab::new(gitlab_url.as_deref())?;
+    let mut gitea = Gitea::new(gitea_url.as_deref())?;
+
+    let mut repo_html_url = None;
+    let remote_url = match hosting {
+        None if opts.local => None,
+        Some(hosting) => {
+            let provider: &mut dyn Provider = match hosting {
+                Hosting::Github => &mut github,
+                Hosting::Gitlab => &mut gitlab,
+                Hosting::Gitea => &mut gitea,
+            };
+            ensure_logged_in(provider, opts.yes).await?;
+            let repo = create_hosted_repo(&opts, provider).await?;
+            repo_html_url = repo.html_url.clone();
+
+            let protocol = match opts.protocol {
+                Some(GitProtocol::Ssh) => 0,
+                Some(GitProtocol::Https) => 1,
+                None => dialoguer::Select::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Which protocol would you like to use?")
+                    .items(&["SSH", "HTTPS"])
+                    .default(0)
+                    .interact()?,
             };
-            let spinner = Spinner::new(
-                spinners::Dots9,
-                "Creating repository".to_string(),
-                Color::Blue,
-            );
-            let repo = github.create_repo(repo_info).await?;
-            spinner.success(&format!("Created repository {}!", &repo.name));
-
-            let protocol = dialoguer::Select::with_theme(&ColorfulTheme::default())
-                .with_prompt("Which protocol would you like to use?")
-                .items(&["SSH", "HTTPS"])
-                .default(0)
-                .interact()?;
 
-            if protocol == 0 {
+            let remote_url = if protocol == 0 {
                 if let Some(remote) = repo.ssh_url {
-                    GitUrl::parse(&remote.to_string()).map_err(|e| {
+                    GitUrl::parse(&remote).map_err(|e| {
                         anyhow::anyhow!(format!("Could not parse {} as a git url: {}", remote, e))
                     })?
                 } else {
                     return Err(anyhow!("No URL found for created repository"));
                 }
             } else {
-                GitUrl::parse(&repo.url.to_string()).map_err(|e| {
-                    anyhow::anyhow!(format!("Could not parse {} as a git url: {}", &repo.url, e))
+                GitUrl::parse(&repo.https_url).map_err(|e| {
+                    anyhow::anyhow!(format!(
+                        "Could not parse {} as a git url: {}",
+                        repo.https_url, e
+                    ))
                 })?
-            }
+            };
+            Some(remote_url)
         }
-        1 => {
-            let remote_url: GitUrl = dialoguer::Input::with_theme(&ColorfulTheme::default())
-                .with_prompt("Enter the URL of your remote repository")
-                .interact()?;
+        None => {
+            let remote_url: GitUrl = if let Some(remote) = &opts.remote {
+                GitUrl::parse(remote)
+                    .map_err(|e| anyhow!("Could not parse {} as a git url: {}", remote, e))?
+            } else {
+                dialoguer::Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Enter the URL of your remote repository")
+                    .interact()?
+            };
             if remote_url.to_string().is_empty() {
                 return Err(anyhow!("No URL provided, cancelling."));
             }
-            remote_url
+            Some(remote_url)
         }
-        _ => unreachable!("Invalid selection made"),
     };
 
-    let git_protocol = match remote_url.scheme {
-        git_url_parse::Scheme::Https => GitProtocol::Https,
-        git_url_parse::Scheme::Ssh => GitProtocol::Ssh,
-        unsupported => {
-            return Err(anyhow!(
-                "Git protocol {} is not yet supported by Confinuum :/",
-                unsupported
-            ))
-        }
+    // The repository already has the template's config, so it's used like any existing config
+    if let (Some(_), Some(remote_url)) = (&opts.template, &remote_url) {
+        return init_from_git(&remote_url.to_string(), &config_dir, None, opts.yes);
+    }
+
+    // Without a remote the protocol is only a preference, until `remote add` sets it from the URL
+    let git_protocol = match &remote_url {
+        Some(remote_url) => git_protocol(remote_url)?,
+        None => opts.protocol.unwrap_or(GitProtocol::Ssh),
     };
 
-    let signature_source = match dialoguer::Select::with_theme(&ColorfulTheme::default())
-        .with_prompt("How would you like to sign your commits? Confinuum can source your name/email from you github account, or your git config.")
-        .items(&["GitHub", "Git config"])
-        .interact()? {
-            0 => SignatureSource::Github,
-            1 => SignatureSource::GitConfig,
-            _ => unreachable!("Impossible selection made!"),
-        };
+    let signature_source = match opts.signature {
+        Some(signature_source) => signature_source,
+        None => match dialoguer::Select::with_theme(&ColorfulTheme::default())
+            .with_prompt("How would you like to sign your commits? Confinuum can source your name/email from your GitHub, GitLab or Gitea account, or your git config.")
+            .items(&["GitHub", "GitLab", "Gitea", "Git config"])
+            .default(match hosting {
+                None if opts.local => 3,
+                Some(Hosting::Gitlab) => 1,
+                Some(Hosting::Gitea) => 2,
+                _ => 0,
+            })
+            .interact()? {
+                0 => SignatureSource::Github,
+                1 => SignatureSource::Gitlab,
+                2 => SignatureSource::Gitea,
+                3 => SignatureSource::GitConfig,
+                _ => unreachable!("Impossible selection made!"),
+            },
+    };
 
-    // Get the user's signature
-    let signature = match signature_source {
-        SignatureSource::Github => github
-            .get_user_signature()
-            .await
-            .context("Could not fetch user signature from github")?,
-        SignatureSource::GitConfig => {
+    // Get the user's signature, unless one was given with --name and --email
+    let signature = match (git::identity_override()?, &signature_source) {
+        (Some(signature), _) => signature,
+        (None, SignatureSource::Github | SignatureSource::Gitlab | SignatureSource::Gitea) => {
+            let provider: &mut dyn Provider = match signature_source {
+                SignatureSource::Github => &mut github,
+                SignatureSource::Gitlab => &mut gitlab,
+                _ => &mut gitea,
+            };
+            ensure_logged_in(provider, opts.yes).await?;
+            provider.get_user_signature().await.with_context(|| {
+                format!("Could not fetch user signature from {}", provider.hosting())
+            })?
+        }
+        (None, SignatureSource::GitConfig) if opts.yes => git::gitconfig::get_user_sig()?,
+        (None, SignatureSource::GitConfig) => {
             // allows users to set values in config if they don't exist
             git::gitconfig::get_user_sig_with_prompt()?
         }
     };
 
-    let spinner = Spinner::new_shared(
-        spinners::Dots9,
-        "Welcome to confinuum! Setting things up",
-        Color::Blue,
-    );
+    // Make sure the remote is empty, otherwise pushing the initial commit will be rejected
+    let remote_branches = match &remote_url {
+        Some(remote_url) => list_remote_branches(&remote_url.to_string())?,
+        None => Vec::new(),
+    };
+    let mut branch = "main".to_owned();
+    if let Some(remote_url) = remote_url.as_ref().filter(|_| !remote_branches.is_empty()) {
+        if opts.yes {
+            return Err(anyhow!(
+                "Remote {} is not empty, cancelling. Use `confinuum init --git {}` to use it directly.",
+                remote_url,
+                remote_url
+            ));
+        }
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "The remote repository already has {} branch{} ({}). What would you like to do?",
+                remote_branches.len(),
+                if remote_branches.len() == 1 { "" } else { "es" },
+                remote_branches.join(", ")
+            ))
+            .items(&[
+                "Abort",
+                "Clone the remote and use the config it contains",
+                "Keep the existing branches and start the config on a new orphan branch",
+            ])
+            .default(0)
+            .interact_opt()?;
+        match selection {
+            Some(1) => {
+                return init_from_git(&remote_url.to_string(), &config_dir, None, opts.yes)
+            }
+            Some(2) => {
+                branch = dialoguer::Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Name of the new branch")
+                    .default("confinuum".to_owned())
+                    .validate_with(|name: &String| {
+                        if remote_branches.contains(name) {
+                            Err("A branch with that name already exists on the remote")
+                        } else if !git2::Branch::name_is_valid(name).unwrap_or(false) {
+                            Err("Not a valid branch name")
+                        } else {
+                            Ok(())
+                        }
+                    })
+                    .interact_text()?;
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Remote {} is not empty, cancelling. Use `confinuum init --git {}` to use it directly.",
+                    remote_url,
+                    remote_url
+                ))
+            }
+        }
+    }
 
     let mut init_opt = git2::RepositoryInitOptions::new();
-    init_opt.initial_head("main");
+    init_opt.initial_head(&branch);
     init_opt.description("My confinuum config");
     init_opt.no_reinit(!force);
     let repo = Repository::init_opts(&config_dir, &init_opt)
         .context("Failed to initialize config git repository")?;
 
-    let mut remote = repo.remote("origin", &remote_url.to_string())?;
+    let remote = match &remote_url {
+        Some(remote_url) => Some(repo.remote("origin", &remote_url.to_string())?),
+        None => None,
+    };
+
+    let spinner = Spinner::new_shared(spinners::Dots9, "Creating initial commit", Color::Blue);
 
-    // TODO: Figure out how to make sure the remote is empty
+    let hosting = hosting.unwrap_or(match signature_source {
+        SignatureSource::Gitlab => Hosting::Gitlab,
+        SignatureSource::Gitea => Hosting::Gitea,
+        _ => Hosting::Github,
+    });
+    let mut config = ConfinuumConfig::init(git_protocol, signature_source);
+    config.confinuum.branch = branch.clone();
+    config.confinuum.hosting = hosting;
+    config.confinuum.gitlab_url = gitlab_url;
+    config.confinuum.gitea_url = gitea_url;
+    std::fs::write(&config_path, toml::to_string_pretty(&config)?)?;
+    let gitignore_path = config_dir.join(".gitignore");
+    // Temporary files are left behind by atomic writes if confinuum is killed mid-write
     std::fs::write(
-        &config_path,
-        toml::to_string_pretty(&ConfinuumConfig::init(git_protocol, signature_source))?,
+        &gitignore_path,
+        "hosts.toml\ngitlab.toml\ngitea.toml\n.*.tmp\n.backups/\n",
     )?;
-    let gitignore_path = config_dir.join(".gitignore");
-    std::fs::write(&gitignore_path, "hosts.toml\n")?;
     let mut index = repo.index()?;
 
     let config_path_rel =
@@ -170,9 +414,14 @@ pub async fn init(git: Option<String>, force: bool, github: &Github) -> Result<(
     //let parent_commit = repo.find_last_commit()?;
     let tree = repo.find_tree(oid)?;
     let message = "Initial confinuum commit! 🎉";
-    repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &[])?;
+    repo.commit_with_config(&signature, message, &tree, &[])?;
     // TODO: Allow signing commits
     // repo.commit_signed(commit_content, signature, signature_field)
+    let Some(mut remote) = remote else {
+        spinner.success("Successfully initialized confinuum locally!");
+        info!("Run `confinuum remote add <url>` when you want to push your configs to a remote.");
+        return Ok(());
+    };
     {
         // Scope ensures that the spinner is dropped before we clear it
         spinner
@@ -180,10 +429,345 @@ pub async fn init(git: Option<String>, force: bool, github: &Github) -> Result<(
             .update_text("Pushing changes to remote");
         let mut pushopt = git2::PushOptions::new();
         pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-        remote.push(&["refs/heads/main:refs/heads/main"], Some(&mut pushopt))?;
+        let refspec = git::branch_refspec(&branch);
+        tracing::info!("Pushing {}", refspec);
+        remote.push(&[refspec.as_str()], Some(&mut pushopt))?;
     }
 
     spinner.success("Successfully initialized confinuum!");
+    if let Some(url) = repo_html_url {
+        info!("Your configs are hosted at {}", url);
+    }
 
     Ok(())
 }
+
+/// Log in to GitHub or GitLab if there are no stored credentials, since init is usually the first
+/// command run on a new machine
+async fn ensure_logged_in(provider: &mut dyn Provider, yes: bool) -> Result<()> {
+    if provider.is_logged_in() {
+        return Ok(());
+    }
+    if yes {
+        return Err(ConfinuumError::NotLoggedIn(provider.hosting()).into());
+    }
+    println!("You need to log in to {} to continue.", provider.hosting());
+    provider.login().await
+}
+
+/// Create the repository that will host the config, asking for its name and description unless
+/// they were provided as options. If the name is already taken, the user can pick another name or
+/// use the existing repository (which is checked for existing branches before pushing).
+async fn create_hosted_repo(opts: &InitOptions, provider: &dyn Provider) -> Result<HostedRepo> {
+    let mut name = match &opts.create_repo {
+        Some(name) => name.clone(),
+        None => dialoguer::Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Name of the repository")
+            .default("confinuum-config".to_owned())
+            .validate_with(|name: &String| validate_repo_name(name).map_err(|e| e.to_string()))
+            .interact_text()?,
+    };
+    let description = match &opts.description {
+        Some(description) => description.clone(),
+        None if opts.yes => "My confinuum config".to_owned(),
+        None => dialoguer::Input::with_theme(&ColorfulTheme::default())
+            .with_prompt("Description of the repository")
+            .default("My confinuum config".to_owned())
+            .interact_text()?,
+    };
+
+    loop {
+        let spinner = Spinner::new_shared(
+            spinners::Dots9,
+            format!("Creating repository {}", name),
+            Color::Blue,
+        );
+        let created = match &opts.template {
+            Some(template) => {
+                provider
+                    .create_repo_from_template(template, &name, &description, !opts.public)
+                    .await
+            }
+            None => {
+                provider
+                    .create_repo(&name, &description, !opts.public)
+                    .await
+            }
+        };
+        let error = match created {
+            Ok(repo) => {
+                spinner.success(&format!("Created repository {}!", &repo.name));
+                return Ok(repo);
+            }
+            Err(e) => e,
+        };
+        let Some(ConfinuumError::RepoExists(_)) = error.downcast_ref::<ConfinuumError>() else {
+            spinner.fail("Could not create repository");
+            return Err(error);
+        };
+        spinner.warn(&format!("Repository {} already exists", name));
+        if opts.yes {
+            return Err(error);
+        }
+
+        let selection = Select::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("You already have a repository named {}", name))
+            .items(&[
+                "Choose a different name",
+                "Use the existing repository",
+                "Abort",
+            ])
+            .default(0)
+            .interact_opt()?;
+        match selection {
+            Some(0) => {
+                name = dialoguer::Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Name of the repository")
+                    .validate_with(|name: &String| {
+                        validate_repo_name(name).map_err(|e| e.to_string())
+                    })
+                    .interact_text()?;
+            }
+            Some(1) => return provider.get_repo(&name).await,
+            _ => return Err(error),
+        }
+    }
+}
+
+/// Check a repository name before asking the hosting service to create it
+/// These are GitHub's rules, which GitLab and Gitea also accept. GitHub would quietly replace
+/// other characters with dashes rather than refuse them.
+fn validate_repo_name(name: &str) -> Result<()> {
+    let invalid = |rule| Err(ConfinuumError::InvalidRepoName(name.to_owned(), rule).into());
+    if name.is_empty() {
+        return invalid("it can't be empty");
+    }
+    if name.len() > 100 {
+        return invalid("it can't be longer than 100 characters");
+    }
+    if name == "." || name == ".." {
+        return invalid("it can't be . or ..");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return invalid("it can only contain letters, digits, '-', '_' and '.'");
+    }
+    Ok(())
+}
+
+/// List the branches that exist on a remote, without needing a local repository
+fn list_remote_branches(url: &str) -> Result<Vec<String>> {
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        "Checking if the remote is empty",
+        Color::Blue,
+    );
+    let branches = {
+        // Scope ensures that all references to the spinner are dropped before we clear it
+        let mut remote = git2::Remote::create_detached(url)?;
+        remote
+            .connect_auth(
+                Direction::Fetch,
+                Some(git::construct_callbacks(spinner.clone())),
+                None,
+            )
+            .with_context(|| format!("Failed to connect to {}", url))?;
+        let branches = remote
+            .list()?
+            .iter()
+            .filter_map(|head| head.name().strip_prefix("refs/heads/"))
+            .map(|name| name.to_owned())
+            .collect::<Vec<_>>();
+        remote.disconnect()?;
+        branches
+    };
+    spinner.clear();
+    Ok(branches)
+}
+
+/// The protocol a remote URL uses, which has to be one confinuum can authenticate with
+pub(super) fn git_protocol(url: &GitUrl) -> Result<GitProtocol> {
+    match &url.scheme {
+        git_url_parse::Scheme::Https => Ok(GitProtocol::Https),
+        git_url_parse::Scheme::Ssh => Ok(GitProtocol::Ssh),
+        unsupported => Err(anyhow!(
+            "Git protocol {} is not yet supported by Confinuum :/",
+            unsupported
+        )),
+    }
+}
+
+/// Clone an existing confinuum config repo, validate it and deploy the selected entries
+/// The repo is cloned into a temporary directory first, so that a failed clone or a repo that
+/// isn't a confinuum config doesn't leave the config directory in a broken state
+/// If a depth is given, only that many commits of history are cloned. With `yes` nothing is
+/// prompted for: commits are signed the way the config says and every entry is deployed.
+fn init_from_git(git_url: &str, config_dir: &Path, depth: Option<u32>, yes: bool) -> Result<()> {
+    let parsed_url = GitUrl::parse(git_url)
+        .map_err(|e| anyhow!("Could not parse {} as a git url: {}", git_url, e))?;
+    let git_protocol = git_protocol(&parsed_url)?;
+
+    let parent_dir = config_dir
+        .parent()
+        .ok_or_else(|| anyhow!("Could not get parent of {}", config_dir.display()))?;
+    let clone_dir =
+        TempDir::new_in(parent_dir, "confinuum-clone").context("Could not create temp dir")?;
+
+    let spinner = Spinner::new_shared(spinners::Dots9, format!("Cloning {}", git_url), Color::Blue);
+    let branch = {
+        // Scope ensures that all references to the spinner are dropped before we clear it
+        let mut fetch_opt = FetchOptions::new();
+        fetch_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+        if let Some(depth) = depth {
+            fetch_opt.depth(depth.try_into().context("Clone depth is too large")?);
+        }
+        let repo = RepoBuilder::new()
+            .fetch_options(fetch_opt)
+            .clone(git_url, clone_dir.path())
+            .with_context(|| format!("Failed to clone {}", git_url))?;
+        let head = repo.head().context(
+            "The cloned repository is empty, use `confinuum init` without --git to set it up",
+        )?;
+        head.shorthand()
+            .ok_or_else(|| anyhow!("Could not get the default branch of {}", git_url))?
+            .to_owned()
+    };
+
+    let config_str =
+        std::fs::read_to_string(clone_dir.path().join("config.toml")).with_context(|| {
+            format!(
+                "{} does not contain a confinuum config (config.toml)",
+                git_url
+            )
+        })?;
+    let mut config = ConfinuumConfig::parse(&config_str)
+        .with_context(|| format!("{} does not contain a valid confinuum config", git_url))?;
+    spinner.success("Cloned config repository");
+
+    // Record the settings for this machine
+    config.confinuum.git_protocol = git_protocol;
+    config.confinuum.branch = branch;
+    config.confinuum.shallow = depth.is_some();
+    let logged_in = match config.confinuum.signature_source {
+        SignatureSource::Github => Some((Hosting::Github, Github::is_authenticated())),
+        SignatureSource::Gitlab => Some((
+            Hosting::Gitlab,
+            Gitlab::new(config.confinuum.gitlab_url.as_deref())?.is_logged_in(),
+        )),
+        SignatureSource::Gitea => Some((
+            Hosting::Gitea,
+            Gitea::new(config.confinuum.gitea_url.as_deref())?.is_logged_in(),
+        )),
+        SignatureSource::GitConfig => None,
+    };
+    match logged_in {
+        // Keeping the config's signature source is the default of the prompt
+        Some((hosting, false)) if yes => info!(
+            "This config signs commits using your {} account, run `{}` to log in",
+            hosting,
+            hosting.login_command()
+        ),
+        Some((hosting, false)) => {
+            let selection = Select::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!("This config signs commits using your {} account, but you aren't logged in to {}. How would you like to sign your commits?", hosting, hosting))
+                .items(&[
+                    format!("{} (log in later with `{}`)", hosting, hosting.login_command()),
+                    "Git config".to_owned(),
+                ])
+                .default(0)
+                .interact()?;
+            if selection == 1 {
+                config.confinuum.signature_source = SignatureSource::GitConfig;
+            }
+        }
+        _ => {}
+    }
+
+    // Move the clone into place, now that we know it's valid
+    for item in std::fs::read_dir(clone_dir.path())? {
+        let item = item?;
+        let destination = config_dir.join(item.file_name());
+        if destination.is_dir() && !destination.is_symlink() {
+            std::fs::remove_dir_all(&destination)
+                .with_context(|| format!("Could not remove {}", destination.display()))?;
+        } else if destination.exists() {
+            std::fs::remove_file(&destination)
+                .with_context(|| format!("Could not remove {}", destination.display()))?;
+        }
+        std::fs::rename(item.path(), &destination).with_context(|| {
+            format!(
+                "Could not move {} to {}",
+                item.path().display(),
+                destination.display()
+            )
+        })?;
+    }
+    if config_dir.join(BACKUP_DIR).exists() {
+        hosting::exclude_from_repo(&format!("{}/", BACKUP_DIR))?;
+    }
+    if config_str != toml::to_string_pretty(&config)? {
+        config.save()?;
+    }
+
+    // Let the user pick which entries they want on this machine
+    let mut names = config
+        .entries
+        .iter()
+        .filter(|(_, entry)| !entry.files.is_empty() && entry.deploy_dir().is_some())
+        .map(|(name, _)| name.clone())
+        .collect::<Vec<_>>();
+    names.sort();
+    if names.is_empty() {
+        info!("The config doesn't contain any entries yet.");
+        return Ok(());
+    }
+    let selected = if yes {
+        (0..names.len()).collect()
+    } else {
+        MultiSelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Which entries do you want to deploy on this machine?")
+            .items(&names)
+            .defaults(&vec![true; names.len()])
+            .interact()?
+    };
+    for idx in selected {
+        super::deploy(Some(&HashSet::from([names[idx].clone()])))?;
+    }
+
+    info!("Successfully initialized confinuum from {}!", git_url);
+    Ok(())
+}
+
+/// Copy the existing config.toml, or the whole config repo, to a timestamped directory under
+/// .backups in the config dir so that it can be recovered after `init --force`
+/// Returns the backup directory.
+fn backup_config(whole_repo: bool) -> Result<PathBuf> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let backup_dir = config_dir.join(BACKUP_DIR).join(format!(
+        "{}-{:02}{:02}{:02}",
+        super::log::format_date(git2::Time::new(now, 0)),
+        now.rem_euclid(86400) / 3600,
+        now.rem_euclid(3600) / 60,
+        now.rem_euclid(60)
+    ));
+    std::fs::create_dir_all(&backup_dir)
+        .with_context(|| format!("Could not create {}", backup_dir.display()))?;
+    // Backups must never end up in the config repo
+    hosting::exclude_from_repo(&format!("{}/", BACKUP_DIR))?;
+    if whole_repo {
+        for item in std::fs::read_dir(&config_dir)? {
+            let item = item?;
+            if item.file_name() != BACKUP_DIR {
+                super::copy_recursive(&item.path(), &backup_dir.join(item.file_name()))?;
+            }
+        }
+    } else {
+        let config_path = ConfinuumConfig::get_path()?;
+        std::fs::copy(&config_path, backup_dir.join("config.toml"))
+            .with_context(|| format!("Could not back up {}", config_path.display()))?;
+    }
+    Ok(backup_dir)
+}
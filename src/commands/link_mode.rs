@@ -0,0 +1,54 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+
+use crate::{
+    cli::info,
+    config::{ConfinuumConfig, LinkMode},
+    git,
+    github::Github,
+};
+
+/// Switch an entry between linking each file and linking its whole directory, commit the change
+/// to config.toml and redeploy the entry with the new mode
+pub async fn set_link_mode(name: String, link_mode: LinkMode, github: &Github) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let mut config = ConfinuumConfig::load()?;
+    let entry = config.entry_mut(&name)?;
+    if entry.link_mode == link_mode {
+        info!("{} already links {}", name, describe(link_mode));
+        return Ok(());
+    }
+    if link_mode == LinkMode::Directory && entry.target_file.is_some() {
+        return Err(anyhow!(
+            "{} is a single-file entry, so it has no directory to link",
+            name
+        ));
+    }
+    entry.link_mode = link_mode;
+    let message = format!("Set `{}` to link {}", name, describe(link_mode));
+    git::commit_config(&repo, &config, &message, github).await?;
+
+    // Undeploying removes the links of either mode, so the old ones are gone before deploying
+    let names = HashSet::from([name.clone()]);
+    super::undeploy(Some(&names))?;
+    super::deploy(Some(&names))?;
+    info!(
+        "{} {} now links {}. Run `confinuum push` to push the change to the remote.",
+        "✔".green(),
+        name,
+        describe(link_mode)
+    );
+    Ok(())
+}
+
+fn describe(link_mode: LinkMode) -> &'static str {
+    match link_mode {
+        LinkMode::Files => "each of its files",
+        LinkMode::Directory => "its whole directory",
+    }
+}
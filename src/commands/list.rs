@@ -1,20 +1,302 @@
-use crate::config::ConfinuumConfig;
-use anyhow::Result;
-use crossterm::style::Stylize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    io::IsTerminal,
+    path::Path,
+};
 
-pub fn list() -> Result<()> {
+use crate::{
+    cli::info,
+    config::{ConfigEntry, ConfinuumConfig},
+    git,
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use crossterm::style::{Color, Stylize};
+use git2::{Repository, Sort};
+
+/// How `list` orders the entries
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ListSort {
+    /// Alphabetically by name
+    Name,
+    /// Most files first
+    Files,
+    /// Most recently changed first
+    Updated,
+}
+
+/// The narrowest the target column of `list --long` is cut down to, to fit the terminal
+const MIN_TARGET_WIDTH: usize = 12;
+
+/// List the config entries, only those with one of `tags` and whose name contains `filter` if
+/// given, as a table with `long`
+/// Ends with a note if there are local commits that haven't been pushed.
+pub fn list(tags: Vec<String>, sort: ListSort, filter: Option<String>, long: bool) -> Result<()> {
     let config = ConfinuumConfig::load()?;
-    for (name, entry) in config.entries {
-        if let Some(target_dir) = &entry.target_dir {
-            println!(
-                "{}: {} files\n\u{21B3} {}",
-                name.bold().yellow(),
-                entry.files.len(),
-                target_dir.display()
-            );
-        } else {
-            println!("{}: uninitialized", name.bold().yellow());
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let unpushed = git::unpushed_note(&repo, &config.confinuum.branch)?;
+    let tagged = config.tagged_entries(&tags);
+    let filter = filter.map(|filter| filter.to_lowercase());
+    let mut entries = config
+        .entries
+        .values()
+        .filter(|entry| {
+            tagged
+                .as_ref()
+                .is_none_or(|tagged| tagged.contains(&entry.name))
+        })
+        .filter(|entry| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| entry.name.to_lowercase().contains(filter))
+        })
+        .collect::<Vec<_>>();
+    // Only looked up when needed, since it walks the history
+    let updated = match (sort, long) {
+        (ListSort::Updated, _) | (_, true) => last_changes(&repo, &entries)?,
+        _ => HashMap::new(),
+    };
+    // The sorts are stable, so entries that tie stay in order of name
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    match sort {
+        ListSort::Name => {}
+        ListSort::Files => entries.sort_by_key(|entry| Reverse(entry.files.len())),
+        // Entries without commits (Reverse(None)) go last
+        ListSort::Updated => entries
+            .sort_by_key(|entry| Reverse(updated.get(&entry.name).map(|time| time.seconds()))),
+    }
+
+    if long {
+        print_table(&config_dir, &entries, &updated);
+    } else {
+        entries.into_iter().for_each(print_entry);
+    }
+    if let Some(unpushed) = unpushed {
+        info!("\n{}", unpushed);
+    }
+    Ok(())
+}
+
+/// Print an entry the way `list` does without --long
+fn print_entry(entry: &ConfigEntry) {
+    let tags = if entry.tags.is_empty() {
+        String::new()
+    } else {
+        format!(" [{}]", entry.tags.join(", ")).cyan().to_string()
+    };
+    if let Some(target) = entry.target() {
+        println!(
+            "{}: {} files{}",
+            styled_name(entry),
+            entry.files.len(),
+            tags
+        );
+        if let Some(description) = &entry.description {
+            println!("  {}", description.as_str().dark_grey());
+        }
+        println!("\u{21B3} {}", target.display());
+    } else {
+        println!("{}: uninitialized{}", styled_name(entry), tags);
+        if let Some(description) = &entry.description {
+            println!("  {}", description.as_str().dark_grey());
         }
     }
+    if let Some(conditions) = conditions_note(entry) {
+        println!("  {}", conditions);
+    }
+}
+
+/// Print the entries as a table with a column each for the target, tags, deploy status and date
+/// of the last change
+/// On a terminal the target column is cut short (from the start, since the end of a path says
+/// the most) so that the rows fit its width.
+fn print_table(config_dir: &Path, entries: &[&ConfigEntry], updated: &HashMap<String, git2::Time>) {
+    const TARGET: usize = 2;
+    let header = ["NAME", "FILES", "TARGET", "TAGS", "STATUS", "UPDATED"];
+    let rows = entries
+        .iter()
+        .map(|entry| {
+            let (status, color) = deploy_status(config_dir, entry);
+            let cells = [
+                entry.name.clone(),
+                entry.files.len().to_string(),
+                entry
+                    .target()
+                    .map_or("-".to_owned(), |target| target.display().to_string()),
+                if entry.tags.is_empty() {
+                    "-".to_owned()
+                } else {
+                    entry.tags.join(",")
+                },
+                status,
+                updated
+                    .get(&entry.name)
+                    .map_or("-".to_owned(), |time| super::log::format_date(*time)),
+            ];
+            (cells, color)
+        })
+        .collect::<Vec<_>>();
+
+    let mut widths = header.map(str::len);
+    for (cells, _) in &rows {
+        for (width, cell) in widths.iter_mut().zip(cells) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    if std::io::stdout().is_terminal() {
+        if let Ok((columns, _)) = crossterm::terminal::size() {
+            // Columns are separated by two spaces
+            let total = widths.iter().sum::<usize>() + 2 * (widths.len() - 1);
+            let excess = total.saturating_sub(columns as usize);
+            widths[TARGET] = widths[TARGET]
+                .saturating_sub(excess)
+                .max(MIN_TARGET_WIDTH.min(widths[TARGET]));
+        }
+    }
+
+    let line = |cells: [String; 6]| {
+        cells
+            .into_iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cut_start(&cell, width)))
+            .collect::<Vec<_>>()
+    };
+    println!(
+        "{}",
+        line(header.map(str::to_owned)).join("  ").trim_end().bold()
+    );
+    for (cells, color) in rows {
+        let [name, files, target, tags, status, date] = line(cells).try_into().unwrap();
+        println!(
+            "{}  {}  {}  {}  {}  {}",
+            name.with(color),
+            files,
+            target,
+            tags.cyan(),
+            status.with(color),
+            date.trim_end().dark_grey()
+        );
+    }
+}
+
+/// Shorten text to `width` characters by cutting off its start, marked with an ellipsis
+fn cut_start(text: &str, width: usize) -> String {
+    let length = text.chars().count();
+    if length <= width {
+        return text.to_owned();
+    }
+    let kept = text.chars().skip(length - width + 1).collect::<String>();
+    format!("\u{2026}{}", kept)
+}
+
+/// A short summary of whether the entry is deployed here, and the color to show it (and the
+/// entry's name) in
+fn deploy_status(config_dir: &Path, entry: &ConfigEntry) -> (String, Color) {
+    if entry.disabled {
+        return ("disabled".to_owned(), Color::DarkGrey);
+    }
+    if entry.target().is_none() {
+        return ("uninitialized".to_owned(), Color::DarkGrey);
+    }
+    if entry.mismatch().is_some() {
+        return ("not for this machine".to_owned(), Color::DarkGrey);
+    }
+    let deployed = entry
+        .files
+        .iter()
+        .filter(|file| super::is_deployed(config_dir, entry, file))
+        .count();
+    match deployed {
+        _ if deployed == entry.files.len() => ("deployed".to_owned(), Color::Green),
+        0 => ("not deployed".to_owned(), Color::Red),
+        _ => (
+            format!("{}/{} deployed", deployed, entry.files.len()),
+            Color::Yellow,
+        ),
+    }
+}
+
+/// The time of the last commit that changed each of the entries' directories
+/// History is walked back from HEAD until every entry has been found, entries that were never
+/// committed are left out.
+fn last_changes(
+    repo: &Repository,
+    entries: &[&ConfigEntry],
+) -> Result<HashMap<String, git2::Time>> {
+    let mut remaining = entries
+        .iter()
+        .map(|entry| entry.name.as_str())
+        .collect::<HashSet<_>>();
+    let mut changes = HashMap::new();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TIME)?;
+    if revwalk.push_head().is_err() {
+        // Nothing has been committed yet
+        return Ok(changes);
+    }
+    for oid in revwalk {
+        if remaining.is_empty() {
+            break;
+        }
+        let commit = repo.find_commit(oid?)?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(parent.tree()?),
+            Err(_) => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        for delta in diff.deltas() {
+            let paths = [delta.old_file().path(), delta.new_file().path()];
+            for path in paths.into_iter().flatten() {
+                let Some(dir) = path.components().next() else {
+                    continue;
+                };
+                let name = dir.as_os_str().to_string_lossy();
+                if remaining.remove(name.as_ref()) {
+                    changes.insert(name.into_owned(), commit.time());
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// The entry's name as it's shown in lists, dimmed with a note if the entry is disabled
+pub(super) fn styled_name(entry: &ConfigEntry) -> String {
+    if entry.disabled {
+        format!("{} (disabled)", entry.name).dark_grey().to_string()
+    } else {
+        entry.name.as_str().bold().yellow().to_string()
+    }
+}
+
+/// The machines an entry is limited to and whether this is one of them, or None if it deploys
+/// everywhere
+pub(super) fn conditions_note(entry: &ConfigEntry) -> Option<String> {
+    let conditions = entry.conditions()?;
+    Some(match entry.mismatch() {
+        None => format!("{} (applies here)", conditions)
+            .dark_grey()
+            .to_string(),
+        Some(reason) => format!("{} (not deployed here: {})", conditions, reason)
+            .yellow()
+            .to_string(),
+    })
+}
+
+/// Print the name of each entry, one per line, for shell completions
+/// Completion scripts call this on every <TAB>, so a missing or unreadable config prints nothing
+/// instead of an error.
+pub fn list_names() -> Result<()> {
+    let Ok(config) = ConfinuumConfig::load() else {
+        return Ok(());
+    };
+    let mut names = config.entries.into_keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
     Ok(())
 }
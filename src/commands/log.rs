@@ -0,0 +1,263 @@
+use std::{io::Write, path::Path, str::FromStr};
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use crossterm::style::Stylize;
+use git2::{Commit, DiffOptions, Repository, Sort};
+
+use crate::{config::ConfinuumConfig, pager};
+
+/// Format a commit time as YYYY-MM-DD in the commit's own timezone
+pub(super) fn format_date(time: git2::Time) -> String {
+    let days = (time.seconds() + time.offset_minutes() as i64 * 60).div_euclid(86400);
+    // Convert days since the unix epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// A date (and optionally a time) given to `log --since` or `--until`
+/// Like the dates `log` shows, it's compared with each commit's time in the commit's own timezone.
+#[derive(Debug, Clone, Copy)]
+pub struct LogDate {
+    /// Seconds since the unix epoch, as if the date were in UTC
+    seconds: i64,
+    /// Whether only a date was given, in which case `--until` includes the whole day
+    whole_day: bool,
+}
+
+impl FromStr for LogDate {
+    type Err = String;
+
+    /// Accepts YYYY-MM-DD, optionally followed by HH:MM or HH:MM:SS (after a space or a T), with
+    /// slashes also allowed between the parts of the date
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid date {:?}, expected e.g. 2024-01-31 or \"2024-01-31 14:30\"",
+                s
+            )
+        };
+        let date = s.trim().replace('/', "-");
+        if let Ok(date) = NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+            return Ok(LogDate {
+                seconds: date.and_time(NaiveTime::MIN).and_utc().timestamp(),
+                whole_day: true,
+            });
+        }
+        let time = DATE_TIME_FORMATS
+            .iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(&date, format).ok())
+            .ok_or_else(invalid)?;
+        Ok(LogDate {
+            seconds: time.and_utc().timestamp(),
+            whole_day: false,
+        })
+    }
+}
+
+/// The formats of a date with a time that `LogDate` accepts
+const DATE_TIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+];
+
+/// Whether a commit's time is within `--since` and `--until`, both of which are inclusive
+fn in_range(time: git2::Time, since: Option<LogDate>, until: Option<LogDate>) -> bool {
+    let seconds = time.seconds() + time.offset_minutes() as i64 * 60;
+    let after_since = since.is_none_or(|since| seconds >= since.seconds);
+    let before_until = until.is_none_or(|until| match until.whole_day {
+        true => seconds < until.seconds + 86400,
+        false => seconds <= until.seconds,
+    });
+    after_since && before_until
+}
+
+/// Whether the commit changed anything under the given path, compared to its first parent
+fn touches_path(repo: &Repository, commit: &Commit, path: &Path) -> Result<bool> {
+    let tree = commit.tree()?;
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree()?),
+        Err(_) => None,
+    };
+    let mut diff_opt = DiffOptions::new();
+    diff_opt.pathspec(path);
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(&mut diff_opt))?;
+    Ok(diff.deltas().len() > 0)
+}
+
+/// Show the history of the config, or of a single entry
+/// With `since` and `until`, only the commits made in that range are shown.
+pub fn log(
+    name: Option<String>,
+    max_count: Option<usize>,
+    since: Option<LogDate>,
+    until: Option<LogDate>,
+    no_pager: bool,
+) -> Result<()> {
+    let config = ConfinuumConfig::load()?;
+    if let Some(name) = &name {
+        if !config.entries.contains_key(name) {
+            return Err(config.entry_not_found(name).into());
+        }
+    }
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TIME)?;
+    revwalk
+        .push_head()
+        .context("Could not find the latest commit")?;
+
+    let mut out = Vec::new();
+    let mut count = 0;
+    for oid in revwalk {
+        if max_count.is_some_and(|max| count >= max) {
+            break;
+        }
+        let commit = repo.find_commit(oid?)?;
+        if !in_range(commit.time(), since, until) {
+            continue;
+        }
+        if let Some(name) = &name {
+            if !touches_path(&repo, &commit, Path::new(name))? {
+                continue;
+            }
+        }
+        let id = commit.as_object().short_id()?;
+        writeln!(
+            out,
+            "{} {} {} {}",
+            id.as_str().unwrap_or_default().yellow(),
+            format_date(commit.time()).dark_grey(),
+            commit.summary().unwrap_or("<no message>"),
+            format!("({})", commit.author().name().unwrap_or("unknown")).dark_grey()
+        )?;
+        count += 1;
+    }
+
+    if count == 0 {
+        match name {
+            Some(name) => println!("No commits found for {}", name),
+            None => println!("No commits found"),
+        }
+        return Ok(());
+    }
+    pager::page(&out, no_pager)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> LogDate {
+        s.parse().unwrap()
+    }
+
+    /// A commit time `seconds` after the unix epoch in a timezone `offset_minutes` from UTC
+    fn commit_time(seconds: i64, offset_minutes: i32) -> git2::Time {
+        git2::Time::new(seconds, offset_minutes)
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(date("1970-01-01").seconds, 0);
+        assert_eq!(date("2024-01-31").seconds, 1706659200);
+        assert_eq!(date("2024/01/31").seconds, 1706659200);
+        assert_eq!(date(" 2024-01-31 ").seconds, 1706659200);
+        assert!(date("2024-01-31").whole_day);
+        assert_eq!(
+            date("2024-01-31 14:30").seconds,
+            1706659200 + 14 * 3600 + 30 * 60
+        );
+        assert_eq!(date("2024-01-31T14:30:15").seconds, 1706659200 + 52215);
+        assert!(!date("2024-01-31 14:30").whole_day);
+    }
+
+    #[test]
+    fn parse_leap_years_and_month_ends() {
+        for valid in [
+            "2024-02-29",
+            "2000-02-29",
+            "2023-01-31",
+            "2023-04-30",
+            "2023-12-31",
+        ] {
+            assert!(valid.parse::<LogDate>().is_ok(), "{} is valid", valid);
+        }
+        for invalid in [
+            "2023-02-29",
+            "1900-02-29",
+            "2023-04-31",
+            "2023-13-01",
+            "2023-00-10",
+        ] {
+            assert!(
+                invalid.parse::<LogDate>().is_err(),
+                "{} is invalid",
+                invalid
+            );
+        }
+        assert_eq!(
+            date("2024-03-01").seconds - date("2024-02-28").seconds,
+            2 * 86400
+        );
+        assert_eq!(
+            date("2023-03-01").seconds - date("2023-02-28").seconds,
+            86400
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for invalid in [
+            "",
+            "yesterday",
+            "2024-01",
+            "2024-01-31 25:00",
+            "2024-01-31 14",
+        ] {
+            let error = invalid.parse::<LogDate>().unwrap_err();
+            assert!(error.contains("expected e.g. 2024-01-31"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn until_includes_the_whole_day() {
+        let until = Some(date("2024-01-31"));
+        let midnight = date("2024-01-31").seconds;
+        assert!(in_range(commit_time(midnight, 0), None, until));
+        assert!(in_range(commit_time(midnight + 86399, 0), None, until));
+        assert!(!in_range(commit_time(midnight + 86400, 0), None, until));
+        // The commit's own timezone decides which day it was made on
+        assert!(in_range(commit_time(midnight + 86400, -60), None, until));
+    }
+
+    #[test]
+    fn until_with_a_time_is_exact() {
+        let until = Some(date("2024-01-31 12:00"));
+        let noon = date("2024-01-31 12:00").seconds;
+        assert!(in_range(commit_time(noon, 0), None, until));
+        assert!(!in_range(commit_time(noon + 1, 0), None, until));
+    }
+
+    #[test]
+    fn since_is_inclusive() {
+        let since = Some(date("2024-01-31"));
+        let midnight = date("2024-01-31").seconds;
+        assert!(in_range(commit_time(midnight, 0), since, None));
+        assert!(!in_range(commit_time(midnight - 1, 0), since, None));
+        assert!(in_range(commit_time(midnight - 1, 60), since, None));
+    }
+}
@@ -1,25 +1,64 @@
 mod add;
+pub mod auth;
 mod check;
 mod delete;
+mod describe;
+mod diff;
+mod disable;
+mod doctor;
+mod export;
+mod grep;
+mod import;
 mod init;
+mod link_mode;
 mod list;
+mod log;
 mod new;
 mod push;
 mod redeploy;
+mod remote;
 mod remove;
+mod restore;
+mod self_update;
 mod show;
+mod status;
+mod sync;
+mod tag;
+mod undeploy;
+mod uninstall;
+mod untrack;
 mod update;
+mod watch;
 
 pub use add::add;
 pub use check::check;
 pub use delete::delete;
-pub use init::init;
-pub use list::list;
-pub use new::new;
-pub use push::push;
+pub use describe::set_description;
+pub use diff::diff;
+pub use disable::{disable, enable};
+pub use doctor::doctor;
+pub use export::{export, ExportFormat};
+pub use grep::grep;
+pub use import::{import_bare_repo, import_stow};
+pub use init::{init, InitOptions};
+pub use link_mode::set_link_mode;
+pub use list::{list, list_names, ListSort};
+pub use log::{log, LogDate};
+pub use new::{new, new_from_repo};
+pub use push::{push, push_entry, push_tagged};
 pub use redeploy::redeploy;
+pub use remote::{add_remote, list_remotes, remove_remote, set_remote_url};
 pub use remove::remove;
-pub use show::show;
+pub use restore::restore;
+pub use self_update::self_update;
+pub use show::{show, TreeStyle};
+pub use status::status;
+pub use sync::sync;
+pub use tag::{add_tags, remove_tags};
+pub use undeploy::undeploy_entries;
+pub use uninstall::uninstall;
+pub use untrack::untrack;
 pub use update::update;
+pub use watch::watch;
 
 pub(self) use crate::deployment::*;
@@ -1,55 +1,53 @@
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
-    config::{ConfigEntry, ConfinuumConfig, SignatureSource},
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{CommitKind, ConfigEntry, ConfinuumConfig},
+    error::ConfinuumError,
     git::{self, RepoExtensions},
     github::Github,
+    interrupt,
+    rollback::Rollback,
 };
 use anyhow::{anyhow, Context, Result};
-use git2::{Direction, FetchOptions, IndexAddOption, Repository};
+use dialoguer::theme::ColorfulTheme;
+use git2::Repository;
 use spinoff::{spinners, Color, Spinner};
-use std::{collections::HashSet, path::PathBuf};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
 
 /// Add a new config entry
 pub async fn new(
     name: String,
     files: Option<Vec<PathBuf>>,
     push: bool,
+    follow_symlinks: bool,
+    yes: bool,
+    offline: bool,
     github: &Github,
 ) -> Result<()> {
-    // TODO: Revert files on error
     // Check for remote changes before adding files
     let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
     let repo = Repository::open(&config_dir)
         .with_context(|| format!("Could not open repository inn {}", config_dir.display()))?;
-    let mut remote = repo.find_remote("origin")?;
-    let spinner = Spinner::new_shared(
-        spinners::Dots9,
-        "Connecting to remote 'origin'",
-        Color::Blue,
-    );
-    remote.connect_auth(
-        Direction::Fetch,
-        Some(git::construct_callbacks(spinner.clone())),
-        None,
-    )?;
-    spinner.update_text("Checking for changes on remote");
-    let mut fetch_opt = FetchOptions::new();
-    fetch_opt.update_fetchhead(true);
-    fetch_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-    remote
-        .fetch(&["main"], Some(&mut fetch_opt), None)
-        .context("Failed to fetch from remote 'origin'")?;
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-    remote.disconnect()?;
-    if analysis.0.is_up_to_date() {
-        spinner.success("No changes found on remote");
-    } else {
-        spinner.fail("Changes found on remote");
-        return Err(anyhow!(
-            "Changes found on remote. Please pull them before adding files."
-        ));
+    let mut config = ConfinuumConfig::load()?;
+    let offline = git::check_remote(&repo, &config.confinuum.branch, "adding files", offline)?;
+
+    ConfigEntry::validate_name(&name)?;
+    if config.entries.contains_key(&name) {
+        return Err(ConfinuumError::EntryExists(name).into());
+    }
+    let mut entry = ConfigEntry::new(name.clone());
+    let plan = files
+        .map(|files| ConfinuumConfig::plan_add(&entry, files, None, follow_symlinks))
+        .transpose()
+        .context("Failed to add files to config")?;
+    if let Some(plan) = &plan {
+        if !super::add::confirm_plan(&name, plan, yes)? {
+            return Ok(());
+        }
     }
 
     let spinner = Spinner::new_shared(
@@ -59,92 +57,303 @@ pub async fn new(
     );
 
     {
-        let mut config = ConfinuumConfig::load()?;
-        if config.entries.contains_key(&name) {
-            return Err(anyhow!(
-            "Entry named {} already exists! Use the `add` and `remove` subcommands to add or remove files from it.",
-            name
-        ));
-        }
+        let rollback = Rollback::new(&[&name])?;
 
-        config.entries.insert(
-            name.clone(),
-            ConfigEntry {
-                name: name.clone(),
-                files: HashSet::new(),
-                target_dir: None,
-            },
-        );
-        let entry = config.entries.get_mut(&name).unwrap();
         let mut result_files = HashSet::new();
-        if let Some(files) = files {
-            ConfinuumConfig::add_files_recursive(entry, files, None, &mut Some(&mut result_files))
+        if let Some(plan) = plan {
+            ConfinuumConfig::apply_add(&mut entry, plan, &mut Some(&mut result_files))
                 .context("Failed to add files to config")?;
         }
+        config.entries.insert(name.clone(), entry);
         config.save().context("Failed to save config file")?;
 
-        let mut index = repo.index()?;
-        let mut imp = |path: &std::path::Path, _data: &[u8]| {
-            if path.starts_with(".git") {
-                return 1; // skip .git/
-            }
-            return 0;
-        };
-        index
-            .add_all(["*"], IndexAddOption::DEFAULT, Some(&mut imp))
-            .context("Could not add files")?;
-        let oid = index.write_tree().context("Failed to write tree")?;
+        let staged = git::stage_paths(
+            &repo,
+            &[&name],
+            result_files
+                .iter()
+                .map(|file| PathBuf::from(&name).join(file)),
+        )?;
         let parent_commit = repo
             .find_last_commit()
             .context("Failed to retrieve last commit")?;
-        let sig = match &config.confinuum.signature_source {
-            SignatureSource::Github => github
-                .get_user_signature()
-                .await
-                .context("Could not fetch user signature from github")?,
-            SignatureSource::GitConfig => {
-                // allows users to set values in config if they don't exist
-                git::gitconfig::get_user_sig()?
-            }
-        };
+        let sig = git::commit_signature(&config, github).await?;
         let tree = repo
-            .find_tree(oid)
+            .find_tree(staged.oid)
             .context("Failed to find new commit tree")?;
-        let message = format!(
-            "Added configs for `{}`{}\n\nNew files:\n{}",
-            name,
-            if result_files.is_empty() {
-                "".to_owned()
-            } else {
-                format!(" with {} files", result_files.len())
-            },
-            result_files
-                .iter()
-                .map(|f| f.display().to_string())
-                .collect::<Vec<_>>()
-                .join("\n")
+        let files = result_files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        let subject = config.confinuum.commit_message.subject(
+            CommitKind::New,
+            &name,
+            &files,
+            format!(
+                "Added configs for `{}`{}",
+                name,
+                if files.is_empty() {
+                    "".to_owned()
+                } else {
+                    format!(" with {} files", files.len())
+                }
+            ),
         );
+        let message = staged.message(format!("{}\n\nNew files:\n{}", subject, files.join("\n")));
 
-        repo.commit(Some("HEAD"), &sig, &sig, &message, &tree, &[&parent_commit])
+        interrupt::check()?;
+        repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
             .context("Failed to commit files")?;
+        rollback.keep();
 
-        super::deploy(Some(&name))?;
+        super::deploy(Some(&HashSet::from([name.clone()])))?;
     }
 
-    if push {
+    if push && !offline {
         {
             let mut pushopt = git2::PushOptions::new();
             pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
             spinner.update_text("Pushing changes to remote");
+            let mut remote = git::find_origin(&repo)?;
             remote
-                .push(&["refs/heads/main:refs/heads/main"], Some(&mut pushopt))
+                .push(
+                    &[git::branch_refspec(&config.confinuum.branch)],
+                    Some(&mut pushopt),
+                )
                 .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
             // Scope to ensure that all references to spinner are dropped before we call success
         }
         spinner.success("Changes pushed successfully.");
     } else {
         spinner.success(&format!("Added entry {}.", name));
+        if push {
+            info!(
+                "Skipping push while offline, run `confinuum push` once the remote is reachable."
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a new config entry for files that are already in the config repo, under `from`
+/// The files deploy to `target` (asked for if it isn't given, since the repo layout doesn't say
+/// where they belong). If `from` isn't the entry's directory, it's moved there.
+pub async fn new_from_repo(
+    name: String,
+    from: PathBuf,
+    target: Option<PathBuf>,
+    push: bool,
+    offline: bool,
+    github: &Github,
+) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    ConfigEntry::validate_name(&name)?;
+    let mut config = ConfinuumConfig::load()?;
+    if config.entries.contains_key(&name) {
+        return Err(ConfinuumError::EntryExists(name).into());
     }
 
+    // Relative to the config repo, since that's where the files are
+    let from_dir = config_dir
+        .join(&from)
+        .canonicalize()
+        .with_context(|| format!("{} does not exist in the config repo", from.display()))?;
+    let from_rel = from_dir
+        .strip_prefix(config_dir.canonicalize()?)
+        .ok()
+        .filter(|rel| !rel.as_os_str().is_empty())
+        .ok_or(anyhow!(
+            "{} is not a directory in the config repo ({})",
+            from.display(),
+            config_dir.display()
+        ))?
+        .to_path_buf();
+    let first = from_rel
+        .components()
+        .next()
+        .map(|first| first.as_os_str().to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if first == ".git" {
+        return Err(anyhow!(
+            "{} is inside the repo's .git directory",
+            from.display()
+        ));
+    }
+    if config.entries.contains_key(&first) {
+        return Err(anyhow!(
+            "{} already holds the files of entry {}",
+            from.display(),
+            first
+        ));
+    }
+    if !from_dir.is_dir() {
+        return Err(anyhow!("{} is not a directory", from.display()));
+    }
+    let mut files = BTreeSet::new();
+    repo_files(&from_dir, &from_dir, &mut files)?;
+    if files.is_empty() {
+        return Err(anyhow!("{} doesn't contain any files", from.display()));
+    }
+
+    let target = match target {
+        Some(target) => target,
+        None if std::io::stdin().is_terminal() => {
+            let target: String = dialoguer::Input::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "Where should the files in {} be deployed to?",
+                    name
+                ))
+                .interact_text()?;
+            match target.strip_prefix("~/") {
+                Some(rest) => PathBuf::from(std::env::var("HOME")?).join(rest),
+                None => PathBuf::from(target),
+            }
+        }
+        None => {
+            return Err(anyhow!(
+                "Pass --target with the directory to deploy {} to",
+                name
+            ))
+        }
+    };
+    if !target.is_absolute() {
+        return Err(anyhow!(
+            "The target directory {} must be an absolute path",
+            target.display()
+        ));
+    }
+    let offline = git::check_remote(&repo, &config.confinuum.branch, "adding files", offline)?;
+
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        format!("Creating {} from {}", name, from_rel.display()),
+        Color::Blue,
+    );
+    let entry_dir = config_dir.join(&name);
+    let moved_from = if from_rel != Path::new(&name) {
+        if entry_dir.symlink_metadata().is_ok() {
+            return Err(anyhow!(
+                "{} already exists in the config repo, move it out of the way or use --from {}",
+                name,
+                name
+            ));
+        }
+        fs::rename(&from_dir, &entry_dir).with_context(|| {
+            format!(
+                "Could not move {} to {}",
+                from_dir.display(),
+                entry_dir.display()
+            )
+        })?;
+        Some(from_rel.to_string_lossy().into_owned())
+    } else {
+        None
+    };
+    {
+        // Taken after the move, so a rollback leaves the files where they are now
+        let rollback = Rollback::new(&[&name])?;
+        let mut entry = ConfigEntry::new(name.clone());
+        entry.target_dir = Some(target.clone());
+        entry.files = files.clone();
+        config.entries.insert(name.clone(), entry);
+        config.save().context("Failed to save config file")?;
+
+        let mut names = vec![name.as_str()];
+        let mut paths = files
+            .iter()
+            .map(|file| PathBuf::from(&name).join(file))
+            .collect::<Vec<_>>();
+        if let Some(moved_from) = &moved_from {
+            // Removes the old location from the index, if it was committed there
+            names.push(moved_from);
+            paths.push(PathBuf::from(moved_from));
+        }
+        let staged = git::stage_paths(&repo, &names, paths)?;
+        let parent_commit = repo
+            .find_last_commit()
+            .context("Failed to retrieve last commit")?;
+        let sig = git::commit_signature(&config, github).await?;
+        let tree = repo
+            .find_tree(staged.oid)
+            .context("Failed to find new commit tree")?;
+        let files = files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        let subject = config.confinuum.commit_message.subject(
+            CommitKind::New,
+            &name,
+            &files,
+            format!(
+                "Added configs for `{}` with {} files from {}",
+                name,
+                files.len(),
+                from_rel.display()
+            ),
+        );
+        let message = staged.message(format!("{}\n\nNew files:\n{}", subject, files.join("\n")));
+        interrupt::check()?;
+        repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
+            .context("Failed to commit files")?;
+        rollback.keep();
+    }
+
+    // Deploying replaces whatever is at the target, which for files that were never added from
+    // there could be something the user wants to keep
+    let existing = files
+        .iter()
+        .filter(|file| {
+            let path = target.join(file);
+            path.symlink_metadata().is_ok() && path.read_link().ok() != Some(entry_dir.join(file))
+        })
+        .count();
+    if existing > 0 {
+        spinner.warn(&format!(
+            "Created {}, but {} of its files already exist in {}, so it wasn't deployed. Move them out of the way and run `confinuum redeploy --only {}`.",
+            name,
+            existing,
+            target.display(),
+            name
+        ));
+    } else {
+        super::deploy(Some(&HashSet::from([name.clone()])))?;
+        spinner.success(&format!("Created {} with {} files", name, files.len()));
+    }
+
+    if push && !offline {
+        let spinner =
+            Spinner::new_shared(spinners::Dots9, "Pushing changes to remote", Color::Blue);
+        {
+            let mut pushopt = git2::PushOptions::new();
+            pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+            let mut remote = git::find_origin(&repo)?;
+            remote
+                .push(
+                    &[git::branch_refspec(&config.confinuum.branch)],
+                    Some(&mut pushopt),
+                )
+                .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
+            // Scope to ensure that all references to spinner are dropped before we call success
+        }
+        spinner.success("Changes pushed successfully.");
+    } else if push {
+        info!("Skipping push while offline, run `confinuum push` once the remote is reachable.");
+    }
+    Ok(())
+}
+
+/// Collect the files under `dir`, relative to `base`
+fn repo_files(base: &Path, dir: &Path, files: &mut BTreeSet<PathBuf>) -> Result<()> {
+    for item in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))? {
+        let path = item?.path();
+        if path.is_dir() && !path.is_symlink() {
+            repo_files(base, &path, files)?;
+        } else {
+            files.insert(path.strip_prefix(base)?.to_path_buf());
+        }
+    }
     Ok(())
 }
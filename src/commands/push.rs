@@ -1,27 +1,64 @@
-use anyhow::{Context, Result};
-use git2::Repository;
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use git2::{Commit, Repository, Sort};
 use spinoff::{spinners, Color, Spinner};
 
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
+    cli::{info, CreateSharedSpinner, SharedSpinner},
     config::ConfinuumConfig,
-    git,
+    git::{self, RepoExtensions},
 };
 
+/// The reference `push_entry` pushes from, which only exists while it's pushing
+const PUSH_ENTRY_REF: &str = "refs/confinuum/push-entry";
+
 pub fn push() -> Result<()> {
     let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
     let repo = Repository::open(&config_dir)
         .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
-    let mut remote = repo.find_remote("origin")?;
+    let branch = ConfinuumConfig::load()?.confinuum.branch;
+    push_ref(
+        &repo,
+        &git::branch_ref(&branch),
+        &branch,
+        "Pushing changes to remote",
+    )?;
+    Ok(())
+}
+
+/// Push only the local commits that change entries with one of the given tags, the way
+/// `push_entry` does for a single entry, or everything if no tags were given
+pub fn push_tagged(tags: Vec<String>) -> Result<()> {
+    let Some(names) = ConfinuumConfig::load()?.tagged_entries(&tags) else {
+        return push();
+    };
+    if names.is_empty() {
+        info!("No entries to push");
+        return Ok(());
+    }
+    push_entries(names)
+}
+
+/// Push a local reference to `branch` on the remote
+fn push_ref(repo: &Repository, reference: &str, branch: &str, message: &str) -> Result<()> {
+    let mut remote = git::find_origin(repo)?;
     let spinner = Spinner::new_shared(
         spinners::Dots9,
         "Connecting to remote 'origin'",
         Color::Blue,
     );
-    spinner.update_text("Pushing changes to remote");
+    spinner.update_text(message.to_owned());
+    let refspec = git::push_refspec(reference, branch);
+    tracing::info!(
+        "Pushing {} to {}",
+        refspec,
+        remote.url().unwrap_or("invalid url")
+    );
     remote
         .push(
-            &["refs/heads/main:refs/heads/main"],
+            &[refspec],
             Some(
                 git2::PushOptions::new()
                     .remote_callbacks(git::construct_callbacks(spinner.clone())),
@@ -32,3 +69,306 @@ pub fn push() -> Result<()> {
     spinner.success("Changes pushed successfully.");
     Ok(())
 }
+
+/// What a local commit changes, as far as pushing a single entry is concerned
+enum Changes {
+    Entry,
+    Others,
+    Both,
+}
+
+/// Push only the local commits that change one entry, and keep the rest local
+/// The entry's commits are replayed on top of the remote branch and pushed, then the other local
+/// commits are replayed on top of them, so the local history ends up as the pushed commits followed
+/// by the unpushed ones. Everything is replayed in memory first, so if any commit changes the
+/// entry along with something else, or can't be moved without the commits around it, nothing is
+/// pushed or changed.
+pub fn push_entry(name: String) -> Result<()> {
+    ConfinuumConfig::load()?.entry(&name)?;
+    push_entries(HashSet::from([name]))
+}
+
+/// Push only the local commits that change the given entries, see `push_entry`
+fn push_entries(names: HashSet<String>) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let config = ConfinuumConfig::load()?;
+    let mut sorted = names.iter().map(String::as_str).collect::<Vec<_>>();
+    sorted.sort();
+    let name = sorted.join(", ");
+    if git::check_remote(&repo, &config.confinuum.branch, "pushing", false)? {
+        return Err(anyhow!(
+            "The remote can't be reached, so nothing can be pushed"
+        ));
+    }
+    let upstream = repo
+        .find_reference("FETCH_HEAD")?
+        .peel_to_commit()
+        .context("Could not find the remote's last commit")?;
+    let head = repo.find_last_commit()?;
+
+    let mut walk = repo.revwalk()?;
+    walk.push(head.id())?;
+    walk.hide(upstream.id())?;
+    walk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    let mut pushed = upstream.clone();
+    let mut pushed_count = 0;
+    let mut kept = Vec::new();
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        let describe = || {
+            format!(
+                "{} ({})",
+                &commit.id().to_string()[..7],
+                commit.summary().unwrap_or_default()
+            )
+        };
+        if commit.parent_count() != 1 {
+            return Err(anyhow!(
+                "Commit {} is a merge, so the local commits can't be split up. Push everything with `confinuum push` instead.",
+                describe()
+            ));
+        }
+        match commit_changes(&repo, &commit, &names)? {
+            Changes::Entry => {
+                pushed = replay(&repo, &commit, &pushed)
+                    .with_context(|| format!("Could not move commit {}", describe()))?;
+                pushed_count += 1;
+            }
+            Changes::Others => kept.push(commit),
+            Changes::Both => {
+                return Err(anyhow!(
+                    "Commit {} changes {} along with other entries, so it can't be pushed on its own. Push everything with `confinuum push` instead.",
+                    describe(),
+                    name
+                ))
+            }
+        }
+    }
+    if pushed_count == 0 {
+        info!("There are no local commits that change {}", name);
+        return Ok(());
+    }
+    let mut local = pushed.clone();
+    for commit in &kept {
+        local = replay(&repo, commit, &local).with_context(|| {
+            format!(
+                "Could not move commit {} after the pushed ones",
+                &commit.id().to_string()[..7]
+            )
+        })?;
+    }
+    // Commits that touch different files can be reordered freely, so this only fails if a commit
+    // was left out by mistake
+    if local.tree_id() != head.tree_id() {
+        return Err(anyhow!(
+            "Splitting up the local commits would change the config, nothing was pushed"
+        ));
+    }
+
+    // Pushing needs a reference to push from
+    let mut temp_ref = repo.reference(PUSH_ENTRY_REF, pushed.id(), true, "push entry")?;
+    let result = push_ref(
+        &repo,
+        PUSH_ENTRY_REF,
+        &config.confinuum.branch,
+        &format!("Pushing {}", name),
+    );
+    temp_ref.delete()?;
+    result?;
+    let mut branch = repo.head()?.resolve()?;
+    branch.set_target(
+        local.id(),
+        &format!("confinuum: pushed {}, rebased the other commits", name),
+    )?;
+
+    info!(
+        "{} Pushed {} commit{} changing {}, {} commit{} left to push",
+        "✔".green(),
+        pushed_count,
+        if pushed_count == 1 { "" } else { "s" },
+        name,
+        kept.len(),
+        if kept.len() == 1 { " is" } else { "s are" }
+    );
+    Ok(())
+}
+
+/// Work out whether a commit changes the given entries, other things, or both
+/// config.toml is compared table by table, so a commit that only edits the entries' own tables
+/// counts as changing just the entries.
+fn commit_changes(repo: &Repository, commit: &Commit, names: &HashSet<String>) -> Result<Changes> {
+    let parent_tree = commit.parent(0)?.tree()?;
+    let tree = commit.tree()?;
+    let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None)?;
+    let (mut entry, mut others) = (false, false);
+    for delta in diff.deltas() {
+        for path in [delta.old_file().path(), delta.new_file().path()]
+            .into_iter()
+            .flatten()
+        {
+            if path == Path::new("config.toml") {
+                let old = git::config_at_tree(repo, &parent_tree)?;
+                let new = git::config_at_tree(repo, &tree)?;
+                let settings = |config: &ConfinuumConfig| toml::to_string(&config.confinuum).ok();
+                others |= settings(&old) != settings(&new);
+                for entry_name in old.entries.keys().chain(new.entries.keys()) {
+                    let table = |config: &ConfinuumConfig| {
+                        config.entries.get(entry_name).map(toml::to_string)
+                    };
+                    if table(&old).map(Result::ok) != table(&new).map(Result::ok) {
+                        if names.contains(entry_name) {
+                            entry = true;
+                        } else {
+                            others = true;
+                        }
+                    }
+                }
+            } else if names.iter().any(|name| path.starts_with(name)) {
+                entry = true;
+            } else {
+                others = true;
+            }
+        }
+    }
+    Ok(match (entry, others) {
+        (true, true) => Changes::Both,
+        (true, false) => Changes::Entry,
+        (false, _) => Changes::Others,
+    })
+}
+
+/// Apply a commit's changes on top of another commit, keeping its message and author
+fn replay<'r>(repo: &'r Repository, commit: &Commit, onto: &Commit) -> Result<Commit<'r>> {
+    let mut index = repo.cherrypick_commit(commit, onto, 0, None)?;
+    if index.has_conflicts() {
+        return Err(anyhow!(
+            "it depends on changes from commits that aren't being moved with it"
+        ));
+    }
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+    let oid = repo.commit(
+        None,
+        &commit.author(),
+        &commit.committer(),
+        commit.message().unwrap_or_default(),
+        &tree,
+        &[onto],
+    )?;
+    Ok(repo.find_commit(oid)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        cli,
+        config::{tests::with_env, ConfigEntry, GitProtocol, SignatureSource},
+        git::tests::{commit_file, remote_and_clone},
+    };
+
+    /// Run `f` with a remote and a config repo with the nvim and zsh entries, in sync with the
+    /// remote. nvim is tagged `editor`.
+    fn with_config_repo(f: impl FnOnce(&Repository, &Repository)) {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let config_dir = dir.path().join("local");
+        let vars = [
+            ("HOME", Some(dir.path())),
+            ("CONFINUUM_CONFIG_DIR", Some(config_dir.as_path())),
+        ];
+        with_env(&vars, || {
+            cli::disable_progress();
+            let (remote, local) = remote_and_clone(dir.path(), "main");
+            let mut config = ConfinuumConfig::init(GitProtocol::Ssh, SignatureSource::GitConfig);
+            for name in ["nvim", "zsh"] {
+                let mut entry = ConfigEntry::new(name.to_owned());
+                if name == "nvim" {
+                    entry.tags.push("editor".to_owned());
+                }
+                config.entries.insert(name.to_owned(), entry);
+            }
+            config.save().unwrap();
+            let contents = std::fs::read_to_string(config_dir.join("config.toml")).unwrap();
+            commit_file(&local, "config.toml", &contents);
+            local
+                .find_remote("origin")
+                .unwrap()
+                .push(&[git::branch_refspec("main")], None)
+                .unwrap();
+            f(&remote, &local);
+        });
+    }
+
+    fn remote_head(remote: &Repository) -> Commit {
+        remote
+            .find_reference(&git::branch_ref("main"))
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+    }
+
+    #[test]
+    fn push_entry_pushes_only_the_entry() {
+        with_config_repo(|remote, local| {
+            commit_file(local, "nvim/init.lua", "vim.o.number = true");
+            commit_file(local, "zsh/.zshrc", "export EDITOR=nvim");
+            commit_file(local, "nvim/lua/plugins.lua", "return {}");
+            let before = local.find_last_commit().unwrap();
+
+            push_entry("nvim".to_owned()).unwrap();
+
+            // Both nvim commits were pushed, in order, without the zsh one
+            let pushed = remote_head(remote);
+            let tree = pushed.tree().unwrap();
+            assert!(tree.get_path(Path::new("nvim/lua/plugins.lua")).is_ok());
+            assert!(tree.get_path(Path::new("zsh/.zshrc")).is_err());
+            let first = pushed.parent(0).unwrap().tree().unwrap();
+            assert!(first.get_path(Path::new("nvim/init.lua")).is_ok());
+            assert!(first.get_path(Path::new("nvim/lua/plugins.lua")).is_err());
+
+            // The zsh commit was moved on top of them, and the config is the same as before
+            let head = local.find_last_commit().unwrap();
+            assert_eq!(head.parent_id(0).unwrap(), pushed.id());
+            assert_eq!(head.tree_id(), before.tree_id());
+            assert!(local.find_reference(PUSH_ENTRY_REF).is_err());
+        });
+    }
+
+    #[test]
+    fn push_tagged_pushes_only_tagged_entries() {
+        with_config_repo(|remote, local| {
+            commit_file(local, "zsh/.zshrc", "export EDITOR=nvim");
+            commit_file(local, "nvim/init.lua", "vim.o.number = true");
+
+            push_tagged(vec!["editor".to_owned()]).unwrap();
+
+            let tree = remote_head(remote).tree().unwrap();
+            assert!(tree.get_path(Path::new("nvim/init.lua")).is_ok());
+            assert!(tree.get_path(Path::new("zsh/.zshrc")).is_err());
+            let head = local.find_last_commit().unwrap();
+            assert_eq!(head.parent_id(0).unwrap(), remote_head(remote).id());
+        });
+    }
+
+    #[test]
+    fn push_entry_refuses_commits_changing_other_entries() {
+        with_config_repo(|remote, local| {
+            let upstream = remote_head(remote).id();
+            let zshrc = local.workdir().unwrap().join("zsh/.zshrc");
+            std::fs::create_dir_all(zshrc.parent().unwrap()).unwrap();
+            std::fs::write(&zshrc, "export EDITOR=nvim").unwrap();
+            let mut index = local.index().unwrap();
+            index.add_path(Path::new("zsh/.zshrc")).unwrap();
+            index.write().unwrap();
+            let both = commit_file(local, "nvim/init.lua", "vim.o.number = true");
+
+            let error = push_entry("nvim".to_owned()).unwrap_err();
+            assert!(error.to_string().contains("along with other entries"));
+            assert_eq!(remote_head(remote).id(), upstream);
+            assert_eq!(local.find_last_commit().unwrap().id(), both);
+        });
+    }
+}
@@ -1,5 +1,97 @@
-pub fn redeploy() -> Result<(), anyhow::Error> {
-    super::undeploy(None::<&str>)?;
-    super::deploy(None::<&str>)?;
+use std::{collections::HashSet, io::IsTerminal};
+
+use anyhow::{anyhow, Result};
+use dialoguer::theme::ColorfulTheme;
+
+use crate::{cli::info, config::ConfinuumConfig};
+
+/// Redeploy configs, optionally only the entries in `only` or all entries except those in `except`
+/// With `tags`, this is further narrowed down to the entries with one of the tags.
+/// Files at target paths that aren't links to the config (e.g. an app replaced a link with a
+/// regular file) are refused, unless `force` is set, in which case they're backed up and replaced
+/// after confirming (or right away with `yes`). With `force`, entries whose files conflict are
+/// deployed anyway too.
+pub fn redeploy(
+    name: Option<String>,
+    only: Vec<String>,
+    except: Vec<String>,
+    tags: Vec<String>,
+    force: bool,
+    yes: bool,
+) -> Result<(), anyhow::Error> {
+    let only = name.into_iter().chain(only).collect::<Vec<_>>();
+    let names = if !only.is_empty() {
+        Some(only.into_iter().collect::<HashSet<_>>())
+    } else if !except.is_empty() {
+        let config = ConfinuumConfig::load()?;
+        if let Some(name) = except
+            .iter()
+            .find(|name| !config.entries.contains_key(*name))
+        {
+            return Err(config.entry_not_found(name).into());
+        }
+        Some(
+            config
+                .entries
+                .into_keys()
+                .filter(|name| !except.contains(name))
+                .collect(),
+        )
+    } else {
+        None
+    };
+    let names = match (names, ConfinuumConfig::load()?.tagged_entries(&tags)) {
+        (Some(names), Some(tagged)) => Some(names.intersection(&tagged).cloned().collect()),
+        (names, tagged) => names.or(tagged),
+    };
+    // Checked before undeploying too, so a conflict leaves the entries deployed as they were.
+    // Deploying warns about them with --force.
+    if force {
+        super::allow_conflicts();
+    } else {
+        super::check_conflicts(names.as_ref())?;
+    }
+    let foreign = super::foreign_files(names.as_ref())?;
+    if !foreign.is_empty() {
+        let list = foreign
+            .iter()
+            .map(|file| format!("  {}", file.display()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !force {
+            return Err(anyhow!(
+                "These files aren't links to the config, so redeploying would replace them:\n{}\nRun with --force to back them up and replace them.",
+                list
+            ));
+        }
+        println!(
+            "These files aren't links to the config and will be replaced:\n{}",
+            list
+        );
+        if !yes {
+            if !std::io::stdin().is_terminal() {
+                return Err(anyhow!(
+                    "Refusing to replace files without confirmation, pass --yes"
+                ));
+            }
+            let confirmed = dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+                .with_prompt("Back them up and replace them?")
+                .default(false)
+                .interact()?;
+            if !confirmed {
+                println!("Cancelled, nothing was changed.");
+                return Ok(());
+            }
+        }
+        let backup_dir = super::backup_files(&foreign, "redeploy")?;
+        info!(
+            "Backed up {} file{} to {}",
+            foreign.len(),
+            if foreign.len() == 1 { "" } else { "s" },
+            backup_dir.display()
+        );
+    }
+    super::undeploy(names.as_ref())?;
+    super::deploy(names.as_ref())?;
     Ok(())
 }
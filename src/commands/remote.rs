@@ -0,0 +1,161 @@
+use std::io::IsTerminal;
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use dialoguer::theme::ColorfulTheme;
+use git2::Repository;
+use git_url_parse::GitUrl;
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{ConfinuumConfig, GitProtocol},
+    error::ConfinuumError,
+    git,
+    github::Github,
+};
+
+/// Add a remote to a config that was initialized with `init --local`, and offer to push the
+/// config to it. The remote has to be empty, like when initializing with `--remote`.
+pub async fn add_remote(url: String, offline: bool, github: &Github) -> Result<()> {
+    let repo = open_repo()?;
+    if let Ok(remote) = repo.find_remote("origin") {
+        return Err(anyhow!(
+            "The config repo already has a remote ({}). Change it with `confinuum remote set-url <url>`.",
+            remote.url().unwrap_or_default()
+        ));
+    }
+    let protocol = url_protocol(&url)?;
+    let mut remote = repo
+        .remote("origin", &url)
+        .with_context(|| format!("Could not add remote {}", url))?;
+    update_protocol(&repo, protocol, github).await?;
+
+    let push = !offline
+        && std::io::stdin().is_terminal()
+        && dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!("Push your configs to {} now?", url))
+            .default(true)
+            .interact()?;
+    if !push {
+        info!(
+            "Added remote {}. Run `confinuum push` to push your configs to it.",
+            url
+        );
+        return Ok(());
+    }
+    let branch = ConfinuumConfig::load()?.confinuum.branch;
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        format!("Pushing configs to {}", url),
+        Color::Blue,
+    );
+    {
+        // Scope ensures that all references to the spinner are dropped before we call success
+        let mut pushopt = git2::PushOptions::new();
+        pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+        let refspec = format!("refs/heads/{}:refs/heads/{}", branch, branch);
+        tracing::info!("Pushing {} to {}", refspec, url);
+        if let Err(e) = remote.push(&[refspec.as_str()], Some(&mut pushopt)) {
+            spinner.fail("Could not push to the new remote");
+            return Err(e).with_context(|| {
+                format!(
+                    "Added remote {}, but pushing to it failed. The remote has to be empty, run `confinuum push` to try again.",
+                    url
+                )
+            });
+        }
+    }
+    spinner.success(&format!("Added remote {} and pushed your configs", url));
+    Ok(())
+}
+
+/// Point `origin` at a different URL, e.g. after moving the repo or switching between SSH and HTTPS
+pub async fn set_remote_url(url: String, github: &Github) -> Result<()> {
+    let repo = open_repo()?;
+    let old_url = git::find_origin(&repo)?
+        .url()
+        .unwrap_or_default()
+        .to_owned();
+    let protocol = url_protocol(&url)?;
+    repo.remote_set_url("origin", &url)
+        .with_context(|| format!("Could not set the URL of 'origin' to {}", url))?;
+    update_protocol(&repo, protocol, github).await?;
+    info!(
+        "{} Changed 'origin' from {} to {}",
+        "✔".green(),
+        old_url,
+        url
+    );
+    Ok(())
+}
+
+/// Remove `origin`, leaving a local-only config like one created with `init --local`
+pub fn remove_remote() -> Result<()> {
+    let repo = open_repo()?;
+    let url = git::find_origin(&repo)?
+        .url()
+        .unwrap_or_default()
+        .to_owned();
+    repo.remote_delete("origin")
+        .context("Could not remove remote 'origin'")?;
+    info!(
+        "{} Removed remote 'origin' ({}). Your configs are only kept locally until you run `confinuum remote add <url>`.",
+        "✔".green(),
+        url
+    );
+    Ok(())
+}
+
+/// Print the config repo's remotes and their URLs
+pub fn list_remotes() -> Result<()> {
+    let repo = open_repo()?;
+    let names = repo.remotes().context("Could not list remotes")?;
+    if names.is_empty() {
+        println!("The config repo has no remotes. Run `confinuum remote add <url>` to add one.");
+        return Ok(());
+    }
+    for name in names.iter().flatten() {
+        let remote = repo.find_remote(name)?;
+        let url = remote.url().unwrap_or_default();
+        if name == "origin" {
+            println!("{}\t{}", name.bold().yellow(), url);
+        } else {
+            // Confinuum only syncs with origin, other remotes are the user's own
+            println!("{}\t{} {}", name, url, "(not synced)".dark_grey());
+        }
+    }
+    Ok(())
+}
+
+fn open_repo() -> Result<Repository> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    if !ConfinuumConfig::exists()? {
+        return Err(ConfinuumError::NotInitialized.into());
+    }
+    Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))
+}
+
+/// The protocol of a remote URL, checked before the remote is changed
+fn url_protocol(url: &str) -> Result<GitProtocol> {
+    let parsed_url =
+        GitUrl::parse(url).map_err(|e| anyhow!("Could not parse {} as a git url: {}", url, e))?;
+    super::init::git_protocol(&parsed_url)
+}
+
+/// Store the protocol of the new remote URL in config.toml, so credentials are picked to match
+async fn update_protocol(repo: &Repository, protocol: GitProtocol, github: &Github) -> Result<()> {
+    let mut config = ConfinuumConfig::load()?;
+    if config.confinuum.git_protocol == protocol {
+        return Ok(());
+    }
+    config.confinuum.git_protocol = protocol;
+    git::commit_config(
+        repo,
+        &config,
+        "Set the git protocol to match the remote",
+        github,
+    )
+    .await
+}
@@ -1,109 +1,80 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::HashSet,
+    fs,
+    io::IsTerminal,
+    path::{Component, Path, PathBuf},
+};
 
 use anyhow::{anyhow, Context, Result};
 use crossterm::style::Stylize;
-use git2::{FetchOptions, IndexAddOption, Repository};
+use dialoguer::theme::ColorfulTheme;
+use git2::Repository;
 use spinoff::{spinners, Color, Spinner};
 
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
-    config::{ConfinuumConfig, SignatureSource},
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{CommitKind, ConfigEntry, ConfinuumConfig, LinkMode},
     git::{self, RepoExtensions},
     github::Github,
+    interrupt,
+    rollback::Rollback,
 };
 
 pub async fn remove(
     name: String,
-    mut files: Vec<PathBuf>,
+    files: Vec<PathBuf>,
     no_confirm: bool,
     no_replace_files: bool,
     push: bool,
+    offline: bool,
     github: &Github,
 ) -> Result<()> {
     // Ensure entry exists
     let config_dir = ConfinuumConfig::get_dir().context("Cannot get config dir")?;
     let mut config = ConfinuumConfig::load().context("Cannot load config file")?;
-    if !config.entries.contains_key(&name) {
-        return Err(anyhow!("No entry named {} found", name.red().bold()));
-    }
-
-    // Ensure all files exist
-    files.iter_mut().try_for_each(|f| -> Result<()> {
-        *f = f
-            .canonicalize()
-            .context(format!("Could not canonicalize {}", f.display()))?;
-        Ok(())
-    })?;
-    for file in &files {
-        if !file.exists() {
-            return Err(anyhow!(
-                "File {} does not exist",
-                file.display().to_string().red().bold()
-            ));
-        }
+    let branch = config.confinuum.branch.clone();
+    let entry = config.entry_mut(&name)?;
+    let entry_dir = config_dir.join(&name);
+    if entry.link_mode == LinkMode::Directory && !no_replace_files {
+        return Err(anyhow!(
+            "{} links its whole directory, so removed files can't be put back in it. Pass --no-replace-files to delete them, or run `confinuum entry {} set-link-mode files` first.",
+            name,
+            name
+        ));
     }
 
-    let entry = config
-        .entries
-        .get_mut(&name)
-        .ok_or_else(|| anyhow!("No entry named {} found", name))?;
-
     // Ensure all files are in the entry
+    let mut removed_files = if files.is_empty() {
+        pick_entry_files(entry)?
+    } else {
+        Vec::new()
+    };
+    if files.is_empty() && removed_files.is_empty() {
+        info!("No files selected, nothing was removed from {}", name);
+        return Ok(());
+    }
     for file in &files {
-        let file = file.strip_prefix(&config_dir.join(&name)).context(format!(
-            "cannot strip prefix {} from {}",
-            config_dir.join(&name).display(),
-            file.display()
-        ))?;
-        if !entry.files.contains(file) {
-            return Err(anyhow!(
+        let relative = resolve_entry_file(file, entry, &entry_dir)?.ok_or_else(|| {
+            anyhow!(
                 "File {} does not exist in entry {}",
                 file.display().to_string().red().bold(),
-                name.yellow().bold()
-            ));
+                name.clone().yellow().bold()
+            )
+        })?;
+        if !removed_files.contains(&relative) {
+            removed_files.push(relative);
         }
     }
 
     // Ensure there aren't changes on remote
     let repo = Repository::open(&config_dir)?;
-    let mut remote = repo.find_remote("origin")?;
-    let spinner = Spinner::new_shared(
-        spinners::Dots9,
-        "Connecting to remote 'origin'",
-        Color::Blue,
-    );
-
-    spinner.update_text("Checking for changes on remote");
-    remote
-        .fetch(
-            &["main"],
-            Some(
-                FetchOptions::new()
-                    .update_fetchhead(true)
-                    .remote_callbacks(git::construct_callbacks(spinner.clone())),
-            ),
-            None,
-        )
-        .context("Failed to fetch from remote 'origin'")?;
-    let fetch_head = repo.find_reference("FETCH_HEAD")?;
-    let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-    // Check if up to date
-    let analysis = repo.merge_analysis(&[&fetch_commit])?;
-    remote.disconnect()?;
-    if !analysis.0.is_up_to_date() {
-        spinner.fail("Changes found on remote");
-        return Err(anyhow!(
-            "Changes found on remote. Please pull them before deleting files."
-        ));
-    }
-
-    spinner.clear();
+    let offline = git::check_remote(&repo, &branch, "removing files", offline)?;
 
     let confirm = no_confirm || {
         let selection = dialoguer::Select::new()
             .with_prompt(format!(
                 "Are you sure you want to delete {} files from {}?",
-                files.len(),
+                removed_files.len(),
                 name.clone().yellow().bold()
             ))
             .items(&["Yes", "No"])
@@ -124,39 +95,45 @@ pub async fn remove(
         spinners::Dots9,
         format!(
             "Confirmed removal of {} files from {}, continuing",
-            files.len(),
+            removed_files.len(),
             &name
         ),
         Color::Blue,
     );
 
-    super::undeploy(Some(&name))?; // Undeploy entry if it's deployed
+    let mut rollback = Rollback::new(&[])?;
+    rollback.redeploy(&name);
+    // Undeploy the entry if it's deployed. Undeploying skips dangling links, which are left behind
+    // when a source was already deleted, so those are removed separately.
+    super::undeploy(Some(&HashSet::from([name.clone()])))?;
+    super::remove_stale_links(entry, &removed_files)?;
+    interrupt::check()?;
+    // Files are moved out of the repo from here on, which redeploying can't undo, so Ctrl-C waits
+    // for the command to finish instead
+    let _deferred = interrupt::Deferred::new();
+    rollback.keep();
 
     {
         // Remove files from entry, and move them to their original location (unless no)
-        let mut removed_files = Vec::new();
-        for file in &files {
-            let file = file.strip_prefix(&config_dir.join(&name)).context(format!(
-                "cannot strip prefix {} from {}",
-                config_dir.join(&name).display(),
-                file.display()
-            ))?;
+        for file in &removed_files {
             spinner.update_text(format!("Removing {}", file.display()));
             entry.files.remove(file);
-            removed_files.push(file.to_path_buf());
-            let source_path = config_dir.join(&name).join(&file);
-            let target_path = entry.target_dir.as_ref().unwrap().join(&file);
-            if !no_replace_files {
-                fs::copy(&source_path, &target_path).with_context(|| {
-                    format!(
-                        "Cannot copy {} to {}",
-                        source_path.display(),
-                        target_path.display()
-                    )
-                })?;
+            let source_path = entry_dir.join(file);
+            let target_path = entry.deploy_dir().unwrap().join(file);
+            if !source_path.exists() {
+                // Already gone from the repo, so there's nothing to restore or delete
+                continue;
+            }
+            if no_replace_files {
+                fs::remove_file(&source_path)
+                    .with_context(|| format!("Cannot remove {}", source_path.display()))?;
+            } else {
+                super::move_file(&source_path, &target_path)?;
             }
-            fs::remove_file(&source_path)
-                .with_context(|| format!("Cannot remove {}", source_path.display()))?;
+        }
+        if entry.files.is_empty() {
+            // A single-file entry without its file is uninitialized, so any files can be added
+            entry.target_file = None;
         }
 
         spinner.update_text(format!("Saving config file"));
@@ -164,65 +141,155 @@ pub async fn remove(
         config.save()?;
 
         spinner.update_text(format!("Committing changes"));
-        let mut index = repo.index()?;
-        let mut imp = |path: &std::path::Path, _data: &[u8]| {
-            if path.starts_with(".git") {
-                return 1; // skip .git/
-            }
-            return 0;
-        };
-        index
-            .add_all(["*"], IndexAddOption::DEFAULT, Some(&mut imp))
-            .context("Could not add files")?;
-        let oid = index.write_tree().context("Failed to write tree")?;
+        let staged = git::stage_paths(
+            &repo,
+            &[&name],
+            removed_files
+                .iter()
+                .map(|file| PathBuf::from(&name).join(file)),
+        )?;
         // Get the last commit
         let parent_commit = repo
             .find_last_commit()
             .context("Failed to retrieve last commit")?;
         // Await the user signature from the GitHub API
-        let sig = match &config.confinuum.signature_source {
-            SignatureSource::Github => github
-                .get_user_signature()
-                .await
-                .context("Could not fetch user signature from github")?,
-            SignatureSource::GitConfig => {
-                // allows users to set values in config if they don't exist
-                git::gitconfig::get_user_sig()?
-            }
-        };
+        let sig = git::commit_signature(&config, github).await?;
         let tree = repo
-            .find_tree(oid)
+            .find_tree(staged.oid)
             .context("Failed to find new commit tree")?;
-        let message = format!(
-            "Deleted {} files from `{}`\n\nDeleted files:\n{}",
-            files.len(),
-            name,
-            removed_files
-                .iter()
-                .map(|f| f.display().to_string())
-                .collect::<Vec<_>>()
-                .join("\n")
+        let files = removed_files
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        let subject = config.confinuum.commit_message.subject(
+            CommitKind::Remove,
+            &name,
+            &files,
+            format!("Deleted {} files from `{}`", files.len(), name),
         );
+        let message = staged.message(format!(
+            "{}\n\nDeleted files:\n{}",
+            subject,
+            files.join("\n")
+        ));
 
-        repo.commit(Some("HEAD"), &sig, &sig, &message, &tree, &[&parent_commit])
+        repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
             .context("Failed to commit files")?;
 
-        if push {
+        if push && !offline {
             // Push the changes
             spinner.update_text("Pushing changes to remote");
             let mut pushopt = git2::PushOptions::new();
             pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+            let mut remote = git::find_origin(&repo)?;
             remote
-                .push(&["refs/heads/main:refs/heads/main"], Some(&mut pushopt))
+                .push(
+                    &[git::branch_refspec(&config.confinuum.branch)],
+                    Some(&mut pushopt),
+                )
                 .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
         }
     }
-    super::deploy(Some(&name))?; // Deploy entry
+    super::deploy(Some(&HashSet::from([name.clone()])))?; // Deploy entry
     spinner.success(&format!(
         "Successfully removed {} files from {}",
-        files.len(),
+        removed_files.len(),
         &name
     ));
+    if push && offline {
+        info!("Skipping push while offline, run `confinuum push` once the remote is reachable.");
+    }
 
     Ok(())
 }
+
+/// Let the user pick which of the entry's files to remove, for when none were given
+/// Without a terminal to pick on, this fails like it always has when no files are given.
+fn pick_entry_files(entry: &ConfigEntry) -> Result<Vec<PathBuf>> {
+    if entry.files.is_empty() || !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "No files given, pass the files to remove from {}",
+            entry.name
+        ));
+    }
+    let files = entry.files.iter().collect::<Vec<_>>();
+    let selected = dialoguer::MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt(format!(
+            "Files to remove from {} (space to select, enter to confirm)",
+            entry.name
+        ))
+        .items(
+            &files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>(),
+        )
+        .interact()?;
+    Ok(selected
+        .into_iter()
+        .map(|index| files[index].clone())
+        .collect())
+}
+
+/// Find the file in an entry that a path given by the user refers to
+/// The path can be the deployed link, the file in the config repo, or relative to the entry's
+/// directory in the repo. Canonicalizing is tried first since it follows the link, but isn't
+/// required, so files whose source or link is already gone can still be removed.
+fn resolve_entry_file(
+    file: &Path,
+    entry: &ConfigEntry,
+    entry_dir: &Path,
+) -> Result<Option<PathBuf>> {
+    let in_entry = |relative: &Path| {
+        entry
+            .files
+            .contains(relative)
+            .then(|| relative.to_path_buf())
+    };
+    if let Ok(canonical) = file.canonicalize() {
+        if let Some(relative) = canonical.strip_prefix(entry_dir).ok().and_then(in_entry) {
+            return Ok(Some(relative));
+        }
+    }
+    let absolute = normalize(file)?;
+    if let Some(relative) = absolute.strip_prefix(entry_dir).ok().and_then(in_entry) {
+        return Ok(Some(relative));
+    }
+    if let Some(target_dir) = entry.deploy_dir() {
+        if let Some(relative) = absolute.strip_prefix(target_dir).ok().and_then(in_entry) {
+            // Only a dangling link to the repo or a missing file, since anything else at the target
+            // isn't the entry's file and would be overwritten when the file is restored
+            let link = absolute.read_link().ok();
+            if link == Some(entry_dir.join(&relative)) || (link.is_none() && !absolute.exists()) {
+                return Ok(Some(relative));
+            }
+        }
+    }
+    if file.is_relative() {
+        return Ok(in_entry(file));
+    }
+    Ok(None)
+}
+
+/// Make a path absolute and resolve `.` and `..` in it without touching the filesystem, so it
+/// works for paths that don't exist
+fn normalize(path: &Path) -> Result<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        std::env::current_dir()
+            .context("Could not get the current directory")?
+            .join(path)
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    Ok(normalized)
+}
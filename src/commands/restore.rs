@@ -0,0 +1,92 @@
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use git2::{build::CheckoutBuilder, Repository, ResetType};
+
+use crate::{
+    cli::info,
+    config::ConfinuumConfig,
+    git::{self, RepoExtensions},
+    github::Github,
+};
+
+/// Roll the config back to the state it was in at the given commit, and redeploy it
+/// By default this creates a new commit with the old state so that history stays consistent with
+/// the remote. With `hard`, the branch is reset to the commit instead, discarding later commits.
+pub async fn restore(commit: String, hard: bool, force: bool, github: &Github) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+
+    let changed = super::status::local_changes(&repo)?;
+    if !changed.is_empty() && !force {
+        return Err(anyhow!(
+            "There are {} uncommitted change{} in the config repo. Commit them with `confinuum status`, or use --force to discard them.",
+            changed.len(),
+            if changed.len() == 1 { "" } else { "s" }
+        ));
+    }
+
+    let target = repo
+        .revparse_single(&commit)
+        .with_context(|| format!("Could not find commit {}", commit))?
+        .peel_to_commit()
+        .with_context(|| format!("{} is not a commit", commit))?;
+    let short_id = target
+        .as_object()
+        .short_id()?
+        .as_str()
+        .unwrap_or_default()
+        .to_owned();
+    let head = repo.find_last_commit()?;
+    if head.tree_id() == target.tree_id() && changed.is_empty() {
+        info!("The config is already in the state of {}", short_id);
+        return Ok(());
+    }
+    // Make sure the config at that commit is valid before touching anything
+    git::config_at_tree(&repo, &target.tree()?)
+        .with_context(|| format!("The config at {} is not valid", short_id))?;
+
+    // Committing asks GitHub for the signature and may open an editor, so it's done while the
+    // config is still deployed. HEAD moves, but the files only change at the checkout.
+    if !hard {
+        let config = ConfinuumConfig::load()?;
+        let sig = git::commit_signature(&config, github).await?;
+        let message = format!(
+            "Restore config to {}\n\nRestored the state of {} ({})",
+            short_id,
+            target.id(),
+            target.summary().unwrap_or("<no message>")
+        );
+        repo.commit_with_config(&sig, &message, &target.tree()?, &[&head])
+            .context("Failed to commit restored config")?;
+    }
+
+    let checkout = super::undeploy(None).and_then(|_| match hard {
+        true => repo
+            .reset(target.as_object(), ResetType::Hard, None)
+            .with_context(|| format!("Failed to reset to {}", short_id)),
+        false => repo
+            .checkout_head(Some(CheckoutBuilder::default().force()))
+            .context("Failed to check out restored config"),
+    });
+    if let Err(e) = checkout {
+        // Whatever config.toml is on disk now gets deployed, so the user isn't left without links
+        if let Err(deploy_error) = super::deploy(None) {
+            eprintln!(
+                "{} Could not deploy the config again: {:?}",
+                "!".yellow(),
+                deploy_error
+            );
+        }
+        return Err(e);
+    }
+    super::deploy(None)?;
+
+    info!("{} Restored config to {}", "✔".green(), short_id);
+    if hard {
+        info!("History was rewritten, so pushing will be rejected if the removed commits were already pushed.");
+    } else {
+        info!("Run `confinuum push` to push the change to the remote.");
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::{fs, io::IsTerminal, os::unix::fs::PermissionsExt, path::Path, process::Command};
+
+use anyhow::{anyhow, Context, Result};
+use dialoguer::theme::ColorfulTheme;
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    updates::{self, VERSION},
+};
+
+/// Replace the running binary with the latest release's build for this target
+/// The download is checked against the checksum published with the release, and that it runs,
+/// before anything is replaced. With `check`, only whether there's a newer release is reported.
+pub async fn self_update(check: bool, yes: bool) -> Result<()> {
+    let spinner = Spinner::new_shared(spinners::Dots9, "Checking for a new release", Color::Blue);
+    let release = match updates::latest_release(None).await {
+        Ok(release) => release,
+        Err(e) => {
+            spinner.fail("Could not check for a new release");
+            return Err(e);
+        }
+    };
+    if !release.is_newer() {
+        spinner.success(&format!("confinuum {} is the latest version", VERSION));
+        return Ok(());
+    }
+    spinner.success(&format!(
+        "confinuum {} is available (this is {})",
+        release.version(),
+        VERSION
+    ));
+    info!("Release notes: {}", release.html_url);
+    if check {
+        return Ok(());
+    }
+    if updates::installed_with_homebrew() {
+        return Err(anyhow!(
+            "confinuum was installed with Homebrew, run `brew upgrade confinuum` to update it"
+        ));
+    }
+    let build = release.build().ok_or_else(|| {
+        anyhow!(
+            "The release has no build for {}, install it from source instead",
+            updates::TARGET
+        )
+    })?;
+    let exe = std::env::current_exe()
+        .and_then(|exe| exe.canonicalize())
+        .context("Could not find the confinuum binary")?;
+    if !yes {
+        if !std::io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "Refusing to update without confirmation, pass --yes"
+            ));
+        }
+        let confirmed = dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "Download {} ({}) and replace {}?",
+                build.name,
+                super::show::format_size(build.size),
+                exe.display()
+            ))
+            .default(true)
+            .interact()?;
+        if !confirmed {
+            info!("Cancelled, nothing was changed.");
+            return Ok(());
+        }
+    }
+
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        format!("Downloading {}", build.name),
+        Color::Blue,
+    );
+    let installed = async {
+        let expected = release.checksum(build).await?;
+        let bytes = updates::download(build).await?;
+        let actual = updates::sha256(&bytes);
+        if actual != expected {
+            return Err(anyhow!(
+                "The checksum of {} doesn't match the release's ({} instead of {}), nothing was changed",
+                build.name,
+                actual,
+                expected
+            ));
+        }
+        spinner.update_text("Installing");
+        replace_binary(&exe, &updates::unpack_build(build, bytes)?, release.version())
+    }
+    .await;
+    match installed {
+        Ok(()) => {
+            spinner.success(&format!("Updated confinuum to {}", release.version()));
+            Ok(())
+        }
+        Err(e) => {
+            spinner.fail("Could not update confinuum");
+            Err(e)
+        }
+    }
+}
+
+/// Put the new binary in place of the running one
+/// It's written next to the old one and renamed over it, which is atomic within a filesystem, so
+/// the binary is never half-written. This process keeps running from the old file, which it still
+/// has open.
+fn replace_binary(exe: &Path, binary: &[u8], version: &str) -> Result<()> {
+    let dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("Could not get the directory of {}", exe.display()))?;
+    let new = dir.join(format!(".confinuum-{}.new", std::process::id()));
+    let result = fs::write(&new, binary)
+        .and_then(|_| fs::set_permissions(&new, fs::Permissions::from_mode(0o755)))
+        .with_context(|| {
+            format!(
+                "Could not write to {}, run self-update as a user who can",
+                dir.display()
+            )
+        })
+        .and_then(|_| check_runs(&new, version))
+        .and_then(|_| {
+            fs::rename(&new, exe).with_context(|| format!("Could not replace {}", exe.display()))
+        });
+    if result.is_err() {
+        fs::remove_file(&new).ok();
+    }
+    result
+}
+
+/// Check that a downloaded binary runs on this machine and is the expected version
+fn check_runs(binary: &Path, version: &str) -> Result<()> {
+    let output = Command::new(binary)
+        .arg("--version")
+        .output()
+        .context("The downloaded binary doesn't run on this machine, nothing was changed")?;
+    let reported = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() || !reported.contains(version) {
+        return Err(anyhow!(
+            "The downloaded binary reported {} instead of version {}, nothing was changed",
+            reported.trim(),
+            version
+        ));
+    }
+    Ok(())
+}
@@ -1,18 +1,80 @@
-use std::path::PathBuf;
+use std::{fs, path::PathBuf};
 
 use crate::config::ConfinuumConfig;
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
 use crossterm::style::{Color, Stylize};
 
+/// The characters the file tree is drawn with
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum TreeStyle {
+    /// Box-drawing lines and folder/file icons
+    Unicode,
+    /// Plain ASCII, for terminals and fonts without the unicode glyphs (and for logs)
+    Ascii,
+}
+
+impl TreeStyle {
+    /// Unicode if the locale is UTF-8 and the terminal isn't one known to lack the glyphs
+    pub fn detect() -> Self {
+        let term = std::env::var("TERM").unwrap_or_default();
+        // The first of these that's set decides the character encoding, like in libc
+        let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|value| !value.is_empty()))
+            .unwrap_or_default()
+            .to_lowercase();
+        let utf8 = locale.contains("utf-8") || locale.contains("utf8");
+        if utf8 && term != "dumb" && term != "linux" {
+            TreeStyle::Unicode
+        } else {
+            TreeStyle::Ascii
+        }
+    }
+
+    fn glyphs(self) -> TreeGlyphs {
+        match self {
+            TreeStyle::Unicode => TreeGlyphs {
+                line: "│",
+                branch: "├──",
+                last_branch: "└──",
+                dir: " \u{1F5C1} ",
+                file: " \u{1F5CB}",
+            },
+            TreeStyle::Ascii => TreeGlyphs {
+                line: "|",
+                branch: "|--",
+                last_branch: "`--",
+                dir: " [D]",
+                file: " [F]",
+            },
+        }
+    }
+}
+
+struct TreeGlyphs {
+    line: &'static str,
+    branch: &'static str,
+    last_branch: &'static str,
+    dir: &'static str,
+    file: &'static str,
+}
+
 #[derive(Debug)]
 struct MockDirEntry {
     name: String,
     entries: Vec<MockDirEntry>,
+    /// Size in bytes of the file, only set for files when showing stats
+    size: Option<u64>,
 }
 
 impl MockDirEntry {
     fn new_dir(name: String, entries: Vec<MockDirEntry>) -> Self {
-        Self { name, entries }
+        Self {
+            name,
+            entries,
+            size: None,
+        }
     }
 
     fn dir_add_entry(&mut self, entry: MockDirEntry) {
@@ -28,7 +90,7 @@ impl MockDirEntry {
         None
     }
 
-    fn build_tree(&mut self, path: &PathBuf, depth: usize) {
+    fn build_tree(&mut self, path: &PathBuf, depth: usize, size: Option<u64>) {
         if depth < path.components().count() {
             let item = &path.components().nth(depth).unwrap();
 
@@ -44,55 +106,111 @@ impl MockDirEntry {
                         .unwrap()
                 }
             };
-            dir.build_tree(path, depth + 1)
+            if depth == path.components().count() - 1 {
+                dir.size = size;
+            }
+            dir.build_tree(path, depth + 1, size)
         }
     }
 
-    fn print_tree(&self, depth: usize, last: bool) {
+    /// Count the (directories, files) below this entry
+    fn count(&self) -> (usize, usize) {
+        self.entries.iter().fold((0, 0), |(dirs, files), entry| {
+            if entry.entries.is_empty() {
+                (dirs, files + 1)
+            } else {
+                let (sub_dirs, sub_files) = entry.count();
+                (dirs + sub_dirs + 1, files + sub_files)
+            }
+        })
+    }
+
+    fn print_tree(&self, depth: usize, last: bool, style: TreeStyle) {
+        let glyphs = style.glyphs();
         let (color, icon) = if self.entries.is_empty() {
-            (Color::Reset, " \u{1F5CB}")
+            (Color::Reset, glyphs.file)
         } else {
-            (Color::Blue, " \u{1F5C1} ")
+            (Color::Blue, glyphs.dir)
         };
         if depth == 0 {
             println!("{}", self.name.clone().yellow());
         } else {
             let indent = (((depth as usize) - 1) * 4).checked_sub(1).unwrap_or(0);
             println!(
-                "{}{:indent$}{}{} {}",
-                if indent == 0 { "" } else { "│" },
+                "{}{:indent$}{}{} {}{}",
+                if indent == 0 { "" } else { glyphs.line },
                 "",
-                if last { "└──" } else { "├──" },
+                if last {
+                    glyphs.last_branch
+                } else {
+                    glyphs.branch
+                },
                 icon,
                 self.name.clone().with(color),
-                // Test
+                self.size
+                    .map(|size| format!(" ({})", format_size(size)).dark_grey().to_string())
+                    .unwrap_or_default(),
             );
         }
         for (idx, entry) in self.entries.iter().enumerate() {
-            entry.print_tree(depth + 1, idx == self.entries.len() - 1);
+            entry.print_tree(depth + 1, idx == self.entries.len() - 1, style);
         }
     }
 }
 
-pub fn show(name: String) -> Result<()> {
+pub(super) fn format_size(bytes: u64) -> String {
+    format!("{:.1} KB", bytes as f64 / 1024.)
+}
+
+pub fn show(name: String, stat: bool, tree_style: Option<TreeStyle>) -> Result<()> {
     let config = ConfinuumConfig::load()?;
-    let entry = config
-        .entries
-        .get(&name)
-        .ok_or_else(|| anyhow!("No entry named {} found", name))?;
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let entry = config.entry(&name)?;
 
     let mut root = MockDirEntry::new_dir(
         format!(
             "{} in {}",
             &name,
-            entry.target_dir.as_ref().unwrap().to_string_lossy()
+            entry.deploy_dir().unwrap().to_string_lossy()
         ),
         Vec::new(),
     );
+    let mut total_size = 0;
     for file in &entry.files {
-        root.build_tree(file, 0);
+        let size = if stat {
+            let source_path = config_dir.join(&name).join(file);
+            let size = fs::metadata(&source_path)
+                .with_context(|| format!("Could not read metadata of {}", source_path.display()))?
+                .len();
+            total_size += size;
+            Some(size)
+        } else {
+            None
+        };
+        root.build_tree(file, 0, size);
+    }
+    if let Some(description) = &entry.description {
+        println!("{}", description.as_str().dark_grey());
+    }
+    if !entry.tags.is_empty() {
+        println!("{} {}", "Tags:".dark_grey(), entry.tags.join(", ").cyan());
+    }
+    if let Some(conditions) = super::list::conditions_note(entry) {
+        println!("{}", conditions);
+    }
+    root.print_tree(0, false, tree_style.unwrap_or_else(TreeStyle::detect));
+
+    if stat {
+        let (dirs, files) = root.count();
+        println!(
+            "\n{} director{}, {} file{}, {} total",
+            dirs,
+            if dirs == 1 { "y" } else { "ies" },
+            files,
+            if files == 1 { "" } else { "s" },
+            format_size(total_size).bold()
+        );
     }
-    root.print_tree(0, false);
 
     /* let mut stdout = std::io::stdout();
     queue!(
@@ -103,7 +221,7 @@ pub fn show(name: String) -> Result<()> {
             "{}: {} files in {}\n",
             name.bold().yellow(),
             entry.files.len(),
-            entry.target_dir.as_ref().unwrap().display()
+            entry.deploy_dir().unwrap().display()
         )),
     )?;
 
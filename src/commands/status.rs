@@ -0,0 +1,187 @@
+use std::{collections::HashSet, path::Path};
+
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::{DiffOptions, Repository};
+
+use crate::{
+    cli::info,
+    config::ConfinuumConfig,
+    git::{self, FileDelta, RepoExtensions},
+    github::Github,
+};
+
+/// Collect the tracked files in the config repo that differ from the last commit
+/// Since deployed files are symlinks into the repo, this includes edits made through the links.
+pub(super) fn local_changes(repo: &Repository) -> Result<Vec<FileDelta>> {
+    let head_tree = repo.find_last_commit()?.tree()?;
+    let mut diff_opt = DiffOptions::new();
+    diff_opt.include_untracked(false).include_ignored(false);
+    let mut diff = repo
+        .diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut diff_opt))
+        .context("Failed to diff working directory")?;
+    git::diff_files(&mut diff)
+}
+
+/// Keep only the changes to files of the given entries, or all changes if `entries` is None
+fn filter_changes(changes: Vec<FileDelta>, entries: Option<&HashSet<String>>) -> Vec<FileDelta> {
+    let Some(entries) = entries else {
+        return changes;
+    };
+    changes
+        .into_iter()
+        .filter(|delta| {
+            delta.paths().iter().any(|path| {
+                path.components().next().is_some_and(|entry| {
+                    entries.contains(entry.as_os_str().to_string_lossy().as_ref())
+                })
+            })
+        })
+        .collect()
+}
+
+/// Build a commit message listing the changed files, grouped by the entry they belong to
+fn changes_message(files: &Vec<FileDelta>) -> Result<String> {
+    let (entries, config_updated, unowned) = git::diff_entries(files)?;
+    let mut names = entries.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+
+    let mut message = format!(
+        "Updated {} file{}",
+        files.len(),
+        if files.len() == 1 { "" } else { "s" }
+    );
+    if !names.is_empty() {
+        message.push_str(&format!(" in {}", names.join(", ")));
+    }
+    message.push('\n');
+    for name in &names {
+        let mut changed = entries[name]
+            .iter()
+            .map(|delta| delta.to_string())
+            .collect::<Vec<_>>();
+        changed.sort();
+        message.push_str(&format!("\n{}:\n", name));
+        for file in changed {
+            message.push_str(&format!("    {}\n", file));
+        }
+    }
+    if config_updated {
+        message.push_str("\nconfig.toml updated\n");
+    }
+    if !unowned.is_empty() {
+        message.push_str("\nOther files:\n");
+        for file in unowned {
+            message.push_str(&format!("    {}\n", file.display()));
+        }
+    }
+    Ok(message)
+}
+
+/// Commit any modified tracked files in the config repo, only those of `entries` if given
+/// Returns the number of files committed
+pub(super) async fn commit_local_changes(
+    repo: &Repository,
+    entries: Option<&HashSet<String>>,
+    github: &Github,
+) -> Result<usize> {
+    let changed = filter_changes(local_changes(repo)?, entries);
+    if changed.is_empty() {
+        return Ok(0);
+    }
+
+    let mut index = repo.index()?;
+    for delta in &changed {
+        let removed = delta.removed_paths();
+        for path in delta.paths() {
+            if removed.contains(&path) {
+                index.remove_path(path)?;
+            } else {
+                index.add_path(path)?;
+            }
+        }
+    }
+    // Always staged, so the commit has it even if it had been removed from the index
+    index.add_path(Path::new("config.toml"))?;
+    index.write()?;
+    let oid = index.write_tree().context("Failed to write tree")?;
+    let tree = repo
+        .find_tree(oid)
+        .context("Failed to find new commit tree")?;
+    let parent_commit = repo
+        .find_last_commit()
+        .context("Failed to retrieve last commit")?;
+    let config = ConfinuumConfig::load()?;
+    let sig = git::commit_signature(&config, github).await?;
+    let message = changes_message(&changed)?;
+    repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
+        .context("Failed to commit files")?;
+    Ok(changed.len())
+}
+
+/// Show files that were changed locally (usually by editing them through their deployed symlinks)
+/// and offer to commit them, after noting any local commits that haven't been pushed
+/// With `tags`, only changes to the files of entries with one of the tags are shown and committed.
+pub async fn status(no_confirm: bool, tags: Vec<String>, github: &Github) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let config = ConfinuumConfig::load()?;
+    let entries = config.tagged_entries(&tags);
+
+    if let Some(unpushed) = git::unpushed_note(&repo, &config.confinuum.branch)? {
+        println!("{}", unpushed);
+    }
+    let changed = filter_changes(local_changes(&repo)?, entries.as_ref());
+    if changed.is_empty() {
+        println!("{} No uncommitted changes", "✔".green());
+        return Ok(());
+    }
+
+    println!(
+        "{} {} uncommitted change{}\n",
+        "!".yellow(),
+        changed.len(),
+        if changed.len() == 1 { "" } else { "s" }
+    );
+    println!("{}", changes_message(&changed)?.trim_end());
+    // Entries that are disabled or limited to other machines can still be edited here, but aren't
+    // deployed
+    let mut names = git::diff_entries(&changed)?
+        .0
+        .into_keys()
+        .collect::<Vec<_>>();
+    names.sort();
+    for entry in names.iter().filter_map(|name| config.entries.get(name)) {
+        if entry.disabled {
+            println!("{}", super::list::styled_name(entry));
+        }
+        if let Some(conditions) = super::list::conditions_note(entry) {
+            println!("{}: {}", entry.name, conditions);
+        }
+    }
+    println!();
+
+    let confirm = no_confirm || {
+        let selection = dialoguer::Select::new()
+            .with_prompt("Commit these changes?")
+            .items(&["Yes", "No"])
+            .default(0)
+            .interact_opt()
+            .context("Failed to interact with user, cancelling.")?;
+        selection == Some(0)
+    };
+    if !confirm {
+        info!("Changes left uncommitted.");
+        return Ok(());
+    }
+
+    let committed = commit_local_changes(&repo, entries.as_ref(), github).await?;
+    info!(
+        "{} Committed {} file{}. Run `confinuum push` to push them to the remote.",
+        "✔".green(),
+        committed,
+        if committed == 1 { "" } else { "s" }
+    );
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{self, info, CreateSharedSpinner, SharedSpinner},
+    config::ConfinuumConfig,
+    error::ConfinuumError,
+    git::{self, RepoExtensions},
+    github::Github,
+    lock,
+};
+
+/// Fetch from the remote and count how many commits the local branch is (ahead, behind) it
+fn fetch_ahead_behind(repo: &Repository, branch: &str) -> Result<(usize, usize)> {
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        "Checking for changes on remote",
+        Color::Blue,
+    );
+    let ahead_behind = {
+        // Scope to ensure that all references to spinner are dropped before we clear it
+        let fetch_commit = git::fetch_origin(repo, &spinner, branch)?;
+        let head_commit = repo.find_last_commit()?;
+        repo.graph_ahead_behind(head_commit.id(), fetch_commit.id())?
+    };
+    spinner.clear();
+    Ok(ahead_behind)
+}
+
+/// Commit local changes, pull remote changes and push local commits, so that the local config and
+/// the remote agree. Safe to run repeatedly, it does nothing if everything is already in sync.
+/// Pulled changes are deployed unless `no_deploy` is set, and with `no_push` nothing is pushed.
+async fn sync_once(no_push: bool, no_deploy: bool, github: &Github) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+
+    let branch = ConfinuumConfig::load()?.confinuum.branch;
+
+    let committed = super::status::commit_local_changes(&repo, None, github).await?;
+    let (ahead, behind) = fetch_ahead_behind(&repo, &branch)?;
+    let ahead = if behind > 0 {
+        super::update::pull(!no_push, !no_deploy, github).await?;
+        // A merge commit is pushed along with the merge (unless --no-push), so count what's left
+        fetch_ahead_behind(&repo, &branch)?.0
+    } else {
+        ahead
+    };
+    if ahead > 0 && !no_push {
+        super::push()?;
+    }
+
+    let mut done = Vec::new();
+    if committed > 0 {
+        done.push(format!(
+            "committed {} file{}",
+            committed,
+            if committed == 1 { "" } else { "s" }
+        ));
+    }
+    if behind > 0 {
+        done.push(format!(
+            "pulled {} commit{}",
+            behind,
+            if behind == 1 { "" } else { "s" }
+        ));
+    }
+    if behind > 0 && !no_deploy {
+        done.push("redeployed entries".to_owned());
+    }
+    if ahead > 0 {
+        let commits = format!("{} commit{}", ahead, if ahead == 1 { "" } else { "s" });
+        done.push(match no_push {
+            true => format!("{} not pushed", commits),
+            false => format!("pushed {}", commits),
+        });
+    }
+    if done.is_empty() {
+        info!("{} Already in sync", "✔".green());
+    } else {
+        info!("{} Synced: {}", "✔".green(), done.join(", "));
+    }
+    if no_push && ahead > 0 {
+        info!("Run `confinuum push` to push the local commits to the remote.");
+    } else if no_deploy && behind > 0 {
+        info!("Run `confinuum redeploy` to deploy the pulled changes.");
+    }
+    Ok(())
+}
+
+/// Sync the config with the remote, optionally repeating every `interval` seconds
+/// When repeating, a round that fails doesn't stop the loop, only being interrupted does.
+pub async fn sync(
+    interval: Option<u64>,
+    no_push: bool,
+    no_deploy: bool,
+    github: &Github,
+) -> Result<()> {
+    let Some(interval) = interval else {
+        return sync_once(no_push, no_deploy, github).await;
+    };
+    loop {
+        let result = async {
+            let _lock = lock::acquire()?;
+            sync_once(no_push, no_deploy, github).await
+        }
+        .await;
+        match result {
+            Err(e) if matches!(e.downcast_ref(), Some(ConfinuumError::Interrupted)) => {
+                return Err(e)
+            }
+            // A failed round (e.g. while offline) is reported, and the next one tries again
+            Err(e) => eprintln!("{} Could not sync: {:?}", "!".yellow(), e),
+            Ok(()) => {}
+        }
+        // Each round gets its own line, rather than one when the loop ends
+        cli::print_summary();
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
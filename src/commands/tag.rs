@@ -0,0 +1,72 @@
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+
+use crate::{cli::info, config::ConfinuumConfig, git, github::Github};
+
+/// Add tags to an entry, and commit the change to config.toml
+pub async fn add_tags(name: String, tags: Vec<String>, github: &Github) -> Result<()> {
+    edit_tags(name, tags, true, github).await
+}
+
+/// Remove tags from an entry, and commit the change to config.toml
+pub async fn remove_tags(name: String, tags: Vec<String>, github: &Github) -> Result<()> {
+    edit_tags(name, tags, false, github).await
+}
+
+async fn edit_tags(name: String, tags: Vec<String>, add: bool, github: &Github) -> Result<()> {
+    let tags = tags
+        .iter()
+        .map(|tag| tag.trim().to_owned())
+        .collect::<Vec<_>>();
+    if let Some(tag) = tags
+        .iter()
+        .find(|tag| tag.is_empty() || tag.contains(char::is_whitespace))
+    {
+        return Err(anyhow!(
+            "Invalid tag {:?}: tags can't be empty or contain spaces",
+            tag
+        ));
+    }
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let mut config = ConfinuumConfig::load()?;
+    let entry = config.entry_mut(&name)?;
+
+    let mut changed = Vec::new();
+    for tag in tags {
+        if add && !entry.tags.contains(&tag) {
+            entry.tags.push(tag.clone());
+            changed.push(tag);
+        } else if !add && entry.tags.contains(&tag) {
+            entry.tags.retain(|t| t != &tag);
+            changed.push(tag);
+        } else if !add {
+            info!(
+                "{} {} isn't tagged {}",
+                "!".yellow(),
+                name,
+                tag.as_str().bold()
+            );
+        }
+    }
+    if changed.is_empty() {
+        info!("The tags of {} are unchanged", name);
+        return Ok(());
+    }
+    entry.tags.sort();
+    let message = if add {
+        format!("Tagged `{}` with {}", name, changed.join(", "))
+    } else {
+        format!("Removed tags {} from `{}`", changed.join(", "), name)
+    };
+    git::commit_config(&repo, &config, &message, github).await?;
+
+    info!(
+        "{} {}. Run `confinuum push` to push the change to the remote.",
+        "✔".green(),
+        message.replace('`', "")
+    );
+    Ok(())
+}
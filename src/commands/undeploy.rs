@@ -0,0 +1,36 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use crossterm::style::Stylize;
+
+use crate::cli::info;
+
+/// Remove confinuum's links for an entry, or for all entries, leaving the config itself as it is
+/// With `restore`, the links are replaced with copies of the files instead, so the configs keep
+/// working without confinuum. Running it again only reports that there's nothing left to do.
+pub fn undeploy_entries(name: Option<String>, restore: bool) -> Result<()> {
+    let names = name.map(|name| HashSet::from([name]));
+    let (mut changed, action) = if restore {
+        (super::restore_files(names.as_ref())?, "Restored")
+    } else {
+        (super::undeploy(names.as_ref())?, "Removed")
+    };
+    changed.sort();
+    for path in &changed {
+        info!("{} {}", action, path.display());
+    }
+    if changed.is_empty() && restore {
+        info!("Nothing to restore, every file is already in place");
+    } else if changed.is_empty() {
+        info!("Nothing to undeploy, no files are deployed");
+    } else {
+        info!(
+            "{} {} {} file{}",
+            "✔".green(),
+            action,
+            changed.len(),
+            if changed.len() == 1 { "" } else { "s" }
+        );
+    }
+    Ok(())
+}
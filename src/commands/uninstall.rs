@@ -0,0 +1,154 @@
+use std::{fs, io::IsTerminal, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use dialoguer::theme::ColorfulTheme;
+use git2::Repository;
+
+use crate::{
+    cli::info, config::ConfinuumConfig, git, gitea::Gitea, github::Github, gitlab::Gitlab,
+};
+
+/// The credential files kept in the config directory, alongside the repo
+const AUTH_FILES: [&str; 3] = ["hosts.toml", "gitlab.toml", "gitea.toml"];
+
+/// Stop using confinuum on this machine
+/// Every deployed link is replaced with a copy of its file before anything is deleted, so the
+/// configs keep working. Then the stored credentials and the local clone of the config are
+/// removed, unless `keep_auth` or `keep_repo` are set. The remote is never touched.
+pub fn uninstall(keep_repo: bool, keep_auth: bool, no_confirm: bool) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+    let config = ConfinuumConfig::load()?;
+
+    println!("This will:");
+    println!(
+        "  - replace the links of {} entr{} with copies of the files",
+        config.entries.len(),
+        if config.entries.len() == 1 {
+            "y"
+        } else {
+            "ies"
+        }
+    );
+    if !keep_auth {
+        println!("  - log out of GitHub, GitLab and Gitea");
+    }
+    if !keep_repo {
+        println!("  - delete {}", config_dir.display().to_string().bold());
+        for warning in unsaved_work(&repo, &config)? {
+            println!("    {} {}", "!".yellow(), warning);
+        }
+    }
+    println!("The remote repository is left as it is.");
+    if !no_confirm {
+        if !std::io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "Refusing to uninstall without confirmation, pass --yes"
+            ));
+        }
+        let confirmed = dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Uninstall confinuum from this machine?")
+            .default(false)
+            .interact()?;
+        if !confirmed {
+            println!("Cancelled, nothing was changed.");
+            return Ok(());
+        }
+    }
+
+    // Restoring has to succeed before the repo the links point into is deleted
+    let restored =
+        super::restore_files(None).context("Failed to restore files, nothing was deleted")?;
+    for path in &restored {
+        info!("Restored {}", path.display());
+    }
+    if !keep_auth {
+        let github = Github::logout()?;
+        let gitlab = Gitlab::logout()?;
+        let gitea = Gitea::logout(None)?;
+        if github || gitlab || gitea {
+            info!("Removed stored credentials");
+        }
+    }
+    if !keep_repo {
+        drop(repo);
+        remove_config_dir(&config_dir, keep_auth)?;
+        if keep_auth {
+            info!(
+                "Deleted the config in {}, except for the credentials",
+                config_dir.display()
+            );
+        } else {
+            info!("Deleted {}", config_dir.display());
+        }
+    }
+
+    info!(
+        "{} Uninstalled confinuum, {} file{} restored",
+        "✔".green(),
+        restored.len(),
+        if restored.len() == 1 {
+            " was"
+        } else {
+            "s were"
+        }
+    );
+    Ok(())
+}
+
+/// Describe the work in the local clone that deleting it would lose: uncommitted changes, and
+/// commits that haven't been pushed
+fn unsaved_work(repo: &Repository, config: &ConfinuumConfig) -> Result<Vec<String>> {
+    let mut warnings = Vec::new();
+    let changes = super::status::local_changes(repo)?;
+    if !changes.is_empty() {
+        warnings.push(format!(
+            "{} uncommitted change{} will be lost",
+            changes.len(),
+            if changes.len() == 1 { "" } else { "s" }
+        ));
+    }
+    match git::unpushed_commits(repo, &config.confinuum.branch)? {
+        Some(0) => {}
+        Some(ahead) => warnings.push(format!(
+            "{} commit{} that {} pushed will be lost",
+            ahead,
+            if ahead == 1 { "" } else { "s" },
+            if ahead == 1 {
+                "hasn't been"
+            } else {
+                "haven't been"
+            }
+        )),
+        None => warnings.push("the config has never been pushed, it will be lost".to_owned()),
+    }
+    Ok(warnings)
+}
+
+/// Delete the config directory, or everything in it but the credentials with `keep_auth`
+fn remove_config_dir(config_dir: &Path, keep_auth: bool) -> Result<()> {
+    if !keep_auth {
+        return fs::remove_dir_all(config_dir)
+            .with_context(|| format!("Could not delete {}", config_dir.display()));
+    }
+    for item in fs::read_dir(config_dir)
+        .with_context(|| format!("Could not read {}", config_dir.display()))?
+    {
+        let path = item?.path();
+        if path
+            .file_name()
+            .is_some_and(|name| AUTH_FILES.iter().any(|auth| name == *auth))
+        {
+            continue;
+        }
+        if path.is_dir() && !path.is_symlink() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .with_context(|| format!("Could not delete {}", path.display()))?;
+    }
+    Ok(())
+}
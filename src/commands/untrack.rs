@@ -0,0 +1,158 @@
+use std::{collections::HashSet, fs, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{ConfinuumConfig, LinkMode},
+    error::ConfinuumError,
+    git::{self, RepoExtensions},
+    github::Github,
+    interrupt,
+    rollback::Rollback,
+};
+
+/// Stop managing files without touching what's on disk at their targets
+/// Unlike `remove`, nothing is restored: each deployed link is replaced with a copy of the file it
+/// points to, so the file stays exactly as it currently is.
+pub async fn untrack(
+    name: String,
+    files: Vec<PathBuf>,
+    push: bool,
+    offline: bool,
+    github: &Github,
+) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir().context("Cannot get config dir")?;
+    let mut config = ConfinuumConfig::load().context("Cannot load config file")?;
+    let branch = config.confinuum.branch.clone();
+    let entry = config.entry_mut(&name)?;
+    let entry_dir = config_dir.join(&name);
+    if entry.link_mode == LinkMode::Directory {
+        return Err(anyhow!(
+            "{} links its whole directory, so its files can't stay in place without the repo. Run `confinuum entry {} set-link-mode files` first.",
+            name,
+            name
+        ));
+    }
+
+    // Deployed files are links into the repo, so canonicalizing gives the path within the entry
+    let mut untracked = HashSet::new();
+    for file in &files {
+        let canonical = file
+            .canonicalize()
+            .with_context(|| format!("File {} does not exist", file.display()))?;
+        let relative = canonical
+            .strip_prefix(&entry_dir)
+            .ok()
+            .filter(|relative| entry.files.contains(*relative))
+            .ok_or_else(|| {
+                anyhow!(
+                    "File {} does not exist in entry {}",
+                    file.display().to_string().red().bold(),
+                    name.clone().yellow().bold()
+                )
+            })?;
+        untracked.insert(relative.to_path_buf());
+    }
+
+    let repo = Repository::open(&config_dir)?;
+    let offline = git::check_remote(&repo, &branch, "untracking files", offline)?;
+    let sig = git::commit_signature(&config, github).await?;
+    let entry = config.entry_mut(&name)?;
+    let target_dir = entry
+        .deploy_dir()
+        .ok_or_else(|| ConfinuumError::NoTargetDir(name.clone()))?
+        .to_path_buf();
+
+    let spinner = Spinner::new_shared(spinners::Dots9, "Untracking files", Color::Blue);
+    {
+        // Links replaced with copies so far are linked again if this fails before committing
+        let mut rollback = Rollback::new(&[&name])?;
+        rollback.redeploy(&name);
+        for file in &untracked {
+            spinner.update_text(format!("Untracking {}", file.display()));
+            let source_path = entry_dir.join(file);
+            let target_path = target_dir.join(file);
+            if target_path.read_link().ok().as_ref() == Some(&source_path) {
+                // Copying would write through the link, so it goes first
+                fs::remove_file(&target_path)
+                    .with_context(|| format!("Cannot remove link {}", target_path.display()))?;
+                fs::copy(&source_path, &target_path).with_context(|| {
+                    format!(
+                        "Could not copy {} to {}",
+                        source_path.display(),
+                        target_path.display()
+                    )
+                })?;
+            }
+        }
+        interrupt::check()?;
+        // Files are deleted from the repo from here on, which redeploying can't undo, so Ctrl-C
+        // waits for the command to finish instead
+        let _deferred = interrupt::Deferred::new();
+        rollback.keep();
+
+        for file in &untracked {
+            let source_path = entry_dir.join(file);
+            fs::remove_file(&source_path)
+                .with_context(|| format!("Cannot remove {}", source_path.display()))?;
+            entry.files.remove(file);
+        }
+        if entry.files.is_empty() {
+            // A single-file entry without its file is uninitialized, so any files can be added
+            entry.target_file = None;
+        }
+
+        spinner.update_text("Saving config file");
+        config.save()?;
+
+        spinner.update_text("Committing changes");
+        let staged = git::stage_paths(
+            &repo,
+            &[&name],
+            untracked.iter().map(|file| PathBuf::from(&name).join(file)),
+        )?;
+        let parent_commit = repo
+            .find_last_commit()
+            .context("Failed to retrieve last commit")?;
+        let tree = repo
+            .find_tree(staged.oid)
+            .context("Failed to find new commit tree")?;
+        let mut names = untracked
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        let message = staged.message(format!(
+            "Untracked {} files from `{}`\n\nUntracked files:\n{}",
+            untracked.len(),
+            name,
+            names.join("\n")
+        ));
+        repo.commit_with_config(&sig, &message, &tree, &[&parent_commit])
+            .context("Failed to commit files")?;
+
+        if push && !offline {
+            spinner.update_text("Pushing changes to remote");
+            let mut pushopt = git2::PushOptions::new();
+            pushopt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+            let mut remote = git::find_origin(&repo)?;
+            remote
+                .push(&[git::branch_refspec(&branch)], Some(&mut pushopt))
+                .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
+        }
+    }
+    spinner.success(&format!(
+        "Stopped tracking {} files in {}, they were left in place",
+        untracked.len(),
+        &name
+    ));
+    if push && offline {
+        info!("Skipping push while offline, run `confinuum push` once the remote is reachable.");
+    }
+
+    Ok(())
+}
@@ -1,65 +1,83 @@
 use crate::{
-    cli::{CreateSharedSpinner, SharedSpinner},
-    config::ConfinuumConfig,
-    git,
+    cli::{info, CreateSharedSpinner, SharedSpinner},
+    config::{CommitKind, ConfinuumConfig},
+    error::ConfinuumError,
+    git::{self, RepoExtensions},
+    github::Github,
 };
 use anyhow::{anyhow, Context, Result};
 use crossterm::style::Stylize;
-use git2::{DiffOptions, Direction, FetchOptions, Repository};
+use git2::{DiffOptions, Repository};
 use spinoff::{spinners, Spinner};
+use std::collections::BTreeSet;
 
-pub fn update() -> Result<()> {
-    // TODO: Check for local unstaged changes
-    super::undeploy(None::<&str>)?;
+pub async fn update(offline: bool, github: &Github) -> Result<()> {
+    if offline {
+        let repo = Repository::open(ConfinuumConfig::get_dir()?)
+            .context("Failed to open config directory as a git repo")?;
+        return super::check::local_status(&repo, None, None);
+    }
+    pull(true, true, github).await
+}
 
+/// Fetch and fast-forward or merge the remote's changes
+/// A merge commit is only pushed with `push`, and entries are only redeployed with `deploy`.
+/// Without `deploy` the links stay as they are, so new files aren't linked until the next deploy.
+/// Entries stay deployed while the changes are applied, so a failed fetch or merge leaves every
+/// link in place.
+pub(super) async fn pull(push: bool, deploy: bool, github: &Github) -> Result<()> {
+    // TODO: Check for local unstaged changes
     let config_dir = ConfinuumConfig::get_dir()?;
     if !config_dir.exists() {
         return Err(anyhow!("Config directory does not exist"));
     }
     let repo =
-        Repository::open(config_dir).context("Failed to open config directory as a git repo")?;
-    let mut remote = repo
-        .find_remote("origin")
-        .context("Failed to find remote named 'origin'")?;
+        Repository::open(&config_dir).context("Failed to open config directory as a git repo")?;
+    let config = ConfinuumConfig::load()?;
+    let branch = &config.confinuum.branch;
+    if config.confinuum.shallow {
+        info!(
+            "{} The config repo is a shallow clone, merging remote changes may fail if its history is too short. If it does, run `git fetch --unshallow` in {}",
+            "!".yellow(),
+            config_dir.display()
+        );
+    }
+    let mut remote = git::find_origin(&repo)?;
     let spinner = Spinner::new_shared(
         spinners::Dots9,
         "Connecting to remote 'origin'",
         spinoff::Color::Blue,
     );
 
-    let (analysis, diff_files, fetch_commit, head_commit) = {
-        remote.connect_auth(
-            Direction::Fetch,
-            Some(git::construct_callbacks(spinner.clone())),
-            None,
-        )?;
-        let mut fetch_opt = FetchOptions::new();
-        fetch_opt.update_fetchhead(true);
-
-        fetch_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-
-        remote
-            .fetch(&["main"], Some(&mut fetch_opt), None)
-            .context("Failed to fetch from remote 'origin'")?;
-
-        let fetch_head = repo.find_reference("FETCH_HEAD")?;
-        let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
-        //let head_commit = repo.reference_to_annotated_commit(&head)?;
+    let (analysis, diff_files, fetch_commit, head_commit, remote_config, old_config) = {
+        let fetch_commit = git::fetch_origin(&repo, &spinner, branch)?;
         let analysis = repo.merge_analysis(&[&fetch_commit])?;
 
         let head = repo.head()?;
         let head_commit = repo.reference_to_annotated_commit(&head)?;
         let head_tree = head.peel_to_tree()?;
-        let fetch_tree = fetch_head.peel_to_tree()?;
+        let fetch_tree = repo.find_commit(fetch_commit.id())?.tree()?;
         let mut diff_opt = DiffOptions::default();
-        let diff =
+        let mut diff =
             repo.diff_tree_to_tree(Some(&head_tree), Some(&fetch_tree), Some(&mut diff_opt))?;
-        let diff_files = git::diff_files(&diff)?;
-
-        (analysis, diff_files, fetch_commit, head_commit)
+        let diff_files = git::diff_files(&mut diff)?;
+
+        let remote_config = git::config_at_tree(&repo, &fetch_tree).ok();
+        // Read the pre-update config from git, since the file on disk will be overwritten
+        let old_config = git::config_at_tree(&repo, &head_tree)
+            .context("Could not read config.toml from the current commit")?;
+
+        (
+            analysis,
+            diff_files,
+            fetch_commit,
+            head_commit,
+            remote_config,
+            old_config,
+        )
     };
 
-    let (diff_entries, config_updated) = git::diff_entries(&diff_files)?;
+    let (diff_entries, config_updated, unowned) = git::diff_entries(&diff_files)?;
 
     if analysis.0.is_up_to_date() {
         spinner.success("Already up to date");
@@ -69,68 +87,78 @@ pub fn update() -> Result<()> {
         spinner.success("Already up to date");
     } else if analysis.0.is_fast_forward() {
         spinner.update_text("Applying changes");
-        let refname = "refs/heads/main";
-        let mut reference = repo.find_reference(refname)?;
+        let refname = git::branch_ref(branch);
+        let mut reference = repo.find_reference(&refname)?;
         reference.set_target(fetch_commit.id(), "Fast-Forward")?;
-        repo.set_head(refname)?;
+        repo.set_head(&refname)?;
         repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
         spinner.success("Changes pulled succesfully");
     } else if analysis.0.is_normal() {
         spinner.update_text("Merging changes");
         let local_tree = repo.find_commit(head_commit.id())?.tree()?;
         let remote_tree = repo.find_commit(fetch_commit.id())?.tree()?;
-        let ancestor = repo
-            .find_commit(repo.merge_base(head_commit.id(), fetch_commit.id())?)?
-            .tree()?;
+        let merge_base = repo
+            .merge_base(head_commit.id(), fetch_commit.id())
+            .context("Could not find a common ancestor of the local and remote changes")?;
+        let ancestor = repo.find_commit(merge_base)?.tree()?;
         let mut idx = repo.merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
 
         if idx.has_conflicts() {
             repo.checkout_index(Some(&mut idx), None)?;
             spinner.fail("Merge conflicts detected, aborting");
-            return Ok(());
+            return Err(ConfinuumError::MergeConflict.into());
         }
         let result_tree = repo.find_tree(idx.write_tree_to(&repo)?)?;
         // now create the merge commit
-        let msg = format!(
-            "Merge {} into {}\n\nFiles changed:\n{}",
-            fetch_commit.id(),
-            head_commit.id(),
-            {
-                let mut s = String::new();
-                if config_updated {
-                    s.push_str("config.toml\n");
-                }
-                for (entry, changed_files) in diff_entries {
-                    s.push_str(&format!("{}:\n", entry.bold().yellow()));
-                    for file in changed_files {
-                        s.push_str(&format!("    {}\n", file.display()));
-                    }
+        let mut entries = diff_entries.keys().cloned().collect::<Vec<_>>();
+        entries.sort();
+        let files = diff_entries
+            .values()
+            .flatten()
+            .map(|file| file.to_string())
+            .chain(config_updated.then(|| "config.toml".to_owned()))
+            .chain(unowned.iter().map(|file| file.display().to_string()))
+            .collect::<Vec<_>>();
+        let subject = old_config.confinuum.commit_message.subject(
+            CommitKind::Merge,
+            &entries.join(", "),
+            &files,
+            format!("Merge {} into {}", fetch_commit.id(), head_commit.id()),
+        );
+        let msg = format!("{}\n\nFiles changed:\n{}", subject, {
+            let mut s = String::new();
+            if config_updated {
+                s.push_str("config.toml\n");
+            }
+            for (entry, changed_files) in &diff_entries {
+                s.push_str(&format!("{}:\n", entry.clone().bold().yellow()));
+                for file in changed_files {
+                    s.push_str(&format!("    {}\n", file));
                 }
-                s
             }
-        );
-        let sig = repo.signature()?;
+            for file in &unowned {
+                s.push_str(&format!("{}\n", file.display()));
+            }
+            s
+        });
+        let sig = git::commit_signature(&config, github).await?;
         let local_commit = repo.find_commit(head_commit.id())?;
         let remote_commit = repo.find_commit(fetch_commit.id())?;
 
-        let _merge_commit = repo.commit(
-            Some("HEAD"),
-            &sig,
-            &sig,
-            &msg,
-            &result_tree,
-            &[&local_commit, &remote_commit],
-        )?;
+        let _merge_commit =
+            repo.commit_with_config(&sig, &msg, &result_tree, &[&local_commit, &remote_commit])?;
 
         repo.checkout_head(None)?;
 
-        spinner.update_text("Pushing merged changes");
+        if push {
+            spinner.update_text("Pushing merged changes");
 
-        let mut push_opt = git2::PushOptions::default();
-        push_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
-        remote
-            .push(&["refs/heads/main:refs/heads/main"], Some(&mut push_opt))
-            .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
+            let mut push_opt = git2::PushOptions::default();
+            push_opt.remote_callbacks(git::construct_callbacks(spinner.clone()));
+            remote
+                .push(&[git::branch_refspec(branch)], Some(&mut push_opt))
+                .with_context(|| format!("Failed to push files to {}", remote.url().unwrap()))?;
+        }
 
         spinner.success("Changes merged succesfully");
     } else {
@@ -138,7 +166,59 @@ pub fn update() -> Result<()> {
         return Ok(());
     }
 
-    super::deploy(None::<&str>)?;
+    // Remove symlinks to files that were deleted on the remote, so they aren't left dangling
+    // Files are considered deleted if they vanished from their entry in config.toml, or if the
+    // remote diff deleted or renamed them
+    let new_config = ConfinuumConfig::load()?;
+    let mut removed_links = Vec::new();
+    for (entry_name, entry) in &old_config.entries {
+        // Deploying only creates the links the new config describes, so the old links of an entry
+        // that moved, or that isn't deployed on this machine anymore, are removed here
+        let undeployed = new_config.entries.get(entry_name).is_none_or(|new_entry| {
+            new_entry.disabled
+                || new_entry.mismatch().is_some()
+                || new_entry.deploy_dir() != entry.deploy_dir()
+        });
+        if deploy && undeployed {
+            removed_links.extend(super::remove_entry_links(entry)?);
+            continue;
+        }
+        let mut removed_files = match new_config.entries.get(entry_name) {
+            Some(new_entry) => entry
+                .files
+                .difference(&new_entry.files)
+                .cloned()
+                .collect::<BTreeSet<_>>(),
+            None => entry.files.clone(),
+        };
+        if let Some(changed_files) = diff_entries.get(entry_name) {
+            removed_files.extend(
+                changed_files
+                    .iter()
+                    .flat_map(|delta| delta.removed_paths())
+                    .filter_map(|path| path.strip_prefix(entry_name).ok())
+                    .map(|path| path.to_path_buf()),
+            );
+        }
+        let removed_files = removed_files.into_iter().collect::<Vec<_>>();
+        removed_links.extend(super::remove_stale_links(entry, &removed_files)?);
+    }
+    if !removed_links.is_empty() {
+        info!(
+            "Removed symlinks left stale by the remote changes:\n{}",
+            removed_links
+                .iter()
+                .map(|link| format!("    {}", link.display()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    git::print_unowned(&unowned, remote_config.as_ref());
+
+    if deploy {
+        super::deploy(None)?;
+    }
 
     Ok(())
 }
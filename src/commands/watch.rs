@@ -0,0 +1,137 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::Repository;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{cli::info, config::ConfinuumConfig, github::Github, lock};
+
+/// Watch the deployed files and commit changes to them once they settle for `debounce` seconds,
+/// pushing them if `push` is set (and not offline). Runs until interrupted.
+/// Deployed files are symlinks into the config repo, so edits made through them show up in the
+/// entries' directories there, which is what's watched. Changes to config.toml (e.g. adding an
+/// entry from another shell) update the watched directories.
+pub async fn watch(debounce: u64, push: bool, offline: bool, github: &Github) -> Result<()> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let repo = Repository::open(&config_dir)
+        .with_context(|| format!("Could not open repository in {}", config_dir.display()))?;
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // The receiver only goes away when watching stops
+        tx.send(event).ok();
+    })
+    .context("Could not start watching files")?;
+    // The repo root itself is watched for config.toml and new entry directories
+    watcher
+        .watch(&config_dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Could not watch {}", config_dir.display()))?;
+    let mut watched = watch_entries(&mut watcher, &config_dir, &HashSet::new())?;
+    info!(
+        "Watching {} entries for changes, press Ctrl-C to stop",
+        watched.len()
+    );
+
+    while let Some(event) = rx.recv().await {
+        let mut paths = relevant_paths(&repo, &config_dir, event);
+        // Wait for the changes to settle, e.g. an editor writing a file in several steps
+        while let Ok(Some(event)) =
+            tokio::time::timeout(Duration::from_secs(debounce), rx.recv()).await
+        {
+            paths.extend(relevant_paths(&repo, &config_dir, event));
+        }
+        if paths.is_empty() {
+            continue;
+        }
+
+        if paths.iter().any(|path| path.parent() == Some(&config_dir)) {
+            watched = watch_entries(&mut watcher, &config_dir, &watched)?;
+        }
+        // Taken for each commit rather than while watching, so other commands can run in between
+        let _lock = lock::acquire()?;
+        // A failed commit or push is reported, but shouldn't stop the watch
+        match super::status::commit_local_changes(&repo, None, github).await {
+            Ok(0) => continue,
+            Ok(committed) => info!(
+                "{} Committed {} changed file{}",
+                "✔".green(),
+                committed,
+                if committed == 1 { "" } else { "s" }
+            ),
+            Err(e) => {
+                eprintln!("{} Could not commit the changes: {:?}", "!".yellow(), e);
+                continue;
+            }
+        }
+        if push && !offline {
+            if let Err(e) = super::push() {
+                eprintln!("{} Could not push the changes: {:?}", "!".yellow(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Watch the directories of the config's entries, and stop watching those of removed entries
+/// Returns the directories now being watched.
+fn watch_entries(
+    watcher: &mut RecommendedWatcher,
+    config_dir: &Path,
+    watched: &HashSet<PathBuf>,
+) -> Result<HashSet<PathBuf>> {
+    // A config that's being edited may not parse yet, so keep watching what was watched
+    let Ok(config) = ConfinuumConfig::load() else {
+        return Ok(watched.clone());
+    };
+    let dirs = config
+        .entries
+        .keys()
+        .map(|name| config_dir.join(name))
+        .filter(|dir| dir.is_dir())
+        .collect::<HashSet<_>>();
+    for dir in watched.difference(&dirs) {
+        // Fails if the directory was deleted, which already removed the watch
+        watcher.unwatch(dir).ok();
+    }
+    for dir in dirs.difference(watched) {
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .with_context(|| format!("Could not watch {}", dir.display()))?;
+    }
+    Ok(dirs)
+}
+
+/// The paths of an event that could mean a change to commit or to the watched directories
+/// Reads and changes inside .git (e.g. from committing) are left out, and so are files the repo
+/// ignores, like the lock taken before committing.
+fn relevant_paths(
+    repo: &Repository,
+    config_dir: &Path,
+    event: notify::Result<notify::Event>,
+) -> Vec<PathBuf> {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            eprintln!("{} Error while watching files: {}", "!".yellow(), e);
+            return Vec::new();
+        }
+    };
+    if matches!(event.kind, EventKind::Access(_)) {
+        return Vec::new();
+    }
+    event
+        .paths
+        .into_iter()
+        .filter(|path| !path.starts_with(config_dir.join(".git")))
+        .filter(|path| {
+            path.strip_prefix(config_dir)
+                .map_or(true, |path| !repo.is_path_ignored(path).unwrap_or(false))
+        })
+        .collect()
+}
@@ -0,0 +1,114 @@
+//! Shell completions, with entry names completed from the config
+//! clap only knows the static parts of the CLI, so the generated scripts are extended to call the
+//! hidden `confinuum util list-entries` wherever an entry name is expected.
+
+use std::io::Write;
+
+use anyhow::Result;
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+use crate::cli::Cli;
+
+/// Write the completion script for `shell`
+pub fn generate(shell: Shell, out: &mut impl Write) -> Result<()> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), "confinuum", &mut script);
+    let script = String::from_utf8(script)?;
+    let commands = entry_commands();
+    let script = match shell {
+        Shell::Bash => format!(
+            "{}\n{}",
+            script,
+            BASH.replace("{commands}", &commands.join("|"))
+        ),
+        Shell::Zsh => zsh(&script),
+        Shell::Fish => format!(
+            "{}\n{}",
+            script,
+            FISH.replace("{commands}", &commands.join(" "))
+        ),
+        // Left with the static completions
+        _ => script,
+    };
+    out.write_all(script.as_bytes())?;
+    Ok(())
+}
+
+/// The subcommands (and their aliases) whose first positional argument is an entry name
+fn entry_commands() -> Vec<String> {
+    Cli::command()
+        .get_subcommands()
+        .filter(|command| {
+            command
+                .get_positionals()
+                .next()
+                .is_some_and(|arg| arg.get_id() == "name")
+        })
+        .flat_map(|command| {
+            std::iter::once(command.get_name())
+                .chain(command.get_all_aliases())
+                .map(|name| name.replace('?', "\\?"))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Replace the default completion of entry name arguments with the entry names
+fn zsh(script: &str) -> String {
+    let mut lines = script
+        .lines()
+        .map(|line| {
+            if line.starts_with("':name -- ") || line.starts_with("'::name -- ") {
+                line.replace(":_default'", ":_confinuum_entry_names'")
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>();
+    // The helper has to be defined before the script runs `_confinuum` at the end
+    let call = lines
+        .iter()
+        .position(|line| line.starts_with("if [ \"$funcstack[1]\" = \"_confinuum\" ]"))
+        .unwrap_or(lines.len());
+    lines.insert(call, ZSH.to_owned());
+    lines.join("\n") + "\n"
+}
+
+const BASH: &str = r#"_confinuum_entry_names() {
+    local i words=()
+    for i in "${COMP_WORDS[@]:1:COMP_CWORD-1}"; do
+        [[ $i != -* ]] && words+=("$i")
+    done
+    if [[ ${#words[@]} -eq 1 && ${COMP_WORDS[COMP_CWORD]} != -* ]]; then
+        case "${words[0]}" in
+            {commands})
+                COMPREPLY=( $(compgen -W "$(confinuum util list-entries 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}") )
+                return 0
+                ;;
+        esac
+    fi
+    _confinuum "$@"
+}
+
+if [[ "${BASH_VERSINFO[0]}" -eq 4 && "${BASH_VERSINFO[1]}" -ge 4 || "${BASH_VERSINFO[0]}" -gt 4 ]]; then
+    complete -F _confinuum_entry_names -o nosort -o bashdefault -o default confinuum
+else
+    complete -F _confinuum_entry_names -o bashdefault -o default confinuum
+fi
+"#;
+
+const ZSH: &str = r#"(( $+functions[_confinuum_entry_names] )) ||
+_confinuum_entry_names() {
+    local -a entries
+    entries=(${(f)"$(confinuum util list-entries 2>/dev/null)"})
+    _wanted entries expl 'config entry' compadd -a entries
+}
+"#;
+
+const FISH: &str = r#"function __fish_confinuum_needs_entry
+    set -l words (commandline -opc | string match -v -- '-*')
+    test (count $words) -eq 2; and contains -- $words[2] {commands}
+end
+complete -c confinuum -n __fish_confinuum_needs_entry -f -a "(confinuum util list-entries 2>/dev/null)"
+"#;
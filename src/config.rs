@@ -1,32 +1,197 @@
 //! Configuration file handling for confinuum
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     env::var,
-    path::PathBuf,
+    fs::OpenOptions,
+    io::{IsTerminal, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
 };
 
 use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
 use common_path::common_path_all;
-use serde::{Deserialize, Serialize};
+use crossterm::style::Stylize;
+use dialoguer::theme::ColorfulTheme;
+use serde::{Deserialize, Serialize, Serializer};
+use toml_edit::{Document, Item, Table, Value};
+
+use crate::{
+    cli::{info, par_progress},
+    deployment,
+    error::ConfinuumError,
+    interrupt, migrations,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Confinuum {
+    /// The version of the config format, see the migrations module
+    #[serde(default)]
+    pub version: u32,
     pub git_protocol: GitProtocol,
     /// Where to look for the user's name and email to be used in git commits
     /// If this is set to github, the user's name and email will be fetched from their github account
+    /// (and likewise for gitlab and gitea, using the instance in `gitlab_url` or `gitea_url`)
     /// If this is set to config, the user's name and email will be fetched from the config file
     pub signature_source: SignatureSource,
+    /// The branch of the remote that configs are synced with
+    #[serde(default = "default_branch")]
+    pub branch: String,
+    /// Whether the config repo was cloned with a limited history depth (`init --git --depth`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shallow: bool,
+    /// Don't fall back to the token from the `gh` CLI's login when not logged in with confinuum
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ignore_gh_cli: bool,
+    /// Don't check once a day whether a newer confinuum has been released (also disabled by
+    /// setting `CONFINUUM_NO_UPDATE_CHECK`, e.g. where a package manager handles updates)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub no_update_check: bool,
+    /// The service hosting the remote repo
+    #[serde(default, skip_serializing_if = "Hosting::is_github")]
+    pub hosting: Hosting,
+    /// The URL of a self-hosted GitLab instance, if the config is hosted on one instead of gitlab.com
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitlab_url: Option<String>,
+    /// The URL of the Gitea or Forgejo instance, if the config is hosted on one other than Codeberg
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gitea_url: Option<String>,
+    /// Client id of the GitHub OAuth app to log in with, instead of confinuum's own
+    /// `CONFINUUM_GITHUB_CLIENT_ID` takes precedence over this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github_client_id: Option<String>,
+    /// Scopes to request when logging in to GitHub (`public_repo` is enough if the config repo is
+    /// public). `CONFINUUM_GITHUB_SCOPES` takes precedence over this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub github_scopes: Option<Vec<String>>,
+    /// Templates for the subjects of the commits confinuum makes, see `CommitMessages`
+    #[serde(default, skip_serializing_if = "CommitMessages::is_empty")]
+    pub commit_message: CommitMessages,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Templates for the subjects of generated commit messages, for repos that follow a convention
+/// like conventional commits. `{entry}`, `{count}`, `{files}` and `{hostname}` are replaced, and
+/// the list of files is still added to the body. Operations without a template keep the default.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommitMessages {
+    /// Adding files to an entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub add: Option<String>,
+    /// Removing files from an entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remove: Option<String>,
+    /// Creating an entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new: Option<String>,
+    /// Deleting an entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete: Option<String>,
+    /// Merging remote changes, where `{entry}` is the entries that changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge: Option<String>,
+}
+
+/// The operations that have a commit message template
+#[derive(Debug, Clone, Copy)]
+pub enum CommitKind {
+    Add,
+    Remove,
+    New,
+    Delete,
+    Merge,
+}
+
+impl CommitMessages {
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The subject for a commit, from the operation's template or else `default`
+    pub fn subject(
+        &self,
+        kind: CommitKind,
+        entry: &str,
+        files: &[String],
+        default: String,
+    ) -> String {
+        let template = match kind {
+            CommitKind::Add => &self.add,
+            CommitKind::Remove => &self.remove,
+            CommitKind::New => &self.new,
+            CommitKind::Delete => &self.delete,
+            CommitKind::Merge => &self.merge,
+        };
+        let Some(template) = template else {
+            return default;
+        };
+        template
+            .replace("{entry}", entry)
+            .replace("{count}", &files.len().to_string())
+            .replace("{files}", &files.join(", "))
+            .replace("{hostname}", hostname().as_deref().unwrap_or("unknown"))
+    }
+}
+
+fn default_branch() -> String {
+    "main".to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ValueEnum)]
 pub enum SignatureSource {
     #[serde(rename = "github")]
     Github,
+    #[serde(rename = "gitlab")]
+    Gitlab,
+    #[serde(rename = "gitea")]
+    Gitea,
     #[serde(rename = "gitconfig")]
+    #[value(name = "gitconfig")]
     GitConfig,
 }
 
+/// The services that confinuum can create repos on and fetch the user's identity from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+pub enum Hosting {
+    #[default]
+    #[serde(rename = "github")]
+    Github,
+    #[serde(rename = "gitlab")]
+    Gitlab,
+    /// Gitea and Forgejo instances, such as Codeberg
+    #[serde(rename = "gitea")]
+    Gitea,
+}
+
+impl Hosting {
+    fn is_github(&self) -> bool {
+        *self == Hosting::Github
+    }
+
+    /// The command that logs in to the service
+    pub fn login_command(&self) -> &'static str {
+        match self {
+            Hosting::Github => "confinuum auth login",
+            Hosting::Gitlab => "confinuum auth login --gitlab",
+            Hosting::Gitea => "confinuum auth login --gitea",
+        }
+    }
+}
+
+impl std::fmt::Display for Hosting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hosting::Github => write!(f, "GitHub"),
+            Hosting::Gitlab => write!(f, "GitLab"),
+            Hosting::Gitea => write!(f, "Gitea"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ConfigEntry {
     #[serde(skip)]
@@ -35,12 +200,246 @@ pub struct ConfigEntry {
     /// Example: ~/.config/nvim - files from ~/.config/confinuum/nvim will be symlinked to
     /// ~/.config/nvim/<file>
     /// This must be an absolute path
-    /// Optional only for uninitialized config, it will always be set when adding files
+    /// Optional only for uninitialized config and single-file entries (see target_file)
     pub target_dir: Option<PathBuf>,
-    pub files: HashSet<PathBuf>,
+    /// The exact path a single-file entry deploys its file to, set instead of target_dir
+    /// A lone file like ~/.zshrc has no directory of its own, and using its parent as the
+    /// target_dir would make the entry's base the whole home directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_file: Option<PathBuf>,
+    /// Kept sorted, so that the files array in config.toml only changes where files were added or
+    /// removed, and edits from different machines merge cleanly
+    pub files: BTreeSet<PathBuf>,
+    /// A note on what the entry is for, shown by `list` and `show`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Labels for grouping entries, which commands can be limited to with --tag
+    #[serde(default, alias = "tag", skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Disabled entries are kept in the config but never deployed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Only deploy the entry on these operating systems (all of them if empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only_os: Vec<Os>,
+    /// Only deploy the entry on machines with these hostnames (all of them if empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only_hosts: Vec<String>,
+    /// Never deploy the entry on machines with these hostnames
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_hosts: Vec<String>,
+    /// How the entry is deployed: a link per file, or the entry's whole directory as one link
+    #[serde(default, skip_serializing_if = "LinkMode::is_files")]
+    pub link_mode: LinkMode,
+    /// Shell command to run in the target directory before the entry's files are deployed
+    /// Hooks only run if enabled with --run-hooks or `CONFINUUM_RUN_HOOKS`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_deploy: Option<String>,
+    /// Shell command to run in the target directory after the entry's files are deployed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_deploy: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Where `init --force` backs up the config it replaces and deploys move the directories they'd
+/// replace, relative to the config dir. It's never committed.
+pub const BACKUP_DIR: &str = ".backups";
+
+/// Files in the root of the config repo that an entry's directory must not replace
+const RESERVED_NAMES: [&str; 7] = [
+    ".git",
+    ".backups",
+    ".gitignore",
+    "config.toml",
+    "hosts.toml",
+    "gitlab.toml",
+    "gitea.toml",
+];
+
+impl ConfigEntry {
+    /// An uninitialized entry, without files or a target directory
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            target_dir: None,
+            target_file: None,
+            files: BTreeSet::new(),
+            description: None,
+            tags: Vec::new(),
+            disabled: false,
+            only_os: Vec::new(),
+            only_hosts: Vec::new(),
+            exclude_hosts: Vec::new(),
+            link_mode: LinkMode::Files,
+            pre_deploy: None,
+            post_deploy: None,
+        }
+    }
+
+    /// The directory the entry's files are deployed into, None if the entry is uninitialized
+    /// For a single-file entry this is the directory its file is in.
+    pub fn deploy_dir(&self) -> Option<&Path> {
+        match &self.target_file {
+            Some(target_file) => target_file.parent(),
+            None => self.target_dir.as_deref(),
+        }
+    }
+
+    /// Where the entry deploys to, for display: its target_dir, or the file of a single-file entry
+    pub fn target(&self) -> Option<&Path> {
+        self.target_file.as_deref().or(self.target_dir.as_deref())
+    }
+
+    /// Check that a name can be used for a new entry
+    /// The name becomes a directory in the root of the config repo, so it has to be a single path
+    /// component that doesn't clash with the repo's own files.
+    pub fn validate_name(name: &str) -> Result<()> {
+        let invalid = |rule| Err(ConfinuumError::InvalidEntryName(name.to_owned(), rule).into());
+        if name.is_empty() {
+            return invalid("it can't be empty");
+        }
+        if name.contains('/') {
+            return invalid("it can't contain a slash");
+        }
+        // Lists of entry names, like `redeploy --only` and `check --porcelain`, are comma separated
+        if name.contains(',') {
+            return invalid("it can't contain a comma");
+        }
+        if name.chars().any(char::is_control) {
+            return invalid("it can't contain control characters");
+        }
+        if name == "." || name == ".." {
+            return invalid("it can't be . or ..");
+        }
+        if RESERVED_NAMES.contains(&name) {
+            return invalid("it's the name of a file confinuum keeps in the config repo");
+        }
+        // Git gives files like .gitattributes and .gitmodules a meaning in the repo root
+        if name.to_ascii_lowercase().starts_with(".git") {
+            return invalid("names starting with .git are reserved for git");
+        }
+        if name == "confinuum" {
+            return invalid("it's the name of the config's settings table");
+        }
+        Ok(())
+    }
+
+    /// Why the entry shouldn't be deployed on this machine, or None if it should
+    pub fn mismatch(&self) -> Option<&'static str> {
+        let on_os = Os::current().is_some_and(|os| self.only_os.contains(&os));
+        if !self.only_os.is_empty() && !on_os {
+            return Some("platform mismatch");
+        }
+        let host = hostname();
+        let matches_host = |hosts: &[String]| {
+            host.as_deref()
+                .is_some_and(|host| hosts.iter().any(|h| same_host(h, host)))
+        };
+        if !self.only_hosts.is_empty() && !matches_host(&self.only_hosts) {
+            return Some("host mismatch");
+        }
+        if matches_host(&self.exclude_hosts) {
+            return Some("host excluded");
+        }
+        None
+    }
+
+    /// The machines the entry is limited to, e.g. "only on linux, not on work-laptop", or None if
+    /// it deploys everywhere
+    pub fn conditions(&self) -> Option<String> {
+        let mut conditions = Vec::new();
+        if !self.only_os.is_empty() {
+            let os = self.only_os.iter().map(Os::to_string).collect::<Vec<_>>();
+            conditions.push(format!("only on {}", os.join(", ")));
+        }
+        if !self.only_hosts.is_empty() {
+            conditions.push(format!("only on {}", self.only_hosts.join(", ")));
+        }
+        if !self.exclude_hosts.is_empty() {
+            conditions.push(format!("not on {}", self.exclude_hosts.join(", ")));
+        }
+        (!conditions.is_empty()).then(|| conditions.join(", "))
+    }
+}
+
+/// How an entry's files are linked into its target directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkMode {
+    /// A link for each file, so the target directory can also hold files confinuum doesn't manage
+    #[default]
+    Files,
+    /// The target directory itself links to the entry's directory in the repo, so files that
+    /// programs create in it end up in the repo too
+    Directory,
+}
+
+impl LinkMode {
+    fn is_files(&self) -> bool {
+        *self == Self::Files
+    }
+}
+
+impl std::fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Files => write!(f, "files"),
+            Self::Directory => write!(f, "directory"),
+        }
+    }
+}
+
+/// An operating system entries can be limited to with `only_os`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Os {
+    Linux,
+    Macos,
+}
+
+impl Os {
+    /// The system confinuum is running on, if it's one entries can be limited to
+    pub fn current() -> Option<Self> {
+        match std::env::consts::OS {
+            "linux" => Some(Self::Linux),
+            "macos" => Some(Self::Macos),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Os {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Linux => write!(f, "linux"),
+            Self::Macos => write!(f, "macos"),
+        }
+    }
+}
+
+/// The name of this machine, as matched against `only_hosts` and `exclude_hosts`
+/// std has no way to get it, so it comes from the `hostname` command, which Linux and macOS both
+/// have.
+fn hostname() -> Option<String> {
+    static HOSTNAME: OnceLock<Option<String>> = OnceLock::new();
+    HOSTNAME
+        .get_or_init(|| {
+            let output = std::process::Command::new("hostname").output().ok()?;
+            let name = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+            (output.status.success() && !name.is_empty()).then_some(name)
+        })
+        .clone()
+}
+
+/// Whether a hostname from the config names this machine
+/// macOS reports names like `laptop.local`, so the domain can be left out in the config.
+fn same_host(configured: &str, host: &str) -> bool {
+    configured.eq_ignore_ascii_case(host)
+        || host
+            .split_once('.')
+            .is_some_and(|(short, _)| configured.eq_ignore_ascii_case(short))
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, ValueEnum)]
 pub enum GitProtocol {
     #[serde(rename = "ssh")]
     Ssh,
@@ -51,68 +450,217 @@ pub enum GitProtocol {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ConfinuumConfig {
     pub confinuum: Confinuum,
-    #[serde(flatten)]
+    #[serde(flatten, serialize_with = "serialize_sorted_map")]
     pub entries: HashMap<String, ConfigEntry>,
 }
 
+// Entries are written sorted, so that saving the same config on two machines gives the same file
+// and doesn't cause merge conflicts
+fn serialize_sorted_map<S: Serializer>(
+    map: &HashMap<String, ConfigEntry>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
 impl ConfinuumConfig {
     pub fn init(git_protocol: GitProtocol, signature_source: SignatureSource) -> Self {
         Self {
             confinuum: Confinuum {
+                version: migrations::CURRENT_VERSION,
                 git_protocol,
                 signature_source,
+                branch: default_branch(),
+                shallow: false,
+                ignore_gh_cli: false,
+                no_update_check: false,
+                hosting: Hosting::Github,
+                gitlab_url: None,
+                gitea_url: None,
+                github_client_id: None,
+                github_scopes: None,
+                commit_message: CommitMessages::default(),
             },
             entries: HashMap::new(),
         }
     }
 
+    /// The names of the entries with any of the given tags, or None (meaning all entries) if no
+    /// tags were given. Tags that no entry has are warned about, since they're likely typos.
+    pub fn tagged_entries(&self, tags: &[String]) -> Option<HashSet<String>> {
+        if tags.is_empty() {
+            return None;
+        }
+        for tag in tags {
+            if !self.entries.values().any(|entry| entry.tags.contains(tag)) {
+                eprintln!(
+                    "{} No entry is tagged {}",
+                    "!".yellow(),
+                    tag.as_str().bold()
+                );
+            }
+        }
+        Some(
+            self.entries
+                .iter()
+                .filter(|(_, entry)| entry.tags.iter().any(|tag| tags.contains(tag)))
+                .map(|(name, _)| name.clone())
+                .collect(),
+        )
+    }
+
+    /// The entry with this name, or an error suggesting the entries with similar names
+    pub fn entry(&self, name: &str) -> Result<&ConfigEntry, ConfinuumError> {
+        self.entries
+            .get(name)
+            .ok_or_else(|| self.entry_not_found(name))
+    }
+
+    /// The entry with this name to change, or an error suggesting the entries with similar names
+    pub fn entry_mut(&mut self, name: &str) -> Result<&mut ConfigEntry, ConfinuumError> {
+        if !self.entries.contains_key(name) {
+            return Err(self.entry_not_found(name));
+        }
+        Ok(self.entries.get_mut(name).expect("the entry exists"))
+    }
+
+    /// The error for a name that isn't an entry, with the names it might be a typo of
+    pub fn entry_not_found(&self, name: &str) -> ConfinuumError {
+        ConfinuumError::EntryNotFound(name.to_owned(), self.similar_entries(name))
+    }
+
+    /// The names of the entries that `name` might be a typo of, closest first
+    /// A name is close if it's within a Levenshtein distance of a third of its length (but at least
+    /// 2, so swapping two letters counts), ignoring case, or if it starts with `name`.
+    pub fn similar_entries(&self, name: &str) -> Vec<String> {
+        const MAX_SUGGESTIONS: usize = 3;
+        let name = name.to_lowercase();
+        let mut similar = self
+            .entries
+            .keys()
+            .filter_map(|entry| {
+                let lowercase = entry.to_lowercase();
+                let distance = strsim::levenshtein(&name, &lowercase);
+                let close = distance <= (lowercase.chars().count() / 3).max(2)
+                    && distance < name.chars().count();
+                (close || (!name.is_empty() && lowercase.starts_with(&name)))
+                    .then_some((distance, entry))
+            })
+            .collect::<Vec<_>>();
+        similar.sort();
+        similar
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+
+    /// The name of the entry the user meant by `name`
+    /// If there's no entry with that name but exactly one with a similar name, and stdin is a
+    /// terminal, asks whether that was the one meant.
+    pub fn resolve_entry_name(&self, name: String) -> Result<String> {
+        if self.entries.contains_key(&name) {
+            return Ok(name);
+        }
+        let similar = self.similar_entries(&name);
+        if let [candidate] = similar.as_slice() {
+            if std::io::stdin().is_terminal()
+                && dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!(
+                        "No entry named {}, did you mean {}?",
+                        name,
+                        candidate.as_str().bold()
+                    ))
+                    .default(true)
+                    .interact()?
+            {
+                return Ok(candidate.clone());
+            }
+        }
+        Err(ConfinuumError::EntryNotFound(name, similar).into())
+    }
+
+    /// Ask which entry to use when no name was given, searching their names as the user types
+    pub fn select_entry(&self) -> Result<String> {
+        if self.entries.is_empty() {
+            return Err(anyhow!(
+                "There are no entries yet, create one with `confinuum entry <name> create`"
+            ));
+        }
+        if !std::io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "No entry name given, and one can only be picked from a list in a terminal"
+            ));
+        }
+        let mut names = self.entries.keys().collect::<Vec<_>>();
+        names.sort();
+        let selected = dialoguer::FuzzySelect::with_theme(&ColorfulTheme::default())
+            .with_prompt("Entry")
+            .items(&names)
+            .default(0)
+            .interact()?;
+        Ok(names[selected].clone())
+    }
+
+    /// Copy files into the entry's directory in the config repo and add them to the entry
+    /// Files that are symlinks are refused unless `follow_symlinks` is set, in which case the file
+    /// they point to is added instead (and the entry will deploy a link where the target was).
     pub fn add_files_recursive(
         entry: &mut ConfigEntry,
         files: Vec<PathBuf>,
-        mut base: Option<PathBuf>,
+        base: Option<PathBuf>,
+        follow_symlinks: bool,
         result_files: &mut Option<&mut HashSet<PathBuf>>,
     ) -> Result<PathBuf> {
-        let config_dir = ConfinuumConfig::get_dir().context("Could not get config dir")?;
-        let files_dir = config_dir.join(&entry.name);
+        let plan = Self::plan_add(entry, files, base, follow_symlinks)?;
+        Self::apply_add(entry, plan, result_files)
+    }
 
-        let canonicalized = files
-            .iter()
-            .map(|x| {
-                x.canonicalize()
-                    .map_err(|e| anyhow!("Failed to canonicalize: {}", e))
-            })
-            .collect::<Result<Vec<PathBuf>>>()?;
+    /// Work out which files adding `files` to the entry would copy, without touching anything
+    /// This does the same checks as copying them would, so a plan that's made can be applied.
+    pub fn plan_add(
+        entry: &ConfigEntry,
+        files: Vec<PathBuf>,
+        mut base: Option<PathBuf>,
+        follow_symlinks: bool,
+    ) -> Result<AddPlan> {
+        let canonicalized = resolve_files(&files, follow_symlinks)?;
+        let mut target_dir = entry.target_dir.clone();
+        let mut target_file = entry.target_file.clone();
+        let mut entry_files = entry.files.clone();
+        if base.is_none() {
+            if let Some(target_file) = &entry.target_file {
+                return Err(anyhow!(
+                    "{} is a single-file entry for {}, so files can't be added to it. Create a separate entry for them.",
+                    entry.name,
+                    target_file.display()
+                ));
+            }
+            // A lone file is deployed to exactly where it was, rather than into its parent
+            // directory, so that the entry doesn't take over e.g. the whole home directory
+            if entry.files.is_empty() && canonicalized.len() == 1 && canonicalized[0].is_file() {
+                let parent = canonicalized[0].parent().ok_or(anyhow!(
+                    "Could not get parent of {}",
+                    canonicalized[0].display()
+                ))?;
+                base = Some(parent.to_path_buf());
+                target_dir = None;
+                target_file = Some(canonicalized[0].clone());
+            }
+        }
         if base.is_none() {
             let prev_entry_files = entry
                 .files
                 .iter()
-                .map(|f| {
-                    entry
-                        .target_dir
-                        .as_ref()
-                        .unwrap()
-                        .join(&entry.name)
-                        .join(&f)
-                })
+                .map(|f| entry.target_dir.as_ref().unwrap().join(f))
                 .collect::<Vec<_>>();
             let all = prev_entry_files.iter().chain(canonicalized.iter());
-            let new_base = if all.clone().count() == 1 && canonicalized[0].is_file() {
-                canonicalized[0]
-                    .clone()
-                    .parent()
-                    .ok_or(anyhow!(
-                        "Could not get parent of {}",
-                        canonicalized[0].display()
-                    ))?
-                    .to_path_buf()
-            } else {
-                common_path_all(all.map(|x| x.as_path()))
-                    .ok_or(anyhow!("Could not find common base path"))?
-            };
+            let new_base = common_path_all(all.map(|x| x.as_path()))
+                .ok_or(anyhow!("Could not find common base path"))?;
 
             if let Some(target_dir) = &entry.target_dir {
                 if &new_base != target_dir {
-                    let mut new = HashSet::new();
+                    let mut new = BTreeSet::new();
                     for entry in entry.files.iter() {
                         let old = target_dir.join(&entry);
                         new.insert(
@@ -125,70 +673,102 @@ impl ConfinuumConfig {
                                 .to_path_buf(),
                         );
                     }
-                    entry.files = new;
+                    entry_files = new;
                 }
             }
 
+            target_dir = Some(new_base.clone());
             base = Some(new_base);
-            entry.target_dir = Some(base.clone().unwrap());
         }
+        let base = base.unwrap();
 
-        // First pass, collect all files and copy them to the config directory
-        let mut new_files = vec![];
-        for file in canonicalized {
-            if !file.exists() {
-                return Err(anyhow!("File does not exist: {:?}", file));
-            }
-            if file.is_dir() {
-                if file.file_name().unwrap() == ".git" {
-                    continue;
-                }
-                let entries = file
-                    .read_dir()
-                    .context(format!("Could not read dir {}", file.display()))?
-                    .filter_map(|x| if let Ok(x) = x { Some(x.path()) } else { None })
-                    .collect::<Vec<_>>();
-                Self::add_files_recursive(entry, entries, base.clone(), result_files)?;
-            } else {
-                let source_path = files_dir.join(
-                    file.strip_prefix(&base.clone().unwrap()).with_context(|| {
-                        format!(
-                            "Could not strip prefix {} from {}",
-                            base.as_ref().unwrap().display(),
-                            file.display()
-                        )
-                    })?,
-                );
-                let parent_folder = source_path.parent().ok_or(anyhow!(
-                    "Could not get parent folder for file: {:?}",
-                    source_path
-                ))?;
-                if !parent_folder.exists() {
-                    std::fs::create_dir_all(parent_folder).with_context(|| {
-                        format!("Could not create dirs {}", parent_folder.display())
-                    })?;
-                }
+        // Keyed by the path in the entry, so a file given twice is only copied once
+        let mut copies = BTreeMap::new();
+        collect_files(canonicalized, &base, follow_symlinks, &mut copies)?;
+        Ok(AddPlan {
+            base,
+            target_dir,
+            target_file,
+            entry_files,
+            copies: copies.into_iter().collect(),
+        })
+    }
 
-                let repo_rel_source_path = source_path
-                    .strip_prefix(&config_dir.join(&entry.name))
-                    .with_context(|| {
-                        format!(
-                            "Could not strip prefix {} from {}",
-                            &config_dir.display(),
-                            &source_path.display()
-                        )
-                    })?
-                    .to_path_buf();
-                new_files.push(repo_rel_source_path.clone());
-                std::fs::copy(&file, &source_path).with_context(|| {
-                    format!(
-                        "Could not copy {} to {}",
-                        file.display(),
-                        source_path.display()
-                    )
-                })?;
+    /// Copy the planned files into the entry's directory and add them to the entry
+    /// If any copy fails, the files that were overwritten are put back and the new ones removed.
+    /// Returns the directory the files were added relative to.
+    pub fn apply_add(
+        entry: &mut ConfigEntry,
+        plan: AddPlan,
+        result_files: &mut Option<&mut HashSet<PathBuf>>,
+    ) -> Result<PathBuf> {
+        let config_dir = ConfinuumConfig::get_dir().context("Could not get config dir")?;
+        let files_dir = config_dir.join(&entry.name);
+        let AddPlan {
+            base,
+            target_dir,
+            target_file,
+            entry_files,
+            copies,
+        } = plan;
+
+        // Directories are created up front, so that parallel copies don't race to create them
+        let dirs = copies
+            .iter()
+            .filter_map(|(path, _)| files_dir.join(path).parent().map(Path::to_path_buf))
+            .collect::<BTreeSet<_>>();
+        for dir in dirs {
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Could not create dirs {}", dir.display()))?;
+        }
+        let results = par_progress(
+            &copies,
+            "Copying files",
+            |(path, _)| path.clone(),
+            |(path, file)| {
+                let source_path = files_dir.join(path);
+                // A safe point to stop, the copies made so far are undone below
+                interrupt::check()?;
+                // A file that's already in the repo is backed up until every copy has succeeded
+                let backup = match source_path.exists() {
+                    true => Some(backup_file(&source_path)?),
+                    false => None,
+                };
+                match std::fs::copy(file, &source_path) {
+                    Ok(_) => Ok((source_path, backup)),
+                    Err(e) => {
+                        undo_copy(&source_path, backup.as_deref());
+                        Err(anyhow::Error::from(e).context(format!(
+                            "Could not copy {} to {}",
+                            file.display(),
+                            source_path.display()
+                        )))
+                    }
+                }
+            },
+        );
+        let (copied, errors): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+        let copied = copied.into_iter().filter_map(Result::ok);
+        if !errors.is_empty() {
+            // Put back the files that were overwritten and remove the new ones, so the entry is
+            // left as it was
+            for (source_path, backup) in copied {
+                undo_copy(&source_path, backup.as_deref());
             }
+            interrupt::check()?;
+            return deployment::collect_errors(
+                errors.into_iter().filter_map(Result::err).collect(),
+                "copy",
+            )
+            .map(|_| base);
         }
+        for backup in copied.filter_map(|(_, backup)| backup) {
+            std::fs::remove_file(&backup).ok();
+        }
+        let new_files = copies.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+        entry.target_dir = target_dir;
+        entry.target_file = target_file;
+        entry.files = entry_files;
 
         // Files used to be symlinked here, but that was moved to
         //    the deploy function to be used in commands where needed.
@@ -198,7 +778,7 @@ impl ConfinuumConfig {
             result_files.extend(new_files.iter().cloned());
         }
         entry.files.extend(new_files);
-        Ok(base.unwrap())
+        Ok(base)
     }
 
     pub fn exists() -> Result<bool> {
@@ -212,30 +792,98 @@ impl ConfinuumConfig {
     }
 
     pub fn get_path() -> Result<PathBuf> {
-        Ok(PathBuf::from(var("HOME")?).join(".config/confinuum/config.toml"))
+        Ok(Self::get_dir()?.join("config.toml"))
     }
 
     pub fn get_dir() -> Result<PathBuf> {
-        Ok(PathBuf::from(var("HOME")?).join(".config/confinuum"))
+        Ok(Self::resolve_dir()?.0)
+    }
+
+    /// Find the config directory, along with the environment variable it was found through
+    /// In order: $CONFINUUM_CONFIG_DIR, $XDG_CONFIG_HOME/confinuum, then $HOME/.config/confinuum.
+    /// Empty variables are treated as unset, and so is a relative XDG_CONFIG_HOME (as the XDG spec
+    /// requires). A config that's already in $HOME/.config is still used when there's none under
+    /// XDG_CONFIG_HOME, with a note on how to move it.
+    pub fn resolve_dir() -> Result<(PathBuf, &'static str)> {
+        let non_empty = |name| var(name).ok().filter(|value| !value.is_empty());
+        if let Some(dir) = non_empty("CONFINUUM_CONFIG_DIR").map(PathBuf::from) {
+            // Deployed links point into this directory, so it can't depend on the working directory
+            if !dir.is_absolute() {
+                return Err(anyhow!(
+                    "CONFINUUM_CONFIG_DIR must be an absolute path, but it's set to {}",
+                    dir.display()
+                ));
+            }
+            return Ok((dir, "CONFINUUM_CONFIG_DIR"));
+        }
+        let home_dir = non_empty("HOME").map(|home| PathBuf::from(home).join(".config/confinuum"));
+        if let Some(config_home) = non_empty("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+        {
+            let dir = config_home.join("confinuum");
+            // Configs made before XDG_CONFIG_HOME was set (or respected) are in ~/.config
+            match home_dir.filter(|home_dir| !dir.exists() && home_dir.exists()) {
+                Some(home_dir) if home_dir != dir => {
+                    note_old_dir(&home_dir, &dir);
+                    return Ok((
+                        home_dir,
+                        "HOME, since there's no config under XDG_CONFIG_HOME",
+                    ));
+                }
+                _ => return Ok((dir, "XDG_CONFIG_HOME")),
+            }
+        }
+        let home_dir = home_dir.ok_or_else(|| {
+            anyhow!("Could not find the config directory, since HOME is not set. Set HOME to your home directory, or CONFINUUM_CONFIG_DIR to the directory to keep the config in.")
+        })?;
+        Ok((home_dir, "HOME"))
     }
 
     pub fn load() -> Result<ConfinuumConfig> {
         if !Self::exists()? {
-            return Err(anyhow!(
-                "Config file does not exist. Run `confinuum init` to create one."
-            ));
+            return Err(ConfinuumError::NotInitialized.into());
         }
         let config_str = std::fs::read_to_string(Self::get_path()?)
             .context("Could not load confinuum config")?;
-        let mut config: ConfinuumConfig =
-            toml::from_str(&config_str).context("Could not parse confinuum config")?;
+        let (config, upgraded) = Self::parse_migrated(&config_str)?;
+        tracing::trace!(
+            "Loaded config with {} entries (upgraded: {})",
+            config.entries.len(),
+            upgraded
+        );
+        if upgraded && !UPGRADE_NOTED.swap(true, Ordering::Relaxed) {
+            info!(
+                "Note: config.toml is from an older version of confinuum and was upgraded. It will be saved in the new format with the next change to the config."
+            );
+        }
+        Ok(config)
+    }
+
+    /// Parse a config from a string, such as the contents of config.toml at a given commit
+    /// Configs in an older format are upgraded to the current one.
+    pub fn parse(config_str: &str) -> Result<ConfinuumConfig> {
+        Ok(Self::parse_migrated(config_str)?.0)
+    }
+
+    /// Parse a config, also returning whether it had to be upgraded from an older format
+    fn parse_migrated(config_str: &str) -> Result<(ConfinuumConfig, bool)> {
+        let mut document = config_str
+            .parse::<toml::Table>()
+            .context("Could not parse confinuum config")?;
+        let upgraded = migrations::migrate(&mut document)?;
+        let mut config: ConfinuumConfig = toml::Value::Table(document)
+            .try_into()
+            .context("Could not parse confinuum config")?;
         config.entries.iter_mut().for_each(|(name, entry)| {
             entry.name = name.to_string();
         });
-        Ok(config)
+        Ok((config, upgraded))
     }
 
     /// Save the config to disk (will overwrite existing config)
+    /// Comments and formatting in the existing file are kept wherever the values they belong to
+    /// didn't change.
     pub fn save(&self) -> Result<()> {
         let config_path = Self::get_path()?;
         let config_str = toml::to_string_pretty(self)?;
@@ -243,7 +891,594 @@ impl ConfinuumConfig {
         if !conf_dir.exists() {
             std::fs::create_dir_all(conf_dir)?;
         }
-        std::fs::write(config_path, config_str)?;
-        Ok(())
+        let existing = std::fs::read_to_string(&config_path)
+            .ok()
+            .and_then(|existing| existing.parse::<Document>().ok());
+        let config_str = match existing {
+            Some(mut document) => {
+                let updated = config_str.parse::<Document>()?;
+                merge_table(document.as_table_mut(), updated.as_table());
+                sort_entries(document.as_table_mut());
+                document.to_string()
+            }
+            None => config_str,
+        };
+        tracing::debug!("Saving {}", config_path.display());
+        write_atomic(&config_path, &config_str)
+    }
+}
+
+/// The files adding to an entry would copy, and how the entry changes, from `plan_add`
+pub struct AddPlan {
+    /// The directory the copied files are relative to
+    base: PathBuf,
+    target_dir: Option<PathBuf>,
+    target_file: Option<PathBuf>,
+    /// The entry's files, re-rooted if the new files moved its target directory up
+    entry_files: BTreeSet<PathBuf>,
+    /// Each file to copy by where it goes relative to the entry's directory, with its source
+    copies: Vec<(PathBuf, PathBuf)>,
+}
+
+impl AddPlan {
+    /// Where each file will be copied to, relative to the entry's directory
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.copies.iter().map(|(path, _)| path.as_path())
+    }
+
+    pub fn len(&self) -> usize {
+        self.copies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty()
+    }
+
+    /// The combined size of the files, in bytes
+    pub fn size(&self) -> u64 {
+        self.copies
+            .iter()
+            .filter_map(|(_, file)| file.metadata().ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+}
+
+/// Resolve the files to their canonical paths
+/// Symlinks are refused unless `follow_symlinks` is set, in which case the file they point to is
+/// used instead.
+fn resolve_files(files: &[PathBuf], follow_symlinks: bool) -> Result<Vec<PathBuf>> {
+    for file in files.iter() {
+        let is_symlink = file
+            .symlink_metadata()
+            .is_ok_and(|meta| meta.file_type().is_symlink());
+        if !is_symlink {
+            continue;
+        }
+        let target = file
+            .canonicalize()
+            .with_context(|| format!("{} is a broken symlink", file.display()))?;
+        if !follow_symlinks {
+            return Err(anyhow!(
+                "{} is a symlink to {}. Add {} instead, or use --follow-symlinks to add the file it points to.",
+                file.display(),
+                target.display(),
+                target.display()
+            ));
+        }
+        info!(
+            "{} {} is a symlink, adding {} instead",
+            "!".yellow(),
+            file.display(),
+            target.display()
+        );
+    }
+
+    files
+        .iter()
+        .map(|x| {
+            x.canonicalize()
+                .map_err(|e| anyhow!("Failed to canonicalize: {}", e))
+        })
+        .collect()
+}
+
+/// Walk the files and directories, collecting every file by its path relative to `base`
+fn collect_files(
+    files: Vec<PathBuf>,
+    base: &Path,
+    follow_symlinks: bool,
+    copies: &mut BTreeMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    for file in files {
+        if !file.exists() {
+            return Err(anyhow!("File does not exist: {:?}", file));
+        }
+        if file.is_dir() {
+            if file.file_name().unwrap() == ".git" {
+                continue;
+            }
+            let entries = file
+                .read_dir()
+                .context(format!("Could not read dir {}", file.display()))?
+                .filter_map(|x| if let Ok(x) = x { Some(x.path()) } else { None })
+                .collect::<Vec<_>>();
+            collect_files(
+                resolve_files(&entries, follow_symlinks)?,
+                base,
+                follow_symlinks,
+                copies,
+            )?;
+        } else {
+            let path = file
+                .strip_prefix(base)
+                .with_context(|| {
+                    format!(
+                        "Could not strip prefix {} from {}",
+                        base.display(),
+                        file.display()
+                    )
+                })?
+                .to_path_buf();
+            copies.insert(path, file);
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file in the repo that adding files is about to overwrite, next to it
+/// Returns the path of the copy.
+fn backup_file(path: &Path) -> Result<PathBuf> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let backup = path.with_file_name(format!(".{}.confinuum-backup", file_name));
+    std::fs::copy(path, &backup)
+        .with_context(|| format!("Could not back up {}", path.display()))?;
+    Ok(backup)
+}
+
+/// Undo copying a file into the repo, by putting back the file it overwrote or removing it
+fn undo_copy(path: &Path, backup: Option<&Path>) {
+    match backup {
+        Some(backup) => std::fs::rename(backup, path).ok(),
+        None => std::fs::remove_file(path).ok(),
+    };
+}
+
+/// Update `old` to have the keys and values of `new`, keeping the decoration (comments and
+/// whitespace) of everything in `old` that's still there
+fn merge_table(old: &mut Table, new: &Table) {
+    let removed = old
+        .iter()
+        .map(|(key, _)| key.to_owned())
+        .filter(|key| !new.contains_key(key))
+        .collect::<Vec<_>>();
+    for key in removed {
+        old.remove(&key);
+    }
+    for (key, new_item) in new.iter() {
+        match (old.get_mut(key), new_item) {
+            (Some(Item::Table(old_table)), Item::Table(new_table)) => {
+                merge_table(old_table, new_table)
+            }
+            (Some(Item::Value(old_value)), Item::Value(new_value)) => {
+                if !same_value(old_value, new_value) {
+                    let decor = old_value.decor().clone();
+                    *old_value = new_value.clone();
+                    *old_value.decor_mut() = decor;
+                }
+            }
+            (Some(old_item), _) => *old_item = new_item.clone(),
+            (None, _) => {
+                old.insert(key, new_item.clone());
+            }
+        }
+    }
+}
+
+/// Compare values while ignoring how they're formatted
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.value() == b.value(),
+        (Value::Integer(a), Value::Integer(b)) => a.value() == b.value(),
+        (Value::Float(a), Value::Float(b)) => a.value() == b.value(),
+        (Value::Boolean(a), Value::Boolean(b)) => a.value() == b.value(),
+        (Value::Datetime(a), Value::Datetime(b)) => a.value() == b.value(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| same_value(a, b))
+        }
+        (Value::InlineTable(a), Value::InlineTable(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.get(key).is_some_and(|b| same_value(a, b)))
+        }
+        _ => false,
+    }
+}
+
+/// Order the top-level tables as [confinuum] followed by the entries sorted by name
+fn sort_entries(document: &mut Table) {
+    let mut names = document
+        .iter()
+        .map(|(key, _)| key.to_owned())
+        .filter(|key| key != "confinuum")
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut position = 0;
+    for name in std::iter::once("confinuum".to_owned()).chain(names) {
+        if let Some(Item::Table(table)) = document.get_mut(&name) {
+            table.set_position(position);
+            position += 1;
+            // Sub-tables, like [confinuum.commit_message], stay right below their table
+            for (_, item) in table.iter_mut() {
+                if let Item::Table(table) = item {
+                    table.set_position(position);
+                    position += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Replace the contents of a file without ever leaving it partially written
+/// The contents are written to a temporary file next to it, which is then renamed over the
+/// original, so if anything fails along the way the original is left untouched.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    write_file(path, contents, None)
+}
+
+/// Write a file holding credentials atomically, so that only the user can read or write it
+/// The file is replaced by a new one, so this also tightens the permissions of an existing file.
+pub fn write_secret(path: &Path, contents: &str) -> Result<()> {
+    write_file(path, contents, Some(0o600))
+}
+
+fn write_file(path: &Path, contents: &str, mode: Option<u32>) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", path.display()))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let write = || -> std::io::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if let Some(mode) = mode {
+            options.mode(mode);
+        }
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(contents.as_bytes())?;
+        match (mode, std::fs::metadata(path)) {
+            // The mode given to open only applies to new files, so a stale temp file is fixed here
+            (Some(mode), _) => file.set_permissions(std::fs::Permissions::from_mode(mode))?,
+            // Otherwise keep the original's permissions
+            (None, Ok(meta)) => file.set_permissions(meta.permissions())?,
+            (None, Err(_)) => {}
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    };
+    write().map_err(|e| {
+        std::fs::remove_file(&tmp_path).ok();
+        anyhow!(e).context(format!("Could not write {}", path.display()))
+    })
+}
+
+/// Whether the note about an upgraded config was printed, since it's loaded many times per command
+static UPGRADE_NOTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the note about a config outside of XDG_CONFIG_HOME was printed, since the directory is
+/// looked up many times per command
+static OLD_DIR_NOTED: AtomicBool = AtomicBool::new(false);
+
+/// Note that the config is used from `old_dir` although XDG_CONFIG_HOME says it belongs in
+/// `new_dir`, and how to move it
+/// Deployed files link into the config directory, so it can't just be moved for the user.
+fn note_old_dir(old_dir: &Path, new_dir: &Path) {
+    if OLD_DIR_NOTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    eprintln!(
+        "{} Using the config in {}, since XDG_CONFIG_HOME is set but {} doesn't exist. To move it there, run `confinuum undeploy`, move the directory to {}, then run `confinuum redeploy`.",
+        "!".yellow(),
+        old_dir.display(),
+        new_dir.display(),
+        new_dir.display()
+    );
+}
+
+/// Set by --insecure-auth-files, since auth files are loaded before any command runs
+static ALLOW_INSECURE_AUTH_FILES: AtomicBool = AtomicBool::new(false);
+
+/// Load auth files that other users can read, with a warning instead of an error
+pub fn allow_insecure_auth_files() {
+    ALLOW_INSECURE_AUTH_FILES.store(true, Ordering::Relaxed);
+}
+
+/// The permission bits of a file holding credentials that give access to other users, if any
+pub fn exposed_permissions(path: &Path) -> Result<Option<u32>> {
+    let mode = std::fs::metadata(path)
+        .with_context(|| format!("Could not read metadata of {}", path.display()))?
+        .permissions()
+        .mode();
+    Ok(Some(mode & 0o077).filter(|exposed| *exposed != 0))
+}
+
+/// Refuse to read a file holding credentials that other users can read or write, like ssh does
+/// for private keys. With --insecure-auth-files this only warns.
+pub fn check_secret_permissions(path: &Path) -> Result<()> {
+    let Some(exposed) = exposed_permissions(path)? else {
+        return Ok(());
+    };
+    let mode = exposed | 0o600;
+    if ALLOW_INSECURE_AUTH_FILES.load(Ordering::Relaxed) {
+        eprintln!(
+            "{} {} has permissions {:o}, so other users can access your token! Run `chmod 600 {}` to fix this.",
+            "WARNING:".red().bold(),
+            path.display(),
+            mode,
+            path.display()
+        );
+        return Ok(());
+    }
+    Err(ConfinuumError::InsecureAuthFile(path.to_owned(), mode).into())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::Mutex;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// Held by tests that change environment variables, which the whole process shares
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Run `f` with environment variables set, or removed where they're None, and restore them
+    /// afterwards
+    pub(crate) fn with_env<T>(vars: &[(&str, Option<&Path>)], f: impl FnOnce() -> T) -> T {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved = vars
+            .iter()
+            .map(|(name, _)| (*name, std::env::var_os(name)))
+            .collect::<Vec<_>>();
+        for (name, value) in vars {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+        for (name, value) in saved {
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+        result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+
+    /// A file with the given contents and mode, in a new temp dir
+    fn existing_file(contents: &str, mode: u32) -> (TempDir, PathBuf) {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, contents).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        (dir, path)
+    }
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn write_atomic_replaces_contents() {
+        let (_dir, path) = existing_file("old", 0o640);
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(mode(&path), 0o640);
+    }
+
+    #[test]
+    fn write_secret_restricts_permissions() {
+        let (_dir, path) = existing_file("old", 0o644);
+        write_secret(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(mode(&path), 0o600);
+    }
+
+    #[test]
+    fn failed_write_leaves_original() {
+        for write in [write_atomic, write_secret] {
+            let (dir, path) = existing_file("old", 0o644);
+            // A directory where the temp file goes makes the write fail, even for root (which a
+            // read-only directory wouldn't stop)
+            std::fs::create_dir(dir.path().join(".config.toml.tmp")).unwrap();
+            assert!(write(&path, "new").is_err());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+            assert_eq!(mode(&path), 0o644);
+        }
+    }
+
+    #[test]
+    fn failed_write_in_read_only_dir_leaves_original() {
+        let (dir, path) = existing_file("old", 0o644);
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o555)).unwrap();
+        let result = write_atomic(&path, "new");
+        std::fs::set_permissions(dir.path(), std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Root can write to read-only directories, which the test above covers instead
+        if result.is_ok() {
+            return;
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+        assert!(!dir.path().join(".config.toml.tmp").exists());
+    }
+
+    #[test]
+    fn validate_name() {
+        let cases = [
+            ("nvim", true),
+            ("my-config.d", true),
+            ("..nvim", true),
+            ("git", true),
+            ("", false),
+            (".", false),
+            ("..", false),
+            ("nvim/lua", false),
+            ("/nvim", false),
+            ("nvim/", false),
+            ("nvim,zsh", false),
+            (".git", false),
+            (".gitignore", false),
+            (".gitmodules", false),
+            (".GIT", false),
+            ("config.toml", false),
+            ("hosts.toml", false),
+            (".backups", false),
+            ("confinuum", false),
+            ("nvim\0", false),
+            ("nvim\n", false),
+            ("\tnvim", false),
+            ("nvim\u{7f}", false),
+        ];
+        for (name, valid) in cases {
+            assert_eq!(
+                ConfigEntry::validate_name(name).is_ok(),
+                valid,
+                "validating {:?}",
+                name
+            );
+        }
+        for name in RESERVED_NAMES {
+            assert!(ConfigEntry::validate_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn save_sorts_entries_and_keeps_comments() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let unsorted = r#"[confinuum]
+version = 1
+git_protocol = "ssh" # ssh keys everywhere
+signature_source = "gitconfig"
+
+[zsh]
+# The shell
+target_dir = "/home/user"
+files = [".zshrc"]
+
+# Editor
+[nvim]
+target_dir = "/home/user/.config/nvim"
+files = ["init.lua"]
+"#;
+        with_env(&[("CONFINUUM_CONFIG_DIR", Some(dir.path()))], || {
+            std::fs::write(dir.path().join("config.toml"), unsorted).unwrap();
+            let mut config = ConfinuumConfig::load().unwrap();
+            config
+                .entry_mut("nvim")
+                .unwrap()
+                .files
+                .insert("lua/plugins.lua".into());
+            config.save().unwrap();
+        });
+        let saved = std::fs::read_to_string(dir.path().join("config.toml")).unwrap();
+        let position = |text: &str| {
+            saved
+                .find(text)
+                .unwrap_or_else(|| panic!("{:?} is missing from:\n{}", text, saved))
+        };
+        assert!(position("[confinuum]") < position("[nvim]"));
+        assert!(position("[nvim]") < position("[zsh]"));
+        for comment in ["# The shell", "# ssh keys everywhere", "# Editor"] {
+            position(comment);
+        }
+        // Comments move along with their tables
+        assert!(position("# Editor") < position("[nvim]"));
+        assert!(position("[zsh]") < position("# The shell"));
+        assert!(position("lua/plugins.lua") < position("[zsh]"));
+    }
+
+    #[test]
+    fn save_sorts_existing_files() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let unsorted = r#"[confinuum]
+version = 1
+git_protocol = "ssh"
+signature_source = "gitconfig"
+
+[nvim]
+target_dir = "/home/user/.config/nvim"
+# Written by hand
+files = ["lua/plugins.lua", "init.lua"] # the whole config
+"#;
+        with_env(&[("CONFINUUM_CONFIG_DIR", Some(dir.path()))], || {
+            std::fs::write(dir.path().join("config.toml"), unsorted).unwrap();
+            ConfinuumConfig::load().unwrap().save().unwrap();
+        });
+        let saved = std::fs::read_to_string(dir.path().join("config.toml")).unwrap();
+        let document = saved.parse::<Document>().unwrap();
+        let files = document["nvim"]["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file.as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(files, ["init.lua", "lua/plugins.lua"]);
+        assert!(saved.contains("# Written by hand\nfiles = ["), "{}", saved);
+        assert!(saved.contains("] # the whole config"), "{}", saved);
+    }
+
+    #[test]
+    fn failed_add_restores_overwritten_files() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        with_env(&[("CONFINUUM_CONFIG_DIR", Some(dir.path()))], || {
+            crate::cli::disable_progress();
+            let entry_dir = dir.path().join("nvim");
+            std::fs::create_dir_all(&entry_dir).unwrap();
+            std::fs::write(entry_dir.join("init.lua"), "old").unwrap();
+            let new_file = dir.path().join("init.lua");
+            std::fs::write(&new_file, "new").unwrap();
+            let mut entry = ConfigEntry::new("nvim".to_owned());
+            entry.files.insert("init.lua".into());
+            let plan = AddPlan {
+                base: dir.path().to_path_buf(),
+                target_dir: None,
+                target_file: None,
+                entry_files: entry.files.clone(),
+                copies: vec![
+                    ("init.lua".into(), new_file),
+                    ("plugins.lua".into(), dir.path().join("missing.lua")),
+                ],
+            };
+
+            assert!(ConfinuumConfig::apply_add(&mut entry, plan, &mut None).is_err());
+            let contents = std::fs::read_to_string(entry_dir.join("init.lua")).unwrap();
+            assert_eq!(contents, "old");
+            let files = std::fs::read_dir(&entry_dir)
+                .unwrap()
+                .map(|item| item.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert_eq!(files, ["init.lua"]);
+        });
+    }
+
+    #[test]
+    fn resolve_dir_falls_back_to_home() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (home, xdg) = (dir.path().join("home"), dir.path().join("xdg"));
+        let (home_dir, xdg_dir) = (home.join(".config/confinuum"), xdg.join("confinuum"));
+        let vars = [
+            ("CONFINUUM_CONFIG_DIR", None),
+            ("HOME", Some(home.as_path())),
+            ("XDG_CONFIG_HOME", Some(xdg.as_path())),
+        ];
+        let resolved = || with_env(&vars, || ConfinuumConfig::get_dir().unwrap());
+        // A new config goes under XDG_CONFIG_HOME
+        assert_eq!(resolved(), xdg_dir);
+        // An existing one in ~/.config is still found
+        std::fs::create_dir_all(&home_dir).unwrap();
+        assert_eq!(resolved(), home_dir);
+        // Until there's one under XDG_CONFIG_HOME
+        std::fs::create_dir_all(&xdg_dir).unwrap();
+        assert_eq!(resolved(), xdg_dir);
     }
 }
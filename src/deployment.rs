@@ -1,177 +1,965 @@
 //! Utility functions for the Confinuum CLI
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use anyhow::{anyhow, Context, Result};
+use crossterm::style::Stylize;
+use rayon::prelude::*;
 
-use crate::config::ConfinuumConfig;
+use crate::{
+    cli::{info, par_progress},
+    config::{ConfigEntry, ConfinuumConfig, LinkMode, BACKUP_DIR},
+    error::ConfinuumError,
+    hosting,
+};
 
-pub fn deploy(name: Option<impl Into<String>>) -> Result<()> {
-    let config = ConfinuumConfig::load()?;
-    let config_dir = ConfinuumConfig::get_dir().context("Could not get config dir")?;
-    let name: Option<String> = name.map(|n| n.into());
-    if let Some(name) = &name {
-        if !config.entries.contains_key(name) {
-            return Err(anyhow!("No entry named {} found", name));
-        }
+/// Set by --run-hooks, since deploys happen from many commands
+static RUN_HOOKS: AtomicBool = AtomicBool::new(false);
+
+/// Run entries' deploy hooks for the rest of this invocation
+pub fn enable_hooks() {
+    RUN_HOOKS.store(true, Ordering::Relaxed);
+}
+
+/// Set by `redeploy --force`, to deploy entries even when their files conflict
+static ALLOW_CONFLICTS: AtomicBool = AtomicBool::new(false);
+
+/// Only warn about conflicting files for the rest of this invocation, instead of failing
+pub fn allow_conflicts() {
+    ALLOW_CONFLICTS.store(true, Ordering::Relaxed);
+}
+
+/// Whether hooks run, with --run-hooks or on a machine that opts in by setting
+/// `CONFINUUM_RUN_HOOKS`
+/// The opt-in is never read from config.toml: it's synced, so a commit to the config could make
+/// every machine run whatever it likes.
+fn hooks_enabled() -> bool {
+    RUN_HOOKS.load(Ordering::Relaxed)
+        || std::env::var_os("CONFINUUM_RUN_HOOKS").is_some_and(|value| !value.is_empty())
+}
+
+/// Run one of an entry's deploy hooks, if it has one
+/// Hooks execute arbitrary commands from the config, so they're skipped (with a note) unless the
+/// user opted in, see `hooks_enabled`
+fn run_hook(
+    entry: &ConfigEntry,
+    hook_name: &str,
+    hook: &Option<String>,
+    target_dir: &Path,
+) -> Result<()> {
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+    if !hooks_enabled() {
+        info!(
+            "Skipping {} hook for {} (pass --run-hooks or set CONFINUUM_RUN_HOOKS=1 to run it)",
+            hook_name, entry.name
+        );
+        return Ok(());
     }
+    tracing::info!("Running {} hook for {}: {}", hook_name, entry.name, hook);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .current_dir(target_dir)
+        .env("CONFINUUM_ENTRY", &entry.name)
+        .status()
+        .with_context(|| format!("Could not run {} hook for {}", hook_name, entry.name))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "{} hook for {} failed ({}): {}",
+            hook_name,
+            entry.name,
+            status,
+            hook
+        ));
+    }
+    Ok(())
+}
 
-    let res = config
+/// The entries to (un)deploy: the named entries, or all of them
+/// Entries without files or a target directory are skipped, since there's nothing to deploy
+fn selected_entries<'a>(
+    config: &'a ConfinuumConfig,
+    names: Option<&HashSet<String>>,
+) -> Result<Vec<&'a ConfigEntry>> {
+    if let Some(names) = names {
+        if let Some(name) = names
+            .iter()
+            .find(|name| !config.entries.contains_key(*name))
+        {
+            return Err(config.entry_not_found(name).into());
+        }
+    }
+    Ok(config
         .entries
         .iter()
-        .filter_map(|(entry_name, entry)| {
-            if let Some(name) = &name {
-                if entry_name == name && entry.files.len() > 0 && entry.target_dir.is_some() {
-                    Some(entry)
-                } else {
-                    None
-                }
-            } else {
-                if entry.files.len() > 0 && entry.target_dir.is_some() {
-                    Some(entry)
-                } else {
-                    None
-                }
+        .filter(|(entry_name, _)| names.is_none_or(|names| names.contains(*entry_name)))
+        .map(|(_, entry)| entry)
+        .filter(|entry| !entry.files.is_empty() && entry.deploy_dir().is_some())
+        .collect())
+}
+
+/// Files that more than one entry deploys to, with the two entries, sorted by path
+/// Only entries that deploy on this machine count, and only conflicts involving one of `names`
+/// (or any entry, if None) are returned.
+fn find_conflicts(
+    config: &ConfinuumConfig,
+    names: Option<&HashSet<String>>,
+) -> Vec<(PathBuf, String, String)> {
+    let mut entries = config
+        .entries
+        .values()
+        .filter(|entry| !entry.disabled && entry.mismatch().is_none())
+        .filter(|entry| entry.deploy_dir().is_some())
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut owners = HashMap::new();
+    let mut conflicts = Vec::new();
+    for entry in entries {
+        let deploy_dir = entry.deploy_dir().unwrap();
+        for file in &entry.files {
+            let target = deploy_dir.join(file);
+            if let Some(other) = owners.insert(target.clone(), &entry.name) {
+                conflicts.push((target, other.clone(), entry.name.clone()));
             }
-        })
-        .try_for_each(|entry| -> Result<()> {
-            let target_dir = entry.target_dir.as_ref().unwrap();
-            entry.files.iter().try_for_each(|file| -> Result<()> {
-                let target_path = target_dir.join(&file);
-                let source_path = config_dir.join(&entry.name).join(file);
-                if !source_path.exists() {
-                    return Err(anyhow!(
-                        "File {} does not exist in configs",
-                        source_path.display()
-                    ));
-                }
-                if target_path.exists() {
-                    if target_path.is_symlink() && target_path.read_link()? == source_path {
-                        // If the file is already a symlink to the correct place, do nothing
-                        return Ok(());
-                    }
-                    std::fs::remove_file(&target_path)
-                        .with_context(|| format!("Cannot remove file {}", target_path.display()))?;
-                }
-                std::os::unix::fs::symlink(&source_path, &target_path).with_context(|| {
-                    format!(
-                        "Could not symlink {} to {}",
-                        source_path.display(),
-                        target_path.display()
-                    )
-                })?;
-
-                Ok(())
-            })
-        });
-    if res.is_err() {
-        // If there was an error, undo the symlinks, return the files to their original locations, and return the error
-        config
-            .entries
+        }
+    }
+    conflicts.retain(|(_, a, b)| names.is_none_or(|names| names.contains(a) || names.contains(b)));
+    conflicts.sort();
+    conflicts
+}
+
+/// Check that no file of the given entries (or any entry, if None) deploys to the same path as a
+/// file of another entry, where only one of the links could be. This is done before any link is
+/// created, so a conflict doesn't leave the entries half-deployed. After `allow_conflicts` the
+/// conflicts are only warned about, and the entry last by name wins.
+pub fn check_conflicts(names: Option<&HashSet<String>>) -> Result<()> {
+    let config = ConfinuumConfig::load()?;
+    let conflicts = find_conflicts(&config, names);
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    if ALLOW_CONFLICTS.load(Ordering::Relaxed) {
+        for (target, a, b) in &conflicts {
+            eprintln!(
+                "{} {} is deployed by both {} and {}, linking it to {}",
+                "!".yellow(),
+                target.display(),
+                a,
+                b,
+                b
+            );
+        }
+        return Ok(());
+    }
+    Err(ConfinuumError::DeployConflicts(
+        conflicts
             .iter()
-            .filter_map(|(entry_name, entry)| {
-                if let Some(name) = &name {
-                    if entry_name == name && entry.files.len() > 0 && entry.target_dir.is_some() {
-                        Some(entry)
-                    } else {
-                        None
-                    }
-                } else {
-                    if entry.files.len() > 0 && entry.target_dir.is_some() {
-                        Some(entry)
-                    } else {
-                        None
-                    }
-                }
-            })
-            .try_for_each(|entry| -> Result<()> {
-                let entry_name = &entry.name;
-                let target_dir = entry.target_dir.as_ref().unwrap();
-
-                println!("Error symlinking files, reverting changes...");
-                entry.files.iter().try_for_each(|file| -> Result<()> {
-                    let target_path = target_dir.join(&file);
-                    if !target_path.exists() {
-                        std::fs::copy(&config_dir.join(&entry_name).join(&file), &target_path)
-                            .with_context(|| {
-                                format!(
-                                    "Could not copy {} to {}",
-                                    file.display(),
-                                    target_path.display()
-                                )
-                            })?;
-                    } else if target_path.is_symlink() && target_path.read_link()? == *file {
-                        std::fs::remove_file(&target_path).with_context(|| {
-                            format!("Could not remove {}", target_path.display())
-                        })?;
-                        std::fs::copy(&config_dir.join(&entry_name).join(&file), &target_path)
-                            .with_context(|| {
-                                format!(
-                                    "Could not copy {} to {}",
-                                    config_dir.join(&entry_name).join(&file).display(),
-                                    target_path.display()
-                                )
-                            })?;
-                    }
-                    Ok(())
-                })?;
-
-                Ok(())
+            .map(|(target, a, b)| format!("{} (in {} and {})", target.display(), a, b))
+            .collect(),
+    )
+    .into())
+}
+
+/// Symlink a single file from the config repo to its target
+/// Returns the link path if a new link was created, or None if it was already deployed
+fn link_file(config_dir: &Path, entry: &ConfigEntry, file: &Path) -> Result<Option<PathBuf>> {
+    let target_path = entry.deploy_dir().unwrap().join(file);
+    let source_path = config_dir.join(&entry.name).join(file);
+    if !source_path.exists() {
+        return Err(anyhow!(
+            "File {} does not exist in configs",
+            source_path.display()
+        ));
+    }
+    if target_path.exists() {
+        if target_path.is_symlink() && target_path.read_link()? == source_path {
+            // If the file is already a symlink to the correct place, do nothing
+            return Ok(None);
+        }
+        tracing::debug!("Replacing {}", target_path.display());
+        std::fs::remove_file(&target_path)
+            .with_context(|| format!("Cannot remove file {}", target_path.display()))?;
+    }
+    tracing::debug!(
+        "Linking {} -> {}",
+        target_path.display(),
+        source_path.display()
+    );
+    std::os::unix::fs::symlink(&source_path, &target_path).with_context(|| {
+        format!(
+            "Could not symlink {} to {}",
+            source_path.display(),
+            target_path.display()
+        )
+    })?;
+    Ok(Some(target_path))
+}
+
+/// Whether the entry's target directory is a link to its directory in the repo, as a
+/// directory-mode deploy makes it
+fn links_directory(config_dir: &Path, entry: &ConfigEntry) -> bool {
+    entry
+        .deploy_dir()
+        .and_then(|target_dir| target_dir.read_link().ok())
+        .is_some_and(|link| link == config_dir.join(&entry.name))
+}
+
+/// Whether a file of the entry is deployed, through its own link or a link to the entry's directory
+pub fn is_deployed(config_dir: &Path, entry: &ConfigEntry, file: &Path) -> bool {
+    let source_path = config_dir.join(&entry.name).join(file);
+    if links_directory(config_dir, entry) {
+        return source_path.exists();
+    }
+    entry
+        .deploy_dir()
+        .is_some_and(|target_dir| target_dir.join(file).read_link().ok() == Some(source_path))
+}
+
+/// Link a directory-mode entry's target directory to its directory in the repo
+/// A directory already at the target is removed if all it holds are links into the entry, and
+/// otherwise moved to the backup directory so that nothing in it is lost.
+/// Returns the link path if a new link was created, or None if it was already deployed
+fn link_directory(config_dir: &Path, entry: &ConfigEntry) -> Result<Option<PathBuf>> {
+    let target_dir = entry.deploy_dir().unwrap();
+    let source_dir = config_dir.join(&entry.name);
+    if links_directory(config_dir, entry) {
+        return Ok(None);
+    }
+    if target_dir.is_symlink() {
+        std::fs::remove_file(target_dir)
+            .with_context(|| format!("Cannot remove link {}", target_dir.display()))?;
+    } else if target_dir.is_dir() && only_links_into(target_dir, &source_dir)? {
+        std::fs::remove_dir_all(target_dir)
+            .with_context(|| format!("Cannot remove directory {}", target_dir.display()))?;
+    } else if target_dir.exists() {
+        let backup = backup_target(config_dir, entry)?;
+        info!(
+            "Moved {} to {}, since it has files that aren't part of {}",
+            target_dir.display(),
+            backup.display(),
+            entry.name
+        );
+    } else if let Some(parent) = target_dir.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create directory {}", parent.display()))?;
+    }
+    tracing::debug!(
+        "Linking {} -> {}",
+        target_dir.display(),
+        source_dir.display()
+    );
+    std::os::unix::fs::symlink(&source_dir, target_dir).with_context(|| {
+        format!(
+            "Could not symlink {} to {}",
+            source_dir.display(),
+            target_dir.display()
+        )
+    })?;
+    Ok(Some(target_dir.to_path_buf()))
+}
+
+/// Whether everything in a directory is a link into `source_dir`, like the links a files-mode
+/// deploy of the entry leaves
+fn only_links_into(dir: &Path, source_dir: &Path) -> Result<bool> {
+    for item in std::fs::read_dir(dir)? {
+        let path = item?.path();
+        let only_links = if path.is_symlink() {
+            path.read_link()?.starts_with(source_dir)
+        } else if path.is_dir() {
+            only_links_into(&path, source_dir)?
+        } else {
+            false
+        };
+        if !only_links {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Move whatever is at a directory-mode entry's target directory into the backup directory
+/// Returns where it was moved to.
+fn backup_target(config_dir: &Path, entry: &ConfigEntry) -> Result<PathBuf> {
+    let target_dir = entry.deploy_dir().unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let backup = config_dir
+        .join(BACKUP_DIR)
+        .join(format!("{}-{}", entry.name, now));
+    std::fs::create_dir_all(config_dir.join(BACKUP_DIR))
+        .with_context(|| format!("Could not create {}", BACKUP_DIR))?;
+    // Backups must never end up in the config repo
+    hosting::exclude_from_repo(&format!("{}/", BACKUP_DIR))?;
+    std::fs::rename(target_dir, &backup).with_context(|| {
+        format!(
+            "Could not move {} to {}, move it out of the way and deploy again",
+            target_dir.display(),
+            backup.display()
+        )
+    })?;
+    Ok(backup)
+}
+
+/// Replace a link created by a failed deploy with a copy of the file it points to, so the file is
+/// back in its original location
+fn revert_link(link: &Path) -> Result<()> {
+    let source_path = link.read_link()?;
+    std::fs::remove_file(link).with_context(|| format!("Could not remove {}", link.display()))?;
+    std::fs::copy(&source_path, link).with_context(|| {
+        format!(
+            "Could not copy {} to {}",
+            source_path.display(),
+            link.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// errno for a rename across filesystems (the same on Linux and macOS)
+const EXDEV: i32 = 18;
+
+/// Move a file, e.g. out of the config repo back to where it's deployed
+/// A rename is atomic and keeps the file's permissions and timestamps, but only works within one
+/// filesystem, so across filesystems the file is copied and the original removed instead.
+pub fn move_file(source: &Path, target: &Path) -> Result<()> {
+    move_file_with(source, target, |source, target| {
+        std::fs::rename(source, target)
+    })
+}
+
+/// `move_file`, trying to move the file with `rename` first
+fn move_file_with(
+    source: &Path,
+    target: &Path,
+    rename: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> Result<()> {
+    match rename(source, target) {
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            std::fs::copy(source, target).with_context(|| {
+                format!("Cannot copy {} to {}", source.display(), target.display())
             })?;
+            std::fs::remove_file(source)
+                .with_context(|| format!("Cannot remove {}", source.display()))
+        }
+        result => result
+            .with_context(|| format!("Cannot move {} to {}", source.display(), target.display())),
+    }
+}
+
+/// Combine the errors from parallel work into one, keeping the first as the cause
+pub fn collect_errors(errors: Vec<anyhow::Error>, action: &str) -> Result<()> {
+    let count = errors.len();
+    match errors.into_iter().next() {
+        None => Ok(()),
+        Some(first) if count == 1 => Err(first),
+        Some(first) => Err(first.context(format!("Failed to {} {} files", action, count))),
     }
+}
+
+/// Deploy the given entries, or all entries if None
+/// Each entry is deployed on its own: its pre_deploy hook runs, then its files are linked, then its
+/// post_deploy hook runs. An entry that fails at any step is left undeployed without stopping the
+/// others, and the failures are reported once every entry has been attempted.
+pub fn deploy(names: Option<&HashSet<String>>) -> Result<()> {
+    check_conflicts(names)?;
+    let config = ConfinuumConfig::load()?;
+    let config_dir = ConfinuumConfig::get_dir().context("Could not get config dir")?;
+    let mut entries = selected_entries(&config, names)?;
+    // Undeploying doesn't check these, so links left from before an entry was disabled or limited
+    // to other machines are removed
+    entries.retain(|entry| {
+        let reason = match entry.disabled {
+            true => Some("disabled"),
+            false => entry.mismatch(),
+        };
+        match reason {
+            Some(reason) => {
+                info!("{} skipped ({})", entry.name, reason);
+                false
+            }
+            None => true,
+        }
+    });
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // Conflicting files (only left with `allow_conflicts`) are linked once, for the last entry by name
+    let owners = entries
+        .iter()
+        .filter(|entry| entry.link_mode == LinkMode::Files)
+        .flat_map(|entry| {
+            let target_dir = entry.deploy_dir().unwrap();
+            entry
+                .files
+                .iter()
+                .map(move |file| (target_dir.join(file), entry.name.as_str()))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut failed = Vec::new();
+    for entry in &entries {
+        let target_dir = entry.deploy_dir().unwrap();
+        let files = entry
+            .files
+            .iter()
+            .filter(|file| owners.get(&target_dir.join(file)) == Some(&entry.name.as_str()))
+            .collect::<Vec<_>>();
+        if let Err(e) = deploy_entry(&config_dir, entry, &files) {
+            failed.push((entry.name.as_str(), e));
+        }
+    }
+    match failed.len() {
+        0 => Ok(()),
+        1 => {
+            let (name, e) = failed.remove(0);
+            Err(e.context(format!("Could not deploy {}", name)))
+        }
+        _ => {
+            for (name, e) in &failed {
+                eprintln!("{} Could not deploy {}: {:#}", "!".yellow(), name, e);
+            }
+            let names = failed.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            Err(anyhow!("Could not deploy {}", names.join(", ")))
+        }
+    }
+}
+
+/// Run an entry's pre_deploy hook, link the given files (or its directory, in directory mode),
+/// and run its post_deploy hook
+/// If linking or the post_deploy hook fails, the links created for the entry are reverted.
+fn deploy_entry(config_dir: &Path, entry: &ConfigEntry, files: &[&PathBuf]) -> Result<()> {
+    let target_dir = entry.deploy_dir().unwrap();
+    let hook_dir = match entry.link_mode {
+        // The target directory only exists once it's linked, so the hook runs in its parent
+        LinkMode::Directory => target_dir.parent().unwrap_or(target_dir),
+        LinkMode::Files => {
+            // Left from deploying the entry as a directory, and files linked through it would
+            // replace the files in the repo
+            if links_directory(config_dir, entry) {
+                std::fs::remove_file(target_dir)
+                    .with_context(|| format!("Cannot remove link {}", target_dir.display()))?;
+            }
+            target_dir
+        }
+    };
+    // Created up front so the hook has a directory to run in. Undeploying leaves it in place.
+    std::fs::create_dir_all(hook_dir)
+        .with_context(|| format!("Could not create directory {}", hook_dir.display()))?;
+    run_hook(entry, "pre_deploy", &entry.pre_deploy, hook_dir)?;
 
+    let linked = match entry.link_mode {
+        LinkMode::Directory => link_directory(config_dir, entry)?.into_iter().collect(),
+        LinkMode::Files => link_files(config_dir, entry, files)?,
+    };
+    if let Err(e) = run_hook(entry, "post_deploy", &entry.post_deploy, target_dir) {
+        info!("Reverting the links to {}...", entry.name);
+        revert_links(entry, &linked)?;
+        return Err(e);
+    }
     Ok(())
 }
 
-pub fn undeploy(name: Option<impl Into<String>>) -> Result<()> {
+/// Link the given files of a files-mode entry, in parallel
+/// Every file is attempted before reverting, so no link is reverted while it's being created.
+/// Returns the links that were created.
+fn link_files(config_dir: &Path, entry: &ConfigEntry, files: &[&PathBuf]) -> Result<Vec<PathBuf>> {
+    let target_dir = entry.deploy_dir().unwrap();
+    // On a fresh machine the directories the files live in may not exist yet. They're created
+    // here rather than by each link, so that parallel links don't race to create them.
+    let dirs = files
+        .iter()
+        .filter_map(|file| target_dir.join(file).parent().map(Path::to_path_buf))
+        .collect::<BTreeSet<_>>();
+    for dir in dirs.iter().filter(|dir| !dir.exists()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create directory {}", dir.display()))?;
+    }
+    let (linked, errors): (Vec<_>, Vec<_>) = par_progress(
+        files,
+        "Linking files",
+        |file| target_dir.join(file),
+        |file| link_file(config_dir, entry, file),
+    )
+    .into_iter()
+    .partition(|res| res.is_ok());
+    let linked = linked
+        .into_iter()
+        .filter_map(|res| res.ok().flatten())
+        .collect::<Vec<_>>();
+    let errors = errors
+        .into_iter()
+        .filter_map(|res| res.err())
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        info!("Error symlinking files, reverting changes...");
+        revert_links(entry, &linked)?;
+    }
+    collect_errors(errors, "deploy")?;
+    Ok(linked)
+}
+
+/// Undo the links a deploy of the entry created, returning the files to their original locations
+fn revert_links(entry: &ConfigEntry, links: &[PathBuf]) -> Result<()> {
+    let errors = match entry.link_mode {
+        // Anything a directory link replaced is in the backup directory, so the link is just removed
+        LinkMode::Directory => links
+            .iter()
+            .filter_map(|link| {
+                std::fs::remove_file(link)
+                    .with_context(|| format!("Could not remove {}", link.display()))
+                    .err()
+            })
+            .collect(),
+        LinkMode::Files => links
+            .par_iter()
+            .filter_map(|link| revert_link(link).err())
+            .collect(),
+    };
+    collect_errors(errors, "revert")
+}
+
+/// The files at the given entries' target paths (or all entries' if None) that deploying would
+/// replace: anything that isn't already a link to the entry's file, or to another entry's file
+/// where entries conflict
+/// Entries that aren't deployed on this machine are left out, and so are directory-mode entries,
+/// since deploying them moves what's in the way to the backup directory on its own.
+pub fn foreign_files(names: Option<&HashSet<String>>) -> Result<Vec<PathBuf>> {
     let config = ConfinuumConfig::load()?;
     let config_dir = ConfinuumConfig::get_dir()?;
-    let name: Option<String> = name.map(|n| n.into());
-    if let Some(name) = &name {
-        if !config.entries.contains_key(name) {
-            return Err(anyhow!("No entry named {} found", name));
+    let entries = selected_entries(&config, names)?
+        .into_iter()
+        .filter(|entry| !entry.disabled && entry.mismatch().is_none())
+        .filter(|entry| entry.link_mode == LinkMode::Files)
+        .filter(|entry| !links_directory(&config_dir, entry))
+        .collect::<Vec<_>>();
+    let mut foreign = deployed_files(&config_dir, &entries)
+        .into_iter()
+        .filter(|(target, _)| target.exists() || target.is_symlink())
+        .filter(|(target, source)| {
+            target.read_link().map_or(true, |link| {
+                link != *source && !(link.starts_with(&config_dir) && target.exists())
+            })
+        })
+        .map(|(target, _)| target)
+        .collect::<Vec<_>>();
+    foreign.sort();
+    Ok(foreign)
+}
+
+/// Move files out of the way into a new directory under the backup directory, keeping their
+/// absolute paths below it (so ~/.zshrc ends up in .backups/<dir>/home/<user>/.zshrc)
+/// Returns the directory they were moved to.
+pub fn backup_files(files: &[PathBuf], label: &str) -> Result<PathBuf> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let backup_dir = config_dir
+        .join(BACKUP_DIR)
+        .join(format!("{}-{}", label, now));
+    std::fs::create_dir_all(&backup_dir)
+        .with_context(|| format!("Could not create {}", backup_dir.display()))?;
+    // Backups must never end up in the config repo
+    hosting::exclude_from_repo(&format!("{}/", BACKUP_DIR))?;
+    for file in files {
+        let backup = backup_dir.join(file.strip_prefix("/").unwrap_or(file));
+        tracing::info!("Backing up {} to {}", file.display(), backup.display());
+        if let Some(parent) = backup.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Could not create {}", parent.display()))?;
+        }
+        // Renaming fails across filesystems, where the file has to be copied instead
+        if std::fs::rename(file, &backup).is_err() {
+            copy_recursive(file, &backup)?;
+            if file.is_dir() && !file.is_symlink() {
+                std::fs::remove_dir_all(file)
+            } else {
+                std::fs::remove_file(file)
+            }
+            .with_context(|| format!("Could not remove {}", file.display()))?;
         }
     }
+    Ok(backup_dir)
+}
 
-    config
-        .entries
+/// Undeploy the given entries, or all entries if None
+/// Only links pointing at the entries' files in the repo are removed. Returns the removed links.
+pub fn undeploy(names: Option<&HashSet<String>>) -> Result<Vec<PathBuf>> {
+    let config = ConfinuumConfig::load()?;
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let entries = selected_entries(&config, names)?;
+
+    // Directory links are checked whatever the entry's mode, in case it was changed since deploying
+    let mut removed = Vec::new();
+    for entry in &entries {
+        if links_directory(&config_dir, entry) {
+            let target_dir = entry.deploy_dir().unwrap();
+            std::fs::remove_file(target_dir)
+                .with_context(|| format!("Could not remove {}", target_dir.display()))?;
+            removed.push(target_dir.to_path_buf());
+        }
+    }
+    let (links, errors): (Vec<_>, Vec<_>) = deployed_files(&config_dir, &entries)
+        .into_par_iter()
+        .filter(|(symlink, expected_target)| {
+            symlink.exists()
+                && symlink.is_symlink()
+                && symlink
+                    .read_link()
+                    .is_ok_and(|link_target| link_target == *expected_target)
+        })
+        .map(|(symlink, _)| {
+            tracing::debug!("Removing link {}", symlink.display());
+            std::fs::remove_file(&symlink)
+                .with_context(|| format!("Could not remove {}", symlink.display()))
+                .map(|_| symlink)
+        })
+        .partition(|res| res.is_ok());
+    collect_errors(
+        errors.into_iter().filter_map(|res| res.err()).collect(),
+        "undeploy",
+    )?;
+    removed.extend(links.into_iter().filter_map(|res| res.ok()));
+    Ok(removed)
+}
+
+/// Replace the given entries' links (or all entries' if None) with copies of the files they point
+/// to, so the files stay in place without confinuum
+/// Files that are missing from their target are copied there too if the entry would be deployed
+/// on this machine, while anything else at a target path is left alone. Returns the restored
+/// files.
+pub fn restore_files(names: Option<&HashSet<String>>) -> Result<Vec<PathBuf>> {
+    let config = ConfinuumConfig::load()?;
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let entries = selected_entries(&config, names)?;
+
+    // A directory link is replaced with a copy of the whole directory, including files programs
+    // created in it that aren't part of the entry
+    let mut restored_dirs = Vec::new();
+    for entry in &entries {
+        if links_directory(&config_dir, entry) {
+            let target_dir = entry.deploy_dir().unwrap();
+            std::fs::remove_file(target_dir)
+                .with_context(|| format!("Could not remove {}", target_dir.display()))?;
+            copy_recursive(&config_dir.join(&entry.name), target_dir)?;
+            restored_dirs.push(target_dir.to_path_buf());
+        }
+    }
+    let deployable = entries
         .iter()
-        .filter_map(|(entry_name, entry)| {
-            if let Some(name) = &name {
-                if entry_name == name && entry.files.len() > 0 && entry.target_dir.is_some() {
-                    Some(entry)
-                } else {
-                    None
+        .filter(|entry| !entry.disabled && entry.mismatch().is_none())
+        .flat_map(|entry| deployed_files(&config_dir, &[*entry]))
+        .map(|(target, _)| target)
+        .collect::<HashSet<_>>();
+
+    let (restored, errors): (Vec<_>, Vec<_>) = deployed_files(&config_dir, &entries)
+        .into_par_iter()
+        .filter_map(|(target, source)| {
+            if !source.exists() {
+                return None;
+            }
+            if target.is_symlink() {
+                // Links that point elsewhere aren't confinuum's to replace
+                if target.read_link().ok()? != source {
+                    return None;
                 }
+                Some(revert_link(&target).map(|_| target))
+            } else if !target.exists() && deployable.contains(&target) {
+                Some(copy_file(&source, &target).map(|_| target))
             } else {
-                if entry.files.len() > 0 && entry.target_dir.is_some() {
-                    Some(entry)
-                } else {
-                    None
-                }
+                None
             }
         })
-        .try_for_each(|entry| -> Result<()> {
-            let entry_name = &entry.name;
-            let target_dir = entry.target_dir.as_ref().unwrap();
+        .partition(|res| res.is_ok());
+    collect_errors(
+        errors.into_iter().filter_map(|res| res.err()).collect(),
+        "restore",
+    )?;
+    restored_dirs.extend(restored.into_iter().filter_map(|res| res.ok()));
+    Ok(restored_dirs)
+}
+
+/// Each file of the entries as the path it's deployed to and the file in the repo it links to
+fn deployed_files(config_dir: &Path, entries: &[&ConfigEntry]) -> Vec<(PathBuf, PathBuf)> {
+    entries
+        .iter()
+        .flat_map(|entry| {
+            let target_dir = entry.deploy_dir().unwrap();
+            entry.files.iter().map(move |file| {
+                (
+                    target_dir.join(file),
+                    config_dir.join(&entry.name).join(file),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Copy a file from the repo to a target path that doesn't exist yet
+fn copy_file(source: &Path, target: &Path) -> Result<()> {
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Could not create directory {}", parent.display()))?;
+    }
+    std::fs::copy(source, target).with_context(|| {
+        format!(
+            "Could not copy {} to {}",
+            source.display(),
+            target.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// Remove deployed symlinks for files that were removed from an entry's repo directory
+/// Only symlinks pointing into the repo are removed, and dangling symlinks are handled
+/// Returns the removed symlink paths
+pub fn remove_stale_links(entry: &ConfigEntry, files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let Some(target_dir) = entry.deploy_dir() else {
+        return Ok(Vec::new());
+    };
+    let mut removed = Vec::new();
+    for file in files {
+        let symlink = target_dir.join(file);
+        let expected_target = config_dir.join(&entry.name).join(file);
+        // is_symlink doesn't follow the link, so this also catches dangling symlinks
+        if symlink.is_symlink() && symlink.read_link()? == expected_target {
+            std::fs::remove_file(&symlink)
+                .with_context(|| format!("Could not remove {}", symlink.display()))?;
+            removed.push(symlink);
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove every link of an entry as `entry` describes it, including dangling ones
+/// For an entry an update removed or moved elsewhere, whose old links deploying wouldn't replace
+/// Returns the removed link paths
+pub fn remove_entry_links(entry: &ConfigEntry) -> Result<Vec<PathBuf>> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    let mut removed = Vec::new();
+    if links_directory(&config_dir, entry) {
+        let target_dir = entry.deploy_dir().unwrap();
+        std::fs::remove_file(target_dir)
+            .with_context(|| format!("Could not remove {}", target_dir.display()))?;
+        removed.push(target_dir.to_path_buf());
+    }
+    let files = entry.files.iter().cloned().collect::<Vec<_>>();
+    removed.extend(remove_stale_links(entry, &files)?);
+    Ok(removed)
+}
+
+/// Copy a file or directory, keeping symlinks as links
+pub fn copy_recursive(source: &Path, destination: &Path) -> Result<()> {
+    let context = || {
+        format!(
+            "Could not copy {} to {}",
+            source.display(),
+            destination.display()
+        )
+    };
+    if source.is_symlink() {
+        std::os::unix::fs::symlink(source.read_link()?, destination).with_context(context)?;
+    } else if source.is_dir() {
+        std::fs::create_dir_all(destination).with_context(context)?;
+        for item in std::fs::read_dir(source)? {
+            let item = item?;
+            copy_recursive(&item.path(), &destination.join(item.file_name()))?;
+        }
+    } else {
+        std::fs::copy(source, destination).with_context(context)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{
+        cli,
+        config::{tests::with_env, GitProtocol, SignatureSource},
+    };
+
+    #[test]
+    fn deploy_nested_files_into_empty_home() {
+        let home = TempDir::new("confinuum-test").unwrap();
+        let config_dir = home.path().join(".config/confinuum");
+        let target_dir = home.path().join(".config/nvim");
+        let vars = [
+            ("HOME", Some(home.path())),
+            ("CONFINUUM_CONFIG_DIR", Some(config_dir.as_path())),
+        ];
+        with_env(&vars, || {
+            cli::disable_progress();
+            let mut config = ConfinuumConfig::init(GitProtocol::Ssh, SignatureSource::GitConfig);
+            let mut entry = ConfigEntry::new("nvim".to_owned());
+            entry.target_dir = Some(target_dir.clone());
+            entry.files.insert(PathBuf::from("init.lua"));
             entry
                 .files
-                .iter()
-                .map(|file| {
-                    (
-                        target_dir.join(file),
-                        config_dir.join(entry_name).join(file),
-                    )
-                })
-                .try_for_each(|(symlink, expected_target)| -> Result<()> {
-                    if symlink.exists() && symlink.is_symlink() {
-                        if let Ok(link_target) = symlink.read_link() {
-                            if link_target == expected_target {
-                                std::fs::remove_file(symlink)?;
-                            }
-                        }
-                    }
-                    Ok(())
-                })?;
-            Ok(())
-        })?;
+                .insert(PathBuf::from("lua/plugins/telescope.lua"));
+            config.entries.insert("nvim".to_owned(), entry);
+            config.save().unwrap();
+            let source_dir = config_dir.join("nvim/lua/plugins");
+            std::fs::create_dir_all(&source_dir).unwrap();
+            std::fs::write(config_dir.join("nvim/init.lua"), "").unwrap();
+            std::fs::write(source_dir.join("telescope.lua"), "").unwrap();
 
-    Ok(())
+            deploy(None).unwrap();
+
+            for file in ["init.lua", "lua/plugins/telescope.lua"] {
+                let link = target_dir.join(file);
+                assert_eq!(
+                    link.read_link().unwrap(),
+                    config_dir.join("nvim").join(file)
+                );
+            }
+            // The directories in between are created as real directories, not links
+            for dir in [
+                ".config/nvim",
+                ".config/nvim/lua",
+                ".config/nvim/lua/plugins",
+            ] {
+                let meta = std::fs::symlink_metadata(home.path().join(dir)).unwrap();
+                assert!(meta.is_dir(), "{} is not a directory", dir);
+            }
+        });
+    }
+
+    #[test]
+    fn failing_hooks_only_undeploy_their_entry() {
+        let home = TempDir::new("confinuum-test").unwrap();
+        let config_dir = home.path().join(".config/confinuum");
+        let vars = [
+            ("HOME", Some(home.path())),
+            ("CONFINUUM_CONFIG_DIR", Some(config_dir.as_path())),
+            ("CONFINUUM_RUN_HOOKS", Some(Path::new("1"))),
+        ];
+        with_env(&vars, || {
+            cli::disable_progress();
+            let mut config = ConfinuumConfig::init(GitProtocol::Ssh, SignatureSource::GitConfig);
+            let hooks = [
+                ("bash", Some("exit 1"), None),
+                ("nvim", None, Some("exit 1")),
+                ("zsh", Some("true"), Some("true")),
+            ];
+            for (name, pre_deploy, post_deploy) in hooks {
+                let mut entry = ConfigEntry::new(name.to_owned());
+                entry.target_dir = Some(home.path().join(name));
+                entry.files.insert(PathBuf::from("config"));
+                entry.pre_deploy = pre_deploy.map(str::to_owned);
+                entry.post_deploy = post_deploy.map(str::to_owned);
+                config.entries.insert(name.to_owned(), entry);
+                std::fs::create_dir_all(config_dir.join(name)).unwrap();
+                std::fs::write(config_dir.join(name).join("config"), name).unwrap();
+            }
+            config.save().unwrap();
+
+            let error = deploy(None).unwrap_err();
+            assert_eq!(error.to_string(), "Could not deploy bash, nvim");
+            // The pre_deploy hook failed before anything was linked
+            assert!(!home.path().join("bash/config").exists());
+            // The post_deploy hook failed, so the link was reverted to a copy
+            let nvim = home.path().join("nvim/config");
+            assert!(!nvim.is_symlink());
+            assert_eq!(std::fs::read_to_string(nvim).unwrap(), "nvim");
+            assert_eq!(
+                home.path().join("zsh/config").read_link().unwrap(),
+                config_dir.join("zsh/config")
+            );
+        });
+    }
+
+    /// Redeploys a synthetic config of 5k files, the scale parallel linking is meant for.
+    /// Run with `--nocapture` to see the timings.
+    #[test]
+    fn redeploy_5k_files() {
+        let home = TempDir::new("confinuum-test").unwrap();
+        let config_dir = home.path().join(".config/confinuum");
+        let vars = [
+            ("HOME", Some(home.path())),
+            ("CONFINUUM_CONFIG_DIR", Some(config_dir.as_path())),
+        ];
+        with_env(&vars, || {
+            cli::disable_progress();
+            let mut config = ConfinuumConfig::init(GitProtocol::Ssh, SignatureSource::GitConfig);
+            for i in 0..50 {
+                let name = format!("entry{}", i);
+                let mut entry = ConfigEntry::new(name.clone());
+                entry.target_dir = Some(home.path().join(&name));
+                for j in 0..100 {
+                    let file = PathBuf::from(format!("dir{}/file{}", j % 10, j));
+                    let source = config_dir.join(&name).join(&file);
+                    std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+                    std::fs::write(source, "").unwrap();
+                    entry.files.insert(file);
+                }
+                config.entries.insert(name, entry);
+            }
+            config.save().unwrap();
+
+            let start = std::time::Instant::now();
+            deploy(None).unwrap();
+            let deployed = start.elapsed();
+            let start = std::time::Instant::now();
+            let removed = undeploy(None).unwrap();
+            let undeployed = start.elapsed();
+            let start = std::time::Instant::now();
+            deploy(None).unwrap();
+            let redeployed = start.elapsed();
+            eprintln!(
+                "5000 files: deploy {:?}, undeploy {:?}, deploy again {:?}",
+                deployed, undeployed, redeployed
+            );
+
+            assert_eq!(removed.len(), 5000);
+            let linked = deployed_files(&config_dir, &config.entries.values().collect::<Vec<_>>())
+                .into_iter()
+                .filter(|(target, source)| target.read_link().ok().as_ref() == Some(source))
+                .count();
+            assert_eq!(linked, 5000);
+        });
+    }
+
+    #[test]
+    fn move_file_renames() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        std::fs::write(&source, "contents").unwrap();
+        move_file(&source, &target).unwrap();
+        assert!(!source.exists());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "contents");
+    }
+
+    #[test]
+    fn move_file_copies_across_filesystems() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        std::fs::write(&source, "contents").unwrap();
+        move_file_with(&source, &target, |_, _| {
+            Err(std::io::Error::from_raw_os_error(EXDEV))
+        })
+        .unwrap();
+        assert!(!source.exists());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "contents");
+    }
+
+    #[test]
+    fn move_file_keeps_the_source_when_renaming_fails() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        std::fs::write(&source, "contents").unwrap();
+        let result = move_file_with(&source, &target, |_, _| {
+            Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+        });
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&source).unwrap(), "contents");
+        assert!(!target.exists());
+    }
 }
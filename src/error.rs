@@ -0,0 +1,174 @@
+//! Error types for confinuum
+//! Commands return these for the common failure cases so they can be told apart (e.g. for exit
+//! codes), while anyhow is still used to wrap lower-level errors.
+
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::config::Hosting;
+
+#[derive(Debug, Error)]
+pub enum ConfinuumError {
+    #[error("Config file does not exist. Run `confinuum init` to create one.")]
+    NotInitialized,
+    #[error("Config file already exists. Use --force to overwrite.")]
+    AlreadyInitialized,
+    /// No entry has the name, with the names of the entries it might be a typo of
+    #[error("No entry named {0} found{}", did_you_mean(.1))]
+    EntryNotFound(String, Vec<String>),
+    #[error("Entry named {0} already exists! Use the `add` and `remove` subcommands to add or remove files from it.")]
+    EntryExists(String),
+    /// The entry name can't be used, with the rule it breaks
+    #[error("Invalid entry name {0:?}: {1}")]
+    InvalidEntryName(String, &'static str),
+    /// The repository name isn't allowed by the hosting service, with the rule it breaks
+    #[error("Invalid repository name {0:?}: {1}")]
+    InvalidRepoName(String, &'static str),
+    /// The entry has no target directory yet, so none of its files are deployed
+    #[error(
+        "Entry {0} has no target directory, add files to it with `confinuum entry {0} add` first"
+    )]
+    NoTargetDir(String),
+    /// The remote has changes that need to be pulled before the given action can be performed
+    #[error("Changes found on remote. Please pull them before {0}.")]
+    RemoteAhead(&'static str),
+    #[error(
+        "The config repo doesn't have a remote yet. Run `confinuum remote add <url>` to add one."
+    )]
+    NoRemote,
+    #[error("A repository named {0} already exists on your account")]
+    RepoExists(String),
+    #[error("Authentication failed")]
+    AuthFailed,
+    #[error("Not logged in to {0}. Run `{}` to log in.", .0.login_command())]
+    NotLoggedIn(Hosting),
+    #[error("Your {0} authorization has expired. Run `{}` to log in again.", .0.login_command())]
+    AuthExpired(Hosting),
+    #[error("{} has permissions {:o}, so other users can access your token. Run `chmod 600 {}` to fix this, or pass --insecure-auth-files to use it anyway.", .0.display(), .1, .0.display())]
+    InsecureAuthFile(PathBuf, u32),
+    #[error("config.toml was written by a newer version of confinuum (config version {0}, this version supports up to {1}). Please upgrade confinuum.")]
+    ConfigTooNew(u32, u32),
+    #[error("Merge conflicts detected, resolve them in the config repository and try again")]
+    MergeConflict,
+    /// Another confinuum process is changing the config, described by its pid
+    #[error("Another confinuum process ({0}) is still changing the config. Try again once it has finished.")]
+    Locked(String),
+    /// Files that more than one entry deploys to, each with the entries
+    #[error("More than one entry deploys the same files, so only one of them could be linked:\n  {}\nRemove the file from one of the entries, or run `confinuum redeploy --force` to deploy anyway.", .0.join("\n  "))]
+    DeployConflicts(Vec<String>),
+    /// Stopped by Ctrl-C or SIGTERM
+    #[error("Interrupted")]
+    Interrupted,
+}
+
+/// Suggest the names the user may have meant, as the end of an error message
+fn did_you_mean(names: &[String]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => format!(", did you mean `{}`?", name),
+        [names @ .., last] => format!(
+            ", did you mean {} or `{}`?",
+            names
+                .iter()
+                .map(|name| format!("`{}`", name))
+                .collect::<Vec<_>>()
+                .join(", "),
+            last
+        ),
+    }
+}
+
+/// Exit codes returned by confinuum, so that scripts can react to specific failures
+/// Keep this in sync with the exit status section of the CLI help
+pub mod exit_code {
+    /// Any error not covered by a more specific code
+    pub const FAILURE: u8 = 1;
+    /// Confinuum has not been initialized on this machine
+    pub const NOT_INITIALIZED: u8 = 2;
+    /// The remote has changes that need to be pulled with `confinuum update`
+    pub const REMOTE_AHEAD: u8 = 3;
+    /// Authentication with the remote or the hosting service failed
+    pub const AUTH_FAILED: u8 = 4;
+    /// Merging remote changes resulted in conflicts
+    pub const MERGE_CONFLICT: u8 = 5;
+    /// The hosting service rejected the stored token and the user didn't log in again
+    pub const AUTH_EXPIRED: u8 = 6;
+    /// Another confinuum process kept the config locked for too long
+    pub const LOCKED: u8 = 7;
+    /// Stopped by Ctrl-C or SIGTERM, like shells report for SIGINT
+    pub const INTERRUPTED: u8 = 130;
+}
+
+impl ConfinuumError {
+    /// The exit code the process should return when failing with this error
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ConfinuumError::NotInitialized => exit_code::NOT_INITIALIZED,
+            ConfinuumError::RemoteAhead(_) => exit_code::REMOTE_AHEAD,
+            ConfinuumError::AuthFailed | ConfinuumError::NotLoggedIn(_) => exit_code::AUTH_FAILED,
+            ConfinuumError::MergeConflict => exit_code::MERGE_CONFLICT,
+            ConfinuumError::AuthExpired(_) => exit_code::AUTH_EXPIRED,
+            ConfinuumError::Locked(_) => exit_code::LOCKED,
+            ConfinuumError::Interrupted => exit_code::INTERRUPTED,
+            _ => exit_code::FAILURE,
+        }
+    }
+}
+
+/// Find the exit code for an error returned by a command, looking through the whole error chain
+pub fn exit_code_for(error: &anyhow::Error) -> u8 {
+    // A ConfinuumError attached with `.context(..)` isn't one of the chain's causes as far as
+    // downcasting the causes goes, but anyhow's own downcast finds it
+    if let Some(e) = error.downcast_ref::<ConfinuumError>() {
+        return e.exit_code();
+    }
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<ConfinuumError>() {
+            return e.exit_code();
+        }
+        if let Some(e) = cause.downcast_ref::<git2::Error>() {
+            if e.code() == git2::ErrorCode::Auth {
+                return exit_code::AUTH_FAILED;
+            }
+        }
+    }
+    exit_code::FAILURE
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn exit_code_for_root_error() {
+        let error = anyhow::Error::from(ConfinuumError::RemoteAhead("pushing"));
+        assert_eq!(exit_code_for(&error), exit_code::REMOTE_AHEAD);
+    }
+
+    #[test]
+    fn exit_code_for_context() {
+        let error = anyhow!("Bad credentials").context(ConfinuumError::AuthFailed);
+        assert_eq!(exit_code_for(&error), exit_code::AUTH_FAILED);
+        let error = error.context("Could not log in");
+        assert_eq!(exit_code_for(&error), exit_code::AUTH_FAILED);
+    }
+
+    #[test]
+    fn exit_code_for_expired_token() {
+        // How GitHub's reauthentication reports a token that's still rejected after logging in again
+        let error =
+            anyhow!("Bad credentials").context(ConfinuumError::AuthExpired(Hosting::Github));
+        assert_eq!(exit_code_for(&error), exit_code::AUTH_EXPIRED);
+    }
+
+    #[test]
+    fn exit_code_for_other_errors() {
+        assert_eq!(
+            exit_code_for(&anyhow!("Something else")),
+            exit_code::FAILURE
+        );
+    }
+}
@@ -1,31 +1,56 @@
 //! Git-related functionality for confinuum
 
 use anyhow::{anyhow, Context, Result};
-use crossterm::{
-    cursor::MoveToColumn,
-    style::{self, Print, Stylize},
-};
+use crossterm::style::{self, Print, Stylize};
 use dialoguer::theme::ColorfulTheme;
 
 use email_address::EmailAddress;
 use git2::{
-    Commit, Config, Diff, DiffDelta, DiffFormat, DiffHunk, DiffLine, ObjectType, PackBuilderStage,
-    Progress, Repository, Signature,
+    AnnotatedCommit, BranchType, Commit, Config, Delta, Diff, DiffDelta, DiffFindOptions,
+    DiffFormat, DiffHunk, DiffLine, DiffLineType, DiffOptions, ErrorClass, ErrorCode, FetchOptions,
+    ObjectType, PackBuilderStage, Progress, Remote, Repository, Signature, Tree,
 };
+use similar::ChangeTag;
 
-use spinoff::Spinner;
+use spinoff::{spinners, Color, Spinner};
 
 use std::{
     cell::RefCell,
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Write as _,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::Command,
     rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
 };
 
-use crate::config::ConfinuumConfig;
+use crate::{
+    cli::{self, info, CreateSharedSpinner, ProgressSpinner, SharedSpinner},
+    config::{ConfinuumConfig, SignatureSource},
+    error::ConfinuumError,
+    gitea::Gitea,
+    github::{self, Github},
+    gitlab::Gitlab,
+    hosting::Provider,
+    interrupt,
+};
 
 pub trait RepoExtensions {
     fn find_last_commit(&self) -> anyhow::Result<Commit>;
+    /// Commit `tree` onto HEAD, refusing to if config.toml isn't in it
+    /// A commit without config.toml would make every entry vanish from the config on each machine
+    /// that pulls it, e.g. if it had been untracked by hand.
+    fn commit_with_config(
+        &self,
+        sig: &Signature,
+        message: &str,
+        tree: &Tree,
+        parents: &[&Commit],
+    ) -> anyhow::Result<git2::Oid>;
 }
 
 impl RepoExtensions for Repository {
@@ -34,9 +59,26 @@ impl RepoExtensions for Repository {
         obj.into_commit()
             .map_err(|_| anyhow!("Couldn't find commit"))
     }
+
+    fn commit_with_config(
+        &self,
+        sig: &Signature,
+        message: &str,
+        tree: &Tree,
+        parents: &[&Commit],
+    ) -> anyhow::Result<git2::Oid> {
+        if tree.get_name("config.toml").is_none() {
+            return Err(anyhow!(
+                "Refusing to commit, config.toml would be missing from the commit. Run `git add config.toml` in {} to track it again.",
+                self.workdir().unwrap_or(self.path()).display()
+            ));
+        }
+        let message = final_message(self, message)?;
+        Ok(self.commit(Some("HEAD"), sig, sig, &message, tree, parents)?)
+    }
 }
 
-fn find_ssh_key() -> anyhow::Result<PathBuf> {
+pub fn find_ssh_key() -> anyhow::Result<PathBuf> {
     let ssh_dir =
         PathBuf::from(std::env::var("HOME").context("Could not find home directory")?).join(".ssh");
 
@@ -45,15 +87,554 @@ fn find_ssh_key() -> anyhow::Result<PathBuf> {
         .map(|key| ssh_dir.join(key))
         .find(|key| key.exists())
         .ok_or_else(|| anyhow!("No SSH key found"))?;
+    tracing::debug!("Using SSH key {}", key.display());
 
     Ok(key)
 }
 
+/// Whether a git error was caused by being unable to reach the remote, as opposed to the remote
+/// rejecting or not containing something
+pub fn is_network_error(error: &git2::Error) -> bool {
+    matches!(
+        error.class(),
+        ErrorClass::Net | ErrorClass::Http | ErrorClass::Ssh | ErrorClass::Os
+    )
+}
+
+/// Find the `origin` remote, which a config initialized with `init --local` doesn't have
+pub fn find_origin(repo: &Repository) -> Result<Remote<'_>> {
+    match repo.find_remote("origin") {
+        Err(e) if e.code() == ErrorCode::NotFound => Err(ConfinuumError::NoRemote.into()),
+        remote => remote.context("Failed to find remote named 'origin'"),
+    }
+}
+
+/// The full name of the local `branch`
+pub fn branch_ref(branch: &str) -> String {
+    format!("refs/heads/{}", branch)
+}
+
+/// The refspec that pushes `source`, a local reference, to `branch` on origin
+pub fn push_refspec(source: &str, branch: &str) -> String {
+    format!("{}:{}", source, branch_ref(branch))
+}
+
+/// The refspec that pushes the local `branch` to the same branch on origin
+pub fn branch_refspec(branch: &str) -> String {
+    push_refspec(&branch_ref(branch), branch)
+}
+
+/// Fetch `branch` from origin and return the fetched commit, which is also left in FETCH_HEAD
+/// Fetching connects and authenticates on its own, so there's no separate `connect_auth`: doing
+/// both authenticates twice, which prompts for credentials twice when they aren't stored.
+pub fn fetch_origin<'r>(
+    repo: &'r Repository,
+    spinner: &Rc<RefCell<ProgressSpinner>>,
+    branch: &str,
+) -> Result<AnnotatedCommit<'r>> {
+    let mut remote = find_origin(repo)?;
+    let mut fetch_opt = FetchOptions::new();
+    fetch_opt.update_fetchhead(true);
+    fetch_opt.remote_callbacks(construct_callbacks(spinner.clone()));
+    // Ctrl-C cancels the transfer from its progress callback instead of exiting mid-fetch
+    let _deferred = interrupt::Deferred::new();
+    tracing::info!(
+        "Fetching {} from origin ({})",
+        branch,
+        remote.url().unwrap_or("invalid url")
+    );
+    match remote.fetch(&[branch], Some(&mut fetch_opt), None) {
+        Err(_) if interrupt::interrupted() => return Err(ConfinuumError::Interrupted.into()),
+        result => result.context("Failed to fetch from remote 'origin'")?,
+    }
+    remote.disconnect()?;
+
+    let fetch_head = repo.find_reference("FETCH_HEAD")?;
+    let fetch_commit = repo.reference_to_annotated_commit(&fetch_head)?;
+    tracing::debug!("Fetched {} at {}", branch, fetch_commit.id());
+    Ok(fetch_commit)
+}
+
+/// Count the commits on the local branch that the last fetched state of origin doesn't have
+/// Only the refs are read, the remote isn't contacted. Returns None if origin has no such branch
+/// (the config was never pushed, or has no remote).
+pub fn unpushed_commits(repo: &Repository, branch: &str) -> Result<Option<usize>> {
+    let Some(local) = repo
+        .find_branch(branch, BranchType::Local)
+        .ok()
+        .and_then(|local| local.get().target())
+    else {
+        return Ok(Some(0));
+    };
+    let Some(upstream) = repo
+        .find_branch(&format!("origin/{}", branch), BranchType::Remote)
+        .ok()
+        .and_then(|upstream| upstream.get().target())
+    else {
+        return Ok(None);
+    };
+    let (ahead, _) = repo.graph_ahead_behind(local, upstream)?;
+    Ok(Some(ahead))
+}
+
+/// A note about local commits that haven't been pushed, or None if there are none
+/// Uses the last fetched state of the remote, so it's cheap enough to show on every `list`.
+pub fn unpushed_note(repo: &Repository, branch: &str) -> Result<Option<String>> {
+    Ok(match unpushed_commits(repo, branch)? {
+        Some(0) => None,
+        Some(ahead) => Some(format!(
+            "{} {} unpushed commit{}, run `confinuum push` to push {}",
+            "!".yellow(),
+            ahead,
+            if ahead == 1 { "" } else { "s" },
+            if ahead == 1 { "it" } else { "them" }
+        )),
+        None if repo.find_remote("origin").is_ok() => Some(format!(
+            "{} The config has never been pushed, run `confinuum push` to push it",
+            "!".yellow()
+        )),
+        None => None,
+    })
+}
+
+/// Make sure `branch` on the remote doesn't have changes that need to be pulled before performing
+/// `action`
+/// Returns whether to continue offline: with `offline` set the remote isn't checked at all, and if
+/// the remote can't be reached the user is asked whether to continue offline instead of failing.
+/// A repo without a remote is always offline.
+pub fn check_remote(
+    repo: &Repository,
+    branch: &str,
+    action: &'static str,
+    offline: bool,
+) -> Result<bool> {
+    if offline {
+        tracing::debug!(
+            "Not checking the remote before {}, --offline was passed",
+            action
+        );
+        return Ok(true);
+    }
+    if repo.find_remote("origin").is_err() {
+        info!(
+            "The config repo has no remote, so it isn't checked for changes. Run `confinuum remote add <url>` to add one."
+        );
+        return Ok(true);
+    }
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        "Checking for changes on remote",
+        Color::Blue,
+    );
+    let fetch_commit = match fetch_origin(repo, &spinner, branch) {
+        Ok(fetch_commit) => fetch_commit,
+        Err(e) => {
+            let network_error = e
+                .downcast_ref::<git2::Error>()
+                .filter(|e| is_network_error(e))
+                .map(|e| e.message().to_owned());
+            tracing::warn!("Could not fetch from origin: {:#}", e);
+            let Some(message) = network_error else {
+                spinner.fail("Could not fetch from remote 'origin'");
+                return Err(e);
+            };
+            spinner.fail("Could not reach remote 'origin'");
+            let go_offline = std::io::stdin().is_terminal()
+                && dialoguer::Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt("The remote is unreachable. Continue offline?")
+                    .default(true)
+                    .interact()
+                    .unwrap_or(false);
+            if go_offline {
+                return Ok(true);
+            }
+            return Err(anyhow!(
+                "Could not reach remote 'origin': {}\nUse --offline to continue without checking the remote.",
+                message
+            ));
+        }
+    };
+    let analysis = repo.merge_analysis(&[&fetch_commit])?;
+    if !analysis.0.is_up_to_date() {
+        tracing::info!(
+            "Remote has commits that aren't merged locally, not running {}",
+            action
+        );
+        spinner.fail("Changes found on remote");
+        return Err(ConfinuumError::RemoteAhead(action).into());
+    }
+    spinner.clear();
+    Ok(false)
+}
+
+/// The credentials last handed to git2, for --verbose
+/// git2 only asks again after the remote rejects what it was given, so once an operation succeeds
+/// this describes the credentials it authenticated with.
+static LAST_CREDENTIAL: Mutex<Option<String>> = Mutex::new(None);
+
+fn record_credential(description: String) {
+    tracing::info!("Authenticating via {}", description);
+    *LAST_CREDENTIAL.lock().unwrap() = Some(description);
+}
+
+/// Print which credentials were used to authenticate with the remote, if it was contacted
+pub fn report_credential() {
+    if let Some(description) = LAST_CREDENTIAL.lock().unwrap().take() {
+        println!(
+            "{}",
+            format!("Authenticated via {}", description).dark_grey()
+        );
+    }
+}
+
+/// Replace the home directory at the start of a path with ~, for display
+fn tilde_path(path: &Path) -> String {
+    match std::env::var("HOME")
+        .ok()
+        .and_then(|home| path.strip_prefix(home).ok())
+    {
+        Some(relative) => format!("~/{}", relative.display()),
+        None => path.display().to_string(),
+    }
+}
+
+/// Set by --message, to replace the subject of the commits made by a single invocation
+static SUBJECT_OVERRIDE: Mutex<Option<String>> = Mutex::new(None);
+/// Set by --edit
+static EDIT_MESSAGE: AtomicBool = AtomicBool::new(false);
+
+/// Use `subject` as the first line of the commit messages for the rest of this invocation, keeping
+/// the generated body with the list of files
+pub fn override_subject(subject: String) {
+    *SUBJECT_OVERRIDE.lock().unwrap() = Some(subject);
+}
+
+/// Open each commit message in the editor before committing, for the rest of this invocation
+pub fn enable_edit_message() {
+    EDIT_MESSAGE.store(true, Ordering::Relaxed);
+}
+
+/// The message to commit with, after applying --message and --edit to the generated one
+fn final_message(repo: &Repository, message: &str) -> Result<String> {
+    let message = match SUBJECT_OVERRIDE.lock().unwrap().as_ref() {
+        Some(subject) => match message.split_once('\n') {
+            Some((_, body)) => format!("{}\n{}", subject, body),
+            None => subject.clone(),
+        },
+        None => message.to_owned(),
+    };
+    if !EDIT_MESSAGE.load(Ordering::Relaxed) {
+        return Ok(message);
+    }
+
+    // Written where git writes it, so editors recognize it as a commit message
+    let path = repo.path().join("COMMIT_EDITMSG");
+    std::fs::write(
+        &path,
+        format!(
+            "{}\n\n# Lines starting with '#' are left out, and an empty message aborts the commit.\n",
+            message.trim_end()
+        ),
+    )
+    .with_context(|| format!("Could not write {}", path.display()))?;
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    // Through the shell, since the editor may come with arguments (e.g. `code --wait`)
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg(&editor)
+        .arg(&path)
+        .status()
+        .with_context(|| format!("Could not run editor {}", editor))?;
+    if !status.success() {
+        return Err(anyhow!("Editor {} failed ({})", editor, status));
+    }
+    let edited = std::fs::read_to_string(&path)
+        .with_context(|| format!("Could not read {}", path.display()))?;
+    let message = edited
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if message.trim().is_empty() {
+        return Err(anyhow!("Aborting the commit, the commit message is empty"));
+    }
+    Ok(message.trim().to_owned())
+}
+
+/// Set by --name and --email, to commit as someone else for a single invocation
+static IDENTITY_OVERRIDE: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+/// Commit with the given name and email for the rest of this invocation, instead of the identity
+/// from the config's signature source
+pub fn override_identity(name: String, email: String) -> Result<()> {
+    if !EmailAddress::is_valid(&email) {
+        return Err(anyhow!("{} is not a valid email address", email));
+    }
+    Signature::now(&name, &email).context("Invalid commit identity")?;
+    *IDENTITY_OVERRIDE.lock().unwrap() = Some((name, email));
+    Ok(())
+}
+
+/// The signature set with --name and --email, if they were passed
+pub fn identity_override() -> Result<Option<Signature<'static>>> {
+    match IDENTITY_OVERRIDE.lock().unwrap().as_ref() {
+        Some((name, email)) => Ok(Some(Signature::now(name, email)?)),
+        None => Ok(None),
+    }
+}
+
+/// Get the signature to commit with, based on the config's signature source
+/// If the hosting service can't be reached, this falls back to the identity in the git config (with a
+/// warning), so that being offline or rate limited doesn't block committing locally.
+/// An identity passed with --name and --email takes precedence over all of them.
+pub async fn commit_signature(
+    config: &ConfinuumConfig,
+    github: &Github,
+) -> Result<Signature<'static>> {
+    if let Some(sig) = identity_override()? {
+        return Ok(sig);
+    }
+    let gitlab;
+    let gitea;
+    let provider: &dyn Provider = match &config.confinuum.signature_source {
+        SignatureSource::Github => github,
+        SignatureSource::Gitlab => {
+            gitlab = Gitlab::new(config.confinuum.gitlab_url.as_deref())?;
+            &gitlab
+        }
+        SignatureSource::Gitea => {
+            gitea = Gitea::new(config.confinuum.gitea_url.as_deref())?;
+            &gitea
+        }
+        SignatureSource::GitConfig => return gitconfig::get_user_sig(),
+    };
+    let hosting = provider.hosting();
+    match provider.get_user_signature().await {
+        Ok(sig) => Ok(sig),
+        Err(e) if github::is_unavailable(&e) => {
+            tracing::warn!("Could not fetch the signature from {}: {:#}", hosting, e);
+            let sig = gitconfig::get_user_sig().with_context(|| {
+                format!(
+                    "Could not fetch user signature from {}, or from git config",
+                    hosting
+                )
+            })?;
+            info!(
+                "{} Could not reach {}, signing with {} <{}> from your git config instead",
+                "!".yellow(),
+                hosting,
+                sig.name().unwrap_or_default(),
+                sig.email().unwrap_or_default()
+            );
+            Ok(sig)
+        }
+        Err(e) => Err(e.context(format!("Could not fetch user signature from {}", hosting))),
+    }
+}
+
+/// Save the config and commit config.toml on its own, for changes that only touch the config
+pub async fn commit_config(
+    repo: &Repository,
+    config: &ConfinuumConfig,
+    message: &str,
+    github: &Github,
+) -> Result<()> {
+    config.save().context("Failed to save config file")?;
+    let mut index = repo.index()?;
+    index.add_path(Path::new("config.toml"))?;
+    index.write()?;
+    let oid = index.write_tree().context("Failed to write tree")?;
+    let tree = repo
+        .find_tree(oid)
+        .context("Failed to find new commit tree")?;
+    let parent_commit = repo
+        .find_last_commit()
+        .context("Failed to retrieve last commit")?;
+    let sig = commit_signature(config, github).await?;
+    let commit = repo
+        .commit_with_config(&sig, message, &tree, &[&parent_commit])
+        .context("Failed to commit config")?;
+    tracing::debug!("Committed {}: {}", commit, message);
+    Ok(())
+}
+
+/// Set by --include-dirty, to commit changes that were already in the repo along with a command's
+static INCLUDE_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Commit uncommitted changes to other files along with each command's own for the rest of this
+/// invocation, instead of leaving them out with a warning
+pub fn include_dirty() {
+    INCLUDE_DIRTY.store(true, Ordering::Relaxed);
+}
+
+/// A tree staged by `stage_paths`, ready to commit
+pub struct StagedTree {
+    pub oid: git2::Oid,
+    /// Uncommitted changes from before the command that were staged with --include-dirty
+    pub dirty: Vec<FileDelta>,
+}
+
+impl StagedTree {
+    /// The commit message, with the uncommitted changes that were included listed below it
+    pub fn message(&self, message: String) -> String {
+        if self.dirty.is_empty() {
+            return message;
+        }
+        format!(
+            "{}\n\nAlso includes uncommitted changes:\n{}",
+            message,
+            self.dirty
+                .iter()
+                .map(|delta| delta.to_string())
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+    }
+}
+
+/// Stage the given repo-relative paths along with config.toml, and write the tree to commit
+/// Paths are staged one by one, which (unlike `add_all`) doesn't skip ones matched by an ignore
+/// rule, so config.toml is always staged.
+/// Paths that no longer exist are removed from the index (directories along with everything in
+/// them), so only what the command changed is committed rather than everything in the repo. Other
+/// files that differ from HEAD (like edits made through deployed links, or changes staged with
+/// git) are left out with a warning, unless --include-dirty was passed. As a safety net, the tree is compared with HEAD
+/// and rejected if it touches anything other than `entries`' directories, config.toml and the
+/// included changes.
+pub fn stage_paths(
+    repo: &Repository,
+    entries: &[&str],
+    paths: impl IntoIterator<Item = PathBuf>,
+) -> Result<StagedTree> {
+    let workdir = repo
+        .workdir()
+        .ok_or(anyhow!("The config repo has no working directory"))?;
+    let mut paths = paths.into_iter().collect::<Vec<_>>();
+    paths.push(PathBuf::from("config.toml"));
+
+    // Changes from before this command, which it isn't responsible for
+    let head_tree = repo
+        .find_last_commit()
+        .context("Failed to retrieve last commit")?
+        .tree()?;
+    let mut diff_opt = DiffOptions::new();
+    diff_opt.include_untracked(false).include_ignored(false);
+    let mut diff = repo
+        .diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut diff_opt))
+        .context("Failed to diff working directory")?;
+    let dirty = diff_files(&mut diff)?
+        .into_iter()
+        .filter(|delta| {
+            !delta
+                .paths()
+                .iter()
+                .all(|file| paths.iter().any(|path| file.starts_with(path)))
+        })
+        .collect::<Vec<_>>();
+    let dirty = if dirty.is_empty() {
+        dirty
+    } else if INCLUDE_DIRTY.load(Ordering::Relaxed) {
+        paths.extend(
+            dirty
+                .iter()
+                .flat_map(|delta| delta.paths())
+                .map(PathBuf::clone),
+        );
+        dirty
+    } else {
+        tracing::debug!(
+            "Leaving out uncommitted changes: {}",
+            dirty
+                .iter()
+                .map(|delta| delta.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        let mut dirty_entries = dirty
+            .iter()
+            .flat_map(|delta| delta.paths())
+            .filter_map(|path| {
+                path.components()
+                    .next()
+                    .map(|entry| entry.as_os_str().to_string_lossy().into_owned())
+            })
+            .collect::<Vec<_>>();
+        dirty_entries.sort();
+        dirty_entries.dedup();
+        eprintln!(
+            "{} You have uncommitted changes in {}, they will NOT be included in this commit. Run `confinuum status` to commit them, or pass --include-dirty to include them.",
+            "!".yellow(),
+            dirty_entries.join(", ")
+        );
+        Vec::new()
+    };
+
+    // Built on HEAD's tree rather than what's staged, so changes staged before this command are
+    // left out like any other uncommitted change. They're unstaged, but stay in the working
+    // directory.
+    let mut index = repo.index()?;
+    index.read_tree(&head_tree)?;
+    for path in &paths {
+        tracing::debug!("Staging {}", path.display());
+        if workdir.join(path).exists() {
+            index
+                .add_path(path)
+                .with_context(|| format!("Could not stage {}", path.display()))?;
+        } else if index.get_path(path, 0).is_some() {
+            index
+                .remove_path(path)
+                .with_context(|| format!("Could not stage removal of {}", path.display()))?;
+        } else {
+            index
+                .remove_dir(path, 0)
+                .with_context(|| format!("Could not stage removal of {}", path.display()))?;
+        }
+    }
+    let oid = index.write_tree().context("Failed to write tree")?;
+
+    let diff = repo.diff_tree_to_tree(Some(&head_tree), Some(&repo.find_tree(oid)?), None)?;
+    let expected = |path: &Path| {
+        path == Path::new("config.toml")
+            || entries.iter().any(|entry| path.starts_with(entry))
+            || dirty
+                .iter()
+                .any(|delta| delta.paths().iter().any(|dirty| *dirty == path))
+    };
+    let unexpected = diff
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+        .flatten()
+        .filter(|path| !expected(path))
+        .map(|path| path.display().to_string())
+        .collect::<BTreeSet<_>>();
+    if !unexpected.is_empty() {
+        // Don't leave the half-staged changes behind for the next command to pick up
+        index.read(true)?;
+        return Err(anyhow!(
+            "Refusing to commit, the staged changes include files outside of {}:\n{}\nThis is a bug, please report it to https://github.com/willothy/confinuum/issues",
+            entries.join(", "),
+            unexpected.into_iter().collect::<Vec<_>>().join("\n")
+        ));
+    }
+    index.write()?;
+    tracing::debug!("Staged tree {}", oid);
+    Ok(StagedTree { oid, dirty })
+}
+
 /// Remote callbacks
-pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCallbacks<'a> {
+pub fn construct_callbacks<'a>(spinner: Rc<RefCell<ProgressSpinner>>) -> git2::RemoteCallbacks<'a> {
     let mut callbacks = git2::RemoteCallbacks::new();
     callbacks.credentials(
         move |url: &str, username: Option<&str>, allowed_types: git2::CredentialType| {
+            tracing::debug!(
+                "{} asked for credentials (username {:?}, allowed {:?})",
+                url,
+                username,
+                allowed_types
+            );
             if allowed_types.contains(git2::CredentialType::USERNAME) {
                 let username = username.unwrap_or("git");
                 return git2::Cred::username(username);
@@ -64,6 +645,7 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
             {
                 let key_path = find_ssh_key()
                     .map_err(|_| git2::Error::from_str("Could not find SSH key in ~/.ssh"))?;
+                record_credential(format!("SSH key {}", tilde_path(&key_path)));
                 return git2::Cred::ssh_key(
                     username.unwrap_or("git"),
                     None,
@@ -75,8 +657,9 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
             if allowed_types.contains(git2::CredentialType::SSH_MEMORY) {
                 let key_path = find_ssh_key()
                     .map_err(|_| git2::Error::from_str("Could not find SSH key in ~/.ssh"))?;
-                let key = std::fs::read_to_string(key_path)
+                let key = std::fs::read_to_string(&key_path)
                     .map_err(|_| git2::Error::from_str("Could not read SSH key"))?;
+                record_credential(format!("SSH key {}", tilde_path(&key_path)));
                 return git2::Cred::ssh_key_from_memory(
                     username.unwrap_or("git"),
                     None,
@@ -88,12 +671,15 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
             if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
                 let config = git2::Config::open_default()?;
                 if let Ok(cred) = git2::Cred::credential_helper(&config, url, username) {
+                    let helper = config.get_string("credential.helper").unwrap_or_default();
+                    record_credential(format!("token from git credential helper '{}'", helper));
                     return Ok(cred);
                 } else {
                     let username = username.unwrap_or("git");
                     let password =
                         rpassword::prompt_password(format!("Password for '{}': ", username))
                             .map_err(|_| git2::Error::from_str("Could not prompt for password"))?;
+                    record_credential(format!("password for '{}'", username));
                     return git2::Cred::userpass_plaintext(username, &password);
                 }
             }
@@ -105,6 +691,10 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
         .certificate_check(move |_cert, _valid| Ok(git2::CertificateCheckStatus::CertificateOk));
     let transfer_spinner = spinner.clone();
     callbacks.transfer_progress(move |stats: Progress| {
+        // Returning false cancels the transfer
+        if !cli::progress_enabled() {
+            return !interrupt::interrupted();
+        }
         let received_objects = stats.received_objects();
         let total_objects = stats.total_objects();
 
@@ -116,10 +706,14 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
             total_objects,
             recv_done.then_some(", done.").unwrap_or_default()
         ));
-        true
+        !interrupt::interrupted()
     });
     let push_update_spinner = spinner.clone();
     callbacks.push_update_reference(move |refname: &str, status: Option<&str>| {
+        match status {
+            Some(status) => tracing::warn!("Remote rejected {}: {}", refname, status),
+            None => tracing::info!("Pushed {}", refname),
+        }
         if let Some(status) = status {
             push_update_spinner
                 .clone()
@@ -128,6 +722,10 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
         }
         Ok(())
     });
+    if !cli::progress_enabled() {
+        // Nothing would show the progress, so there's no need to format it for every object
+        return callbacks;
+    }
     let push_transfer_spinner = spinner.clone();
     callbacks.push_transfer_progress(move |progress: usize, total: usize, bytes: usize| {
         push_transfer_spinner
@@ -160,133 +758,319 @@ pub fn construct_callbacks<'a>(spinner: Rc<RefCell<Spinner>>) -> git2::RemoteCal
         true
     });
     let pack_spinner = spinner.clone();
+    // Reused between calls, and the spinner is only updated when the text changes, since
+    // libgit2 reports progress for every object
+    let mut pack_text = String::new();
+    let mut shown_text = String::new();
     callbacks.pack_progress(
         move |stage: PackBuilderStage, current: usize, total: usize| {
-            let done = if current >= total { ", done." } else { "." };
-            match stage {
-                PackBuilderStage::AddingObjects => pack_spinner
-                    .clone()
-                    .borrow_mut()
-                    .update_text(format!("Adding objects: {}{}", current, done)),
-                PackBuilderStage::Deltafication => {
-                    pack_spinner.clone().borrow_mut().update_text(format!(
-                        "Resolving deltas: ({}%) {} / {}{}",
-                        current as f64 / total as f64,
-                        current,
-                        total,
-                        done
-                    ));
+            pack_text.clear();
+            // libgit2 doesn't know the total while adding objects, and reports it as 0
+            let _ = match stage {
+                PackBuilderStage::AddingObjects => {
+                    write!(pack_text, "Adding objects: {}", current)
                 }
+                PackBuilderStage::Deltafication if total == 0 => {
+                    write!(pack_text, "Resolving deltas: {}", current)
+                }
+                PackBuilderStage::Deltafication => write!(
+                    pack_text,
+                    "Resolving deltas: {}% ({}/{}){}",
+                    current * 100 / total,
+                    current,
+                    total,
+                    if current >= total { ", done." } else { "" }
+                ),
+            };
+            if pack_text != shown_text {
+                shown_text.clone_from(&pack_text);
+                pack_spinner.update_text(pack_text.clone());
             }
         },
     );
     callbacks
 }
 
-pub fn print_diff(diff: &Diff, format: DiffFormat) -> Result<()> {
-    let mut stdout = std::io::stdout().lock();
+fn diff_line_style(line_type: DiffLineType) -> (style::ContentStyle, &'static str) {
+    use crossterm::style::Color::*;
+    let mut style = style::ContentStyle::new();
+    let mut origin = "";
+    match line_type {
+        DiffLineType::Addition => {
+            style.foreground_color = Some(Green);
+            origin = "+";
+        }
+        DiffLineType::Deletion => {
+            style.foreground_color = Some(Red);
+            origin = "-";
+        }
+        DiffLineType::FileHeader => {
+            style.foreground_color = Some(Reset);
+            style.attributes.set(style::Attribute::Bold);
+        }
+        DiffLineType::HunkHeader => {
+            style.foreground_color = Some(Blue);
+        }
+        DiffLineType::Binary => {
+            style.foreground_color = Some(Reset);
+            style.attributes.set(style::Attribute::Bold);
+        }
+        _ => {}
+    }
+    (style, origin)
+}
+
+fn write_diff_line(out: &mut impl Write, line_type: DiffLineType, content: &str) {
+    let (style, origin) = diff_line_style(line_type);
+    crossterm::queue!(
+        out,
+        Print(style.apply(format!(
+            "{}{}{}\n",
+            origin,
+            content.trim_end(),
+            if line_type == DiffLineType::HunkHeader {
+                "\n"
+            } else {
+                ""
+            }
+        ))),
+    )
+    .ok();
+}
+
+/// Write a deleted line and the added line that replaced it, highlighting the words that changed
+fn write_word_diff_pair(out: &mut impl Write, old: &str, new: &str) {
+    let (deletion_style, deletion_origin) = diff_line_style(DiffLineType::Deletion);
+    let (addition_style, addition_origin) = diff_line_style(DiffLineType::Addition);
+    let mut deletion_emphasis = deletion_style;
+    deletion_emphasis.attributes.set(style::Attribute::Reverse);
+    let mut addition_emphasis = addition_style;
+    addition_emphasis.attributes.set(style::Attribute::Reverse);
+
+    let (old, new) = (old.trim_end(), new.trim_end());
+    let mut old_line = deletion_style.apply(deletion_origin).to_string();
+    let mut new_line = addition_style.apply(addition_origin).to_string();
+    for change in similar::TextDiff::from_words(old, new).iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Equal => {
+                old_line.push_str(&deletion_style.apply(change.value()).to_string());
+                new_line.push_str(&addition_style.apply(change.value()).to_string());
+            }
+            ChangeTag::Delete => {
+                old_line.push_str(&deletion_emphasis.apply(change.value()).to_string())
+            }
+            ChangeTag::Insert => {
+                new_line.push_str(&addition_emphasis.apply(change.value()).to_string())
+            }
+        }
+    }
+    crossterm::queue!(out, Print(format!("{}\n{}\n", old_line, new_line))).ok();
+}
+
+/// Pair up a run of deleted lines with the added lines that follow them and write them out,
+/// highlighting changed words in each pair. Unpaired lines are written as usual.
+fn flush_word_diff(out: &mut impl Write, deletions: &mut Vec<String>, additions: &mut Vec<String>) {
+    let paired = deletions.len().min(additions.len());
+    for (old, new) in deletions.iter().zip(additions.iter()) {
+        write_word_diff_pair(out, old, new);
+    }
+    for old in deletions.iter().skip(paired) {
+        write_diff_line(out, DiffLineType::Deletion, old);
+    }
+    for new in additions.iter().skip(paired) {
+        write_diff_line(out, DiffLineType::Addition, new);
+    }
+    deletions.clear();
+    additions.clear();
+}
 
-    crossterm::queue!(stdout, MoveToColumn(0) /* Print("\n") */)?;
+/// Render a diff with colors into the given writer
+/// Rendering into a buffer lets callers decide whether to page the output or print it directly
+/// If word_diff is set, changed words within modified lines are highlighted
+pub fn print_diff(
+    diff: &Diff,
+    format: DiffFormat,
+    word_diff: bool,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut deletions = Vec::new();
+    let mut additions = Vec::new();
     diff.print(
         format,
         |_delta: DiffDelta, _hunk: Option<DiffHunk>, line: DiffLine| -> bool {
-            use crossterm::style::Color::*;
-            let mut style = style::ContentStyle::new();
-            let mut origin = "";
-            match line.origin_value() {
-                git2::DiffLineType::Addition => {
-                    style.foreground_color = Some(Green);
-                    origin = "+";
-                }
-                git2::DiffLineType::Deletion => {
-                    style.foreground_color = Some(Red);
-                    origin = "-";
-                }
-                git2::DiffLineType::FileHeader => {
-                    style.foreground_color = Some(Reset);
-                    style.attributes.set(style::Attribute::Bold);
-                }
-                git2::DiffLineType::HunkHeader => {
-                    style.foreground_color = Some(Blue);
-                }
-                git2::DiffLineType::Binary => {
-                    style.foreground_color = Some(Reset);
-                    style.attributes.set(style::Attribute::Bold);
-                }
-                _ => {}
+            let line_type = line.origin_value();
+            let content = String::from_utf8(line.content().to_vec()).unwrap_or_default();
+            if !word_diff {
+                write_diff_line(out, line_type, &content);
+                return true;
             }
 
-            crossterm::queue!(
-                stdout,
-                Print(style.apply(format!(
-                    "{}{}{}\n",
-                    origin,
-                    String::from_utf8(line.content().to_vec())
-                        .unwrap_or_default()
-                        .trim_end(),
-                    if line.origin_value() == git2::DiffLineType::HunkHeader {
-                        "\n"
-                    } else {
-                        ""
+            match line_type {
+                DiffLineType::Deletion => {
+                    if !additions.is_empty() {
+                        flush_word_diff(out, &mut deletions, &mut additions);
                     }
-                ))),
-            )
-            .ok();
+                    deletions.push(content);
+                }
+                DiffLineType::Addition => additions.push(content),
+                _ => {
+                    flush_word_diff(out, &mut deletions, &mut additions);
+                    write_diff_line(out, line_type, &content);
+                }
+            }
             true
         },
     )?;
+    flush_word_diff(out, &mut deletions, &mut additions);
 
-    crossterm::queue!(stdout, Print("\n"))?;
-    std::io::Write::flush(&mut stdout)?;
+    crossterm::queue!(out, Print("\n"))?;
+    out.flush()?;
     Ok(())
 }
 
-pub fn diff_files(diff: &Diff) -> Result<Vec<PathBuf>> {
+/// A change to a single file in a diff
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FileDelta {
+    Added(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+    Renamed { from: PathBuf, to: PathBuf },
+}
+
+impl FileDelta {
+    /// All paths touched by this change (both the old and new path for renames)
+    pub fn paths(&self) -> Vec<&PathBuf> {
+        match self {
+            FileDelta::Added(path) | FileDelta::Modified(path) | FileDelta::Deleted(path) => {
+                vec![path]
+            }
+            FileDelta::Renamed { from, to } => vec![from, to],
+        }
+    }
+
+    /// Paths that no longer exist after this change
+    pub fn removed_paths(&self) -> Vec<&PathBuf> {
+        match self {
+            FileDelta::Deleted(path) => vec![path],
+            FileDelta::Renamed { from, .. } => vec![from],
+            _ => vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for FileDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileDelta::Added(path) => write!(f, "{} (added)", path.display()),
+            FileDelta::Modified(path) => write!(f, "{}", path.display()),
+            FileDelta::Deleted(path) => write!(f, "{} (deleted)", path.display()),
+            FileDelta::Renamed { from, to } => {
+                write!(f, "{} -> {}", from.display(), to.display())
+            }
+        }
+    }
+}
+
+/// Collect the changed files in a diff, detecting renames
+pub fn diff_files(diff: &mut Diff) -> Result<Vec<FileDelta>> {
+    diff.find_similar(Some(DiffFindOptions::new().renames(true)))?;
     let mut files = Vec::new();
     for delta in diff.deltas() {
-        if let Some(file) = delta.new_file().path().map(|p| p.to_path_buf()) {
-            files.push(file);
-        }
+        let old_path = delta.old_file().path().map(|p| p.to_path_buf());
+        let new_path = delta.new_file().path().map(|p| p.to_path_buf());
+        let file = match (delta.status(), old_path, new_path) {
+            (Delta::Added, _, Some(path)) => FileDelta::Added(path),
+            (Delta::Deleted, Some(path), _) => FileDelta::Deleted(path),
+            (Delta::Renamed, Some(from), Some(to)) => FileDelta::Renamed { from, to },
+            (_, _, Some(path)) | (_, Some(path), None) => FileDelta::Modified(path),
+            (_, None, None) => continue,
+        };
+        files.push(file);
     }
     Ok(files)
 }
 
-pub fn diff_entries(files: &Vec<PathBuf>) -> Result<(HashMap<String, HashSet<PathBuf>>, bool)> {
-    let mut entries = HashMap::new();
+/// The changed files of each entry, whether config.toml changed, and the files no entry owns
+pub type EntryChanges = (HashMap<String, HashSet<FileDelta>>, bool, Vec<PathBuf>);
+
+/// Group changed files by the entry they belong to
+/// Returns the changes per entry, whether config.toml changed, and any files that don't
+/// belong to a known entry (such as a README, or an entry that was added on another machine)
+/// Deleted and renamed files are attributed to the entry that owned their old path.
+/// Changes to the root `.gitignore` are left out entirely, since confinuum writes it itself on
+/// init and it doesn't belong to an entry or need the user's attention.
+pub fn diff_entries(files: &Vec<FileDelta>) -> Result<EntryChanges> {
+    let mut entries: HashMap<String, HashSet<FileDelta>> = HashMap::new();
+    let mut unowned = Vec::new();
     let config = ConfinuumConfig::load()?;
     let mut config_updated = false;
-    for file in files {
-        let components = file.components();
-        if components.count() == 1 {
-            // File is in root of config directory
-            if file.components().next().unwrap().as_os_str() == "config.toml" {
-                config_updated = true;
-            }
+    for delta in files {
+        for file in delta.paths() {
+            let components = file.components();
+            if components.count() == 1 {
+                // File is in root of config directory
+                if file.components().next().unwrap().as_os_str() == "config.toml" {
+                    config_updated = true;
+                } else if file.components().next().unwrap().as_os_str() != ".gitignore" {
+                    unowned.push(file.to_path_buf());
+                }
 
-            continue;
+                continue;
+            }
+            let entry = file
+                .components()
+                .next()
+                .unwrap()
+                .as_os_str()
+                .to_string_lossy()
+                .to_string();
+            if config.entries.contains_key(&entry) {
+                entries.entry(entry).or_default().insert(delta.clone());
+            } else {
+                unowned.push(file.to_path_buf());
+            }
         }
-        let entry = file
+    }
+    Ok((entries, config_updated, unowned))
+}
+
+/// Load the config as it exists in the given tree (e.g. the remote's config.toml after a fetch)
+pub fn config_at_tree(repo: &Repository, tree: &Tree) -> Result<ConfinuumConfig> {
+    let blob = tree
+        .get_path(Path::new("config.toml"))
+        .context("Could not find config.toml in tree")?
+        .to_object(repo)?
+        .peel_to_blob()?;
+    let config_str =
+        std::str::from_utf8(blob.content()).context("config.toml is not valid utf-8")?;
+    ConfinuumConfig::parse(config_str)
+}
+
+/// Print a note listing changed files that don't belong to any known entry
+/// If the remote config is provided, files belonging to entries that only exist on the remote are
+/// pointed out, since that's the usual cause.
+pub fn print_unowned(unowned: &[PathBuf], remote_config: Option<&ConfinuumConfig>) {
+    if unowned.is_empty() {
+        return;
+    }
+    println!("\nFiles outside known entries:");
+    for file in unowned {
+        let remote_entry = file
             .components()
             .next()
-            .unwrap()
-            .as_os_str()
-            .to_string_lossy()
-            .to_string();
-        if config.entries.contains_key(&entry) {
-            if entries.contains_key(&entry) {
-                let entry_files: &mut HashSet<PathBuf> = entries.get_mut(&entry).unwrap();
-                entry_files.insert(file.to_path_buf());
-            } else {
-                entries.insert(entry, HashSet::from_iter(vec![file.to_path_buf()]));
-            }
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .filter(|_| file.components().count() > 1)
+            .filter(|entry| remote_config.is_some_and(|config| config.entries.contains_key(entry)));
+        if let Some(entry) = remote_entry {
+            println!(
+                "    {} (belongs to entry {}, which is new on the remote)",
+                file.display(),
+                entry.yellow()
+            );
         } else {
-            return Err(anyhow!(
-                "Found file that does not belong to any entry: {}",
-                file.display()
-            ));
+            println!("    {}", file.display());
         }
     }
-    Ok((entries, config_updated))
 }
 
 pub mod gitconfig {
@@ -373,3 +1157,178 @@ pub mod gitconfig {
         ))
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::config::{ConfigEntry, GitProtocol};
+
+    /// A bare repo standing in for the remote, and a clone of it with one commit pushed to
+    /// `branch` (not main, so tests see which branch is used)
+    pub(crate) fn remote_and_clone(dir: &Path, branch: &str) -> (Repository, Repository) {
+        let remote = Repository::init_bare(dir.join("remote.git")).unwrap();
+        let local = clone(dir, "local", branch);
+        commit_file(&local, "config.toml", "");
+        local
+            .find_remote("origin")
+            .unwrap()
+            .push(&[branch_refspec(branch)], None)
+            .unwrap();
+        (remote, local)
+    }
+
+    fn clone(dir: &Path, name: &str, branch: &str) -> Repository {
+        let mut init_opt = git2::RepositoryInitOptions::new();
+        init_opt.initial_head(branch);
+        let repo = Repository::init_opts(dir.join(name), &init_opt).unwrap();
+        repo.remote("origin", dir.join("remote.git").to_str().unwrap())
+            .unwrap();
+        repo
+    }
+
+    /// Commit a file, along with anything already staged
+    pub(crate) fn commit_file(repo: &Repository, path: &str, contents: &str) -> git2::Oid {
+        let file = repo.workdir().unwrap().join(path);
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(file, contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(path)).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = Signature::now("Test", "test@example.com").unwrap();
+        let parent = repo.find_last_commit().ok();
+        repo.commit(
+            Some("HEAD"),
+            &sig,
+            &sig,
+            "Test",
+            &tree,
+            parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+    }
+
+    fn spinner() -> Rc<RefCell<ProgressSpinner>> {
+        cli::disable_progress();
+        Spinner::new_shared(spinners::Dots9, "Testing", Color::Blue)
+    }
+
+    #[test]
+    fn stage_paths_leaves_out_staged_changes() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let repo = clone(dir.path(), "local", "main");
+        commit_file(&repo, "config.toml", "");
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join("README.md"), "staged").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("README.md")).unwrap();
+        index.write().unwrap();
+        std::fs::create_dir(workdir.join("nvim")).unwrap();
+        std::fs::write(workdir.join("nvim/init.lua"), "").unwrap();
+        std::fs::write(workdir.join("config.toml"), "[confinuum]").unwrap();
+
+        let staged = stage_paths(&repo, &["nvim"], [PathBuf::from("nvim/init.lua")]).unwrap();
+        let tree = repo.find_tree(staged.oid).unwrap();
+        assert!(tree.get_path(Path::new("nvim/init.lua")).is_ok());
+        assert!(tree.get_path(Path::new("config.toml")).is_ok());
+        assert!(tree.get_path(Path::new("README.md")).is_err());
+        assert!(staged.dirty.is_empty());
+        // Only unstaged, the file itself is left alone
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("README.md")).unwrap(),
+            "staged"
+        );
+    }
+
+    #[test]
+    fn diff_entries_groups_files_and_skips_gitignore() {
+        let home = TempDir::new("confinuum-test").unwrap();
+        let config_dir = home.path().join(".config/confinuum");
+        let vars = [
+            ("HOME", Some(home.path())),
+            ("CONFINUUM_CONFIG_DIR", Some(config_dir.as_path())),
+        ];
+        crate::config::tests::with_env(&vars, || {
+            let mut config = ConfinuumConfig::init(GitProtocol::Ssh, SignatureSource::GitConfig);
+            let entry = ConfigEntry::new("nvim".into());
+            config.entries.insert("nvim".into(), entry);
+            config.save().unwrap();
+
+            let files = vec![
+                FileDelta::Modified(".gitignore".into()),
+                FileDelta::Modified("config.toml".into()),
+                FileDelta::Added("README.md".into()),
+                FileDelta::Added("nvim/init.lua".into()),
+                FileDelta::Deleted("zsh/.zshrc".into()),
+            ];
+            let (entries, config_updated, unowned) = diff_entries(&files).unwrap();
+
+            assert!(config_updated);
+            assert_eq!(
+                entries.get("nvim"),
+                Some(&HashSet::from([FileDelta::Added("nvim/init.lua".into())]))
+            );
+            assert_eq!(entries.len(), 1);
+            assert_eq!(
+                unowned,
+                vec![PathBuf::from("README.md"), PathBuf::from("zsh/.zshrc")]
+            );
+        });
+    }
+
+    #[test]
+    fn fetch_origin_fetches_the_branch() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (_remote, local) = remote_and_clone(dir.path(), "dotfiles");
+        let other = clone(dir.path(), "other", "dotfiles");
+        let fetched = fetch_origin(&other, &spinner(), "dotfiles").unwrap();
+        assert_eq!(fetched.id(), local.find_last_commit().unwrap().id());
+    }
+
+    #[test]
+    fn fetch_origin_fails_for_a_missing_branch() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (_remote, local) = remote_and_clone(dir.path(), "dotfiles");
+        assert!(fetch_origin(&local, &spinner(), "main").is_err());
+    }
+
+    #[test]
+    fn check_remote_up_to_date() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (_remote, local) = remote_and_clone(dir.path(), "dotfiles");
+        spinner();
+        assert!(!check_remote(&local, "dotfiles", "testing", false).unwrap());
+    }
+
+    #[test]
+    fn check_remote_finds_remote_changes() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let (_remote, local) = remote_and_clone(dir.path(), "dotfiles");
+        let other = clone(dir.path(), "other", "dotfiles");
+        let fetched = fetch_origin(&other, &spinner(), "dotfiles").unwrap();
+        other
+            .branch("dotfiles", &other.find_commit(fetched.id()).unwrap(), false)
+            .unwrap();
+        commit_file(&other, "config.toml", "# changed");
+        other
+            .find_remote("origin")
+            .unwrap()
+            .push(&[branch_refspec("dotfiles")], None)
+            .unwrap();
+
+        let error = check_remote(&local, "dotfiles", "testing", false).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ConfinuumError>(),
+            Some(ConfinuumError::RemoteAhead("testing"))
+        ));
+    }
+
+    #[test]
+    fn check_remote_offline() {
+        let dir = TempDir::new("confinuum-test").unwrap();
+        let local = clone(dir.path(), "local", "dotfiles");
+        // Nothing is fetched, so the missing remote repo doesn't matter
+        assert!(check_remote(&local, "dotfiles", "testing", true).unwrap());
+    }
+}
@@ -0,0 +1,352 @@
+//! Gitea support, for configs hosted on Codeberg or a self-hosted Gitea or Forgejo instance
+//! Like GitLab, this authenticates with an access token, since OAuth apps are registered per
+//! instance.
+
+use std::{cell::RefCell, collections::HashMap, fs};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use git2::Signature;
+use reqwest::{
+    header::{ACCEPT, AUTHORIZATION},
+    Method, StatusCode,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::{self, ConfinuumConfig, Hosting},
+    error::ConfinuumError,
+    github::{self, AuthUser},
+    hosting::{self, HostedRepo, Provider},
+};
+
+/// The instance used when no URL is configured
+pub const DEFAULT_URL: &str = "https://codeberg.org";
+
+pub struct Gitea {
+    client: reqwest::Client,
+    /// The instance's base URL, without a trailing slash
+    url: String,
+    /// None if the user hasn't logged in to this instance
+    token: RefCell<Option<String>>,
+    /// The user's name and email, loaded from the auth file or fetched once per run
+    user: RefCell<Option<AuthUser>>,
+}
+
+impl Gitea {
+    /// Load the stored credentials for the instance at `url` (Codeberg if not given), if there are
+    /// any
+    pub fn new(url: Option<&str>) -> Result<Self> {
+        let url = url.unwrap_or(DEFAULT_URL).trim_end_matches('/').to_owned();
+        let host = if GiteaAuthFile::exists()? {
+            GiteaAuthFile::load()?.hosts.remove(&url)
+        } else {
+            None
+        };
+        if let Some(host) = &host {
+            github::register_token(secrecy::Secret::new(host.token.clone()));
+        }
+        let (token, user) = match host {
+            Some(host) => (Some(host.token), Some(host.user)),
+            None => (None, None),
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url,
+            token: RefCell::new(token),
+            user: RefCell::new(user),
+        })
+    }
+
+    /// The instance's base URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Log in with an access token and store it, after checking that it works
+    /// Credentials for other instances are kept.
+    pub async fn login_with_token(&self, token: String) -> Result<AuthUser> {
+        github::register_token(secrecy::Secret::new(token.clone()));
+        let previous = self.token.replace(Some(token.clone()));
+        let user = match self.fetch_user().await {
+            Ok(user) => user,
+            Err(e) => {
+                *self.token.borrow_mut() = previous;
+                return Err(e.context("The token was rejected by Gitea"));
+            }
+        };
+        let mut auth_file = if GiteaAuthFile::exists()? {
+            GiteaAuthFile::load()?
+        } else {
+            GiteaAuthFile::default()
+        };
+        auth_file.hosts.insert(
+            self.url.clone(),
+            GiteaHost {
+                token,
+                user: user.clone(),
+            },
+        );
+        auth_file.save()?;
+        *self.user.borrow_mut() = Some(user.clone());
+        Ok(user)
+    }
+
+    /// Remove the stored credentials for the instance at `url`, or for all instances
+    /// Returns false if there were none
+    pub fn logout(url: Option<&str>) -> Result<bool> {
+        if !GiteaAuthFile::exists()? {
+            return Ok(false);
+        }
+        let path = GiteaAuthFile::get_path()?;
+        let Some(url) = url else {
+            fs::remove_file(&path)
+                .with_context(|| format!("Could not remove {}", path.display()))?;
+            return Ok(true);
+        };
+        let mut auth_file = GiteaAuthFile::load()?;
+        if auth_file.hosts.remove(url.trim_end_matches('/')).is_none() {
+            return Ok(false);
+        }
+        auth_file.save()?;
+        Ok(true)
+    }
+
+    /// Get the user's name and email, using the copy stored in the auth file when there is one
+    pub async fn get_auth_user(&self) -> Result<AuthUser> {
+        if let Some(user) = self.user.borrow().as_ref() {
+            return Ok(user.clone());
+        }
+        let user = self.fetch_user().await?;
+        *self.user.borrow_mut() = Some(user.clone());
+        Ok(user)
+    }
+
+    async fn fetch_user(&self) -> Result<AuthUser> {
+        let user: UserRes = self.request(Method::GET, "/user", None).await?;
+        // Gitea hides the address of users who keep their email private, in which case commits are
+        // attributed with the instance's noreply address like GitHub does
+        let email = match user.email.filter(|email| !email.is_empty()) {
+            Some(email) => email,
+            None => {
+                let domain = reqwest::Url::parse(&self.url)?
+                    .host_str()
+                    .ok_or_else(|| anyhow!("{} has no host", self.url))?
+                    .to_owned();
+                format!("{}@noreply.{}", user.login, domain)
+            }
+        };
+        Ok(AuthUser {
+            name: user.login,
+            email,
+        })
+    }
+
+    /// Make a call to the REST API, with the stored token
+    async fn request<R: DeserializeOwned>(
+        &self,
+        method: Method,
+        route: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<R> {
+        let token = self
+            .token
+            .borrow()
+            .clone()
+            .ok_or(ConfinuumError::NotLoggedIn(Hosting::Gitea))?;
+        let mut request = self
+            .client
+            .request(method, format!("{}/api/v1{}", self.url, route))
+            .header(AUTHORIZATION, format!("token {}", token))
+            .header(ACCEPT, "application/json");
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        tracing::debug!("Gitea API {} returned {}", route, response.status());
+        match response.status() {
+            // 403 is a token without the scopes confinuum needs (or a revoked one), which logging
+            // in again fixes
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(ConfinuumError::AuthExpired(Hosting::Gitea).into())
+            }
+            status if !status.is_success() => {
+                let message = response
+                    .json::<ErrorRes>()
+                    .await
+                    .map(|e| e.message)
+                    .unwrap_or_default();
+                Err(GiteaError { status, message }.into())
+            }
+            _ => Ok(response.json().await?),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for Gitea {
+    fn hosting(&self) -> Hosting {
+        Hosting::Gitea
+    }
+
+    fn is_logged_in(&self) -> bool {
+        self.token.borrow().is_some()
+    }
+
+    async fn login(&mut self) -> Result<()> {
+        println!(
+            "Create an access token with read access to your user and write access to repositories at {}/user/settings/applications",
+            self.url
+        );
+        let token = dialoguer::Password::new()
+            .with_prompt("Access token")
+            .interact()
+            .context("Failed to interact with user, cancelling.")?;
+        self.login_with_token(token.trim().to_owned()).await?;
+        Ok(())
+    }
+
+    async fn create_repo(
+        &self,
+        name: &str,
+        description: &str,
+        private: bool,
+    ) -> Result<HostedRepo> {
+        let body = serde_json::json!({
+            "name": name,
+            "description": description,
+            "private": private,
+        });
+        match self
+            .request::<RepoRes>(Method::POST, "/user/repos", Some(&body))
+            .await
+        {
+            Ok(repo) => Ok(repo.into()),
+            // Gitea responds with 409 if the user already has a repository with that name
+            Err(e)
+                if e.downcast_ref::<GiteaError>()
+                    .is_some_and(|e| e.status == StatusCode::CONFLICT) =>
+            {
+                Err(ConfinuumError::RepoExists(name.to_owned()).into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_repo(&self, name: &str) -> Result<HostedRepo> {
+        let user = self.get_auth_user().await?;
+        let repo: RepoRes = self
+            .request(
+                Method::GET,
+                &format!(
+                    "/repos/{}/{}",
+                    urlencoding::encode(&user.name),
+                    urlencoding::encode(name)
+                ),
+                None,
+            )
+            .await
+            .with_context(|| format!("Could not find repository {}/{}", user.name, name))?;
+        Ok(repo.into())
+    }
+
+    async fn get_user_signature(&self) -> Result<Signature<'static>> {
+        let user = self.get_auth_user().await?;
+        Ok(Signature::now(&user.name, &user.email)?)
+    }
+}
+
+/// An error response from the Gitea API
+#[derive(Debug, thiserror::Error)]
+#[error("Gitea responded with {status}: {message}")]
+pub struct GiteaError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ErrorRes {
+    #[serde(default)]
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserRes {
+    login: String,
+    email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RepoRes {
+    name: String,
+    html_url: String,
+    ssh_url: String,
+    clone_url: String,
+}
+
+impl From<RepoRes> for HostedRepo {
+    fn from(repo: RepoRes) -> Self {
+        Self {
+            name: repo.name,
+            html_url: Some(repo.html_url),
+            ssh_url: Some(repo.ssh_url),
+            https_url: repo.clone_url,
+        }
+    }
+}
+
+/// The stored Gitea credentials, keyed by the URL of the instance they're for
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct GiteaAuthFile {
+    #[serde(default)]
+    pub hosts: HashMap<String, GiteaHost>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GiteaHost {
+    pub token: String,
+    pub user: AuthUser,
+}
+
+// Written by hand so that the token can't end up in logs or error messages
+impl std::fmt::Debug for GiteaHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GiteaHost")
+            .field("token", &"[REDACTED]")
+            .field("user", &self.user)
+            .finish()
+    }
+}
+
+impl GiteaAuthFile {
+    pub fn get_path() -> Result<std::path::PathBuf> {
+        Ok(ConfinuumConfig::get_dir()?.join("gitea.toml"))
+    }
+
+    pub fn exists() -> Result<bool> {
+        let path = Self::get_path()?;
+        if path.is_dir() {
+            return Err(anyhow!(
+                "Gitea auth file is a directory. Please remove it and try again."
+            ));
+        }
+        Ok(path.is_file())
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::get_path()?;
+        config::check_secret_permissions(&path)?;
+        let file = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read from {}", path.display()))?;
+        Ok(toml::from_str(&file)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let conf_dir = ConfinuumConfig::get_dir()?;
+        if !conf_dir.exists() {
+            fs::create_dir_all(&conf_dir)?;
+        }
+        hosting::exclude_from_repo("gitea.toml")?;
+        config::write_secret(&Self::get_path()?, &toml::to_string(&self)?)
+    }
+}
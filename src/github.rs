@@ -1,54 +1,267 @@
-use crate::config::{self, ConfinuumConfig};
+use crate::{
+    cli::{CreateSharedSpinner, SharedSpinner},
+    config::{self, ConfinuumConfig, Hosting},
+    error::ConfinuumError,
+    hosting::{HostedRepo, Provider},
+};
 use anyhow::{anyhow, Context, Result};
-use either::Either;
+use async_trait::async_trait;
+use crossterm::style::Stylize;
 use git2::Signature;
 use octocrab::{auth::OAuth, models};
-use reqwest::header::ACCEPT;
+use reqwest::{header::ACCEPT, StatusCode};
 use secrecy::ExposeSecret;
-use serde::{Deserialize, Serialize};
-use std::{fs, time::Duration};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use spinoff::{spinners, Color, Spinner};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fs,
+    future::Future,
+    io::IsTerminal,
+    path::PathBuf,
+    process::Stdio,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// The tokens used during this run, kept so that they can be redacted from panic messages
+static ACTIVE_TOKENS: Mutex<Vec<secrecy::Secret<String>>> = Mutex::new(Vec::new());
 
 pub struct Github {
-    client: octocrab::Octocrab,
+    /// None if the user hasn't logged in, in which case anything that needs GitHub fails with a
+    /// hint to run `confinuum auth login`
+    /// This is replaced if the user logs in again after the token was rejected.
+    client: RefCell<Option<octocrab::Octocrab>>,
+    /// The user's name and email, loaded from the auth file or fetched once per run
+    user: RefCell<Option<AuthUser>>,
+    /// Where the token came from
+    source: Cell<TokenSource>,
+}
+
+/// Where the token used for API calls came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSource {
+    /// Confinuum's own credentials in hosts.toml
+    Confinuum,
+    /// The official `gh` CLI's login, which no API call has succeeded with yet
+    GhUnverified,
+    /// The official `gh` CLI's login
+    Gh,
 }
 
 impl Github {
-    pub async fn new() -> anyhow::Result<Self> {
-        if Self::is_authenticated() {
-            let auth_file = AuthFile::load()?;
-            let host = auth_file.auth;
-            let auth = OAuth::from(&host);
+    /// Load the stored GitHub credentials, if there are any
+    /// Without stored credentials, the token from the `gh` CLI's login is used if there is one
+    /// (unless `ignore_gh_cli` is set in the config). It isn't written to hosts.toml, and it's
+    /// checked against the API the first time it's needed.
+    pub fn new() -> anyhow::Result<Self> {
+        if !AuthFile::exists()? {
+            let ignore_gh = ConfinuumConfig::load().is_ok_and(|c| c.confinuum.ignore_gh_cli);
+            if let Some(token) = gh_token().filter(|_| !ignore_gh) {
+                let github = Self::with_auth(OAuth {
+                    access_token: secrecy::Secret::new(token),
+                    token_type: "bearer".to_owned(),
+                    scope: Vec::new(),
+                })?;
+                github.source.set(TokenSource::GhUnverified);
+                return Ok(github);
+            }
             return Ok(Self {
-                client: octocrab::Octocrab::builder()
+                client: RefCell::new(None),
+                user: RefCell::new(None),
+                source: Cell::new(TokenSource::Confinuum),
+            });
+        }
+        let auth_file = AuthFile::load().map_err(|e| match e.downcast_ref() {
+            // Logging in again wouldn't help with this one
+            Some(ConfinuumError::InsecureAuthFile(..)) => e,
+            _ => e.context(
+                "Could not load GitHub credentials, run `confinuum auth login` to log in again",
+            ),
+        })?;
+        let github = Self::with_auth(OAuth::from(&auth_file.auth))?;
+        *github.user.borrow_mut() = Some(auth_file.user);
+        Ok(github)
+    }
+
+    fn with_auth(auth: OAuth) -> anyhow::Result<Self> {
+        register_token(auth.access_token.clone());
+        Ok(Self {
+            client: RefCell::new(Some(
+                octocrab::Octocrab::builder()
                     .oauth(auth)
                     .add_header(ACCEPT, "application/vnd.github+json".to_string())
                     .build()?,
-            });
+            )),
+            user: RefCell::new(None),
+            source: Cell::new(TokenSource::Confinuum),
+        })
+    }
+
+    fn client(&self) -> anyhow::Result<octocrab::Octocrab> {
+        self.client
+            .borrow()
+            .clone()
+            .ok_or_else(|| ConfinuumError::NotLoggedIn(Hosting::Github).into())
+    }
+
+    /// Where the token in use came from
+    pub fn token_source(&self) -> TokenSource {
+        self.source.get()
+    }
+
+    /// Stop using the token borrowed from the `gh` CLI after GitHub rejected it, so that
+    /// confinuum falls back to its own login
+    fn drop_gh_token(&self) {
+        eprintln!(
+            "{} The token from the gh CLI was rejected by GitHub, ignoring it.",
+            "!".yellow()
+        );
+        *self.client.borrow_mut() = None;
+        self.source.set(TokenSource::Confinuum);
+    }
+
+    /// Make an API call, handling GitHub rejecting the stored token (e.g. because it was revoked)
+    /// The stored credentials are removed so the broken token isn't used again, and when running
+    /// interactively the user can log in again, after which the call is retried once.
+    /// A token borrowed from the `gh` CLI is only checked by the calls made with it, and dropped
+    /// if GitHub rejects it.
+    async fn with_reauth<T, F, Fut>(&self, call: F) -> anyhow::Result<T>
+    where
+        F: Fn(octocrab::Octocrab) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let error = match call(self.client()?).await {
+            Err(e) if is_auth_error(&e) => e,
+            res => {
+                if res.is_ok() && self.source.get() == TokenSource::GhUnverified {
+                    self.source.set(TokenSource::Gh);
+                }
+                return res;
+            }
+        };
+        tracing::warn!("GitHub rejected the token: {:#}", error);
+        if self.source.get() == TokenSource::GhUnverified {
+            self.drop_gh_token();
         }
+        eprintln!(
+            "{} Your GitHub authorization has expired or was revoked.",
+            "!".yellow()
+        );
+        Self::logout()?;
+        let relogin = std::io::stdin().is_terminal()
+            && dialoguer::Confirm::new()
+                .with_prompt("Log in to GitHub again now?")
+                .default(true)
+                .interact()
+                .unwrap_or(false);
+        if !relogin {
+            return Err(error.context(ConfinuumError::AuthExpired(Hosting::Github)));
+        }
+        self.replace(Self::login().await?);
+        call(self.client()?).await
+    }
 
+    /// Switch to the credentials of a new login
+    fn replace(&self, github: Github) {
+        *self.client.borrow_mut() = github.client.into_inner();
+        *self.user.borrow_mut() = github.user.into_inner();
+        self.source.set(github.source.get());
+    }
+
+    /// Log in with the device flow and store the credentials, replacing any existing ones
+    pub async fn login() -> anyhow::Result<Self> {
         let auth = Self::authenticate().await?;
-        let host = AuthHost::from(&auth);
+        Self::store_auth(auth).await
+    }
 
-        let github = Self {
-            client: octocrab::Octocrab::builder()
-                .oauth(auth)
-                .add_header(ACCEPT, "application/vnd.github+json".to_string())
-                .build()?,
+    /// Log in with a personal access token and store it, after checking that it works
+    pub async fn login_with_token(token: String) -> anyhow::Result<Self> {
+        let auth = |scope| OAuth {
+            access_token: secrecy::Secret::new(token.clone()),
+            token_type: "bearer".to_owned(),
+            scope,
         };
+        let (_, scopes) = Self::with_auth(auth(Vec::new()))?
+            .verify()
+            .await
+            .context("The token was rejected by GitHub")?;
+        Self::store_auth(auth(scopes)).await
+    }
+
+    async fn store_auth(auth: OAuth) -> anyhow::Result<Self> {
+        let host = AuthHost::from(&auth);
+        let github = Self::with_auth(auth)?;
 
         // Save the auth token to be reused later
+        let user = Self::fetch_auth_user(github.client()?).await?;
         let auth_file = AuthFile {
             auth: host,
-            user: github.get_auth_user().await?,
+            user: user.clone(),
         };
-
         auth_file.save()?;
+        *github.user.borrow_mut() = Some(user);
 
         Ok(github)
     }
 
+    /// Remove the stored credentials
+    /// Returns false if there were none
+    pub fn logout() -> anyhow::Result<bool> {
+        if !AuthFile::exists()? {
+            return Ok(false);
+        }
+        let path = AuthFile::get_path()?;
+        fs::remove_file(&path).with_context(|| format!("Could not remove {}", path.display()))?;
+        Ok(true)
+    }
+
+    /// Check that the stored token still works with a single API call
+    /// Returns the user's login and the scopes GitHub reports for the token
+    pub async fn verify(&self) -> anyhow::Result<(String, Vec<String>)> {
+        let client = self.client()?;
+        let url = client.absolute_url("/user")?;
+        let response = send(|| client._get(url.clone(), None::<&()>)).await?;
+        let scopes = response
+            .headers()
+            .get("x-oauth-scopes")
+            .and_then(|scopes| scopes.to_str().ok())
+            .map(|scopes| {
+                scopes
+                    .split(',')
+                    .map(|scope| scope.trim().to_owned())
+                    .filter(|scope| !scope.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let user: models::User = response.json().await?;
+        Ok((user.login, scopes))
+    }
+
+    /// Get the user's name and email, using the copy stored in the auth file when there is one so
+    /// that commits can be signed without any API calls (and while offline)
     pub async fn get_auth_user(&self) -> anyhow::Result<AuthUser> {
-        let res: Vec<EmailRes> = self.client.get("/user/public_emails", None::<&()>).await?;
+        if let Some(user) = self.user.borrow().as_ref() {
+            return Ok(user.clone());
+        }
+        self.refresh_user().await
+    }
+
+    /// Fetch the user's name and email from GitHub, and update the copy in the auth file
+    pub async fn refresh_user(&self) -> anyhow::Result<AuthUser> {
+        let user = self.with_reauth(Self::fetch_auth_user).await?;
+        if let Ok(mut auth_file) = AuthFile::load() {
+            auth_file.user = user.clone();
+            auth_file.save()?;
+        }
+        *self.user.borrow_mut() = Some(user.clone());
+        Ok(user)
+    }
+
+    async fn fetch_auth_user(client: octocrab::Octocrab) -> anyhow::Result<AuthUser> {
+        let res: Vec<EmailRes> = get_json(&client, "/user/public_emails").await?;
         let email = res
             .into_iter()
             .find(|e| {
@@ -56,23 +269,18 @@ impl Github {
             })
             .ok_or_else(|| anyhow!("No primary email found"))?
             .email;
-        let user = self.client.current().user().await?;
+        let user: models::User = get_json(&client, "/user").await?;
         Ok(AuthUser {
             name: user.login,
             email,
         })
     }
 
-    pub async fn get_user_signature(&self) -> anyhow::Result<Signature> {
-        let user = self.get_auth_user().await?;
-        Ok(Signature::now(&user.name, &user.email)?)
-    }
-
     pub fn is_authenticated() -> bool {
         if let Ok(true) = AuthFile::exists() {
             AuthFile::load().is_ok()
         } else {
-            false
+            gh_token().is_some()
         }
     }
 
@@ -82,53 +290,474 @@ impl Github {
             .add_header(ACCEPT, "application/json".to_string())
             .build()?;
 
-        // TODO: Figure out how to get this in without hardcoding it
-        let client_id = secrecy::Secret::from("49a3a1366a197af11b86".to_owned());
+        let client_id = secrecy::Secret::from(oauth_client_id());
+        let scopes = oauth_scopes();
         let codes = auth_client
-            .authenticate_as_device(&client_id, &["public_repo", "repo"])
+            .authenticate_as_device(&client_id, &scopes)
             .await?;
 
         println!(
             "Open this link in your browser and enter {}:\n{}",
             codes.user_code, codes.verification_uri
         );
+        // The code can't be entered once it expires, so there's no point waiting any longer
+        let expires_in = match codes.expires_in {
+            0 => DEVICE_CODE_LIFETIME,
+            seconds => Duration::from_secs(seconds),
+        };
+        let expires = Instant::now() + expires_in;
         let mut interval = Duration::from_secs(codes.interval);
-        let mut clock = tokio::time::interval(interval);
-        let auth = loop {
+        let mut clock = tokio::time::interval_at(Instant::now() + interval, interval);
+        clock.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        while Instant::now() < expires {
             clock.tick().await;
-            match codes.poll_once(&auth_client, &client_id).await? {
-                Either::Left(auth) => break auth,
-                Either::Right(cont) => match cont {
-                    octocrab::auth::Continue::SlowDown => {
-                        // Slow down polling
-                        interval += Duration::from_secs(5);
-                        clock = tokio::time::interval(interval);
-                        clock.tick().await;
-                    }
-                    octocrab::auth::Continue::AuthorizationPending => {
-                        // Keep polling
-                    }
-                },
+            let poll: DevicePoll = auth_client
+                .post(
+                    "/login/oauth/access_token",
+                    Some(&serde_json::json!({
+                        "client_id": client_id.expose_secret(),
+                        "device_code": codes.device_code,
+                        "grant_type": "urn:ietf:params:oauth:grant-type:device_code",
+                    })),
+                )
+                .await?;
+            let (error, description) = match poll {
+                DevicePoll::Authorized(auth) => return Ok(auth),
+                DevicePoll::Failed {
+                    error,
+                    error_description,
+                } => (error, error_description),
+            };
+            match error {
+                DevicePollError::AuthorizationPending => {
+                    // Keep polling
+                }
+                DevicePollError::SlowDown => {
+                    // GitHub asks for 5 more seconds between polls each time it says to slow down
+                    interval += Duration::from_secs(5);
+                    clock = tokio::time::interval_at(Instant::now() + interval, interval);
+                    clock.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                }
+                DevicePollError::ExpiredToken => break,
+                DevicePollError::AccessDenied => {
+                    return Err(anyhow::Error::from(ConfinuumError::AuthFailed)
+                        .context("Authorization was denied in the browser"));
+                }
+                DevicePollError::Other => {
+                    return Err(
+                        anyhow::Error::from(ConfinuumError::AuthFailed).context(format!(
+                            "GitHub rejected the authorization: {}",
+                            description.unwrap_or_else(|| "unknown error".to_owned())
+                        )),
+                    );
+                }
             }
+        }
+        Err(anyhow::Error::from(ConfinuumError::AuthFailed).context(
+            "Authorization timed out, the code expired before it was entered. Run `confinuum init` (or `confinuum auth login`) again to get a new code.",
+        ))
+    }
+}
+
+/// How long a device code lasts if GitHub doesn't say
+const DEVICE_CODE_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+/// GitHub's response to polling for the token of a device code
+/// octocrab's `poll_once` only understands the responses that mean "keep polling", and fails to
+/// parse the ones that end the flow, so the polling is done here.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DevicePoll {
+    Authorized(OAuth),
+    Failed {
+        error: DevicePollError,
+        error_description: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DevicePollError {
+    AuthorizationPending,
+    SlowDown,
+    ExpiredToken,
+    AccessDenied,
+    #[serde(other)]
+    Other,
+}
+
+#[async_trait(?Send)]
+impl Provider for Github {
+    fn hosting(&self) -> Hosting {
+        Hosting::Github
+    }
+
+    fn is_logged_in(&self) -> bool {
+        self.client.borrow().is_some()
+    }
+
+    async fn login(&mut self) -> anyhow::Result<()> {
+        self.replace(Self::login().await?);
+        Ok(())
+    }
+
+    async fn create_repo(
+        &self,
+        name: &str,
+        description: &str,
+        private: bool,
+    ) -> anyhow::Result<HostedRepo> {
+        let repo_info = &RepoCreateInfo {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            private,
+            is_template: false,
+            opt: None,
         };
-        Ok(auth)
+        self.with_reauth(|client| async move {
+            let url = client.absolute_url("/user/repos")?;
+            let response = send(|| client._post(url.clone(), Some(repo_info))).await;
+            match response {
+                Ok(response) => Ok(response.json::<models::Repository>().await?.into()),
+                // GitHub responds with 422 and a validation error on the name field if it's taken
+                Err(e) if is_name_taken(&e) => {
+                    Err(ConfinuumError::RepoExists(repo_info.name.clone()).into())
+                }
+                Err(e) => Err(e),
+            }
+        })
+        .await
     }
 
-    pub async fn create_repo(
+    async fn create_repo_from_template(
         &self,
-        repo_info: RepoCreateInfo,
-    ) -> anyhow::Result<models::Repository> {
-        let new_repo = self
-            .client
-            .post::<RepoCreateInfo, models::Repository>(
-                "https://api.github.com/user/repos",
-                Some(&repo_info),
-            )
+        template: &str,
+        name: &str,
+        description: &str,
+        private: bool,
+    ) -> anyhow::Result<HostedRepo> {
+        let (owner, repo) = template
+            .split_once('/')
+            .filter(|(owner, repo)| !owner.is_empty() && !repo.is_empty() && !repo.contains('/'))
+            .ok_or_else(|| anyhow!("Template {} should be given as owner/repo", template))?;
+        let repo_info = &RepoGenerateInfo {
+            name: name.to_owned(),
+            description: description.to_owned(),
+            private,
+            include_all_branches: false,
+        };
+        let created = self
+            .with_reauth(|client| async move {
+                let url = client.absolute_url(format!("/repos/{}/{}/generate", owner, repo))?;
+                let response = send(|| client._post(url.clone(), Some(repo_info))).await;
+                match response {
+                    Ok(response) => Ok(response.json::<models::Repository>().await?),
+                    Err(e) if is_name_taken(&e) => {
+                        Err(ConfinuumError::RepoExists(repo_info.name.clone()).into())
+                    }
+                    Err(e) => {
+                        Err(e.context(format!("Could not create a repository from {}", template)))
+                    }
+                }
+            })
             .await?;
-        Ok(new_repo)
+
+        // GitHub copies the template's contents after responding, and the repository can't be
+        // cloned until it's done
+        let full_name = created
+            .full_name
+            .clone()
+            .ok_or_else(|| anyhow!("GitHub didn't say who owns the new repository"))?;
+        let branches_route = format!("/repos/{}/branches", full_name);
+        for _ in 0..TEMPLATE_COPY_POLLS {
+            let branches: Vec<serde_json::Value> = self
+                .with_reauth(|client| {
+                    let route = branches_route.clone();
+                    async move { get_json(&client, &route).await }
+                })
+                .await?;
+            if !branches.is_empty() {
+                return Ok(created.into());
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Err(anyhow!(
+            "GitHub didn't finish copying {} into {} in time, run `confinuum init --git <url>` with the new repository's URL once it's done",
+            template,
+            name
+        ))
+    }
+
+    async fn get_repo(&self, name: &str) -> anyhow::Result<HostedRepo> {
+        self.with_reauth(|client| async move {
+            let user: models::User = get_json(&client, "/user").await?;
+            let repo: models::Repository =
+                get_json(&client, &format!("/repos/{}/{}", user.login, name))
+                    .await
+                    .with_context(|| {
+                        format!("Could not find repository {}/{}", user.login, name)
+                    })?;
+            Ok(repo.into())
+        })
+        .await
+    }
+
+    async fn get_user_signature(&self) -> anyhow::Result<Signature<'static>> {
+        let user = self.get_auth_user().await?;
+        Ok(Signature::now(&user.name, &user.email)?)
     }
 }
 
+impl From<models::Repository> for HostedRepo {
+    fn from(repo: models::Repository) -> Self {
+        Self {
+            name: repo.name,
+            html_url: repo.html_url.map(|url| url.to_string()),
+            ssh_url: repo.ssh_url,
+            https_url: repo.clone_url.unwrap_or(repo.url).to_string(),
+        }
+    }
+}
+
+/// The OAuth app used for the device flow, unless another one is configured
+const DEFAULT_CLIENT_ID: &str = "49a3a1366a197af11b86";
+
+/// The scopes requested in the device flow by default
+/// `repo` is needed to create and push to private repos; `public_repo` is enough for public ones.
+const DEFAULT_SCOPES: [&str; 2] = ["public_repo", "repo"];
+
+/// The client id of the OAuth app to log in with, from `CONFINUUM_GITHUB_CLIENT_ID`, then the
+/// config's `github_client_id`, then the built-in app
+fn oauth_client_id() -> String {
+    std::env::var("CONFINUUM_GITHUB_CLIENT_ID")
+        .ok()
+        .filter(|id| !id.trim().is_empty())
+        .or_else(|| {
+            ConfinuumConfig::load()
+                .ok()
+                .and_then(|config| config.confinuum.github_client_id)
+        })
+        .unwrap_or_else(|| DEFAULT_CLIENT_ID.to_owned())
+}
+
+/// The scopes to request when logging in, from `CONFINUUM_GITHUB_SCOPES` (comma separated), then
+/// the config's `github_scopes`, then the defaults
+pub fn oauth_scopes() -> Vec<String> {
+    std::env::var("CONFINUUM_GITHUB_SCOPES")
+        .ok()
+        .map(|scopes| {
+            scopes
+                .split(',')
+                .map(|scope| scope.trim().to_owned())
+                .filter(|scope| !scope.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|scopes| !scopes.is_empty())
+        .or_else(|| {
+            ConfinuumConfig::load()
+                .ok()
+                .and_then(|config| config.confinuum.github_scopes)
+        })
+        .unwrap_or_else(|| DEFAULT_SCOPES.map(str::to_owned).to_vec())
+}
+
+/// Get the token from the `gh` CLI's login to github.com, if the user is logged in with it
+/// gh stores the token in hosts.yml unless it was able to use the system keyring, in which case
+/// `gh auth token` is the only way to get at it.
+fn gh_token() -> Option<String> {
+    let config_dir = match std::env::var_os("GH_CONFIG_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir).join("gh"),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config/gh"),
+        },
+    };
+    let hosts = fs::read_to_string(config_dir.join("hosts.yml")).ok()?;
+    let mut hosts: HashMap<String, GhHost> = serde_yaml::from_str(&hosts).ok()?;
+    let host = hosts.remove("github.com")?;
+    if let Some(token) = host.oauth_token.filter(|token| !token.is_empty()) {
+        return Some(token);
+    }
+    let output = std::process::Command::new("gh")
+        .args(["auth", "token", "--hostname", "github.com"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let token = String::from_utf8(output.stdout).ok()?.trim().to_owned();
+    (output.status.success() && !token.is_empty()).then_some(token)
+}
+
+/// A host entry in gh's hosts.yml
+#[derive(Debug, Deserialize)]
+struct GhHost {
+    oauth_token: Option<String>,
+}
+
+/// How many times to check (once a second) whether a repository created from a template is ready
+const TEMPLATE_COPY_POLLS: usize = 30;
+
+/// How many times a request is retried after hitting the rate limit before giving up
+const RATE_LIMIT_RETRIES: usize = 3;
+
+/// Send a request to the API, waiting for the rate limit to reset and retrying if it was hit
+/// Error responses are turned into `octocrab::Error::GitHub` like octocrab's own helpers do, with
+/// a `GithubError` for the status as context.
+async fn send<F, Fut>(request: F) -> anyhow::Result<reqwest::Response>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = octocrab::Result<reqwest::Response>>,
+{
+    let mut retries = 0;
+    loop {
+        let response = request().await?;
+        tracing::debug!(
+            "GitHub API {} returned {}",
+            response.url().path(),
+            response.status()
+        );
+        let wait = rate_limit_wait(&response);
+        match wait {
+            Some(wait) if retries < RATE_LIMIT_RETRIES => {
+                retries += 1;
+                tracing::info!("Hit the GitHub rate limit, retrying in {}s", wait.as_secs());
+                wait_for_rate_limit(wait).await;
+            }
+            _ => {
+                let error = GithubError {
+                    status: response.status(),
+                    rate_limited: wait.is_some(),
+                };
+                return octocrab::map_github_error(response)
+                    .await
+                    .map_err(|e| anyhow::Error::from(e).context(error));
+            }
+        }
+    }
+}
+
+/// GET a route of the API and deserialize the response
+async fn get_json<R: DeserializeOwned>(
+    client: &octocrab::Octocrab,
+    route: &str,
+) -> anyhow::Result<R> {
+    let url = client.absolute_url(route)?;
+    let response = send(|| client._get(url.clone(), None::<&()>)).await?;
+    Ok(response.json().await?)
+}
+
+/// How long to wait before retrying, if the response says the rate limit was hit
+/// GitHub sends `Retry-After` for secondary rate limits, and `X-RateLimit-Reset` (a unix timestamp)
+/// with `X-RateLimit-Remaining: 0` when the primary limit is used up. Other 403s are left alone.
+fn rate_limit_wait(response: &reqwest::Response) -> Option<Duration> {
+    let status = response.status();
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok())
+    };
+    if let Some(seconds) = header("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+    if header("x-ratelimit-remaining") == Some(0) {
+        if let Some(reset) = header("x-ratelimit-reset") {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            // Give GitHub's clock a second of slack so the retry doesn't land just before the reset
+            return Some(Duration::from_secs(reset.saturating_sub(now) + 1));
+        }
+    }
+    // GitHub recommends waiting at least a minute when a secondary limit is hit without a header
+    (status == StatusCode::TOO_MANY_REQUESTS).then(|| Duration::from_secs(60))
+}
+
+/// Sleep until the rate limit resets, counting down on a spinner
+async fn wait_for_rate_limit(wait: Duration) {
+    let message = |remaining: u64| {
+        format!(
+            "GitHub rate limit reached, retrying in {}",
+            if remaining >= 60 {
+                format!("{}m {:02}s", remaining / 60, remaining % 60)
+            } else {
+                format!("{}s", remaining)
+            }
+        )
+    };
+    let spinner = Spinner::new_shared(spinners::Dots9, message(wait.as_secs()), Color::Yellow);
+    let deadline = Instant::now() + wait;
+    let mut clock = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        clock.tick().await;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        spinner.update_text(message(remaining.as_secs() + 1));
+    }
+    spinner.clear();
+}
+
+/// Whether an error means GitHub (or another hosting service) couldn't be used right now
+/// (unreachable, or the user isn't logged in or their token was rejected), as opposed to an
+/// unexpected response
+pub fn is_unavailable(error: &anyhow::Error) -> bool {
+    if is_auth_error(error) {
+        return true;
+    }
+    // Checked on the error itself too, since that also finds errors attached as context
+    if matches!(
+        error.downcast_ref::<ConfinuumError>(),
+        Some(ConfinuumError::NotLoggedIn(_) | ConfinuumError::AuthExpired(_))
+    ) {
+        return true;
+    }
+    error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<octocrab::Error>(),
+            Some(octocrab::Error::Http { .. })
+        ) || cause.downcast_ref::<reqwest::Error>().is_some()
+    })
+}
+
+/// Whether an error is GitHub rejecting the token: a 401, or a 403 that isn't the rate limit (the
+/// token was revoked, or is missing a scope the request needs)
+pub fn is_auth_error(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<GithubError>().is_some_and(|e| {
+        e.status == StatusCode::UNAUTHORIZED
+            || (e.status == StatusCode::FORBIDDEN && !e.rate_limited)
+    })
+}
+
+/// Whether a GitHub API error is the validation error for a repository name that's already in use
+fn is_name_taken(error: &anyhow::Error) -> bool {
+    let Some(octocrab::Error::GitHub { source }) = error.downcast_ref::<octocrab::Error>() else {
+        return false;
+    };
+    source.errors.iter().flatten().any(|e| {
+        e.get("field").and_then(|f| f.as_str()) == Some("name")
+            && e.get("message")
+                .and_then(|m| m.as_str())
+                .is_some_and(|m| m.contains("already exists"))
+    })
+}
+
+/// The status of an error response from the GitHub API, attached to octocrab's error for it
+/// (which leaves the status out)
+#[derive(Debug, thiserror::Error)]
+#[error("GitHub responded with {status}")]
+pub struct GithubError {
+    pub status: StatusCode,
+    /// Whether the response was the rate limit, which is still hit after retrying
+    pub rate_limited: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RepoCreateInfo {
     pub name: String,
@@ -139,6 +768,15 @@ pub struct RepoCreateInfo {
     pub opt: Option<RepoCreateInfoOpt>,
 }
 
+/// The body of a request to create a repository from a template
+#[derive(Debug, Serialize)]
+struct RepoGenerateInfo {
+    name: String,
+    description: String,
+    private: bool,
+    include_all_branches: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RepoCreateInfoOpt {
     pub has_downloads: Option<bool>,
@@ -160,19 +798,30 @@ pub struct AuthFile {
     pub auth: AuthHost,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AuthHost {
     pub token: String,
     pub token_type: String,
     pub scopes: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthUser {
     pub name: String,
     pub email: String,
 }
 
+// Written by hand so that the token can't end up in logs or error messages
+impl std::fmt::Debug for AuthHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthHost")
+            .field("token", &"[REDACTED]")
+            .field("token_type", &self.token_type)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
 impl From<&OAuth> for AuthHost {
     fn from(oauth: &OAuth) -> Self {
         Self {
@@ -215,6 +864,7 @@ impl AuthFile {
             ));
         }
         let path = Self::get_path()?;
+        config::check_secret_permissions(&path)?;
         let file = std::fs::read_to_string(&path)
             .with_context(|| format!("Could not read from {}", path.display()))?;
         let auth_file: Self = toml::from_str(&file)?;
@@ -228,7 +878,108 @@ impl AuthFile {
         if !conf_dir.exists() {
             std::fs::create_dir_all(conf_dir)?;
         }
-        fs::write(path, file)?;
-        Ok(())
+        config::write_secret(&path, &file)
+    }
+}
+
+/// Remember a token that's in use, so that it's redacted from panic messages
+pub fn register_token(token: secrecy::Secret<String>) {
+    if let Ok(mut tokens) = ACTIVE_TOKENS.lock() {
+        tokens.push(token);
+    }
+}
+
+/// Replace anything that looks like a GitHub token (`ghp_...`, `gho_...` etc.), as well as the token
+/// currently in use, with a placeholder
+pub fn redact_tokens(text: &str) -> String {
+    let mut text = text.to_owned();
+    if let Ok(tokens) = ACTIVE_TOKENS.lock() {
+        for token in tokens.iter() {
+            let token = token.expose_secret();
+            if !token.is_empty() {
+                text = text.replace(token.as_str(), "[REDACTED]");
+            }
+        }
+    }
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(idx) = rest.find("gh") {
+        let candidate = &rest[idx..];
+        let bytes = candidate.as_bytes();
+        let is_token = bytes.len() > 4
+            && b"pousr".contains(&bytes[2])
+            && bytes[3] == b'_'
+            && bytes[4].is_ascii_alphanumeric();
+        if is_token {
+            redacted.push_str(&rest[..idx + 4]);
+            redacted.push_str("[REDACTED]");
+            let len = candidate[4..]
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(candidate.len() - 4);
+            rest = &candidate[4 + len..];
+        } else {
+            redacted.push_str(&rest[..idx + 2]);
+            rest = &candidate[2..];
+        }
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unavailable_when_not_logged_in() {
+        let error = anyhow::Error::from(ConfinuumError::NotLoggedIn(Hosting::Github));
+        assert!(is_unavailable(&error));
+        assert!(is_unavailable(
+            &error.context("Could not create the repository")
+        ));
+    }
+
+    #[test]
+    fn unavailable_with_context() {
+        let error =
+            anyhow!("Bad credentials").context(ConfinuumError::AuthExpired(Hosting::Github));
+        assert!(is_unavailable(&error));
+    }
+
+    fn response_error(status: StatusCode, rate_limited: bool) -> anyhow::Error {
+        anyhow!("Bad credentials").context(GithubError {
+            status,
+            rate_limited,
+        })
+    }
+
+    #[test]
+    fn auth_errors() {
+        assert!(is_auth_error(&response_error(
+            StatusCode::UNAUTHORIZED,
+            false
+        )));
+        // A revoked token, or one without the scope a request needs
+        assert!(is_auth_error(&response_error(StatusCode::FORBIDDEN, false)));
+        let error = response_error(StatusCode::FORBIDDEN, false).context("Could not find repo");
+        assert!(is_auth_error(&error));
+    }
+
+    #[test]
+    fn not_auth_errors() {
+        assert!(!is_auth_error(&response_error(StatusCode::FORBIDDEN, true)));
+        assert!(!is_auth_error(&response_error(
+            StatusCode::NOT_FOUND,
+            false
+        )));
+        assert!(!is_auth_error(&anyhow!("Bad credentials")));
+    }
+
+    #[test]
+    fn available_for_other_errors() {
+        assert!(!is_unavailable(&anyhow!("Repository already exists")));
+        let error = anyhow!("Bad credentials").context(ConfinuumError::AuthFailed);
+        assert!(!is_unavailable(&error));
     }
 }
@@ -0,0 +1,337 @@
+//! GitLab support, for configs hosted on gitlab.com or a self-hosted instance
+//! GitLab's device flow needs an application registered on each instance, so this authenticates
+//! with a personal access token instead.
+
+use std::{cell::RefCell, fs};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use git2::Signature;
+use reqwest::{header::ACCEPT, Method, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::{self, ConfinuumConfig, Hosting},
+    error::ConfinuumError,
+    github::{self, AuthUser},
+    hosting::{self, HostedRepo, Provider},
+};
+
+/// The instance used when no URL is configured
+pub const DEFAULT_URL: &str = "https://gitlab.com";
+
+pub struct Gitlab {
+    client: reqwest::Client,
+    /// The instance's base URL, without a trailing slash
+    url: String,
+    /// None if the user hasn't logged in to this instance
+    token: RefCell<Option<String>>,
+    /// The user's name and email, loaded from the auth file or fetched once per run
+    user: RefCell<Option<AuthUser>>,
+}
+
+impl Gitlab {
+    /// Load the stored credentials for the instance at `url` (gitlab.com if not given), if there are
+    /// any. Credentials stored for a different instance are ignored.
+    pub fn new(url: Option<&str>) -> Result<Self> {
+        let url = url.unwrap_or(DEFAULT_URL).trim_end_matches('/').to_owned();
+        let auth_file = if GitlabAuthFile::exists()? {
+            Some(GitlabAuthFile::load()?).filter(|auth_file| auth_file.url == url)
+        } else {
+            None
+        };
+        if let Some(auth_file) = &auth_file {
+            github::register_token(secrecy::Secret::new(auth_file.token.clone()));
+        }
+        let (token, user) = match auth_file {
+            Some(auth_file) => (Some(auth_file.token), Some(auth_file.user)),
+            None => (None, None),
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            url,
+            token: RefCell::new(token),
+            user: RefCell::new(user),
+        })
+    }
+
+    /// The instance's base URL
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Log in with a personal access token and store it, after checking that it works
+    pub async fn login_with_token(&self, token: String) -> Result<AuthUser> {
+        github::register_token(secrecy::Secret::new(token.clone()));
+        let previous = self.token.replace(Some(token.clone()));
+        let user = match self.fetch_user().await {
+            Ok(user) => user,
+            Err(e) => {
+                *self.token.borrow_mut() = previous;
+                return Err(e.context("The token was rejected by GitLab"));
+            }
+        };
+        let auth_file = GitlabAuthFile {
+            url: self.url.clone(),
+            token,
+            user: user.clone(),
+        };
+        auth_file.save()?;
+        *self.user.borrow_mut() = Some(user.clone());
+        Ok(user)
+    }
+
+    /// Remove the stored credentials
+    /// Returns false if there were none
+    pub fn logout() -> Result<bool> {
+        if !GitlabAuthFile::exists()? {
+            return Ok(false);
+        }
+        let path = GitlabAuthFile::get_path()?;
+        fs::remove_file(&path).with_context(|| format!("Could not remove {}", path.display()))?;
+        Ok(true)
+    }
+
+    /// Get the user's name and email, using the copy stored in the auth file when there is one
+    pub async fn get_auth_user(&self) -> Result<AuthUser> {
+        if let Some(user) = self.user.borrow().as_ref() {
+            return Ok(user.clone());
+        }
+        let user = self.fetch_user().await?;
+        *self.user.borrow_mut() = Some(user.clone());
+        Ok(user)
+    }
+
+    async fn fetch_user(&self) -> Result<AuthUser> {
+        let user: UserRes = self.request(Method::GET, "/user", None).await?;
+        let email = user
+            .commit_email
+            .or(user.public_email)
+            .or(user.email)
+            .filter(|email| !email.is_empty())
+            .ok_or_else(|| anyhow!("No email found on your GitLab account"))?;
+        Ok(AuthUser {
+            name: user.username,
+            email,
+        })
+    }
+
+    /// Make a call to the REST API, with the stored token
+    async fn request<R: DeserializeOwned>(
+        &self,
+        method: Method,
+        route: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<R> {
+        let token = self
+            .token
+            .borrow()
+            .clone()
+            .ok_or(ConfinuumError::NotLoggedIn(Hosting::Gitlab))?;
+        let mut request = self
+            .client
+            .request(method, format!("{}/api/v4{}", self.url, route))
+            .header("PRIVATE-TOKEN", token)
+            .header(ACCEPT, "application/json");
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.send().await?;
+        tracing::debug!("GitLab API {} returned {}", route, response.status());
+        match response.status() {
+            // 403 is a token without the `api` scope (or a revoked one), which logging in again fixes
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(ConfinuumError::AuthExpired(Hosting::Gitlab).into())
+            }
+            status if !status.is_success() => {
+                let message = response
+                    .json::<ErrorRes>()
+                    .await
+                    .map(|e| e.message.or(e.error).unwrap_or_default())
+                    .unwrap_or_default();
+                Err(GitlabError { status, message }.into())
+            }
+            _ => Ok(response.json().await?),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Provider for Gitlab {
+    fn hosting(&self) -> Hosting {
+        Hosting::Gitlab
+    }
+
+    fn is_logged_in(&self) -> bool {
+        self.token.borrow().is_some()
+    }
+
+    async fn login(&mut self) -> Result<()> {
+        println!(
+            "Create a personal access token with the `api` scope at {}/-/profile/personal_access_tokens",
+            self.url
+        );
+        let token = dialoguer::Password::new()
+            .with_prompt("Personal access token")
+            .interact()
+            .context("Failed to interact with user, cancelling.")?;
+        self.login_with_token(token.trim().to_owned()).await?;
+        Ok(())
+    }
+
+    async fn create_repo(
+        &self,
+        name: &str,
+        description: &str,
+        private: bool,
+    ) -> Result<HostedRepo> {
+        let body = serde_json::json!({
+            "name": name,
+            "description": description,
+            "visibility": if private { "private" } else { "public" },
+        });
+        match self
+            .request::<ProjectRes>(Method::POST, "/projects", Some(&body))
+            .await
+        {
+            Ok(project) => Ok(project.into()),
+            // GitLab responds with 400 and "has already been taken" on the name or path if it's taken
+            Err(e)
+                if e.downcast_ref::<GitlabError>()
+                    .is_some_and(|e| e.message.contains("has already been taken")) =>
+            {
+                Err(ConfinuumError::RepoExists(name.to_owned()).into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn get_repo(&self, name: &str) -> Result<HostedRepo> {
+        let user = self.get_auth_user().await?;
+        let project: ProjectRes = self
+            .request(
+                Method::GET,
+                // The project is given by its whole path, encoded as one segment
+                &format!(
+                    "/projects/{}",
+                    urlencoding::encode(&format!("{}/{}", user.name, name))
+                ),
+                None,
+            )
+            .await
+            .with_context(|| format!("Could not find repository {}/{}", user.name, name))?;
+        Ok(project.into())
+    }
+
+    async fn get_user_signature(&self) -> Result<Signature<'static>> {
+        let user = self.get_auth_user().await?;
+        Ok(Signature::now(&user.name, &user.email)?)
+    }
+}
+
+/// An error response from the GitLab API
+#[derive(Debug, thiserror::Error)]
+#[error("GitLab responded with {status}: {message}")]
+pub struct GitlabError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+/// GitLab sends errors as `{"message": ...}` (where the message can be an object of field errors),
+/// or as `{"error": ...}` for some endpoints
+#[derive(Debug, Deserialize)]
+struct ErrorRes {
+    #[serde(default, deserialize_with = "message_to_string")]
+    message: Option<String>,
+    error: Option<String>,
+}
+
+fn message_to_string<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(message) => Some(message),
+        serde_json::Value::Null => None,
+        other => Some(other.to_string()),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct UserRes {
+    username: String,
+    email: Option<String>,
+    public_email: Option<String>,
+    commit_email: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProjectRes {
+    name: String,
+    web_url: String,
+    ssh_url_to_repo: String,
+    http_url_to_repo: String,
+}
+
+impl From<ProjectRes> for HostedRepo {
+    fn from(project: ProjectRes) -> Self {
+        Self {
+            name: project.name,
+            html_url: Some(project.web_url),
+            ssh_url: Some(project.ssh_url_to_repo),
+            https_url: project.http_url_to_repo,
+        }
+    }
+}
+
+/// The stored GitLab credentials, kept separate from hosts.toml (which holds GitHub's)
+#[derive(Serialize, Deserialize)]
+pub struct GitlabAuthFile {
+    pub url: String,
+    pub token: String,
+    pub user: AuthUser,
+}
+
+// Written by hand so that the token can't end up in logs or error messages
+impl std::fmt::Debug for GitlabAuthFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GitlabAuthFile")
+            .field("url", &self.url)
+            .field("token", &"[REDACTED]")
+            .field("user", &self.user)
+            .finish()
+    }
+}
+
+impl GitlabAuthFile {
+    pub fn get_path() -> Result<std::path::PathBuf> {
+        Ok(ConfinuumConfig::get_dir()?.join("gitlab.toml"))
+    }
+
+    pub fn exists() -> Result<bool> {
+        let path = Self::get_path()?;
+        if path.is_dir() {
+            return Err(anyhow!(
+                "GitLab auth file is a directory. Please remove it and try again."
+            ));
+        }
+        Ok(path.is_file())
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::get_path()?;
+        config::check_secret_permissions(&path)?;
+        let file = fs::read_to_string(&path)
+            .with_context(|| format!("Could not read from {}", path.display()))?;
+        Ok(toml::from_str(&file)?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let conf_dir = ConfinuumConfig::get_dir()?;
+        if !conf_dir.exists() {
+            fs::create_dir_all(&conf_dir)?;
+        }
+        hosting::exclude_from_repo("gitlab.toml")?;
+        config::write_secret(&Self::get_path()?, &toml::to_string(&self)?)
+    }
+}
@@ -0,0 +1,86 @@
+//! The services that can host the config repo
+//! Git operations go through git2 no matter where the repo is hosted, so this only covers the API
+//! calls used to create the repo and sign commits with the user's account.
+
+use std::{fs, io::Write};
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use git2::Signature;
+
+use crate::config::{ConfinuumConfig, Hosting};
+
+/// A repository on a hosting service
+pub struct HostedRepo {
+    pub name: String,
+    /// The repository's page in the browser
+    pub html_url: Option<String>,
+    pub ssh_url: Option<String>,
+    pub https_url: String,
+}
+
+#[async_trait(?Send)]
+pub trait Provider {
+    /// Which service this is
+    fn hosting(&self) -> Hosting;
+
+    /// Whether credentials were loaded, i.e. whether the service can be used without logging in
+    fn is_logged_in(&self) -> bool;
+
+    /// Log in interactively and store the credentials, replacing any existing ones
+    async fn login(&mut self) -> Result<()>;
+
+    /// Create a repository on the user's account
+    /// Fails with `ConfinuumError::RepoExists` if the user already has a repository with that name.
+    async fn create_repo(&self, name: &str, description: &str, private: bool)
+        -> Result<HostedRepo>;
+
+    /// Create a repository on the user's account with the contents of a template repository,
+    /// given as `owner/repo`
+    /// Only GitHub has templates, so the other services fail.
+    async fn create_repo_from_template(
+        &self,
+        template: &str,
+        _name: &str,
+        _description: &str,
+        _private: bool,
+    ) -> Result<HostedRepo> {
+        Err(anyhow!(
+            "Can't create a repository from template {}: {} doesn't support templates",
+            template,
+            self.hosting()
+        ))
+    }
+
+    /// Get one of the user's repositories by name
+    async fn get_repo(&self, name: &str) -> Result<HostedRepo>;
+
+    /// The signature to sign commits with, made from the account's name and email
+    async fn get_user_signature(&self) -> Result<Signature<'static>>;
+}
+
+/// Make sure a file holding credentials can't be committed to the config repo
+/// It's added to the repo's local exclude file, since the .gitignore of configs created by older
+/// versions only covers hosts.toml.
+pub fn exclude_from_repo(file_name: &str) -> Result<()> {
+    let git_dir = ConfinuumConfig::get_dir()?.join(".git");
+    if !git_dir.is_dir() {
+        return Ok(());
+    }
+    let exclude_path = git_dir.join("info/exclude");
+    let exclude = fs::read_to_string(&exclude_path).unwrap_or_default();
+    if exclude.lines().any(|line| line.trim() == file_name) {
+        return Ok(());
+    }
+    fs::create_dir_all(git_dir.join("info"))?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&exclude_path)
+        .with_context(|| format!("Could not open {}", exclude_path.display()))?;
+    if !exclude.is_empty() && !exclude.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{}", file_name)?;
+    Ok(())
+}
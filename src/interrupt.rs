@@ -0,0 +1,87 @@
+//! Ctrl-C (SIGINT) and SIGTERM handling
+//! Outside of a `Deferred` section a signal exits right away, after restoring the terminal. Inside
+//! one, the first signal only sets a flag, and the operation stops at the next `check` (or when a
+//! git transfer's progress callback sees it) so that its error path can roll back. A second
+//! signal always exits right away.
+
+use std::{
+    io::stdout,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    cli,
+    error::{exit_code, ConfinuumError},
+};
+
+/// Set by the first signal
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// The number of live `Deferred` sections
+static DEFERRED: AtomicUsize = AtomicUsize::new(0);
+
+/// Listen for SIGINT and SIGTERM for the rest of the process
+pub fn install() {
+    tokio::spawn(async {
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            if INTERRUPTED.swap(true, Ordering::SeqCst) || DEFERRED.load(Ordering::SeqCst) == 0 {
+                exit();
+            }
+            eprintln!("\nStopping, press Ctrl-C again to exit right away");
+        }
+    });
+}
+
+/// Whether a signal was received
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fail with `ConfinuumError::Interrupted` if a signal was received
+/// Call this where stopping is safe, e.g. between copying files.
+pub fn check() -> Result<()> {
+    match interrupted() {
+        true => Err(ConfinuumError::Interrupted.into()),
+        false => Ok(()),
+    }
+}
+
+/// While one of these is alive, a signal waits for the operation to stop at a `check`
+pub struct Deferred(());
+
+impl Deferred {
+    pub fn new() -> Self {
+        DEFERRED.fetch_add(1, Ordering::SeqCst);
+        Deferred(())
+    }
+}
+
+impl Drop for Deferred {
+    fn drop(&mut self) {
+        DEFERRED.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Restore the terminal a spinner may have left the cursor hidden in, and exit
+fn exit() -> ! {
+    if cli::progress_enabled() {
+        crossterm::execute!(
+            stdout(),
+            crossterm::cursor::MoveToColumn(0),
+            crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
+            crossterm::cursor::Show
+        )
+        .ok();
+    }
+    eprintln!("Interrupted");
+    std::process::exit(exit_code::INTERRUPTED.into())
+}
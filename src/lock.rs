@@ -0,0 +1,93 @@
+//! Keeps two confinuum processes from changing the config at the same time
+//! Commands that change the config repo, config.toml or the deployed links hold an advisory lock on
+//! a file in the config directory while they run. The lock is released when the guard is dropped,
+//! and by the OS if the process dies without dropping it.
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use spinoff::{spinners, Color, Spinner};
+
+use crate::{
+    cli::{CreateSharedSpinner, SharedSpinner},
+    config::ConfinuumConfig,
+    error::ConfinuumError,
+    hosting,
+};
+
+const LOCK_FILE: &str = ".confinuum.lock";
+
+/// How long to wait for another process to finish before giving up
+const LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Held while a command changes the config
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Take the lock, waiting up to a minute for another confinuum process to release it
+/// Returns None if there's no config directory yet, since there's nothing to protect.
+pub fn acquire() -> Result<Option<InstanceLock>> {
+    let config_dir = ConfinuumConfig::get_dir()?;
+    if !config_dir.is_dir() {
+        return Ok(None);
+    }
+    let path = config_dir.join(LOCK_FILE);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            tracing::info!("{} is locked by another process", path.display());
+            wait_for_lock(&file, &path)?
+        }
+        Err(TryLockError::Error(e)) => {
+            return Err(e).with_context(|| format!("Could not lock {}", path.display()))
+        }
+    }
+    file.set_len(0)?;
+    write!(file, "{}", std::process::id())?;
+    hosting::exclude_from_repo(LOCK_FILE)?;
+    tracing::debug!("Locked {}", path.display());
+    Ok(Some(InstanceLock { _file: file }))
+}
+
+/// Keep trying to take the lock held by another process, with a spinner naming it
+fn wait_for_lock(file: &File, path: &Path) -> Result<()> {
+    // Written by the process holding the lock, only for the messages
+    let holder = match fs::read_to_string(path) {
+        Ok(pid) if !pid.trim().is_empty() => format!("pid {}", pid.trim()),
+        _ => "unknown pid".to_owned(),
+    };
+    let spinner = Spinner::new_shared(
+        spinners::Dots9,
+        format!("Waiting for another confinuum process ({})", holder),
+        Color::Yellow,
+    );
+    let start = Instant::now();
+    let result = loop {
+        match file.try_lock() {
+            Ok(()) => break Ok(()),
+            Err(TryLockError::WouldBlock) if start.elapsed() < LOCK_TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(100))
+            }
+            Err(TryLockError::WouldBlock) => break Err(ConfinuumError::Locked(holder).into()),
+            Err(TryLockError::Error(e)) => {
+                break Err(e).with_context(|| format!("Could not lock {}", path.display()))
+            }
+        }
+    };
+    spinner.clear();
+    result
+}
@@ -0,0 +1,102 @@
+//! Diagnostic logging with tracing
+//! Every run logs at debug level to confinuum.log in the cache directory, so that bug reports can
+//! include what happened, and the logs of the previous few runs are kept next to it. Nothing is
+//! logged to the console unless -v (info), -vv (debug) or -vvv (trace) raise the level, or
+//! RUST_LOG sets the filter.
+
+use std::{
+    fs::{self, File},
+    io::{IsTerminal, Write},
+    path::PathBuf,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{filter::Targets, layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+const LOG_FILE: &str = "confinuum.log";
+
+/// How many runs' logs to keep, including the current one
+const KEEP_LOGS: usize = 5;
+
+/// Where the log file is written, in $XDG_CACHE_HOME/confinuum (or ~/.cache/confinuum)
+pub fn log_path() -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_dir.join("confinuum").join(LOG_FILE))
+}
+
+/// Start logging for this run, with the console level set by the number of -v flags
+/// Logging is only for diagnostics, so a log file that can't be written is silently skipped.
+pub fn init(verbosity: u8) {
+    let file_layer = open_log().map(|file| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(Mutex::new(file))
+            .with_filter(
+                // Libraries only add noise to the file unless something went wrong in them
+                Targets::new()
+                    .with_target("confinuum", LevelFilter::DEBUG)
+                    .with_default(LevelFilter::WARN),
+            )
+    });
+
+    let console_filter = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|filter| Targets::from_str(&filter).ok())
+        .unwrap_or_else(|| {
+            let level = match verbosity {
+                0 => LevelFilter::OFF,
+                1 => LevelFilter::INFO,
+                2 => LevelFilter::DEBUG,
+                _ => LevelFilter::TRACE,
+            };
+            Targets::new().with_target("confinuum", level)
+        });
+    let console_layer = tracing_subscriber::fmt::layer()
+        .with_writer(|| ConsoleWriter)
+        .without_time()
+        .with_filter(console_filter);
+
+    tracing_subscriber::registry()
+        .with(file_layer)
+        .with(console_layer)
+        .try_init()
+        .ok();
+}
+
+/// Move the previous runs' logs back by one and open a new log file
+fn open_log() -> Option<File> {
+    let path = log_path()?;
+    fs::create_dir_all(path.parent()?).ok()?;
+    let numbered = |n: usize| path.with_file_name(format!("{}.{}", LOG_FILE, n));
+    for n in (1..KEEP_LOGS - 1).rev() {
+        fs::rename(numbered(n), numbered(n + 1)).ok();
+    }
+    fs::rename(&path, numbered(1)).ok();
+    File::create(&path).ok()
+}
+
+/// Writes log lines to stderr, clearing the line a spinner is drawn on first
+/// The spinner redraws itself below the log line on its next frame.
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if crate::cli::progress_enabled() && std::io::stderr().is_terminal() {
+            crossterm::execute!(
+                std::io::stdout(),
+                crossterm::cursor::MoveToColumn(0),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine)
+            )
+            .ok();
+        }
+        std::io::stderr().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
@@ -4,53 +4,102 @@
 
 #![cfg(not(windows))]
 
-use anyhow::Result;
-use std::io::stdout;
+use std::{
+    io::{stdout, IsTerminal},
+    process::ExitCode,
+};
 
 mod cli;
 mod commands;
+mod completions;
 mod config;
 mod deployment;
+mod error;
 mod git;
+mod gitea;
 mod github;
+mod gitlab;
+mod hosting;
+mod interrupt;
+mod lock;
+mod logging;
+mod migrations;
+mod pager;
+mod rollback;
+mod updates;
 
 // TODO: Allow for an entry to contain submodules or be a submodule
 // TODO: You shouldn't have to specify the entry when removing a file, we can figure that out from the file's path
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     // Panic handler
+    // Everything printed here goes through `redact_tokens`, since panic output tends to get pasted
+    // into issue reports
     std::panic::set_hook(Box::new(|info| {
-        crossterm::execute!(
-            stdout(),
-            crossterm::cursor::MoveToColumn(0),
-            crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
-            crossterm::cursor::Show
-        )
-        .unwrap();
+        if stdout().is_terminal() {
+            crossterm::execute!(
+                stdout(),
+                crossterm::cursor::MoveToColumn(0),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
+                crossterm::cursor::Show
+            )
+            .unwrap();
+        }
         println!("\nThe program has panicked! Please report this to https://github.com/willothy/confinuum/issues");
         if let Some(location) = info.location() {
-            let message = info
-                .payload()
+            let payload = info.payload();
+            let message = payload
                 .downcast_ref::<&str>()
-                .unwrap_or(&"<could not get panic message>");
-            println!("Panicked with \"{}\" at {}", message, location);
-            println!("Backtrace:\n{}", std::backtrace::Backtrace::force_capture());
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+                .unwrap_or("<could not get panic message>");
+            println!(
+                "Panicked with \"{}\" at {}",
+                github::redact_tokens(message),
+                location
+            );
+            let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+            println!("Backtrace:\n{}", github::redact_tokens(&backtrace));
+            tracing::error!(
+                "Panicked with \"{}\" at {}",
+                github::redact_tokens(message),
+                location
+            );
+        }
+        if let Some(log) = logging::log_path() {
+            println!(
+                "Please include the log from {} in the report.",
+                log.display()
+            );
         }
     }));
 
+    interrupt::install();
+
     let res = if let Err(e) = cli::Cli::run().await {
-        crossterm::execute!(
-            stdout(),
-            crossterm::cursor::MoveToColumn(0),
-            crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
-        )
-        .ok(); // Not worth throwing an error if this doesn't work, just print the error
-        Err(e)
+        if cli::progress_enabled() {
+            crossterm::execute!(
+                stdout(),
+                crossterm::cursor::MoveToColumn(0),
+                crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine),
+            )
+            .ok(); // Not worth throwing an error if this doesn't work, just print the error
+        }
+        let code = error::exit_code_for(&e);
+        if code == error::exit_code::INTERRUPTED {
+            eprintln!("Interrupted");
+        } else {
+            tracing::debug!("Failed: {}", github::redact_tokens(&format!("{:?}", e)));
+            eprintln!("Error: {:?}", e);
+        }
+        ExitCode::from(code)
     } else {
-        Ok(())
+        ExitCode::SUCCESS
     };
-    crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+    if cli::progress_enabled() {
+        crossterm::execute!(std::io::stdout(), crossterm::cursor::Show).unwrap();
+    }
 
     res
 }
@@ -0,0 +1,132 @@
+//! Upgrades of config.toml from older versions of its format
+//! Each migration takes the document from one version to the next, so a config of any older
+//! version can be brought up to date by running the migrations after its version in order.
+//! Configs written before the `version` field existed are version 0.
+
+use anyhow::{anyhow, Result};
+use toml::{Table, Value};
+
+use crate::error::ConfinuumError;
+
+/// The config format version written by this build
+pub const CURRENT_VERSION: u32 = 1;
+
+/// A migration returns whether it changed anything other than the version number
+type Migration = fn(&mut Table) -> Result<bool>;
+
+/// The migration at index `n` upgrades a version `n` config to version `n + 1`
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [fill_defaults];
+
+/// Upgrade a parsed config.toml to the current version
+/// Returns whether the config had to be changed, beyond setting its version.
+pub fn migrate(document: &mut Table) -> Result<bool> {
+    let version = match document.get("confinuum").and_then(|c| c.get("version")) {
+        None => 0,
+        Some(Value::Integer(version)) => u32::try_from(*version)
+            .map_err(|_| anyhow!("Invalid config version {} in config.toml", version))?,
+        Some(other) => return Err(anyhow!("Invalid config version {} in config.toml", other)),
+    };
+    if version > CURRENT_VERSION {
+        return Err(ConfinuumError::ConfigTooNew(version, CURRENT_VERSION).into());
+    }
+    let mut changed = false;
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        changed |= migration(document)?;
+        confinuum_table(document)?.insert("version".to_owned(), Value::Integer(from as i64 + 1));
+    }
+    Ok(changed)
+}
+
+/// The [confinuum] table, created if the config doesn't have one
+fn confinuum_table(document: &mut Table) -> Result<&mut Table> {
+    document
+        .entry("confinuum")
+        .or_insert_with(|| Value::Table(Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("`confinuum` in config.toml must be a table"))
+}
+
+/// Version 0 to 1: early configs could be missing settings and entry fields that are now required
+/// The defaults are what confinuum did before the settings existed. A missing `target_dir` is left
+/// missing, since that's how an uninitialized entry is stored.
+fn fill_defaults(document: &mut Table) -> Result<bool> {
+    let mut changed = false;
+    let confinuum = confinuum_table(document)?;
+    for (key, default) in [("git_protocol", "ssh"), ("signature_source", "gitconfig")] {
+        if !confinuum.contains_key(key) {
+            confinuum.insert(key.to_owned(), Value::String(default.to_owned()));
+            changed = true;
+        }
+    }
+    for (name, entry) in document.iter_mut().filter(|(name, _)| *name != "confinuum") {
+        let entry = entry
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("Entry `{}` in config.toml must be a table", name))?;
+        if !entry.contains_key("files") {
+            entry.insert("files".to_owned(), Value::Array(Vec::new()));
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Table {
+        config.parse().unwrap()
+    }
+
+    #[test]
+    fn migrates_unversioned_configs() {
+        let mut document = parse(
+            r#"[confinuum]
+git_protocol = "https"
+
+[nvim]
+target_dir = "/home/user/.config/nvim"
+"#,
+        );
+        assert!(migrate(&mut document).unwrap());
+        let expected = parse(
+            r#"[confinuum]
+version = 1
+git_protocol = "https"
+signature_source = "gitconfig"
+
+[nvim]
+target_dir = "/home/user/.config/nvim"
+files = []
+"#,
+        );
+        assert_eq!(document, expected);
+    }
+
+    #[test]
+    fn leaves_current_configs_alone() {
+        let config = r#"[confinuum]
+version = 1
+git_protocol = "ssh"
+signature_source = "github"
+
+[nvim]
+target_dir = "/home/user/.config/nvim"
+files = ["init.lua"]
+"#;
+        let mut document = parse(config);
+        assert!(!migrate(&mut document).unwrap());
+        assert_eq!(document, parse(config));
+    }
+
+    #[test]
+    fn refuses_newer_configs() {
+        let mut document = parse(&format!("[confinuum]\nversion = {}\n", CURRENT_VERSION + 1));
+        let error = migrate(&mut document).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(ConfinuumError::ConfigTooNew(found, supported))
+                if *found == CURRENT_VERSION + 1 && *supported == CURRENT_VERSION
+        ));
+    }
+}
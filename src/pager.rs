@@ -0,0 +1,68 @@
+//! Paging of long command output, modeled after git's pager handling
+
+use std::{
+    env::var,
+    io::{IsTerminal, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::Result;
+
+const DEFAULT_PAGER: &str = "less -FRX";
+
+/// Resolve the pager command to use, or None if paging is disabled
+/// CONFINUUM_PAGER takes priority over PAGER, and setting either to `cat` or an empty string disables paging
+fn pager_command() -> Option<String> {
+    let pager = var("CONFINUUM_PAGER")
+        .or_else(|_| var("PAGER"))
+        .unwrap_or_else(|_| DEFAULT_PAGER.to_owned());
+    let pager = pager.trim();
+    if pager.is_empty() || pager == "cat" {
+        None
+    } else {
+        Some(pager.to_owned())
+    }
+}
+
+/// Whether the content is too tall to fit in the terminal
+fn exceeds_terminal(content: &[u8]) -> bool {
+    let lines = content.iter().filter(|b| **b == b'\n').count();
+    match crossterm::terminal::size() {
+        Ok((_, rows)) => lines >= rows as usize,
+        Err(_) => false,
+    }
+}
+
+/// Write the rendered content to stdout, piping it through the user's pager when stdout is a
+/// terminal and the content doesn't fit on screen. Falls back to printing directly if the pager
+/// can't be spawned.
+pub fn page(content: &[u8], no_pager: bool) -> Result<()> {
+    let stdout = std::io::stdout();
+    let pager = if no_pager || !stdout.is_terminal() || !exceeds_terminal(content) {
+        None
+    } else {
+        pager_command()
+    };
+
+    if let Some(pager) = pager {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&pager)
+            .env("LESS", var("LESS").unwrap_or_else(|_| "FRX".to_owned()))
+            .stdin(Stdio::piped())
+            .spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The pager exiting early (e.g. the user pressing q) closes the pipe, which isn't an error
+                stdin.write_all(content).ok();
+            }
+            child.wait()?;
+            return Ok(());
+        }
+    }
+
+    let mut stdout = stdout.lock();
+    stdout.write_all(content)?;
+    stdout.flush()?;
+    Ok(())
+}
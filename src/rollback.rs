@@ -0,0 +1,147 @@
+//! Undoing the changes a command made to the config repo when it fails before committing them
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use crossterm::style::Stylize;
+use git2::{ObjectType, Repository};
+
+use crate::{config::ConfinuumConfig, deployment, interrupt::Deferred};
+
+/// Restores the config repo when dropped, unless the changes were kept
+/// Files the command added to the given entries' directories are removed, config.toml is written
+/// back as it was, and anything staged for them is reset to HEAD. Files that were already there are left alone, along with any
+/// uncommitted edits to them. Being a `Deferred` section, Ctrl-C stops the command at its next
+/// check and rolls back, rather than exiting halfway.
+pub struct Rollback {
+    config_dir: PathBuf,
+    config_path: PathBuf,
+    config: Option<String>,
+    /// Each entry directory, with the paths that were in it (including itself, if it existed)
+    dirs: Vec<(PathBuf, HashSet<PathBuf>)>,
+    redeploy: HashSet<String>,
+    kept: bool,
+    _deferred: Deferred,
+}
+
+impl Rollback {
+    /// Take note of config.toml and the contents of the entries' directories
+    pub fn new(entries: &[&str]) -> Result<Self> {
+        let config_dir = ConfinuumConfig::get_dir().context("Failed to fetch config dir")?;
+        let config_path = ConfinuumConfig::get_path()?;
+        let config = fs::read_to_string(&config_path).ok();
+        let dirs = entries
+            .iter()
+            .map(|entry| {
+                let dir = config_dir.join(entry);
+                let paths = paths_in(&dir)?;
+                Ok((dir, paths))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            config_dir,
+            config_path,
+            config,
+            dirs,
+            redeploy: HashSet::new(),
+            kept: false,
+            _deferred: Deferred::new(),
+        })
+    }
+
+    /// Deploy the entry again when rolling back, for commands that undeploy it first
+    pub fn redeploy(&mut self, name: &str) {
+        self.redeploy.insert(name.to_owned());
+    }
+
+    /// The changes were committed (or can no longer be undone this way), so leave them
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+
+    /// Returns whether there was anything to undo
+    fn undo(&self) -> Result<bool> {
+        let mut changed = false;
+        for (dir, before) in &self.dirs {
+            let mut added = paths_in(dir)?
+                .difference(before)
+                .cloned()
+                .collect::<Vec<_>>();
+            // Deepest first, so directories are empty by the time they're removed
+            added.sort_by_key(|path| std::cmp::Reverse(path.components().count()));
+            for path in added {
+                if path.is_dir() && !path.is_symlink() {
+                    fs::remove_dir(&path)
+                } else {
+                    fs::remove_file(&path)
+                }
+                .with_context(|| format!("Could not remove {}", path.display()))?;
+                changed = true;
+            }
+        }
+        if let Some(config) = &self.config {
+            if fs::read_to_string(&self.config_path).ok().as_ref() != Some(config) {
+                fs::write(&self.config_path, config)
+                    .with_context(|| format!("Could not restore {}", self.config_path.display()))?;
+                changed = true;
+            }
+        }
+        if changed {
+            let repo = Repository::open(&self.config_dir).with_context(|| {
+                format!("Could not open repository in {}", self.config_dir.display())
+            })?;
+            let head = repo.head()?.peel(ObjectType::Commit)?;
+            let mut paths = self
+                .dirs
+                .iter()
+                .filter_map(|(dir, _)| dir.strip_prefix(&self.config_dir).ok())
+                .collect::<Vec<_>>();
+            paths.push(Path::new("config.toml"));
+            repo.reset_default(Some(&head), paths)
+                .context("Could not unstage the changes")?;
+        }
+        if !self.redeploy.is_empty() {
+            deployment::deploy(Some(&self.redeploy))?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+        match self.undo() {
+            Ok(true) => eprintln!("Rolled back the changes, nothing was committed"),
+            Ok(false) => {}
+            Err(e) => eprintln!(
+                "{} Could not roll back all of the changes: {:?}",
+                "!".yellow(),
+                e
+            ),
+        }
+    }
+}
+
+/// Every file and directory under `dir`, and `dir` itself, if it exists
+fn paths_in(dir: &Path) -> Result<HashSet<PathBuf>> {
+    let mut paths = HashSet::new();
+    if dir.symlink_metadata().is_err() {
+        return Ok(paths);
+    }
+    paths.insert(dir.to_path_buf());
+    if dir.is_dir() && !dir.is_symlink() {
+        for item in
+            fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?
+        {
+            paths.extend(paths_in(&item?.path())?);
+        }
+    }
+    Ok(paths)
+}
@@ -0,0 +1,317 @@
+//! Checking for and installing new releases of confinuum
+//! Releases are published on GitHub with a build for each target, named after its target triple
+//! (either the binary itself or a .tar.gz holding it), and a SHA-256 checksum for each build in
+//! `<build>.sha256` or in a SHA256SUMS file.
+
+use std::{
+    fs,
+    io::{IsTerminal, Read},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::ACCEPT;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{cli, config::ConfinuumConfig};
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/willothy/confinuum/releases/latest";
+
+/// The version of this binary
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The target triple this binary was built for, set by build.rs
+pub const TARGET: &str = env!("CONFINUUM_TARGET");
+
+/// How often the check after commands asks GitHub for the latest release
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long the check after commands waits for GitHub, so a slow network doesn't hold them up
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+const CHECK_FILE: &str = "update-check.toml";
+
+/// The names of the files a release can list every build's checksum in
+const CHECKSUM_FILES: [&str; 3] = ["SHA256SUMS", "sha256sums.txt", "checksums.txt"];
+
+#[derive(Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    pub html_url: String,
+    pub assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+    pub size: u64,
+}
+
+impl Release {
+    /// The release's version, from its tag without the leading v
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    /// Whether the release is newer than this binary
+    pub fn is_newer(&self) -> bool {
+        is_newer(self.version(), VERSION)
+    }
+
+    /// The build for the target this binary was built for
+    pub fn build(&self) -> Option<&Asset> {
+        self.assets.iter().find(|asset| {
+            asset.name.contains(TARGET)
+                && !asset.name.ends_with(".sha256")
+                && !CHECKSUM_FILES.contains(&asset.name.as_str())
+        })
+    }
+
+    /// The SHA-256 checksum the release lists for one of its assets, as lowercase hex
+    pub async fn checksum(&self, asset: &Asset) -> Result<String> {
+        let sum_file = format!("{}.sha256", asset.name);
+        let listed = self
+            .assets
+            .iter()
+            .find(|other| other.name == sum_file)
+            .or_else(|| {
+                self.assets
+                    .iter()
+                    .find(|other| CHECKSUM_FILES.contains(&other.name.as_str()))
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "The release has no checksum for {}, so it can't be verified",
+                    asset.name
+                )
+            })?;
+        let sums = String::from_utf8(download(listed).await?)
+            .with_context(|| format!("{} is not a checksum file", listed.name))?;
+        // Lines are `<checksum>  <file>`, or just the checksum in a file for one asset
+        sums.lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                Some((fields.next()?, fields.next()))
+            })
+            .find(|(_, file)| {
+                file.map_or(listed.name == sum_file, |file| {
+                    file.trim_start_matches('*') == asset.name
+                })
+            })
+            .map(|(sum, _)| sum.to_ascii_lowercase())
+            .ok_or_else(|| anyhow!("{} has no checksum for {}", listed.name, asset.name))
+    }
+}
+
+fn client(timeout: Option<Duration>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().user_agent(format!("confinuum/{}", VERSION));
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    Ok(builder.build()?)
+}
+
+/// Ask GitHub for the latest release, leaving out pre-releases
+pub async fn latest_release(timeout: Option<Duration>) -> Result<Release> {
+    let response = client(timeout)?
+        .get(LATEST_RELEASE_URL)
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await
+        .context("Could not reach GitHub")?;
+    tracing::debug!("Latest release request returned {}", response.status());
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Could not get the latest release from GitHub ({})",
+            response.status()
+        ));
+    }
+    let release: Release = response.json().await?;
+    record_check(Some(release.version()));
+    Ok(release)
+}
+
+/// Download one of a release's assets
+pub async fn download(asset: &Asset) -> Result<Vec<u8>> {
+    tracing::info!("Downloading {}", asset.browser_download_url);
+    let response = client(None)?
+        .get(&asset.browser_download_url)
+        .send()
+        .await
+        .with_context(|| format!("Could not download {}", asset.name))?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Could not download {} ({})",
+            asset.name,
+            response.status()
+        ));
+    }
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// The SHA-256 checksum of some bytes, as lowercase hex
+pub fn sha256(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Get the binary out of a downloaded build, which is either the binary or a .tar.gz holding it
+pub fn unpack_build(asset: &Asset, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if !asset.name.ends_with(".tar.gz") && !asset.name.ends_with(".tgz") {
+        return Ok(bytes);
+    }
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(bytes.as_slice()));
+    for file in archive
+        .entries()
+        .with_context(|| format!("Could not read {}", asset.name))?
+    {
+        let mut file = file.with_context(|| format!("Could not read {}", asset.name))?;
+        if file.path()?.file_name() == Some("confinuum".as_ref()) {
+            let mut binary = Vec::new();
+            file.read_to_end(&mut binary)
+                .with_context(|| format!("Could not read {}", asset.name))?;
+            return Ok(binary);
+        }
+    }
+    Err(anyhow!("{} has no confinuum binary in it", asset.name))
+}
+
+/// Compare two versions made of dot separated numbers, like 0.2.1
+/// A version with a pre-release suffix (0.3.0-beta.1) is older than the same version without one.
+/// Versions that can't be parsed are never newer.
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> Option<(Vec<u64>, bool)> {
+        let (numbers, pre) = match version.split_once('-') {
+            Some((numbers, _)) => (numbers, true),
+            None => (version, false),
+        };
+        let numbers = numbers
+            .split('.')
+            .map(|number| number.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+        // A release sorts after its pre-releases
+        Some((numbers, !pre))
+    }
+    match (parse(candidate), parse(current)) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+/// Whether the binary is managed by Homebrew, which should be used to update it instead
+pub fn installed_with_homebrew() -> bool {
+    std::env::current_exe()
+        .and_then(|exe| exe.canonicalize())
+        .is_ok_and(|exe| {
+            exe.components()
+                .any(|component| component.as_os_str() == "Cellar")
+        })
+}
+
+/// The command to update with, for the notice after commands
+fn update_command() -> &'static str {
+    if installed_with_homebrew() {
+        "brew upgrade confinuum"
+    } else {
+        "confinuum self-update"
+    }
+}
+
+/// When GitHub was last asked for the latest release, and what it was
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CheckState {
+    /// Seconds since the unix epoch
+    checked: u64,
+    latest: Option<String>,
+}
+
+/// Where the check state is kept, in $XDG_STATE_HOME/confinuum (or ~/.local/state/confinuum)
+fn check_path() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+    Some(state_dir.join("confinuum").join(CHECK_FILE))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn load_check(path: &Path) -> CheckState {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|state| toml::from_str(&state).ok())
+        .unwrap_or_default()
+}
+
+/// Remember that GitHub was just asked, and the latest version if it answered
+/// The state only saves a request a day, so failing to write it isn't worth reporting.
+fn record_check(latest: Option<&str>) {
+    let Some(path) = check_path() else {
+        return;
+    };
+    let mut state = load_check(&path);
+    state.checked = now();
+    if let Some(latest) = latest {
+        state.latest = Some(latest.to_owned());
+    }
+    let saved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, toml::to_string(&state).unwrap_or_default()));
+    if let Err(e) = saved {
+        tracing::debug!("Could not save {}: {}", path.display(), e);
+    }
+}
+
+/// Whether the check after commands was turned off, in the config or the environment
+fn check_disabled() -> bool {
+    std::env::var_os("CONFINUUM_NO_UPDATE_CHECK").is_some_and(|value| !value.is_empty())
+        || ConfinuumConfig::load().is_ok_and(|config| config.confinuum.no_update_check)
+}
+
+/// Print a line to stderr after a command if a newer release is available, asking GitHub at most
+/// once a day
+/// Only done on a terminal, so scripts and cron jobs never wait on GitHub, and never fails the
+/// command it runs after.
+pub async fn notify() {
+    if cli::quiet() || !std::io::stderr().is_terminal() || check_disabled() {
+        return;
+    }
+    let Some(path) = check_path() else {
+        return;
+    };
+    let state = load_check(&path);
+    let latest = if now().saturating_sub(state.checked) >= CHECK_INTERVAL.as_secs() {
+        match latest_release(Some(CHECK_TIMEOUT)).await {
+            Ok(release) => Some(release.version().to_owned()),
+            Err(e) => {
+                // Not asked again until tomorrow, rather than slowing down every command offline
+                tracing::debug!("Could not check for a new release: {:#}", e);
+                record_check(None);
+                state.latest
+            }
+        }
+    } else {
+        state.latest
+    };
+    if let Some(latest) = latest.filter(|latest| is_newer(latest, VERSION)) {
+        eprintln!(
+            "A newer confinuum is available ({} -> {}), run `{}` to update",
+            VERSION,
+            latest,
+            update_command()
+        );
+    }
+}